@@ -0,0 +1,144 @@
+use crate::control::{self, Request, Response};
+use crate::fusefs::FilesystemState;
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Server, StatusCode};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, error, info, warn};
+
+/// Maximum size of a request body.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+type HttpRequest = hyper::Request<Body>;
+type HttpResponse = hyper::Response<Body>;
+
+/// Serve the admin API on `addr`, so that a mounted filesystem can
+/// be managed over the network. Every request must carry the header
+/// 'Authorization: Bearer <token>'. The endpoints are:
+///
+/// * `POST /v1/request`: execute the control request in the body
+///   (e.g. `{"Mirror":{"path":"photos","store":"/mnt/backup"}}`)
+///   and return the final response. Paths are relative to the root
+///   of the filesystem.
+/// * `GET /v1/stats`, `/v1/health`, `/v1/stores`, `/v1/snapshots`
+///   and `/v1/jobs`: shorthands for the corresponding requests.
+pub async fn serve(addr: SocketAddr, token: String, state: Arc<RwLock<FilesystemState>>) {
+    let token = Arc::new(token);
+
+    let make_service = make_service_fn(move |_| {
+        let token = Arc::clone(&token);
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                handle(req, Arc::clone(&token), Arc::clone(&state))
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(err) => {
+            error!("Cannot listen on {}: {}", addr, err);
+            return;
+        }
+    };
+
+    info!("Admin API listening on {}.", addr);
+
+    if let Err(err) = server.await {
+        error!("Admin API failed: {}", err);
+    }
+}
+
+/// Compare the secrets `a` and `b` in time that doesn't depend on
+/// where they differ.
+fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn reply(status: StatusCode, body: Vec<u8>) -> HttpResponse {
+    hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn reply_error(status: StatusCode, msg: &str) -> HttpResponse {
+    let res = Response::Error { msg: msg.into() };
+    reply(status, serde_json::to_vec(&res).unwrap())
+}
+
+async fn read_request(req: HttpRequest) -> Result<Request, HttpResponse> {
+    let too_large = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+        .map_or(false, |len| len > MAX_BODY_SIZE);
+    if too_large {
+        return Err(reply_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request is too large.",
+        ));
+    }
+
+    // The length header is optional (e.g. with chunked encoding),
+    // so enforce the limit while reading as well.
+    let mut body = req.into_body();
+    let mut data = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| reply_error(StatusCode::BAD_REQUEST, &err.to_string()))?;
+        if data.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(reply_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request is too large.",
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+
+    serde_json::from_slice(&data)
+        .map_err(|err| reply_error(StatusCode::BAD_REQUEST, &err.to_string()))
+}
+
+async fn handle(
+    req: HttpRequest,
+    token: Arc<String>,
+    state: Arc<RwLock<FilesystemState>>,
+) -> Result<HttpResponse, hyper::Error> {
+    let expected = format!("Bearer {}", token);
+    let authorized = req.headers().get(AUTHORIZATION).map_or(false, |value| {
+        secrets_equal(value.as_bytes(), expected.as_bytes())
+    });
+    if !authorized {
+        warn!("Rejecting unauthorized admin request for '{}'.", req.uri());
+        return Ok(reply_error(StatusCode::UNAUTHORIZED, "Not authorized."));
+    }
+
+    debug!("Admin request: {} {}", req.method(), req.uri());
+
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let request = match (&method, path.as_str()) {
+        (&Method::POST, "/v1/request") => match read_request(req).await {
+            Ok(request) => request,
+            Err(res) => return Ok(res),
+        },
+        (&Method::GET, "/v1/stats") => Request::Stats {},
+        (&Method::GET, "/v1/health") => Request::Health {},
+        (&Method::GET, "/v1/stores") => Request::ListStores {},
+        (&Method::GET, "/v1/snapshots") => Request::ListSnapshots {},
+        (&Method::GET, "/v1/jobs") => Request::ListJobs {},
+        _ => return Ok(reply_error(StatusCode::NOT_FOUND, "No such endpoint.")),
+    };
+
+    let res = control::execute(request, state).await;
+    let status = match res {
+        Response::Error { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+        _ => StatusCode::OK,
+    };
+    Ok(reply(status, serde_json::to_vec(&res).unwrap()))
+}
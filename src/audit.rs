@@ -0,0 +1,65 @@
+use crate::error::Result;
+use crate::hash::Hash;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event")]
+pub enum AuditEvent {
+    /// A file was erased from the given stores after its last
+    /// reference was deleted.
+    Erased { hash: Hash, stores: Vec<String> },
+    /// A file wasn't erased because it was referenced again before
+    /// the erase ran.
+    EraseCancelled { hash: Hash },
+    /// Erasing a file from a store failed.
+    EraseFailed {
+        hash: Hash,
+        store: String,
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Seconds since the epoch.
+    time: u64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// An append-only file recording security-relevant events, one JSON
+/// object per line.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn record(&self, event: &AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(&Record {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            event,
+        })
+        .unwrap();
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+}
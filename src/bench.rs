@@ -0,0 +1,161 @@
+use crate::control::{self, Request, Response};
+use crate::error::{Error, Result};
+use crate::fusefs::FilesystemState;
+use serde::Serialize;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// Size of the writes used to create the large file.
+const WRITE_SIZE: usize = 1024 * 1024;
+
+/// The parameters of the `hugefs bench` workload. Runs with the same
+/// parameters are comparable.
+#[derive(Debug, Clone, Serialize)]
+pub struct Workload {
+    pub small_files: usize,
+    pub small_file_size: usize,
+    pub large_file_size: u64,
+    pub random_reads: usize,
+    pub read_size: usize,
+    pub stats: usize,
+}
+
+/// The result of one phase of the workload.
+#[derive(Debug, Serialize)]
+pub struct Phase {
+    pub name: String,
+    pub ops: u64,
+    pub bytes: u64,
+    pub seconds: f64,
+}
+
+impl Phase {
+    pub fn ops_per_sec(&self) -> f64 {
+        self.ops as f64 / self.seconds
+    }
+
+    pub fn mib_per_sec(&self) -> f64 {
+        self.bytes as f64 / (1024.0 * 1024.0) / self.seconds
+    }
+}
+
+/// A deterministic pseudo-random generator (xorshift64), so that
+/// every run writes the same data and reads the same offsets.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+fn timed(name: &str, f: impl FnOnce() -> Result<(u64, u64)>) -> Result<Phase> {
+    let start = Instant::now();
+    let (ops, bytes) = f()?;
+    Ok(Phase {
+        name: name.into(),
+        ops,
+        bytes,
+        seconds: start.elapsed().as_secs_f64().max(1e-9),
+    })
+}
+
+/// Run `workload` against the filesystem `state` mounted on
+/// `mount_point`, which should be empty. The large file is finalized
+/// before it's read back, so that the reads go through the stores.
+pub fn run(
+    workload: &Workload,
+    mount_point: &Path,
+    state: &Arc<RwLock<FilesystemState>>,
+    rt: &mut tokio::runtime::Runtime,
+) -> Result<Vec<Phase>> {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut phases = vec![];
+
+    let small_dir = mount_point.join("small");
+    std::fs::create_dir(&small_dir)?;
+    let small_path = |n: usize| -> PathBuf { small_dir.join(format!("{:08}", n)) };
+
+    let mut data = vec![0u8; workload.small_file_size];
+    rng.fill(&mut data);
+    phases.push(timed("small-file create", || {
+        for n in 0..workload.small_files {
+            std::fs::File::create(small_path(n))?.write_all(&data)?;
+        }
+        Ok((
+            workload.small_files as u64,
+            (workload.small_files * workload.small_file_size) as u64,
+        ))
+    })?);
+
+    let large_path = mount_point.join("large");
+    let mut buf = vec![0u8; WRITE_SIZE];
+    phases.push(timed("large sequential write", || {
+        let mut file = std::fs::File::create(&large_path)?;
+        let mut written = 0;
+        let mut ops = 0;
+        while written < workload.large_file_size {
+            let n = std::cmp::min(WRITE_SIZE as u64, workload.large_file_size - written) as usize;
+            rng.fill(&mut buf[..n]);
+            file.write_all(&buf[..n])?;
+            written += n as u64;
+            ops += 1;
+        }
+        file.sync_all()?;
+        Ok((ops, written))
+    })?);
+
+    let req = Request::Finalize {
+        path: "large".into(),
+        recursive: false,
+    };
+    match rt.block_on(control::execute(req, Arc::clone(state))) {
+        Response::Finalize(_) => {}
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected response."),
+    }
+
+    let mut buf = vec![0u8; workload.read_size];
+    phases.push(timed("random read", || {
+        let blocks = workload.large_file_size / workload.read_size as u64;
+        if blocks == 0 {
+            return Ok((0, 0));
+        }
+        let mut file = std::fs::File::open(&large_path)?;
+        for _ in 0..workload.random_reads {
+            let offset = (rng.next() % blocks) * workload.read_size as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        Ok((
+            workload.random_reads as u64,
+            (workload.random_reads * workload.read_size) as u64,
+        ))
+    })?);
+
+    phases.push(timed("metadata stat storm", || {
+        if workload.small_files == 0 {
+            return Ok((0, 0));
+        }
+        for n in 0..workload.stats {
+            std::fs::metadata(small_path(n % workload.small_files))?;
+        }
+        Ok((workload.stats as u64, 0))
+    })?);
+
+    Ok(phases)
+}
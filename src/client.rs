@@ -0,0 +1,76 @@
+use crate::control::{Request, Response};
+use crate::error::{Error, Result};
+use crate::fusefs;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Seek, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Find the root of the mounted hugefs filesystem containing `path`.
+/// Returns the root and the path of `path` relative to the root.
+pub fn get_fs_root(path: &Path) -> Result<(PathBuf, PathBuf)> {
+    let mut path = PathBuf::from(path);
+    let mut sub: Vec<OsString> = vec![];
+
+    loop {
+        if path.clone().join(fusefs::CONTROL_NAME).exists() {
+            let mut sub2 = PathBuf::new();
+            for s in sub.iter().rev() {
+                sub2 = sub2.join(s);
+            }
+            debug!("Found root '{}', sub '{}'.", path.display(), sub2.display());
+            return Ok((path.into(), sub2));
+        }
+        if let Some(file_name) = path.file_name() {
+            sub.push(file_name.into());
+        }
+        if !path.pop() {
+            return Err(Error::NotHugefs);
+        }
+    }
+}
+
+/// Send a request to the daemon serving the filesystem mounted at
+/// `root`, and wait for its response. `progress` is called with the
+/// progress updates sent while the request runs. The batches of a
+/// `StatusTree` response are combined into one.
+pub fn execute_request(
+    root: &Path,
+    req: Request,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<Response> {
+    let control_path = root.join(fusefs::CONTROL_NAME);
+
+    let mut control_file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(control_path)?;
+
+    let mut req_s = serde_json::to_string(&req).unwrap();
+    req_s.push('\n');
+
+    control_file.write_all(req_s.as_bytes())?;
+
+    control_file.seek(std::io::SeekFrom::Start(0))?;
+
+    let mut entries = vec![];
+
+    for line in BufReader::new(control_file).lines() {
+        let res = serde_json::from_str(&line?).map_err(|_| Error::BadControlResponse)?;
+
+        debug!("Control response: {:?}", res);
+
+        match res {
+            Response::Progress { done, total } => progress(done, total),
+            Response::StatusTreeEntries { mut files } => entries.append(&mut files),
+            Response::StatusTree(mut res) => {
+                entries.append(&mut res.files);
+                res.files = entries;
+                return Ok(Response::StatusTree(res));
+            }
+            res => return Ok(res),
+        }
+    }
+
+    Err(Error::BadControlResponse)
+}
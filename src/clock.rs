@@ -0,0 +1,44 @@
+/* A clock that can be overridden, allowing tests to simulate the
+ * passage of time. */
+
+use std::time::SystemTime;
+
+#[cfg(test)]
+use std::{cell::Cell, time::Duration};
+
+#[cfg(test)]
+thread_local! {
+    /// The mock time, or `None` if the system clock should be used.
+    /// It's per thread so that tests running in parallel don't see
+    /// each other's clocks.
+    static MOCK_TIME: Cell<Option<SystemTime>> = Cell::new(None);
+}
+
+#[cfg(not(test))]
+pub fn now() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(test)]
+pub fn now() -> SystemTime {
+    MOCK_TIME.with(|t| t.get()).unwrap_or_else(SystemTime::now)
+}
+
+/// Make `now()` on this thread return `time` until the clock is
+/// advanced or reset.
+#[cfg(test)]
+pub fn set_mock_time(time: SystemTime) {
+    MOCK_TIME.with(|t| t.set(Some(time)));
+}
+
+/// Advance the mock time by `d`.
+#[cfg(test)]
+pub fn advance(d: Duration) {
+    MOCK_TIME.with(|t| t.set(t.get().map(|time| time + d)));
+}
+
+/// Go back to using the system clock.
+#[cfg(test)]
+pub fn reset() {
+    MOCK_TIME.with(|t| t.set(None));
+}
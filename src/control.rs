@@ -1,31 +1,331 @@
 use crate::{
+    audit::AuditEvent,
+    encrypted_store::Key,
     error::{Error, Result},
-    fs::{Contents, Ino},
-    fusefs::FilesystemState,
+    fs::{
+        Contents, Ino, Inode, Media, Quota, Superblock, TierPolicy, Time, Usage, SUPERBLOCK_VERSION,
+    },
+    fusefs::{finalize_file, FilesystemState, SNAPSHOTS_NAME},
     hash::Hash,
+    jobs::{Job, JobKind, JobStatus},
+    local_store::LocalStore,
+    power::PowerStatus,
+    stats::{OpMetrics, StoreCounters},
+    store::{RecallInfo, Store},
 };
-use log::debug;
 use serde::{Deserialize, Serialize};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Status { path: PathBuf },
-    Mirror { path: PathBuf, store: String },
+    Status {
+        path: PathBuf,
+    },
+    Mirror {
+        path: PathBuf,
+        store: String,
+    },
+    ListStores {},
+    StatusTree {
+        path: PathBuf,
+    },
+    Finalize {
+        path: PathBuf,
+        recursive: bool,
+    },
+    AddStore {
+        url: String,
+        key_file: Option<PathBuf>,
+    },
+    RemoveStore {
+        url: String,
+    },
+    DiskUsage {
+        path: PathBuf,
+    },
+    WhichPath {
+        hash: Hash,
+    },
+    Freeze {},
+    Thaw {},
+    Stats {},
+    Health {},
+    RegisterMedia {
+        name: String,
+        url: String,
+    },
+    AttachMedia {
+        name: String,
+    },
+    DetachMedia {
+        name: String,
+    },
+    Exclude {
+        path: PathBuf,
+        store: String,
+        remove: bool,
+    },
+    EnqueueJob {
+        kind: JobKind,
+        priority: i32,
+        idempotency_key: Option<String>,
+        callback: Option<String>,
+    },
+    ListJobs {},
+    SetQuota {
+        path: PathBuf,
+        /// The new quota, or `None` to remove it.
+        quota: Option<Quota>,
+    },
+    ListQuotas {},
+    SetTierPolicy {
+        path: PathBuf,
+        /// The new policy, or `None` to remove it.
+        policy: Option<TierPolicy>,
+    },
+    ListTierPolicies {},
+    Evict {
+        path: PathBuf,
+        store: String,
+    },
+    Pin {
+        path: PathBuf,
+        store: String,
+        /// Whether to unpin the files instead.
+        remove: bool,
+    },
+    SetStagingStore {
+        path: PathBuf,
+        /// The store on which new files are created, or `None` to
+        /// use the first suitable store.
+        store: Option<String>,
+    },
+    Adopt {
+        /// Absolute path of a file outside the filesystem.
+        src: PathBuf,
+        dest: PathBuf,
+        /// The store to add the file to, or `None` for the first
+        /// suitable store.
+        store: Option<String>,
+        /// Whether to hard link the file into the store if possible.
+        link: bool,
+    },
+    Clone {
+        src: PathBuf,
+        dst: PathBuf,
+    },
+    /// Create `path` as an immutable file with the given contents,
+    /// which must already be present in a store.
+    Link {
+        path: PathBuf,
+        hash: Hash,
+        size: u64,
+    },
+    SetReadStore {
+        path: PathBuf,
+        /// The store to read from, or `None` to remove the override.
+        store: Option<String>,
+        /// The process to which the override applies, or `None` for
+        /// all processes.
+        pid: Option<u32>,
+    },
+    CreateSnapshot {
+        name: String,
+    },
+    ListSnapshots {},
+    DeleteSnapshot {
+        name: String,
+    },
+    Diff {
+        old: PathBuf,
+        new: PathBuf,
+    },
+    ListRecalls {},
+    /// Group the files of each directory below `path` together in
+    /// the stores that support it (see `Store::cluster()`).
+    Cluster {
+        path: PathBuf,
+    },
+    /// Delete the files in the local store `store` that no
+    /// filesystem references (see `LocalStore::gc()`).
+    Gc {
+        store: String,
+        dry_run: bool,
+    },
+    /// Return the files below `path` that match `filter`.
+    Find {
+        path: PathBuf,
+        filter: FindFilter,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Error { msg: String },
+    Error {
+        msg: String,
+    },
     Status(StatusResponse),
     Mirror(MirrorResponse),
+    ListStores(ListStoresResponse),
+    StatusTree(StatusTreeResponse),
+    /// A batch of the entries of a `StatusTree` request, sent
+    /// before the final response, which holds the last batch.
+    StatusTreeEntries {
+        files: Vec<TreeEntry>,
+    },
+    Finalize(FinalizeResponse),
+    Progress {
+        done: u64,
+        total: u64,
+    },
+    AddStore {},
+    RemoveStore {},
+    DiskUsage(DiskUsageResponse),
+    WhichPath {
+        paths: Vec<PathBuf>,
+    },
+    Freeze(FreezeResponse),
+    Thaw {},
+    Stats(StatsResponse),
+    Health(HealthReport),
+    RegisterMedia {},
+    AttachMedia {
+        present: usize,
+        copied: usize,
+    },
+    DetachMedia {
+        present: usize,
+    },
+    Exclude {
+        files: usize,
+    },
+    EnqueueJob {
+        id: u64,
+        existing: bool,
+    },
+    ListJobs {
+        jobs: Vec<Job>,
+    },
+    SetQuota {},
+    ListQuotas {
+        quotas: Vec<QuotaInfo>,
+    },
+    SetTierPolicy {},
+    ListTierPolicies {
+        policies: Vec<TierPolicyInfo>,
+    },
+    Evict {
+        removed: bool,
+    },
+    Pin {
+        files: usize,
+    },
+    SetStagingStore {
+        dirs: usize,
+    },
+    Adopt {
+        hash: Hash,
+        size: u64,
+        store: String,
+    },
+    Clone {
+        ino: Ino,
+    },
+    Link {
+        store: String,
+    },
+    SetReadStore {},
+    CreateSnapshot {},
+    ListSnapshots {
+        snapshots: Vec<SnapshotInfo>,
+    },
+    DeleteSnapshot {},
+    Diff {
+        changes: Vec<DiffEntry>,
+    },
+    ListRecalls {
+        recalls: Vec<RecallInfo>,
+    },
+    Cluster {
+        clusters: usize,
+        moved: usize,
+    },
+    Gc {
+        deleted: Vec<Hash>,
+    },
+    Find {
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuotaInfo {
+    pub path: PathBuf,
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TierPolicyInfo {
+    pub path: PathBuf,
+    pub policy: TierPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created: Time,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// The criteria of `Request::Find`. Only regular files match; fields
+/// that are not set don't restrict the result.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FindFilter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// "mutable" or "immutable".
+    pub file_type: Option<String>,
+    /// Only files modified less than this many days ago.
+    pub newer_than_days: Option<u64>,
+    /// Only files modified at least this many days ago.
+    pub older_than_days: Option<u64>,
+    /// Only files that the attached store with this URL has.
+    pub present_in: Option<String>,
+    /// Only files that the attached store with this URL lacks.
+    pub missing_from: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffEntry {
+    /// The path relative to the directories being compared.
+    pub path: PathBuf,
+    pub change: Change,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StatusResponse {
     pub ino: Ino,
     pub info: FileType,
+    #[serde(default)]
+    pub excluded_stores: Vec<String>,
+    #[serde(default)]
+    pub pinned_stores: Vec<String>,
+    #[serde(default)]
+    pub staging_store: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,14 +333,165 @@ pub struct MirrorResponse {
     pub from: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusTreeResponse {
+    pub files: Vec<TreeEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TreeEntry {
+    pub path: PathBuf,
+    pub ino: Ino,
+    pub info: FileType,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FinalizeResponse {
+    pub files: Vec<FinalizedFile>,
+    /// Files skipped because they're open for writing or already
+    /// being finalized.
+    #[serde(default)]
+    pub busy: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FinalizedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: Hash,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FreezeResponse {
+    /// The immutable files in the frozen filesystem.
+    pub manifest: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: Hash,
+    pub stores: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiskUsageResponse {
+    /// Sum of the sizes of all files.
+    pub logical_size: u64,
+    /// Sum of the sizes of all distinct immutable files.
+    pub unique_size: u64,
+    /// Sum of the sizes of mutable files.
+    pub mutable_size: u64,
+    pub stores: Vec<StoreUsage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoreUsage {
+    pub url: String,
+    /// Sum of the sizes of the distinct immutable files present in
+    /// this store.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub power: PowerStatus,
+    pub mirrors_verified: u64,
+    pub mirror_verification_failures: u64,
+    /// Seconds since the filesystem was mounted.
+    #[serde(default)]
+    pub uptime: u64,
+    /// Number of FUSE requests by type.
+    #[serde(default)]
+    pub ops: BTreeMap<String, u64>,
+    /// Concurrency and latency of FUSE requests by type.
+    #[serde(default)]
+    pub op_metrics: BTreeMap<String, OpMetrics>,
+    #[serde(default)]
+    pub stores: BTreeMap<String, StoreCounters>,
+    #[serde(default)]
+    pub readahead_hits: u64,
+    #[serde(default)]
+    pub readahead_misses: u64,
+    /// Bytes written to files whose contents already existed.
+    #[serde(default)]
+    pub deduplicated_bytes: u64,
+    /// The stores that failed their last health check, with the
+    /// error.
+    #[serde(default)]
+    pub unhealthy_stores: BTreeMap<String, String>,
+}
+
+/// The result of a consistency check of the filesystem.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Format version of the state file when it was loaded.
+    pub schema_version: u32,
+    /// Number of files that haven't been finalized.
+    pub mutable_files: usize,
+    /// Number of orphaned mutable files in each store, found at
+    /// mount time.
+    pub orphaned_files: BTreeMap<String, usize>,
+    /// The stores that failed their last health check, with the
+    /// error.
+    pub unhealthy_stores: BTreeMap<String, String>,
+}
+
+impl HealthReport {
+    pub fn new(fs: &FilesystemState) -> Self {
+        Self {
+            schema_version: fs.schema_version,
+            mutable_files: fs.superblock.mutable_files(),
+            orphaned_files: fs.orphaned_files.clone(),
+            unhealthy_stores: fs
+                .unhealthy_stores()
+                .iter()
+                .map(|(url, err)| (url.clone(), err.clone()))
+                .collect(),
+        }
+    }
+
+    /// Return whether the filesystem needs no attention. Mutable
+    /// files are normal and don't count.
+    pub fn is_clean(&self) -> bool {
+        self.schema_version == SUPERBLOCK_VERSION
+            && self.orphaned_files.is_empty()
+            && self.unhealthy_stores.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListStoresResponse {
+    pub stores: Vec<StoreInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoreInfo {
+    pub url: String,
+    pub writable: bool,
+    #[serde(default)]
+    pub reference: bool,
+    pub key_fingerprint: Option<String>,
+    pub error: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum FileType {
-    Directory {},
+    Directory {
+        /// The tree hash of the directory, if it contains no mutable
+        /// files.
+        #[serde(default)]
+        tree_hash: Option<Hash>,
+    },
     ImmutableFile {
         size: u64,
         hash: Hash,
         stores: Vec<String>,
+        /// Detached media that have a copy of this file.
+        #[serde(default)]
+        offline_media: Vec<String>,
     },
     MutableFile {},
     Symlink {},
@@ -57,23 +508,89 @@ impl FileType {
     }
 }
 
+/// Sink for the newline-separated responses written by the daemon
+/// to a control file. Zero or more `Response::Progress` lines may
+/// precede the final response.
+#[derive(Clone)]
+pub struct Progress {
+    tx: UnboundedSender<String>,
+}
+
+impl Progress {
+    fn send(&self, res: &Response) {
+        let mut res = serde_json::to_string(res).unwrap();
+        debug!("Control response: {}", res);
+        res.push('\n');
+        // The client may have closed the control file already.
+        let _ = self.tx.send(res);
+    }
+
+    pub fn report(&self, done: u64, total: u64) {
+        self.send(&Response::Progress { done, total });
+    }
+}
+
+/// The daemon's side of the output of a control file.
+pub struct ControlOutput {
+    /// Output that hasn't been read yet.
+    buf: Vec<u8>,
+    /// The offset of the first byte in `buf`.
+    start: usize,
+    rx: UnboundedReceiver<String>,
+}
+
+impl ControlOutput {
+    /// Return up to `size` bytes of output starting at `offset`,
+    /// waiting until some data at that offset is available or the
+    /// request has finished. Output before `offset` is discarded,
+    /// so it can't be read again.
+    pub async fn read(&mut self, offset: u64, size: u32) -> Vec<u8> {
+        let offset = offset as usize;
+        if offset < self.start {
+            return vec![];
+        }
+        while self.start + self.buf.len() <= offset {
+            match self.rx.recv().await {
+                Some(s) => self.buf.extend_from_slice(s.as_bytes()),
+                None => break,
+            }
+        }
+        let consumed = std::cmp::min(offset - self.start, self.buf.len());
+        self.buf.drain(..consumed);
+        self.start += consumed;
+        self.buf.iter().take(size as usize).map(|s| *s).collect()
+    }
+}
+
+pub fn output_channel() -> (Progress, ControlOutput) {
+    let (tx, rx) = unbounded_channel();
+    (
+        Progress { tx },
+        ControlOutput {
+            buf: vec![],
+            start: 0,
+            rx,
+        },
+    )
+}
+
 pub async fn handle_message(
-    rx: tokio::sync::mpsc::UnboundedReceiver<u8>,
+    rx: UnboundedReceiver<u8>,
+    progress: Progress,
     fs: Arc<RwLock<FilesystemState>>,
-) -> String {
-    let res = match handle_inner(rx, fs).await {
+) {
+    let res = match handle_inner(rx, &progress, fs).await {
         Ok(res) => res,
         Err(err) => Response::Error {
             msg: err.to_string(),
         },
     };
-    let res = serde_json::to_string(&res).unwrap();
-    debug!("Control response: {}", res);
-    res
+    progress.send(&res);
 }
 
 async fn handle_inner(
-    mut rx: tokio::sync::mpsc::UnboundedReceiver<u8>,
+    mut rx: UnboundedReceiver<u8>,
+    progress: &Progress,
     fs: Arc<RwLock<FilesystemState>>,
 ) -> Result<Response> {
     let mut req = Vec::new();
@@ -91,88 +608,1807 @@ async fn handle_inner(
 
     let req: Request = serde_json::from_str(&req).map_err(|_| Error::BadControlRequest)?;
 
+    dispatch(req, progress, fs).await
+}
+
+/// Execute a control request that didn't come through a control
+/// file, e.g. from the admin API. Progress reports are discarded.
+pub async fn execute(req: Request, fs: Arc<RwLock<FilesystemState>>) -> Response {
+    let (progress, _) = output_channel();
+    let res = match req {
+        // There is nobody to stream the batches to.
+        Request::StatusTree { path } => {
+            let mut files = vec![];
+            let res = handle_status_tree(&path, &mut |batch| files.extend(batch), fs).await;
+            res.map(|mut res| {
+                files.append(&mut res.files);
+                Response::StatusTree(StatusTreeResponse { files })
+            })
+        }
+        req => dispatch(req, &progress, fs).await,
+    };
+    match res {
+        Ok(res) => res,
+        Err(err) => Response::Error {
+            msg: err.to_string(),
+        },
+    }
+}
+
+async fn dispatch(
+    req: Request,
+    progress: &Progress,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<Response> {
     match req {
         Request::Status { path } => handle_status(&path, fs).await.map(|x| Response::Status(x)),
-        Request::Mirror { path, store } => handle_mirror(&path, &store, fs)
+        Request::Mirror { path, store } => handle_mirror(&path, &store, progress, fs)
             .await
             .map(|x| Response::Mirror(x)),
+        Request::ListStores {} => handle_list_stores(fs)
+            .await
+            .map(|x| Response::ListStores(x)),
+        Request::StatusTree { path } => handle_status_tree(
+            &path,
+            &mut |files| progress.send(&Response::StatusTreeEntries { files }),
+            fs,
+        )
+        .await
+        .map(|x| Response::StatusTree(x)),
+        Request::Finalize { path, recursive } => handle_finalize(&path, recursive, progress, fs)
+            .await
+            .map(|x| Response::Finalize(x)),
+        Request::Freeze {} => handle_freeze(progress, fs)
+            .await
+            .map(|x| Response::Freeze(x)),
+        Request::Thaw {} => {
+            fs.write().unwrap().superblock.frozen = false;
+            info!("Filesystem thawed.");
+            Ok(Response::Thaw {})
+        }
+        Request::Stats {} => {
+            let fs = fs.read().unwrap();
+            Ok(Response::Stats(StatsResponse {
+                power: fs.power.status(),
+                mirrors_verified: fs.mirror_stats.verified,
+                mirror_verification_failures: fs.mirror_stats.failed,
+                uptime: fs.counters.uptime(),
+                ops: fs.counters.ops(),
+                op_metrics: fs.counters.op_metrics(),
+                stores: fs.counters.stores(),
+                readahead_hits: fs.counters.readahead_hits(),
+                readahead_misses: fs.counters.readahead_misses(),
+                deduplicated_bytes: fs.counters.deduplicated_bytes(),
+                unhealthy_stores: fs
+                    .unhealthy_stores()
+                    .iter()
+                    .map(|(url, err)| (url.clone(), err.clone()))
+                    .collect(),
+            }))
+        }
+        Request::Health {} => Ok(Response::Health(HealthReport::new(&fs.read().unwrap()))),
+        Request::ListRecalls {} => {
+            let stores = fs.read().unwrap().stores.clone();
+            Ok(Response::ListRecalls {
+                recalls: stores.iter().flat_map(|store| store.recalls()).collect(),
+            })
+        }
+        Request::Cluster { path } => handle_cluster(&path, fs)
+            .await
+            .map(|(clusters, moved)| Response::Cluster { clusters, moved }),
+        Request::Gc { store, dry_run } => handle_gc(&store, dry_run, fs)
+            .await
+            .map(|deleted| Response::Gc { deleted }),
+        Request::Find { path, filter } => handle_find(&path, &filter, fs)
+            .await
+            .map(|paths| Response::Find { paths }),
+        Request::RegisterMedia { name, url } => {
+            let mut fs = fs.write().unwrap();
+            if fs.superblock.media.contains_key(&name) {
+                return Err(Error::DuplicateStore(name));
+            }
+            fs.superblock.media.insert(
+                name,
+                Media {
+                    url,
+                    hashes: HashSet::new(),
+                },
+            );
+            Ok(Response::RegisterMedia {})
+        }
+        Request::AttachMedia { name } => handle_attach_media(&name, progress, fs)
+            .await
+            .map(|(present, copied)| Response::AttachMedia { present, copied }),
+        Request::DetachMedia { name } => handle_detach_media(&name, fs)
+            .await
+            .map(|present| Response::DetachMedia { present }),
+        Request::Exclude {
+            path,
+            store,
+            remove,
+        } => {
+            let fs = fs.read().unwrap();
+            let inode = fs.superblock.lookup_path(&path)?;
+            if !remove && inode.read().unwrap().pinned_stores.contains(&store) {
+                return Err(Error::Pinned(store, path));
+            }
+            let files = set_exclusion(&fs.superblock, &inode, &store, remove)?;
+            Ok(Response::Exclude { files })
+        }
+        Request::EnqueueJob {
+            kind,
+            priority,
+            idempotency_key,
+            callback,
+        } => {
+            let (id, existing) = fs.write().unwrap().superblock.jobs.enqueue(
+                kind,
+                priority,
+                idempotency_key,
+                callback,
+            )?;
+            Ok(Response::EnqueueJob { id, existing })
+        }
+        Request::ListJobs {} => Ok(Response::ListJobs {
+            jobs: fs.read().unwrap().superblock.jobs.list(),
+        }),
+        Request::SetQuota { path, quota } => {
+            let mut fs = fs.write().unwrap();
+            let inode = fs.superblock.lookup_path(&path)?;
+            let ino = {
+                let inode = inode.read().unwrap();
+                inode.get_directory()?;
+                inode.ino
+            };
+            fs.superblock.set_quota(ino, quota);
+            Ok(Response::SetQuota {})
+        }
+        Request::ListQuotas {} => {
+            let fs = fs.read().unwrap();
+            let root = fs.superblock.get_inode(fs.superblock.get_root_ino())?;
+            let mut dirs = vec![];
+            walk_tree(
+                &fs.superblock,
+                &root,
+                Path::new(""),
+                &mut |path, _, inode| {
+                    if fs.superblock.quotas.contains_key(&inode.ino) {
+                        dirs.push((path.to_path_buf(), inode.ino));
+                    }
+                    Ok(())
+                },
+            )?;
+            let quotas = dirs
+                .into_iter()
+                .map(|(path, ino)| QuotaInfo {
+                    path,
+                    quota: fs.superblock.quotas[&ino].clone(),
+                    usage: fs.superblock.usage(ino),
+                })
+                .collect();
+            Ok(Response::ListQuotas { quotas })
+        }
+        Request::SetTierPolicy { path, policy } => {
+            let mut fs = fs.write().unwrap();
+            let inode = fs.superblock.lookup_path(&path)?;
+            let ino = {
+                let inode = inode.read().unwrap();
+                inode.get_directory()?;
+                inode.ino
+            };
+            match policy {
+                Some(policy) => {
+                    if policy.fast == policy.archive {
+                        return Err(Error::BadArguments(
+                            "the fast and archive stores must differ".into(),
+                        ));
+                    }
+                    fs.superblock.tier_policies.insert(ino, policy)
+                }
+                None => fs.superblock.tier_policies.remove(&ino),
+            };
+            Ok(Response::SetTierPolicy {})
+        }
+        Request::ListTierPolicies {} => {
+            let fs = fs.read().unwrap();
+            let root = fs.superblock.get_inode(fs.superblock.get_root_ino())?;
+            let mut policies = vec![];
+            walk_tree(
+                &fs.superblock,
+                &root,
+                Path::new(""),
+                &mut |path, _, inode| {
+                    if let Some(policy) = fs.superblock.tier_policies.get(&inode.ino) {
+                        policies.push(TierPolicyInfo {
+                            path: path.to_path_buf(),
+                            policy: policy.clone(),
+                        });
+                    }
+                    Ok(())
+                },
+            )?;
+            Ok(Response::ListTierPolicies { policies })
+        }
+        Request::Evict { path, store } => handle_evict(&path, &store, fs)
+            .await
+            .map(|removed| Response::Evict { removed }),
+        Request::Pin {
+            path,
+            store,
+            remove,
+        } => {
+            let fs = fs.read().unwrap();
+            let inode = fs.superblock.lookup_path(&path)?;
+            if !remove && inode.read().unwrap().excluded_stores.contains(&store) {
+                return Err(Error::StoreExcluded(store, path));
+            }
+            let files = set_pin(&fs.superblock, &inode, &store, remove)?;
+            Ok(Response::Pin { files })
+        }
+        Request::SetStagingStore { path, store } => {
+            let fs = fs.read().unwrap();
+            let inode = fs.superblock.lookup_path(&path)?;
+            inode.read().unwrap().get_directory()?;
+            if let Some(url) = &store {
+                let st = fs
+                    .stores
+                    .iter()
+                    .find(|st| st.get_url() == *url)
+                    .ok_or_else(|| Error::UnknownStore(url.clone()))?;
+                if st.is_reference() {
+                    return Err(Error::ReferenceStore(url.clone()));
+                }
+                if !st.is_writable() {
+                    return Err(Error::BadArguments(format!(
+                        "store '{}' cannot stage new files",
+                        url
+                    )));
+                }
+                if inode.read().unwrap().excluded_stores.contains(url) {
+                    return Err(Error::StoreExcluded(url.clone(), path));
+                }
+            }
+            let dirs = set_staging_store(&fs.superblock, &inode, store.as_deref())?;
+            Ok(Response::SetStagingStore { dirs })
+        }
+        Request::Adopt {
+            src,
+            dest,
+            store,
+            link,
+        } => handle_adopt(&src, &dest, store.as_deref(), link, fs).await,
+        Request::Clone { src, dst } => {
+            handle_clone(&src, &dst, fs).map(|ino| Response::Clone { ino })
+        }
+        Request::Link { path, hash, size } => handle_link(&path, hash, size, fs)
+            .await
+            .map(|store| Response::Link { store }),
+        Request::SetReadStore { path, store, pid } => {
+            let mut fs = fs.write().unwrap();
+            let ino = {
+                let inode = fs.superblock.lookup_path(&path)?;
+                let inode = inode.read().unwrap();
+                if !inode.is_file() {
+                    return Err(Error::BadArguments(format!(
+                        "'{}' is not a file",
+                        path.display()
+                    )));
+                }
+                inode.ino
+            };
+            match store {
+                Some(url) => {
+                    if !fs.stores.iter().any(|st| st.get_url() == url) {
+                        return Err(Error::UnknownStore(url));
+                    }
+                    fs.read_overrides.insert((ino, pid), url);
+                }
+                None => {
+                    fs.read_overrides.remove(&(ino, pid));
+                }
+            }
+            Ok(Response::SetReadStore {})
+        }
+        Request::CreateSnapshot { name } => {
+            let mut fs = fs.write().unwrap();
+            fs.superblock.check_writable()?;
+            fs.superblock.create_snapshot(&name)?;
+            info!("Created snapshot '{}'.", name);
+            Ok(Response::CreateSnapshot {})
+        }
+        Request::ListSnapshots {} => {
+            let fs = fs.read().unwrap();
+            let mut snapshots = vec![];
+            for (name, ino) in fs.superblock.snapshots() {
+                let created = fs.superblock.get_inode(ino)?.read().unwrap().crtime;
+                snapshots.push(SnapshotInfo {
+                    name,
+                    created,
+                    usage: fs.superblock.usage(ino),
+                });
+            }
+            Ok(Response::ListSnapshots { snapshots })
+        }
+        Request::DeleteSnapshot { name } => {
+            let mut fs = fs.write().unwrap();
+            fs.superblock.check_writable()?;
+            for hash in fs.superblock.delete_snapshot(&name)? {
+                fs.release_hash(hash);
+            }
+            info!("Deleted snapshot '{}'.", name);
+            Ok(Response::DeleteSnapshot {})
+        }
+        Request::Diff { old, new } => {
+            let fs = fs.read().unwrap();
+            let old = lookup_tree(&fs.superblock, &old)?.read().unwrap().ino;
+            let new = lookup_tree(&fs.superblock, &new)?.read().unwrap().ino;
+            let mut changes = vec![];
+            diff_tree(&fs.superblock, old, new, Path::new(""), &mut changes)?;
+            Ok(Response::Diff { changes })
+        }
+        Request::AddStore { url, key_file } => {
+            handle_add_store(&url, key_file.as_ref().map(|p| p.as_path()), fs)
+                .map(|()| Response::AddStore {})
+        }
+        Request::DiskUsage { path } => handle_disk_usage(&path, fs)
+            .await
+            .map(|x| Response::DiskUsage(x)),
+        Request::WhichPath { hash } => {
+            let fs = fs.read().unwrap();
+            let root = fs.superblock.get_inode(fs.superblock.get_root_ino())?;
+            let mut paths = vec![];
+            walk_tree(
+                &fs.superblock,
+                &root,
+                Path::new(""),
+                &mut |path, _, inode| {
+                    if let Contents::RegularFile(file) = &inode.contents {
+                        if file.hash == hash {
+                            paths.push(path.to_path_buf());
+                        }
+                    }
+                    Ok(())
+                },
+            )?;
+            Ok(Response::WhichPath { paths })
+        }
+        Request::RemoveStore { url } => {
+            let store = fs.write().unwrap().remove_store(&url)?;
+            info!("Detached store '{}'.", store.get_url());
+            Ok(Response::RemoveStore {})
+        }
     }
 }
 
-async fn handle_status(path: &Path, fs: Arc<RwLock<FilesystemState>>) -> Result<StatusResponse> {
-    let mut status = {
-        let inode = fs.read().unwrap().superblock.lookup_path(path)?;
-        let inode = inode.read().unwrap();
+fn get_file_type(contents: &Contents) -> FileType {
+    match contents {
+        Contents::Directory(_) => FileType::Directory { tree_hash: None },
+        Contents::RegularFile(file) => FileType::ImmutableFile {
+            size: file.length,
+            hash: file.hash.clone(),
+            stores: vec![],
+            offline_media: vec![],
+        },
+        Contents::MutableFile(_) => FileType::MutableFile {},
+        Contents::Symlink(_) => FileType::Symlink {},
+    }
+}
 
-        let info = match &inode.contents {
-            Contents::Directory(_) => FileType::Directory {},
-            Contents::RegularFile(file) => FileType::ImmutableFile {
-                size: file.length,
-                hash: file.hash.clone(),
-                stores: vec![],
-            },
-            Contents::MutableFile(_) => FileType::MutableFile {},
-            Contents::Symlink(_) => FileType::Symlink {},
-        };
+/// Fill in the stores and offline media that have the immutable
+/// files in `infos`, querying each store once.
+async fn fill_stores(
+    infos: Vec<&mut FileType>,
+    stores: &[Arc<dyn Store>],
+    fs: &RwLock<FilesystemState>,
+) -> Result<()> {
+    let hashes: Vec<Hash> = infos
+        .iter()
+        .filter_map(|info| match info {
+            FileType::ImmutableFile { hash, .. } => Some(hash.clone()),
+            _ => None,
+        })
+        .collect();
 
-        StatusResponse {
-            ino: inode.ino,
-            info,
+    let mut present = vec![];
+    if !hashes.is_empty() {
+        for store in stores {
+            present.push((store.get_url(), store.has_many(&hashes).await?));
         }
-    };
+    }
 
-    if let FileType::ImmutableFile { stores, hash, .. } = &mut status.info {
-        let ss = fs.read().unwrap().stores.clone();
-        for store in ss {
-            if store.has(hash).await? {
-                stores.push(store.get_url());
+    let fs = fs.read().unwrap();
+    for info in infos {
+        if let FileType::ImmutableFile {
+            stores: res,
+            hash,
+            offline_media,
+            ..
+        } = info
+        {
+            for (url, hashes) in &present {
+                if hashes.contains(hash) {
+                    res.push(url.clone());
+                }
+            }
+
+            for (name, media) in &fs.superblock.media {
+                if media.hashes.contains(hash)
+                    && !fs.stores.iter().any(|st| st.get_url() == media.url)
+                {
+                    offline_media.push(name.clone());
+                }
             }
         }
     }
+    Ok(())
+}
 
-    Ok(status)
+/// Return a JSON description of the children of directory `ino`,
+/// served as the contents of the synthetic `.hugefsinfo` files.
+pub async fn directory_info(ino: Ino, fs: &Arc<RwLock<FilesystemState>>) -> Result<Vec<u8>> {
+    let (mut children, stores) = {
+        let fs = fs.read().unwrap();
+        let dir = fs.superblock.get_inode(ino)?;
+        let dir = dir.read().unwrap();
+        let mut children = vec![];
+        for (name, child_ino) in &dir.get_directory()?.entries {
+            let child = fs.superblock.get_inode(*child_ino)?;
+            let info = get_file_type(&child.read().unwrap().contents);
+            children.push((name.clone(), info));
+        }
+        (children, fs.stores.clone())
+    };
+
+    fill_stores(
+        children.iter_mut().map(|(_, info)| info).collect(),
+        &stores,
+        fs,
+    )
+    .await?;
+    let res: std::collections::BTreeMap<_, _> = children.into_iter().collect();
+
+    let mut json = serde_json::to_vec_pretty(&res).unwrap();
+    json.push(b'\n');
+    Ok(json)
 }
 
-async fn handle_mirror(
-    path: &Path,
-    store: &str,
-    fs: Arc<RwLock<FilesystemState>>,
-) -> Result<MirrorResponse> {
-    let (hash, size, stores) = {
+async fn handle_status(path: &Path, fs: Arc<RwLock<FilesystemState>>) -> Result<StatusResponse> {
+    let (mut status, stores) = {
         let fs = fs.read().unwrap();
-        let inode = fs.superblock.lookup_path(path)?;
+        let inode = lookup_tree(&fs.superblock, path)?;
         let inode = inode.read().unwrap();
-        match &inode.contents {
-            Contents::RegularFile(file) => (file.hash.clone(), file.length, fs.stores.clone()),
-            _ => return Err(Error::NotImmutableFile(inode.ino)),
+
+        let mut status = StatusResponse {
+            ino: inode.ino,
+            info: get_file_type(&inode.contents),
+            excluded_stores: inode.excluded_stores.iter().cloned().collect(),
+            pinned_stores: inode.pinned_stores.iter().cloned().collect(),
+            staging_store: inode.staging_store.clone(),
+        };
+
+        if let FileType::Directory { tree_hash } = &mut status.info {
+            *tree_hash = fs.superblock.tree_hash(inode.ino)?;
         }
+
+        (status, fs.stores.clone())
     };
 
-    let dst_store = stores
-        .iter()
-        .find(|st| st.get_url() == store)
-        .ok_or_else(|| Error::UnknownStore(store.into()))?;
+    fill_stores(vec![&mut status.info], &stores, &fs).await?;
 
-    if dst_store.has(&hash).await? {
-        Ok(MirrorResponse { from: None })
+    Ok(status)
+}
+
+/// Add or remove `url` to the excluded stores of `inode` and its
+/// descendants. Returns the number of inodes visited.
+fn set_exclusion(
+    superblock: &Superblock,
+    inode: &Arc<RwLock<Inode>>,
+    url: &str,
+    remove: bool,
+) -> Result<usize> {
+    let mut inode = inode.write().unwrap();
+
+    if remove {
+        inode.excluded_stores.remove(url);
     } else {
-        for src_store in &stores {
-            if Arc::ptr_eq(src_store, dst_store) {
-                continue;
-            }
-            match crate::store::copy_file(&hash, size, src_store.as_ref(), dst_store.as_ref()).await
-            {
-                Ok(()) => {
-                    return Ok(MirrorResponse {
-                        from: Some(src_store.get_url()),
-                    });
-                }
-                Err(Error::NoSuchHash(_)) => {}
-                Err(err) => {
-                    return Err(err);
-                }
-            }
+        inode.excluded_stores.insert(url.into());
+    }
+
+    let mut count = 1;
+
+    if let Contents::Directory(dir) = &inode.contents {
+        for ino in dir.entries.values() {
+            count += set_exclusion(superblock, &superblock.get_inode(*ino)?, url, remove)?;
         }
-        Err(Error::NoSuchHash(hash))
     }
+
+    Ok(count)
+}
+
+/// Add the external file `src` to a store and create an immutable
+/// file for it at `dest`, without copying the data through FUSE.
+pub async fn handle_adopt(
+    src: &Path,
+    dest: &Path,
+    url: Option<&str>,
+    link: bool,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<Response> {
+    if !src.is_absolute() {
+        return Err(Error::BadPath(src.into()));
+    }
+    let name = dest
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::BadPath(dest.into()))?
+        .to_string();
+    let parent_path = dest.parent().unwrap_or_else(|| Path::new(""));
+
+    let st = tokio::fs::metadata(src).await?;
+    if !st.is_file() {
+        return Err(Error::BadArguments(format!(
+            "'{}' is not a regular file",
+            src.display()
+        )));
+    }
+
+    let store = {
+        let fs = fs.read().unwrap();
+        fs.superblock.check_writable()?;
+        let parent = fs.superblock.lookup_path(parent_path)?;
+        let parent = parent.read().unwrap();
+        parent.get_directory()?.check_no_entry(&name)?;
+        fs.superblock.check_quota(
+            parent.ino,
+            Usage {
+                bytes: st.len(),
+                inodes: 1,
+            },
+        )?;
+        let candidates: Vec<_> = fs
+            .stores
+            .iter()
+            .filter(|st| !parent.excluded_stores.contains(&st.get_url()))
+            .filter(|st| !st.is_reference() && st.is_writable())
+            .collect();
+        match url {
+            Some(url) => candidates
+                .into_iter()
+                .find(|st| st.get_url() == url)
+                .cloned()
+                .ok_or_else(|| Error::UnknownStore(url.into()))?,
+            None => candidates
+                .into_iter()
+                .find(|st| fs.is_healthy(st))
+                .cloned()
+                .ok_or(Error::ReadOnly)?,
+        }
+    };
+
+    let src_path = src.to_path_buf();
+    let (size, hash) =
+        tokio::task::spawn_blocking(move || Hash::hash(std::fs::File::open(src_path)?))
+            .await
+            .unwrap()?;
+
+    store.adopt(&hash, src, link).await?;
+    fs.read().unwrap().counters.written(&store.get_url(), size);
+
+    info!(
+        "Adopted '{}' as '{}' ({}) into store '{}'.",
+        src.display(),
+        dest.display(),
+        hash.to_hex(),
+        store.get_url()
+    );
+
+    let fs = &mut *fs.write().unwrap();
+    let parent = fs.superblock.lookup_path(parent_path)?;
+    let mut parent = parent.write().unwrap();
+    let inode = Inode {
+        excluded_stores: parent.excluded_stores.clone(),
+        pinned_stores: parent.pinned_stores.clone(),
+        perm: st.permissions().mode() & 0o7777,
+        uid: st.uid(),
+        gid: st.gid(),
+        mtime: st.modified()?.into(),
+        ..Inode::new(Contents::RegularFile(crate::fs::RegularFile {
+            length: size,
+            hash: hash.clone(),
+        }))
+    };
+    let dir = parent.get_directory_mut()?;
+    // The directory may have changed while we were hashing.
+    dir.check_no_entry(&name)?;
+    let ino = fs.superblock.add_inode(inode);
+    dir.entries.insert(name, ino);
+    parent.mtime = crate::fs::Time::now();
+    let child = fs.superblock.get_inode(ino)?;
+    fs.superblock
+        .entry_added(parent.ino, ino, &child.read().unwrap().contents);
+
+    Ok(Response::Adopt {
+        hash,
+        size,
+        store: store.get_url(),
+    })
+}
+
+/// Create `dst` as a new inode with the same contents as the
+/// immutable file `src`, without copying any data.
+fn handle_clone(src: &Path, dst: &Path, fs: Arc<RwLock<FilesystemState>>) -> Result<Ino> {
+    let name = dst
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::BadPath(dst.into()))?
+        .to_string();
+    let parent_path = dst.parent().unwrap_or_else(|| Path::new(""));
+
+    let fs = &mut *fs.write().unwrap();
+    fs.superblock.check_writable()?;
+
+    let (file, perm, uid, gid, mtime) = {
+        let inode = fs.superblock.lookup_path(src)?;
+        let inode = inode.read().unwrap();
+        match &inode.contents {
+            Contents::RegularFile(file) => (
+                crate::fs::RegularFile {
+                    length: file.length,
+                    hash: file.hash.clone(),
+                },
+                inode.perm,
+                inode.uid,
+                inode.gid,
+                inode.mtime,
+            ),
+            _ => return Err(Error::NotImmutableFile(inode.ino)),
+        }
+    };
+
+    let parent = fs.superblock.lookup_path(parent_path)?;
+    let mut parent = parent.write().unwrap();
+    fs.superblock.check_quota(
+        parent.ino,
+        Usage {
+            bytes: file.length,
+            inodes: 1,
+        },
+    )?;
+    parent.get_directory()?.check_no_entry(&name)?;
+
+    let inode = Inode {
+        excluded_stores: parent.excluded_stores.clone(),
+        pinned_stores: parent.pinned_stores.clone(),
+        perm,
+        uid,
+        gid,
+        mtime,
+        ..Inode::new(Contents::RegularFile(file))
+    };
+    let ino = fs.superblock.add_inode(inode);
+    parent.get_directory_mut()?.entries.insert(name, ino);
+    parent.mtime = crate::fs::Time::now();
+    let child = fs.superblock.get_inode(ino)?;
+    fs.superblock
+        .entry_added(parent.ino, ino, &child.read().unwrap().contents);
+
+    Ok(ino)
+}
+
+/// Look up `path`, which may also refer to a file in a snapshot
+/// (e.g. `.snapshots/<name>/foo`).
+fn lookup_tree(superblock: &Superblock, path: &Path) -> Result<Arc<RwLock<Inode>>> {
+    let mut components = path.components();
+    if components.next() != Some(Component::Normal(std::ffi::OsStr::new(SNAPSHOTS_NAME))) {
+        return superblock.lookup_path(path);
+    }
+    let snapshot_dir = superblock.get_snapshot_dir().ok_or(Error::NoSuchEntry)?;
+    let ino = match components.next() {
+        None => snapshot_dir,
+        Some(Component::Normal(name)) => {
+            let name = name.to_str().ok_or_else(|| Error::BadPath(path.into()))?;
+            *superblock
+                .snapshots()
+                .get(name)
+                .ok_or_else(|| Error::NoSuchSnapshot(name.into()))?
+        }
+        Some(_) => return Err(Error::BadPath(path.into())),
+    };
+    superblock.lookup_path_from(ino, components.as_path())
+}
+
+/// Append the differences between the trees `old` and `new` to
+/// `changes`. Subtrees with equal stored tree hashes are skipped
+/// without visiting them.
+fn diff_tree(
+    superblock: &Superblock,
+    old: Ino,
+    new: Ino,
+    path: &Path,
+    changes: &mut Vec<DiffEntry>,
+) -> Result<()> {
+    if old == new {
+        return Ok(());
+    }
+
+    let entries = |ino: Ino| -> Result<(&'static str, Option<BTreeMap<String, Ino>>)> {
+        let inode = superblock.get_inode(ino)?;
+        let inode = inode.read().unwrap();
+        Ok(match &inode.contents {
+            Contents::Directory(dir) => ("directory", Some(dir.entries.clone())),
+            contents => (get_file_type(contents).get_type(), None),
+        })
+    };
+
+    let (old_type, old_entries) = entries(old)?;
+    let (new_type, new_entries) = entries(new)?;
+
+    if old_type == new_type {
+        let old_hash = superblock.tree_hash(old)?;
+        if old_hash.is_some() && old_hash == superblock.tree_hash(new)? {
+            return Ok(());
+        }
+    }
+
+    match (old_entries, new_entries) {
+        (Some(old_entries), Some(new_entries)) => {
+            for (name, ino) in &old_entries {
+                match new_entries.get(name) {
+                    Some(new_ino) => {
+                        diff_tree(superblock, *ino, *new_ino, &path.join(name), changes)?
+                    }
+                    None => changes.push(DiffEntry {
+                        path: path.join(name),
+                        change: Change::Removed,
+                    }),
+                }
+            }
+            for name in new_entries.keys() {
+                if !old_entries.contains_key(name) {
+                    changes.push(DiffEntry {
+                        path: path.join(name),
+                        change: Change::Added,
+                    });
+                }
+            }
+        }
+        _ => changes.push(DiffEntry {
+            path: path.into(),
+            change: Change::Modified,
+        }),
+    }
+
+    Ok(())
+}
+
+/// Create `path` as an immutable file with hash `hash` if a store
+/// has its contents. Returns the URL of that store.
+async fn handle_link(
+    path: &Path,
+    hash: Hash,
+    size: u64,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::BadPath(path.into()))?
+        .to_string();
+    let parent_path = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let stores: Vec<_> = {
+        let fs = fs.read().unwrap();
+        fs.superblock.check_writable()?;
+        let parent = fs.superblock.lookup_path(parent_path)?;
+        let parent = parent.read().unwrap();
+        parent.get_directory()?.check_no_entry(&name)?;
+        fs.stores
+            .iter()
+            .filter(|st| !parent.excluded_stores.contains(&st.get_url()) && fs.is_healthy(st))
+            .cloned()
+            .collect()
+    };
+
+    let mut found = None;
+    for store in stores {
+        match store.has(&hash).await {
+            Ok(true) => {
+                found = Some(store.get_url());
+                break;
+            }
+            Ok(false) => {}
+            Err(err) => warn!("Cannot check store '{}': {}", store.get_url(), err),
+        }
+    }
+    let url = found.ok_or_else(|| Error::NoSuchHash(hash.clone()))?;
+
+    let fs = &mut *fs.write().unwrap();
+    let parent = fs.superblock.lookup_path(parent_path)?;
+    let mut parent = parent.write().unwrap();
+    fs.superblock.check_quota(
+        parent.ino,
+        Usage {
+            bytes: size,
+            inodes: 1,
+        },
+    )?;
+    let inode = Inode {
+        excluded_stores: parent.excluded_stores.clone(),
+        pinned_stores: parent.pinned_stores.clone(),
+        ..Inode::new(Contents::RegularFile(crate::fs::RegularFile {
+            length: size,
+            hash: hash.clone(),
+        }))
+    };
+    let dir = parent.get_directory_mut()?;
+    dir.check_no_entry(&name)?;
+    let ino = fs.superblock.add_inode(inode);
+    dir.entries.insert(name, ino);
+    parent.mtime = crate::fs::Time::now();
+    let child = fs.superblock.get_inode(ino)?;
+    fs.superblock
+        .entry_added(parent.ino, ino, &child.read().unwrap().contents);
+
+    debug!(
+        "Linked '{}' to {} in store '{}'.",
+        path.display(),
+        hash.to_hex(),
+        url
+    );
+
+    Ok(url)
+}
+
+/// Add or remove `url` to the pinned stores of `inode` and its
+/// descendants. Returns the number of inodes visited.
+fn set_pin(
+    superblock: &Superblock,
+    inode: &Arc<RwLock<Inode>>,
+    url: &str,
+    remove: bool,
+) -> Result<usize> {
+    let mut inode = inode.write().unwrap();
+
+    if remove {
+        inode.pinned_stores.remove(url);
+    } else {
+        inode.pinned_stores.insert(url.into());
+    }
+
+    let mut count = 1;
+
+    if let Contents::Directory(dir) = &inode.contents {
+        for ino in dir.entries.values() {
+            count += set_pin(superblock, &superblock.get_inode(*ino)?, url, remove)?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Set the staging store of `inode` and the directories below it.
+/// Returns the number of directories visited.
+fn set_staging_store(
+    superblock: &Superblock,
+    inode: &Arc<RwLock<Inode>>,
+    url: Option<&str>,
+) -> Result<usize> {
+    let mut inode = inode.write().unwrap();
+
+    inode.staging_store = url.map(|url| url.to_string());
+
+    let mut count = 1;
+
+    if let Contents::Directory(dir) = &inode.contents {
+        for ino in dir.entries.values() {
+            let child = superblock.get_inode(*ino)?;
+            if child.read().unwrap().get_directory().is_ok() {
+                count += set_staging_store(superblock, &child, url)?;
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Remove the copy of the file at `path` from the store `url`,
+/// provided that another store has an intact copy. Returns whether
+/// the store had a copy.
+async fn handle_evict(path: &Path, url: &str, fs: Arc<RwLock<FilesystemState>>) -> Result<bool> {
+    let (hash, size, store) = {
+        let fs = fs.read().unwrap();
+        let inode = fs.superblock.lookup_path(path)?;
+        let inode = inode.read().unwrap();
+        let file = match &inode.contents {
+            Contents::RegularFile(file) => file,
+            _ => return Err(Error::NotImmutableFile(inode.ino)),
+        };
+        let store = fs
+            .stores
+            .iter()
+            .find(|st| st.get_url() == url)
+            .cloned()
+            .ok_or_else(|| Error::UnknownStore(url.into()))?;
+        (file.hash.clone(), file.length, store)
+    };
+
+    if store.is_reference() {
+        return Err(Error::ReferenceStore(url.into()));
+    }
+
+    if fs
+        .read()
+        .unwrap()
+        .superblock
+        .pinned_files(url)
+        .contains_key(&hash)
+    {
+        return Err(Error::Pinned(url.into(), path.into()));
+    }
+
+    if !store.has(&hash).await? {
+        return Ok(false);
+    }
+
+    if !crate::fusefs::has_other_copy(&fs, &store, &hash, size).await {
+        return Err(Error::LastCopy(url.into(), path.into()));
+    }
+
+    info!(
+        "Evicting '{}' ({}) from store '{}'.",
+        path.display(),
+        hash.to_hex(),
+        url
+    );
+    store.remove(&hash).await?;
+
+    Ok(true)
+}
+
+type WalkFn<'a> = dyn FnMut(&Path, &Arc<RwLock<Inode>>, &Inode) -> Result<()> + 'a;
+
+/// Call `f` on the inode at `path` and, if it is a directory,
+/// recursively on all its descendants.
+fn walk_tree(
+    superblock: &Superblock,
+    inode: &Arc<RwLock<Inode>>,
+    path: &Path,
+    f: &mut WalkFn<'_>,
+) -> Result<()> {
+    let guard = inode.read().unwrap();
+
+    f(path, inode, &guard)?;
+
+    if let Contents::Directory(dir) = &guard.contents {
+        for (name, ino) in &dir.entries {
+            let child = superblock.get_inode(*ino)?;
+            walk_tree(superblock, &child, &path.join(name), f)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A regular file considered by `handle_find()`: its path, its hash
+/// if it's immutable, and the store of a mutable file.
+type FindCandidate = (PathBuf, Option<Hash>, Option<String>);
+
+async fn handle_find(
+    path: &Path,
+    filter: &FindFilter,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<Vec<PathBuf>> {
+    match filter.file_type.as_deref() {
+        None | Some("mutable") | Some("immutable") => {}
+        Some(t) => return Err(Error::BadArguments(format!("invalid file type '{}'", t))),
+    }
+
+    let days = |n: u64| Duration::from_secs(n * 24 * 60 * 60);
+
+    let (candidates, present_in, missing_from) = {
+        let fs = fs.read().unwrap();
+        let find_store = |url: &Option<String>| -> Result<Option<Arc<dyn Store>>> {
+            match url {
+                Some(url) => fs
+                    .stores
+                    .iter()
+                    .find(|st| st.get_url() == *url)
+                    .cloned()
+                    .map(Some)
+                    .ok_or_else(|| Error::UnknownStore(url.clone())),
+                None => Ok(None),
+            }
+        };
+        let present_in = find_store(&filter.present_in)?;
+        let missing_from = find_store(&filter.missing_from)?;
+
+        let inode = fs.superblock.lookup_path(path)?;
+        let mut candidates: Vec<FindCandidate> = vec![];
+        walk_tree(&fs.superblock, &inode, path, &mut |path, _, inode| {
+            let (size, candidate) = match &inode.contents {
+                Contents::RegularFile(file) => {
+                    (file.length, (path.into(), Some(file.hash.clone()), None))
+                }
+                Contents::MutableFile(file) => (
+                    file.file.len(),
+                    (path.into(), None, Some(file.store.clone())),
+                ),
+                _ => return Ok(()),
+            };
+            let file_type = if candidate.1.is_some() {
+                "immutable"
+            } else {
+                "mutable"
+            };
+            let age = inode.mtime.elapsed();
+            if filter.min_size.map_or(true, |n| size >= n)
+                && filter.max_size.map_or(true, |n| size <= n)
+                && filter.file_type.as_deref().map_or(true, |t| t == file_type)
+                && filter.newer_than_days.map_or(true, |n| age < days(n))
+                && filter.older_than_days.map_or(true, |n| age >= days(n))
+            {
+                candidates.push(candidate);
+            }
+            Ok(())
+        })?;
+        (candidates, present_in, missing_from)
+    };
+
+    /* Ask the stores about all immutable candidates at once. A
+     * mutable file is only in the store it's being written to. */
+    let hashes: Vec<Hash> = candidates
+        .iter()
+        .filter_map(|(_, hash, _)| hash.clone())
+        .collect();
+    let mut filters = vec![];
+    for (store, wanted) in [(present_in, true), (missing_from, false)] {
+        if let Some(store) = store {
+            let url = store.get_url();
+            let present = if hashes.is_empty() {
+                HashSet::new()
+            } else {
+                store.has_many(&hashes).await?
+            };
+            filters.push((url, present, wanted));
+        }
+    }
+
+    Ok(candidates
+        .into_iter()
+        .filter(|(_, hash, mutable_store)| {
+            filters.iter().all(|(url, present, wanted)| {
+                let has = match (hash, mutable_store) {
+                    (Some(hash), _) => present.contains(hash),
+                    (None, Some(store)) => store == url,
+                    (None, None) => false,
+                };
+                has == *wanted
+            })
+        })
+        .map(|(path, _, _)| path)
+        .collect())
+}
+
+/// Garbage-collect the attached local store `url`, after updating
+/// this filesystem's ledger in it so that its files are kept.
+async fn handle_gc(
+    url: &str,
+    dry_run: bool,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<Vec<Hash>> {
+    let read_only = {
+        let fs = fs.read().unwrap();
+        if fs.superblock.read_only && !dry_run {
+            return Err(Error::ReadOnly);
+        }
+        let store = fs
+            .stores
+            .iter()
+            .find(|st| st.get_url() == url)
+            .ok_or_else(|| Error::UnknownStore(url.into()))?;
+        if store.is_reference() {
+            return Err(Error::ReferenceStore(url.into()));
+        }
+        if !url.starts_with('/') {
+            return Err(Error::BadArguments(format!(
+                "store '{}' is not a local store",
+                url
+            )));
+        }
+        fs.superblock.read_only
+    };
+
+    if !read_only {
+        crate::fusefs::write_ledgers(Arc::clone(&fs)).await?;
+    }
+
+    // This reads the whole store directory, so keep it off the
+    // runtime's workers.
+    let path = PathBuf::from(url);
+    let deleted = tokio::task::spawn_blocking(move || LocalStore::new(path)?.gc(dry_run))
+        .await
+        .unwrap()?;
+    if !dry_run {
+        info!(
+            "Deleted {} unreferenced files from '{}'.",
+            deleted.len(),
+            url
+        );
+    }
+    Ok(deleted)
+}
+
+/// Cluster the immutable files of each directory below `path`.
+/// Returns the number of directories and the number of files moved.
+async fn handle_cluster(path: &Path, fs: Arc<RwLock<FilesystemState>>) -> Result<(usize, usize)> {
+    let (clusters, stores) = {
+        let fs = fs.read().unwrap();
+        let inode = fs.superblock.lookup_path(path)?;
+        let mut clusters = vec![];
+        walk_tree(&fs.superblock, &inode, path, &mut |_, _, inode| {
+            if let Contents::Directory(dir) = &inode.contents {
+                let mut hashes = vec![];
+                for ino in dir.entries.values() {
+                    let child = fs.superblock.get_inode(*ino)?;
+                    let child = child.read().unwrap();
+                    if let Contents::RegularFile(file) = &child.contents {
+                        hashes.push(file.hash.clone());
+                    }
+                }
+                if !hashes.is_empty() {
+                    let name = format!("{}-{}", fs.superblock.get_id(), inode.ino);
+                    clusters.push((name, hashes));
+                }
+            }
+            Ok(())
+        })?;
+        let stores: Vec<_> = fs
+            .stores
+            .iter()
+            .filter(|store| !store.is_reference() && fs.is_healthy(store))
+            .cloned()
+            .collect();
+        (clusters, stores)
+    };
+
+    let mut moved = 0;
+    for store in &stores {
+        for (name, hashes) in &clusters {
+            moved += store.cluster(name, hashes).await?;
+        }
+    }
+
+    if moved > 0 {
+        info!(
+            "Moved {} files into {} clusters below '{}'.",
+            moved,
+            clusters.len(),
+            path.display()
+        );
+    }
+
+    Ok((clusters.len(), moved))
+}
+
+/// Number of entries in each batch produced by
+/// `handle_status_tree()`.
+const STATUS_TREE_BATCH_SIZE: usize = 1000;
+
+/// Return the entries below `path` in the same order as
+/// `walk_tree()`. All but the last batch of entries are passed to
+/// `batch`, so that large trees are neither walked nor sent in one
+/// go.
+async fn handle_status_tree(
+    path: &Path,
+    batch: &mut (dyn FnMut(Vec<TreeEntry>) + Send),
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<StatusTreeResponse> {
+    let (root, stores) = {
+        let fs = fs.read().unwrap();
+        let ino = fs.superblock.lookup_path(path)?.read().unwrap().ino;
+        (ino, fs.stores.clone())
+    };
+
+    let mut todo = vec![(path.to_path_buf(), root)];
+    loop {
+        let mut files = vec![];
+        {
+            let fs = fs.read().unwrap();
+            while files.len() < STATUS_TREE_BATCH_SIZE {
+                let (path, ino) = match todo.pop() {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                // The entry may have been deleted since its directory
+                // was read.
+                let inode = match fs.superblock.get_inode(ino) {
+                    Ok(inode) => inode,
+                    Err(_) => continue,
+                };
+                let inode = inode.read().unwrap();
+                if let Contents::Directory(dir) = &inode.contents {
+                    todo.extend(
+                        dir.entries
+                            .iter()
+                            .rev()
+                            .map(|(name, ino)| (path.join(name), *ino)),
+                    );
+                }
+                files.push(TreeEntry {
+                    path,
+                    ino,
+                    info: get_file_type(&inode.contents),
+                });
+            }
+        }
+
+        fill_stores(
+            files.iter_mut().map(|file| &mut file.info).collect(),
+            &stores,
+            &fs,
+        )
+        .await?;
+
+        if todo.is_empty() {
+            return Ok(StatusTreeResponse { files });
+        }
+        batch(files);
+    }
+}
+
+/// Make the filesystem read-only, finalize all mutable files, and
+/// return a manifest of its contents.
+async fn handle_freeze(
+    progress: &Progress,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<FreezeResponse> {
+    fs.write().unwrap().superblock.frozen = true;
+
+    let res = freeze_inner(progress, Arc::clone(&fs)).await;
+
+    if res.is_err() {
+        fs.write().unwrap().superblock.frozen = false;
+    } else {
+        info!("Filesystem frozen.");
+    }
+
+    res
+}
+
+async fn freeze_inner(
+    progress: &Progress,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<FreezeResponse> {
+    handle_finalize(Path::new(""), true, progress, Arc::clone(&fs)).await?;
+
+    let (files, stores) = {
+        let fs = fs.read().unwrap();
+        let root = fs.superblock.get_inode(fs.superblock.get_root_ino())?;
+        let mut files = vec![];
+        walk_tree(
+            &fs.superblock,
+            &root,
+            Path::new(""),
+            &mut |path, _, inode| {
+                match &inode.contents {
+                    Contents::RegularFile(file) => files.push(ManifestEntry {
+                        path: path.into(),
+                        size: file.length,
+                        hash: file.hash.clone(),
+                        stores: vec![],
+                    }),
+                    // A file may have been created before the freeze took effect.
+                    Contents::MutableFile(_) => return Err(Error::FileBusy(inode.ino)),
+                    _ => {}
+                }
+                Ok(())
+            },
+        )?;
+        (files, fs.stores.clone())
+    };
+
+    let mut manifest = files;
+    let hashes: Vec<Hash> = manifest.iter().map(|file| file.hash.clone()).collect();
+
+    for store in &stores {
+        let present = store.has_many(&hashes).await?;
+        for file in &mut manifest {
+            if present.contains(&file.hash) {
+                file.stores.push(store.get_url());
+            }
+        }
+    }
+
+    Ok(FreezeResponse { manifest })
+}
+
+async fn handle_finalize(
+    path: &Path,
+    recursive: bool,
+    progress: &Progress,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<FinalizeResponse> {
+    let files = {
+        let fs = fs.read().unwrap();
+        // Frozen filesystems are finalized by `handle_freeze`.
+        if fs.superblock.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let inode = fs.superblock.lookup_path(path)?;
+        let mut files = vec![];
+        let mut add = |path: &Path, inode: &Arc<RwLock<Inode>>, inode_: &Inode| {
+            if let Contents::MutableFile(file) = &inode_.contents {
+                files.push((path.to_path_buf(), Arc::clone(inode), file.file.len()));
+            }
+            Ok(())
+        };
+        if recursive {
+            walk_tree(&fs.superblock, &inode, path, &mut add)?;
+        } else {
+            let inode_ = inode.read().unwrap();
+            add(path, &inode, &inode_)?;
+        }
+        files
+    };
+
+    let mut res = FinalizeResponse {
+        files: vec![],
+        busy: vec![],
+    };
+
+    let total = files.iter().map(|(_, _, len)| len).sum();
+    let mut done = 0;
+
+    for (path, inode, len) in files {
+        progress.report(done, total);
+        let report = |hashed: u64| progress.report(done + hashed.min(len), total);
+        match finalize_file(&inode, &fs, &report).await {
+            Ok(Some((size, hash))) => res.files.push(FinalizedFile { path, size, hash }),
+            Ok(None) => {}
+            // Don't let one busy file stop a recursive finalize.
+            Err(Error::FileBusy(_)) if recursive => res.busy.push(path),
+            Err(err) => return Err(err),
+        }
+        done += len;
+    }
+
+    Ok(res)
+}
+
+async fn handle_disk_usage(
+    path: &Path,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<DiskUsageResponse> {
+    let mut res = DiskUsageResponse {
+        logical_size: 0,
+        unique_size: 0,
+        mutable_size: 0,
+        stores: vec![],
+    };
+
+    let (hashes, stores) = {
+        let fs = fs.read().unwrap();
+        let inode = fs.superblock.lookup_path(path)?;
+        let mut hashes = HashMap::new();
+        walk_tree(&fs.superblock, &inode, path, &mut |_, _, inode| {
+            match &inode.contents {
+                Contents::RegularFile(file) => {
+                    res.logical_size += file.length;
+                    hashes.insert(file.hash.clone(), file.length);
+                }
+                Contents::MutableFile(file) => {
+                    res.logical_size += file.file.len();
+                    res.mutable_size += file.file.len();
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        (hashes, fs.stores.clone())
+    };
+
+    res.unique_size = hashes.values().sum();
+
+    let keys: Vec<Hash> = hashes.keys().cloned().collect();
+
+    for store in stores {
+        let present = store.has_many(&keys).await?;
+        res.stores.push(StoreUsage {
+            url: store.get_url(),
+            size: present.iter().map(|hash| hashes[hash]).sum(),
+        });
+    }
+
+    Ok(res)
+}
+
+async fn handle_mirror(
+    path: &Path,
+    store: &str,
+    progress: &Progress,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<MirrorResponse> {
+    let (hash, size, stores, verify_percent) = {
+        let fs = fs.read().unwrap();
+        let inode = fs.superblock.lookup_path(path)?;
+        let inode = inode.read().unwrap();
+        if inode.excluded_stores.contains(store) {
+            return Err(Error::StoreExcluded(store.into(), path.into()));
+        }
+        match &inode.contents {
+            Contents::RegularFile(file) => (
+                file.hash.clone(),
+                file.length,
+                fs.stores.clone(),
+                fs.mirror_stats.verify_percent,
+            ),
+            _ => return Err(Error::NotImmutableFile(inode.ino)),
+        }
+    };
+
+    let dst_store = stores
+        .iter()
+        .find(|st| st.get_url() == store)
+        .ok_or_else(|| Error::UnknownStore(store.into()))?;
+
+    if dst_store.is_reference() {
+        return Err(Error::ReferenceStore(dst_store.get_url()));
+    }
+
+    if dst_store.has(&hash).await? {
+        Ok(MirrorResponse { from: None })
+    } else {
+        let from = copy_to_store(
+            &hash,
+            size,
+            &stores,
+            dst_store,
+            verify_percent,
+            &|done| progress.report(done, size),
+            &fs,
+        )
+        .await?;
+        Ok(MirrorResponse { from: Some(from) })
+    }
+}
+
+/// How long `run_jobs` waits when there is nothing to do.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Run the next queued job, if any. Returns whether a job was run.
+pub async fn run_next_job(fs: &Arc<RwLock<FilesystemState>>) -> bool {
+    let job = match fs.write().unwrap().superblock.jobs.start_next() {
+        Some(job) => job,
+        None => return false,
+    };
+
+    info!("Running job {}: {:?}", job.id, job.kind);
+
+    // Nobody reads the progress of background jobs.
+    let (progress, _) = output_channel();
+
+    let status = match &job.kind {
+        JobKind::Mirror { path, store } => {
+            match handle_mirror(path, store, &progress, Arc::clone(fs)).await {
+                Ok(res) => JobStatus::Done { from: res.from },
+                Err(err) => JobStatus::Failed {
+                    msg: err.to_string(),
+                },
+            }
+        }
+        JobKind::Erase { hash } => match erase_file(hash, fs).await {
+            Ok(()) => JobStatus::Done { from: None },
+            Err(err) => JobStatus::Failed {
+                msg: err.to_string(),
+            },
+        },
+        JobKind::Evict { path, store } => match handle_evict(path, store, Arc::clone(fs)).await {
+            Ok(_) => JobStatus::Done { from: None },
+            Err(err) => JobStatus::Failed {
+                msg: err.to_string(),
+            },
+        },
+    };
+
+    fs.write()
+        .unwrap()
+        .superblock
+        .jobs
+        .finish(job.id, status.clone());
+
+    // A slow callback mustn't hold up the queue.
+    let job = Job { status, ..job };
+    tokio::spawn(async move {
+        match tokio::time::timeout(crate::jobs::CALLBACK_TIMEOUT, crate::jobs::notify(&job)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Cannot notify callback of job {}: {}", job.id, err),
+            Err(_) => warn!("Callback of job {} timed out.", job.id),
+        }
+    });
+
+    true
+}
+
+/// Erase a file from every store that has it, unless it has been
+/// referenced again since the erase was queued. The outcome is
+/// recorded in the audit log. The erase stays pending if it fails,
+/// so that `retry_erases()` tries again later.
+async fn erase_file(hash: &Hash, fs: &Arc<RwLock<FilesystemState>>) -> Result<()> {
+    let audit = |event: AuditEvent| {
+        if let Some(log) = &fs.read().unwrap().audit_log {
+            if let Err(err) = log.record(&event) {
+                warn!("Cannot write audit log: {}", err);
+            }
+        }
+    };
+
+    let (fs_id, stores) = {
+        let mut fs = fs.write().unwrap();
+        if fs.is_in_use(hash) {
+            info!("Not erasing {} since it's in use again.", hash.to_hex());
+            fs.superblock.pending_erases.remove(hash);
+            drop(fs);
+            audit(AuditEvent::EraseCancelled { hash: hash.clone() });
+            return Ok(());
+        }
+        (fs.superblock.get_id().to_string(), fs.stores.clone())
+    };
+
+    let mut erased = vec![];
+
+    for store in stores.iter().filter(|store| !store.is_reference()) {
+        // Shared stores may hold the file for other filesystems.
+        let res = match store.has(hash).await {
+            Ok(false) => continue,
+            Ok(true) => match store.in_other_ledgers(&fs_id, hash).await {
+                Ok(true) => {
+                    info!(
+                        "Not erasing {} from '{}' since another filesystem uses it.",
+                        hash.to_hex(),
+                        store.get_url()
+                    );
+                    continue;
+                }
+                Ok(false) => store.erase(hash).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        if let Err(err) = res {
+            audit(AuditEvent::EraseFailed {
+                hash: hash.clone(),
+                store: store.get_url(),
+                error: err.to_string(),
+            });
+            return Err(err);
+        }
+        erased.push(store.get_url());
+    }
+
+    info!("Erased {} from {} stores.", hash.to_hex(), erased.len());
+
+    fs.write().unwrap().superblock.pending_erases.remove(hash);
+
+    audit(AuditEvent::Erased {
+        hash: hash.clone(),
+        stores: erased,
+    });
+
+    Ok(())
+}
+
+/// Interval between attempts to redo secure erases that failed.
+const ERASE_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Periodically queue the secure erases that are still pending,
+/// including those left over from a previous run.
+pub async fn retry_erases(fs: Arc<RwLock<FilesystemState>>) {
+    loop {
+        fs.write().unwrap().queue_pending_erases();
+        tokio::time::delay_for(ERASE_RETRY_INTERVAL).await;
+    }
+}
+
+/// Execute queued jobs, unless the daemon should be idle.
+pub async fn run_jobs(fs: Arc<RwLock<FilesystemState>>) {
+    loop {
+        let idle = fs.read().unwrap().power.is_idle();
+        if idle || !run_next_job(&fs).await {
+            tokio::time::delay_for(JOB_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Copy a file to `dst_store` from the first other store that has
+/// it. Returns the URL of the source store.
+pub async fn copy_to_store(
+    hash: &Hash,
+    size: u64,
+    stores: &[Arc<dyn Store>],
+    dst_store: &Arc<dyn Store>,
+    verify_percent: u8,
+    progress: &(dyn Fn(u64) + Send + Sync),
+    fs: &RwLock<FilesystemState>,
+) -> Result<String> {
+    for src_store in stores {
+        if Arc::ptr_eq(src_store, dst_store) {
+            continue;
+        }
+        let verify = crate::store::should_verify(hash, verify_percent);
+        let res = crate::store::copy_file(
+            hash,
+            size,
+            src_store.as_ref(),
+            dst_store.as_ref(),
+            progress,
+            verify,
+        )
+        .await;
+        if verify {
+            fs.write()
+                .unwrap()
+                .mirror_stats
+                .record(matches!(res, Err(Error::MirrorCorrupt(..))));
+        }
+        let counters = Arc::clone(&fs.read().unwrap().counters);
+        match res {
+            Ok(()) => {
+                counters.read(&src_store.get_url(), size);
+                counters.written(&dst_store.get_url(), size);
+                return Ok(src_store.get_url());
+            }
+            Err(Error::NoSuchHash(_)) => {}
+            Err(err) => {
+                counters.error(&dst_store.get_url());
+                return Err(err);
+            }
+        }
+    }
+    Err(Error::NoSuchHash(hash.clone()))
+}
+
+/// Attach a removable store, record which files it has, and copy
+/// the files it's missing.
+async fn handle_attach_media(
+    name: &str,
+    progress: &Progress,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<(usize, usize)> {
+    let (url, keys) = {
+        let fs = fs.read().unwrap();
+        let media = fs
+            .superblock
+            .media
+            .get(name)
+            .ok_or_else(|| Error::UnknownStore(name.into()))?;
+        (media.url.clone(), fs.keys.clone())
+    };
+
+    let store = crate::store::open_store(&url, &keys)?;
+    fs.write().unwrap().add_store(Arc::clone(&store))?;
+    info!("Attached media '{}' ({}).", name, url);
+
+    let (files, excluded, stores, verify_percent) = {
+        let fs = fs.read().unwrap();
+        (
+            fs.superblock.all_files(),
+            fs.superblock.excluded_hashes(&url),
+            fs.stores.clone(),
+            fs.mirror_stats.verify_percent,
+        )
+    };
+
+    let total: u64 = files.values().sum();
+    let mut done = 0;
+    let mut present = HashSet::new();
+    let mut copied = 0;
+
+    let hashes: Vec<Hash> = files.keys().cloned().collect();
+    let already_present = store.has_many(&hashes).await?;
+
+    for (hash, size) in files {
+        if excluded.contains(&hash) {
+            done += size;
+            continue;
+        }
+        if !already_present.contains(&hash) {
+            let base = done;
+            copy_to_store(
+                &hash,
+                size,
+                &stores,
+                &store,
+                verify_percent,
+                &|n| progress.report(base + n, total),
+                &fs,
+            )
+            .await?;
+            copied += 1;
+        }
+        done += size;
+        progress.report(done, total);
+        present.insert(hash);
+    }
+
+    let nr_present = present.len();
+
+    if let Some(media) = fs.write().unwrap().superblock.media.get_mut(name) {
+        media.hashes = present;
+    }
+
+    Ok((nr_present, copied))
+}
+
+/// Record which files a removable store has, and detach it.
+async fn handle_detach_media(name: &str, fs: Arc<RwLock<FilesystemState>>) -> Result<usize> {
+    let (store, files) = {
+        let fs = fs.read().unwrap();
+        let media = fs
+            .superblock
+            .media
+            .get(name)
+            .ok_or_else(|| Error::UnknownStore(name.into()))?;
+        let store = fs
+            .stores
+            .iter()
+            .find(|st| st.get_url() == media.url)
+            .cloned()
+            .ok_or_else(|| Error::UnknownStore(media.url.clone()))?;
+        (store, fs.superblock.all_hashes())
+    };
+
+    let files: Vec<Hash> = files.into_iter().collect();
+    let present = store.has_many(&files).await?;
+
+    let nr_present = present.len();
+
+    let mut fs = fs.write().unwrap();
+    if let Some(media) = fs.superblock.media.get_mut(name) {
+        media.hashes = present;
+    }
+    fs.remove_store(&store.get_url())?;
+    info!("Detached media '{}'.", name);
+
+    Ok(nr_present)
+}
+
+fn handle_add_store(
+    url: &str,
+    key_file: Option<&Path>,
+    fs: Arc<RwLock<FilesystemState>>,
+) -> Result<()> {
+    let mut keys = fs.read().unwrap().keys.clone();
+
+    if let Some(key_file) = key_file {
+        let key = Key::from_file(key_file)?;
+        keys.insert(key.fingerprint(), key);
+    }
+
+    let store = crate::store::open_store(url, &keys)?;
+
+    let mut fs = fs.write().unwrap();
+    fs.add_store(Arc::clone(&store))?;
+    fs.keys = keys;
+
+    info!("Attached store '{}'.", store.get_url());
+
+    Ok(())
+}
+
+async fn handle_list_stores(fs: Arc<RwLock<FilesystemState>>) -> Result<ListStoresResponse> {
+    let stores = fs.read().unwrap().stores.clone();
+
+    let mut res = ListStoresResponse { stores: vec![] };
+
+    for store in &stores {
+        res.stores.push(StoreInfo {
+            url: store.get_url(),
+            writable: store.is_writable(),
+            reference: store.is_reference(),
+            key_fingerprint: store.key_fingerprint().map(|fp| fp.0.to_hex()),
+            error: crate::fusefs::probe_store(&fs, store).await,
+        });
+    }
+
+    Ok(res)
 }
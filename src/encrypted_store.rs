@@ -1,13 +1,17 @@
 use crate::hash::Hash;
-use crate::store::{Future, MutableFile, Store};
-use aes_ctr::stream_cipher::generic_array::GenericArray;
+use crate::shamir;
+use crate::store::{Future, MutableFile, RecallInfo, Store};
+use aes_ctr::stream_cipher::generic_array::{typenum::Unsigned, GenericArray};
 use aes_ctr::stream_cipher::{NewStreamCipher, SyncStreamCipher, SyncStreamCipherSeek};
 use aes_ctr::Aes256Ctr;
-use log::debug;
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::debug;
 
 #[derive(Clone)]
 pub struct Key(pub GenericArray<u8, <Aes256Ctr as NewStreamCipher>::KeySize>);
@@ -15,6 +19,14 @@ pub struct Key(pub GenericArray<u8, <Aes256Ctr as NewStreamCipher>::KeySize>);
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct KeyFingerprint(pub Hash);
 
+pub type Keys = HashMap<KeyFingerprint, Key>;
+
+/// Contents of the object that `EncryptedStore::check_key()` decrypts
+/// to prove that a key opens a store. It's added along with the
+/// ledger, so every encrypted store that has been mounted read-write
+/// has one.
+const KEY_CHECK: &[u8] = b"hugefs key check\n";
+
 impl Key {
     pub fn from_file(key_file: &Path) -> std::result::Result<Self, std::io::Error> {
         let mut key = vec![];
@@ -22,6 +34,86 @@ impl Key {
         Ok(Key(GenericArray::clone_from_slice(&key)))
     }
 
+    /// Split this key into `n` share files named `<prefix>.<i>`,
+    /// any `threshold` of which can be combined using
+    /// `from_share_files()`. Each file contains the share's
+    /// x-coordinate, the threshold, its y-values and the
+    /// fingerprint of the key.
+    pub fn split_to_files(
+        &self,
+        prefix: &Path,
+        n: u8,
+        threshold: u8,
+    ) -> std::result::Result<Vec<PathBuf>, std::io::Error> {
+        let mut files = vec![];
+        let fingerprint = self.fingerprint();
+        for share in shamir::split(&self.0, n, threshold)? {
+            let mut path = prefix.as_os_str().to_owned();
+            path.push(format!(".{}", share.x));
+            let path = PathBuf::from(path);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?;
+            file.write_all(&[share.x, threshold])?;
+            file.write_all(&share.y)?;
+            file.write_all(&fingerprint.0 .0)?;
+            files.push(path);
+        }
+        Ok(files)
+    }
+
+    pub fn from_share_files(share_files: &[PathBuf]) -> std::result::Result<Self, std::io::Error> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let key_size = <Aes256Ctr as NewStreamCipher>::KeySize::to_usize();
+
+        let mut shares = vec![];
+        // The threshold and fingerprint, which shares written by
+        // older versions lack.
+        let mut check = None;
+        for share_file in share_files {
+            let mut data = vec![];
+            File::open(share_file)?.read_to_end(&mut data)?;
+            let (x, y, share_check) = if data.len() == 1 + key_size {
+                (data[0], &data[1..], None)
+            } else if data.len() > 2 + key_size {
+                (
+                    data[0],
+                    &data[2..2 + key_size],
+                    Some((data[1], data[2 + key_size..].to_vec())),
+                )
+            } else {
+                return Err(invalid(format!(
+                    "'{}' is not a key share",
+                    share_file.display()
+                )));
+            };
+            if shares.is_empty() {
+                check = share_check;
+            } else if share_check != check {
+                return Err(invalid(format!(
+                    "'{}' is a share of a different key",
+                    share_file.display()
+                )));
+            }
+            shares.push(shamir::Share { x, y: y.to_vec() });
+        }
+
+        let threshold = check.as_ref().map_or(0, |(threshold, _)| *threshold);
+        let key = Key(GenericArray::clone_from_slice(&shamir::combine(
+            &shares, threshold,
+        )?));
+        if let Some((_, fingerprint)) = check {
+            if key.fingerprint().0 .0[..] != fingerprint[..] {
+                return Err(invalid(
+                    "the key shares don't reconstruct the key they were split from".into(),
+                ));
+            }
+        }
+        Ok(key)
+    }
+
     pub fn fingerprint(&self) -> KeyFingerprint {
         KeyFingerprint(Hash::hash(&self.0[..]).unwrap().1)
     }
@@ -32,7 +124,10 @@ impl<'de> serde::Deserialize<'de> for KeyFingerprint {
     where
         D: serde::Deserializer<'de>,
     {
-        Ok(Self(Hash::from_hex(&String::deserialize(deserializer)?)))
+        let s = String::deserialize(deserializer)?;
+        Hash::from_hex(&s)
+            .map(Self)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid key fingerprint '{}'", s)))
     }
 }
 
@@ -46,6 +141,17 @@ impl EncryptedStore {
         Self { inner, key }
     }
 
+    /// Whether this store's key decrypts the key check object. This
+    /// is `false` if the store was encrypted with another key, or if
+    /// it has no key check object yet.
+    pub async fn check_key(&self) -> crate::store::Result<bool> {
+        let hash = key_check_hash();
+        if !self.has(&hash).await? {
+            return Ok(false);
+        }
+        Ok(self.get(&hash, 0, KEY_CHECK.len()).await? == KEY_CHECK)
+    }
+
     fn encrypt_file_hash(&self, file_hash: &Hash) -> (Hash, Aes256Ctr) {
         /* We use the file hash as the IV/nonce. This is safe because
          * by definition this nonce will only be used to encrypt
@@ -99,6 +205,23 @@ impl Store for EncryptedStore {
         })
     }
 
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        Box::pin(async move {
+            let encrypted: HashMap<Hash, &Hash> = file_hashes
+                .iter()
+                .map(|file_hash| (self.encrypt_file_hash(file_hash).0, file_hash))
+                .collect();
+            let keys: Vec<Hash> = encrypted.keys().cloned().collect();
+            Ok(self
+                .inner
+                .has_many(&keys)
+                .await?
+                .iter()
+                .map(|encrypted_file_hash| encrypted[encrypted_file_hash].clone())
+                .collect())
+        })
+    }
+
     fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>> {
         let file_hash = file_hash.clone();
 
@@ -124,4 +247,90 @@ impl Store for EncryptedStore {
     fn get_url(&self) -> String {
         self.inner.get_url()
     }
+
+    fn is_reference(&self) -> bool {
+        self.inner.is_reference()
+    }
+
+    fn key_fingerprint(&self) -> Option<KeyFingerprint> {
+        Some(self.key.fingerprint())
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        self.inner.check_health()
+    }
+
+    fn erase<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let (encrypted_file_hash, _) = self.encrypt_file_hash(file_hash);
+        Box::pin(async move { self.inner.erase(&encrypted_file_hash).await })
+    }
+
+    fn remove<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let (encrypted_file_hash, _) = self.encrypt_file_hash(file_hash);
+        Box::pin(async move { self.inner.remove(&encrypted_file_hash).await })
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        self.inner.max_size()
+    }
+
+    fn used_bytes<'a>(&'a self) -> Future<'a, u64> {
+        self.inner.used_bytes()
+    }
+
+    fn last_access<'a>(&'a self, file_hash: &Hash) -> Future<'a, Option<SystemTime>> {
+        let (encrypted_file_hash, _) = self.encrypt_file_hash(file_hash);
+        Box::pin(async move { self.inner.last_access(&encrypted_file_hash).await })
+    }
+
+    fn write_ledger<'a>(&'a self, fs_id: &'a str, mut hashes: Vec<Hash>) -> Future<'a, ()> {
+        Box::pin(async move {
+            let key_check = key_check_hash();
+            if !self.is_reference() && !self.has(&key_check).await? {
+                self.add(&key_check, KEY_CHECK).await?;
+            }
+            // The ledger keeps the key check object from being
+            // garbage-collected.
+            hashes.push(key_check);
+
+            // The inner store only knows the encrypted hashes.
+            let hashes = hashes.iter().map(|h| self.encrypt_file_hash(h).0).collect();
+            self.inner.write_ledger(fs_id, hashes).await
+        })
+    }
+
+    fn in_other_ledgers<'a>(&'a self, fs_id: &'a str, file_hash: &Hash) -> Future<'a, bool> {
+        let (encrypted_file_hash, _) = self.encrypt_file_hash(file_hash);
+        Box::pin(async move {
+            self.inner
+                .in_other_ledgers(fs_id, &encrypted_file_hash)
+                .await
+        })
+    }
+
+    fn find_orphans<'a>(&'a self, remove: bool) -> Future<'a, Vec<String>> {
+        self.inner.find_orphans(remove)
+    }
+
+    fn recalls(&self) -> Vec<RecallInfo> {
+        self.inner.recalls()
+    }
+
+    fn process_recalls<'a>(&'a self) -> Future<'a, usize> {
+        self.inner.process_recalls()
+    }
+
+    fn cluster<'a>(&'a self, name: &'a str, file_hashes: &'a [Hash]) -> Future<'a, usize> {
+        Box::pin(async move {
+            let encrypted: Vec<Hash> = file_hashes
+                .iter()
+                .map(|file_hash| self.encrypt_file_hash(file_hash).0)
+                .collect();
+            self.inner.cluster(name, &encrypted).await
+        })
+    }
+}
+
+fn key_check_hash() -> Hash {
+    Hash::hash(KEY_CHECK).unwrap().1
 }
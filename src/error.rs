@@ -10,7 +10,7 @@ pub enum Error {
     NotImmutableFile(Ino),
     BadFileHandle(u64),
     NoSuchHash(crate::hash::Hash),
-    StorageError(Box<dyn std::error::Error>),
+    StorageError(Box<dyn std::error::Error + Send + Sync>),
     NoSuchKey(crate::encrypted_store::KeyFingerprint),
     BadControlRequest,
     BadControlResponse,
@@ -18,6 +18,38 @@ pub enum Error {
     BadPath(std::path::PathBuf),
     NotHugefs,
     UnknownStore(String),
+    StoreUnavailable(String),
+    FileBusy(Ino),
+    NameTooLong,
+    DuplicateStore(String),
+    BadArguments(String),
+    BadHash(String),
+    NoLedgers(String),
+    ReadOnly,
+    BadSignature(String),
+    VerificationFailed(usize),
+    MirrorCorrupt(String, crate::hash::Hash),
+    FileHandleInvalidated,
+    ReferenceStore(String),
+    StoreExcluded(String, std::path::PathBuf),
+    EncryptionRequired(String),
+    EncryptionViolations(usize),
+    CallbackFailed(String, String),
+    EraseUnsupported(String),
+    QuotaExceeded(Ino),
+    RemoveUnsupported(String),
+    ListUnsupported(String),
+    LastCopy(String, std::path::PathBuf),
+    Pinned(String, std::path::PathBuf),
+    NoSuchSnapshot(String),
+    SnapshotExists(String),
+    CorruptState(std::path::PathBuf, String),
+    SyncFailed(usize),
+    SchemeExists(String),
+    RecallPending(String, crate::hash::Hash),
+    TruncateUnsupported,
+    UnsupportedStoreFormat(String, u32),
+    StoreHasMutableFiles(String, usize),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -32,6 +64,14 @@ impl From<Error> for FuseError {
             Error::BadFileHandle(_) => libc::ENXIO, // denotes a kernel bug
             Error::NoSuchHash(_) => libc::ENOMEDIUM,
             Error::StorageError(_) => libc::EIO,
+            Error::FileBusy(_) => libc::EBUSY,
+            Error::NameTooLong => libc::ENAMETOOLONG,
+            Error::ReadOnly | Error::ReferenceStore(_) => libc::EROFS,
+            Error::EncryptionRequired(_) => libc::EACCES,
+            Error::FileHandleInvalidated => libc::EIO,
+            Error::QuotaExceeded(_) => libc::EDQUOT,
+            Error::RecallPending(_, _) => libc::EAGAIN,
+            Error::TruncateUnsupported => libc::ENOTSUP,
             _ => libc::EIO,
         }
         .into()
@@ -66,6 +106,83 @@ impl std::fmt::Display for Error {
             Error::BadPath(p) => write!(f, "Bad path '{:#?}'.", p),
             Error::NotHugefs => write!(f, "Path does not refer to a hugefs filesystem."),
             Error::UnknownStore(s) => write!(f, "Unknown store '{}'.", s),
+            Error::StoreUnavailable(s) => write!(f, "Store '{}' is not available.", s),
+            Error::FileBusy(ino) => write!(f, "Inode {} is open for writing.", ino),
+            Error::NameTooLong => write!(f, "Name too long."),
+            Error::DuplicateStore(s) => write!(f, "Store '{}' is already attached.", s),
+            Error::BadArguments(s) => write!(f, "Bad arguments: {}.", s),
+            Error::BadHash(s) => write!(f, "Invalid hash '{}'.", s),
+            Error::NoLedgers(s) => write!(f, "Store '{}' has no reference ledgers.", s),
+            Error::ReadOnly => write!(f, "The filesystem is read-only."),
+            Error::BadSignature(s) => write!(f, "Bad manifest signature: {}.", s),
+            Error::StoreExcluded(s, p) => write!(
+                f,
+                "Path '{}' must not be stored on store '{}'.",
+                p.display(),
+                s
+            ),
+            Error::ReferenceStore(s) => write!(f, "Store '{}' is a reference store.", s),
+            Error::FileHandleInvalidated => {
+                write!(f, "File handle invalidated by a previous write error.")
+            }
+            Error::MirrorCorrupt(s, hash) => write!(
+                f,
+                "Store '{}' returned wrong data for {}.",
+                s,
+                hash.to_hex()
+            ),
+            Error::EncryptionRequired(s) => {
+                write!(f, "Store '{}' only accepts encrypted data.", s)
+            }
+            Error::EncryptionViolations(n) => {
+                write!(f, "{} encryption violations found.", n)
+            }
+            Error::SyncFailed(n) => write!(f, "{} files could not be synced.", n),
+            Error::SchemeExists(s) => write!(f, "Store URL scheme '{}' is already registered.", s),
+            Error::TruncateUnsupported => write!(f, "The file cannot be resized."),
+            Error::StoreHasMutableFiles(s, n) => write!(
+                f,
+                "Store '{}' holds {} files that are being written; finalize them first.",
+                s, n
+            ),
+            Error::UnsupportedStoreFormat(s, version) => write!(
+                f,
+                "Store '{}' has format version {}, but this version of hugefs supports up to {}.",
+                s,
+                version,
+                crate::local_store::CURRENT_FORMAT
+            ),
+            Error::RecallPending(s, hash) => write!(
+                f,
+                "File {} in store '{}' is offline and is being recalled.",
+                hash.to_hex(),
+                s
+            ),
+            Error::CallbackFailed(url, status) => {
+                write!(f, "Callback to '{}' failed with status '{}'.", url, status)
+            }
+            Error::QuotaExceeded(ino) => write!(f, "Quota of directory {} exceeded.", ino),
+            Error::LastCopy(s, p) => write!(
+                f,
+                "Store '{}' has the only intact copy of '{}'.",
+                s,
+                p.display()
+            ),
+            Error::Pinned(s, p) => write!(f, "Path '{}' is pinned to store '{}'.", p.display(), s),
+            Error::RemoveUnsupported(s) => write!(f, "Store '{}' cannot delete files.", s),
+            Error::ListUnsupported(s) => write!(f, "Store '{}' cannot list its files.", s),
+            Error::NoSuchSnapshot(s) => write!(f, "Snapshot '{}' does not exist.", s),
+            Error::SnapshotExists(s) => write!(f, "Snapshot '{}' already exists.", s),
+            Error::CorruptState(p, err) => write!(
+                f,
+                "State file '{}' is corrupt ({}); run 'hugefs meta recover' to repair it.",
+                p.display(),
+                err
+            ),
+            Error::EraseUnsupported(s) => write!(f, "Store '{}' cannot erase files.", s),
+            Error::VerificationFailed(n) => {
+                write!(f, "{} files do not match the manifest.", n)
+            }
         }
     }
 }
@@ -0,0 +1,195 @@
+use crate::error::Error;
+use crate::hash::Hash;
+use crate::store::{Config, Future, MutableFile, RecallInfo, Result, Store};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Probability (in percent) that an operation fails.
+const ERROR_PERCENT: u64 = 5;
+
+/// Probability (in percent) that a read returns less data than requested.
+const PARTIAL_READ_PERCENT: u64 = 5;
+
+/// Maximum latency added to each operation.
+const MAX_LATENCY_MS: u64 = 200;
+
+/// A store wrapper that injects latency, partial reads and transient
+/// errors, to test how hugefs copes with unreliable stores. The
+/// faults are determined by a seed, so failures can be reproduced.
+pub struct FlakyStore {
+    inner: Arc<dyn Store>,
+    rng: Mutex<u64>,
+}
+
+impl FlakyStore {
+    pub fn new(inner: Arc<dyn Store>, seed: u64) -> Self {
+        Self {
+            inner,
+            // xorshift doesn't work with a zero state.
+            rng: Mutex::new(seed | 1),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        let mut x = self.rng.lock().unwrap();
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        *x
+    }
+
+    fn chance(&self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+
+    async fn inject(&self, op: &str) -> Result<()> {
+        let latency = self.next() % (MAX_LATENCY_MS + 1);
+        tokio::time::delay_for(Duration::from_millis(latency)).await;
+        if self.chance(ERROR_PERCENT) {
+            return Err(Error::StorageError(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("injected fault in '{}' on store '{}'", op, self.get_url()),
+            ))));
+        }
+        Ok(())
+    }
+}
+
+impl Store for FlakyStore {
+    fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inject("add").await?;
+            self.inner.add(&file_hash, data).await
+        })
+    }
+
+    fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inject("has").await?;
+            self.inner.has(&file_hash).await
+        })
+    }
+
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        Box::pin(async move {
+            self.inject("has").await?;
+            self.inner.has_many(file_hashes).await
+        })
+    }
+
+    fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inject("get").await?;
+            let mut data = self.inner.get(&file_hash, offset, size).await?;
+            if !data.is_empty() && self.chance(PARTIAL_READ_PERCENT) {
+                let len = (self.next() % data.len() as u64) as usize;
+                data.truncate(len);
+            }
+            Ok(data)
+        })
+    }
+
+    fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        self.inner.create_file()
+    }
+
+    fn reopen_file<'a>(&'a self, path: &'a Path) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        self.inner.reopen_file(path)
+    }
+
+    fn get_config(&self) -> Result<Config> {
+        self.inner.get_config()
+    }
+
+    fn get_url(&self) -> String {
+        self.inner.get_url()
+    }
+
+    fn is_reference(&self) -> bool {
+        self.inner.is_reference()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+
+    fn write_ledger<'a>(&'a self, fs_id: &'a str, hashes: Vec<Hash>) -> Future<'a, ()> {
+        self.inner.write_ledger(fs_id, hashes)
+    }
+
+    fn in_other_ledgers<'a>(&'a self, fs_id: &'a str, file_hash: &Hash) -> Future<'a, bool> {
+        self.inner.in_other_ledgers(fs_id, file_hash)
+    }
+
+    fn find_orphans<'a>(&'a self, remove: bool) -> Future<'a, Vec<String>> {
+        self.inner.find_orphans(remove)
+    }
+
+    fn erase<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inject("erase").await?;
+            self.inner.erase(&file_hash).await
+        })
+    }
+
+    fn adopt<'a>(&'a self, file_hash: &Hash, path: &'a Path, link: bool) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inject("adopt").await?;
+            self.inner.adopt(&file_hash, path, link).await
+        })
+    }
+
+    fn remove<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inject("remove").await?;
+            self.inner.remove(&file_hash).await
+        })
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        self.inner.max_size()
+    }
+
+    fn used_bytes<'a>(&'a self) -> Future<'a, u64> {
+        self.inner.used_bytes()
+    }
+
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        self.inner.list()
+    }
+
+    fn last_access<'a>(&'a self, file_hash: &Hash) -> Future<'a, Option<SystemTime>> {
+        self.inner.last_access(file_hash)
+    }
+
+    fn key_fingerprint(&self) -> Option<crate::encrypted_store::KeyFingerprint> {
+        self.inner.key_fingerprint()
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        Box::pin(async move {
+            self.inject("check_health").await?;
+            self.inner.check_health().await
+        })
+    }
+
+    fn recalls(&self) -> Vec<RecallInfo> {
+        self.inner.recalls()
+    }
+
+    fn process_recalls<'a>(&'a self) -> Future<'a, usize> {
+        self.inner.process_recalls()
+    }
+
+    fn cluster<'a>(&'a self, name: &'a str, file_hashes: &'a [Hash]) -> Future<'a, usize> {
+        self.inner.cluster(name, file_hashes)
+    }
+}
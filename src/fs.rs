@@ -1,17 +1,21 @@
 use crate::error::{Error, Result};
 use crate::hash::Hash;
+use crate::jobs::JobQueue;
 use libc;
 use serde::{Deserialize, Serialize};
-use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet};
 //use std::fs;
 use std::io::{Read, Write};
 //use std::os::unix::fs::MetadataExt;
-use std::path::{Component, Path};
-use std::sync::{Arc, RwLock};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub type Ino = u64;
 
+/// Format version of newly written state files.
+pub const SUPERBLOCK_VERSION: u32 = 1;
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct Time(pub i64);
 
@@ -28,12 +32,19 @@ impl From<SystemTime> for Time {
 }
 
 impl Time {
+    /// The time elapsed since this time, or zero if it lies in the future.
+    pub fn elapsed(&self) -> Duration {
+        crate::clock::now()
+            .duration_since(SystemTime::from(self))
+            .unwrap_or_default()
+    }
+
     pub fn from_nanos(secs: i64, nsecs: i64) -> Self {
         Time(secs * 1000000000 + nsecs)
     }
 
     pub fn now() -> Self {
-        SystemTime::now().into()
+        crate::clock::now().into()
     }
 }
 
@@ -42,6 +53,177 @@ pub struct Superblock {
     inodes: HashMap<Ino, Arc<RwLock<Inode>>>,
     root_ino: Ino,
     next_ino: Ino,
+    /// Lower bound of the generation of the next inode (see
+    /// `Inode::generation`).
+    #[serde(default)]
+    next_generation: u64,
+    /// Unique identifier of this filesystem, used to name its
+    /// reference ledger in stores.
+    #[serde(default = "random_id")]
+    id: String,
+    /// Format version of the state file. State files predating
+    /// versioning have version 0.
+    #[serde(default)]
+    version: u32,
+    /// Whether the filesystem has been frozen into a read-only archive.
+    #[serde(default)]
+    pub frozen: bool,
+    /// Removable stores, keyed by name.
+    #[serde(default)]
+    pub media: BTreeMap<String, Media>,
+    /// Limits on the contents of directories, keyed by inode.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub quotas: BTreeMap<Ino, Quota>,
+    /// Tiering policies of directories, keyed by inode.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tier_policies: BTreeMap<Ino, TierPolicy>,
+    /// The directory containing the roots of the snapshots, exposed
+    /// as `.snapshots` in the root directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    snapshot_dir: Option<Ino>,
+    /// Hashes of deleted files that still have to be securely
+    /// erased from the stores. They're kept here until the erase
+    /// succeeds, so that failed erases can be retried after a
+    /// restart.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub pending_erases: HashSet<Hash>,
+    /// Jobs submitted through the control API.
+    #[serde(default, skip_serializing_if = "JobQueue::is_empty")]
+    pub jobs: JobQueue,
+    /// Whether the filesystem is mounted read-only, e.g. as a
+    /// standby of another filesystem.
+    #[serde(skip)]
+    pub read_only: bool,
+    #[serde(skip)]
+    index: Index,
+}
+
+/// Indexes over the inodes that would be expensive to recompute on
+/// every operation. They're not stored in the state file but rebuilt
+/// when it's loaded, and kept up to date by `entry_added()`,
+/// `entry_moved()`, `entry_removed()` and `contents_replaced()`.
+#[derive(Debug, Default)]
+struct Index {
+    /// The directory containing each inode reachable from the root
+    /// or the snapshots (hard links aren't supported).
+    parents: HashMap<Ino, Ino>,
+    /// Number of reachable immutable files with each hash.
+    refs: HashMap<Hash, usize>,
+    /// The tree hashes of directories computed by `tree_hash()`. If
+    /// a directory is present, so are all directories below it, so
+    /// invalidation can stop at the first ancestor that's absent.
+    tree_hashes: Mutex<HashMap<Ino, Option<Hash>>>,
+    /// The reachable mutable files, whose size changes with every
+    /// write and so isn't included in `quota_usage`.
+    mutable_files: HashMap<Ino, Arc<MutableFile>>,
+    /// The usage of each directory that has a quota, not counting
+    /// mutable files.
+    quota_usage: HashMap<Ino, Usage>,
+}
+
+impl Index {
+    /// Record that `ino`, which has contents `contents`, has become
+    /// reachable.
+    fn add_contents(&mut self, ino: Ino, contents: &Contents) {
+        match contents {
+            Contents::RegularFile(file) => {
+                *self.refs.entry(file.hash.clone()).or_default() += 1;
+            }
+            Contents::MutableFile(file) => {
+                self.mutable_files.insert(ino, Arc::clone(file));
+            }
+            _ => {}
+        }
+    }
+
+    /// Record that `ino`, which has contents `contents`, is no
+    /// longer reachable.
+    fn remove_contents(&mut self, ino: Ino, contents: &Contents) {
+        match contents {
+            Contents::RegularFile(file) => {
+                if let Entry::Occupied(mut e) = self.refs.entry(file.hash.clone()) {
+                    *e.get_mut() -= 1;
+                    if *e.get() == 0 {
+                        e.remove();
+                    }
+                }
+            }
+            Contents::MutableFile(_) => {
+                self.mutable_files.remove(&ino);
+            }
+            _ => {}
+        }
+    }
+
+    /// Add `usage` to the usage of the quota directories `roots`.
+    fn charge(&mut self, roots: &[Ino], usage: Usage) {
+        for root in roots {
+            if let Some(total) = self.quota_usage.get_mut(root) {
+                total.bytes += usage.bytes;
+                total.inodes += usage.inodes;
+            }
+        }
+    }
+
+    /// Subtract `usage` from the usage of the quota directories
+    /// `roots`.
+    fn discharge(&mut self, roots: &[Ino], usage: Usage) {
+        for root in roots {
+            if let Some(total) = self.quota_usage.get_mut(root) {
+                total.bytes = total.bytes.saturating_sub(usage.bytes);
+                total.inodes = total.inodes.saturating_sub(usage.inodes);
+            }
+        }
+    }
+
+    /// Forget the tree hashes of `ino` and its ancestors.
+    fn invalidate(&self, mut ino: Option<Ino>) {
+        let mut tree_hashes = self.tree_hashes.lock().unwrap();
+        while let Some(cur) = ino {
+            if tree_hashes.remove(&cur).is_none() {
+                break;
+            }
+            ino = self.parents.get(&cur).cloned();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_bytes: Option<u64>,
+    pub max_inodes: Option<u64>,
+}
+
+/// Immutable files below a directory with a tiering policy that
+/// haven't been read for `cold_after_days` days are moved from the
+/// `fast` store to the `archive` store, and copied back when read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TierPolicy {
+    pub fast: String,
+    pub archive: String,
+    pub cold_after_days: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+/// A store on removable media that is only attached occasionally.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Media {
+    pub url: String,
+    /// The files that were on the media when it was last attached.
+    pub hashes: HashSet<Hash>,
+}
+
+fn random_id() -> String {
+    let mut buf = [0u8; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .expect("cannot read /dev/urandom");
+    hex::encode(buf)
 }
 
 impl Superblock {
@@ -56,6 +238,519 @@ impl Superblock {
         self.root_ino
     }
 
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Mark the superblock as having the current format version.
+    pub fn upgrade(&mut self) {
+        self.version = SUPERBLOCK_VERSION;
+    }
+
+    /// Return the number of mutable files, i.e. files that haven't
+    /// been finalized yet.
+    pub fn mutable_files(&self) -> usize {
+        self.get_mutable_files().len()
+    }
+
+    /// Return the inodes of the mutable files.
+    pub fn get_mutable_inodes(&self) -> Vec<Arc<RwLock<Inode>>> {
+        self.inodes
+            .values()
+            .filter(|inode| matches!(inode.read().unwrap().contents, Contents::MutableFile(_)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_mutable_files(&self) -> Vec<Arc<MutableFile>> {
+        self.inodes
+            .values()
+            .filter_map(|inode| match &inode.read().unwrap().contents {
+                Contents::MutableFile(file) => Some(Arc::clone(file)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn check_writable(&self) -> Result<()> {
+        if self.frozen || self.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the filesystem is writable and `ino` is not part
+    /// of a snapshot.
+    pub fn check_mutable(&self, ino: Ino) -> Result<()> {
+        self.check_writable()?;
+        if self.get_inode(ino)?.read().unwrap().snapshot {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    /// Return the hashes of all immutable files.
+    pub fn all_hashes(&self) -> HashSet<Hash> {
+        self.inodes
+            .values()
+            .filter_map(|inode| match &inode.read().unwrap().contents {
+                Contents::RegularFile(file) => Some(file.hash.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return the hashes of the files reachable from the root
+    /// directory or the snapshots. Unlike `all_hashes()`, this
+    /// ignores unlinked inodes.
+    pub fn reachable_hashes(&self) -> HashSet<Hash> {
+        self.index.refs.keys().cloned().collect()
+    }
+
+    /// Return the hashes of the files that must not be stored on
+    /// the store with URL `url`.
+    pub fn excluded_hashes(&self, url: &str) -> HashSet<Hash> {
+        self.inodes
+            .values()
+            .filter_map(|inode| {
+                let inode = inode.read().unwrap();
+                match &inode.contents {
+                    Contents::RegularFile(file) if inode.excluded_stores.contains(url) => {
+                        Some(file.hash.clone())
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Return the sizes of the immutable files that are pinned to
+    /// the store `url`, indexed by hash.
+    pub fn pinned_files(&self, url: &str) -> HashMap<Hash, u64> {
+        self.inodes
+            .values()
+            .filter_map(|inode| {
+                let inode = inode.read().unwrap();
+                match &inode.contents {
+                    Contents::RegularFile(file) if inode.pinned_stores.contains(url) => {
+                        Some((file.hash.clone(), file.length))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Return the sizes of all immutable files, indexed by hash.
+    pub fn all_files(&self) -> HashMap<Hash, u64> {
+        self.inodes
+            .values()
+            .filter_map(|inode| match &inode.read().unwrap().contents {
+                Contents::RegularFile(file) => Some((file.hash.clone(), file.length)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return whether a file reachable from the root or a snapshot
+    /// has the given hash. Unlike `all_hashes()`, this ignores
+    /// deleted inodes.
+    pub fn is_referenced(&self, hash: &Hash) -> bool {
+        self.index.refs.contains_key(hash)
+    }
+
+    /// Rebuild the index from the trees below the root and the
+    /// snapshots.
+    fn rebuild_index(&mut self) {
+        let mut index = Index::default();
+        let mut todo: Vec<Ino> = std::iter::once(self.root_ino)
+            .chain(self.snapshot_dir)
+            .collect();
+        while let Some(ino) = todo.pop() {
+            let inode = match self.inodes.get(&ino) {
+                Some(inode) => inode.read().unwrap(),
+                None => continue,
+            };
+            if let Contents::Directory(dir) = &inode.contents {
+                for child in dir.entries.values() {
+                    index.parents.insert(*child, ino);
+                    todo.push(*child);
+                }
+            }
+            index.add_contents(ino, &inode.contents);
+        }
+        self.index = index;
+
+        for dir in self.quotas.keys() {
+            let usage = self.quota_dir_usage(*dir);
+            self.index.quota_usage.insert(*dir, usage);
+        }
+    }
+
+    /// Record that an entry for `ino`, which has contents
+    /// `contents`, has been added to the directory `parent`.
+    pub fn entry_added(&mut self, parent: Ino, ino: Ino, contents: &Contents) {
+        let roots = self.quota_roots(parent);
+        if !roots.is_empty() {
+            let usage = self.entry_usage(contents);
+            self.index.charge(&roots, usage);
+        }
+        self.index.invalidate(Some(parent));
+        self.index.parents.insert(ino, parent);
+        self.index.add_contents(ino, contents);
+    }
+
+    /// Record that the entry for `ino` has been moved to the
+    /// directory `parent`.
+    pub fn entry_moved(&mut self, ino: Ino, parent: Ino) {
+        let old_parent = self.index.parents.insert(ino, parent);
+        self.index.invalidate(old_parent);
+        self.index.invalidate(Some(parent));
+
+        let old_roots = old_parent.map_or_else(Vec::new, |dir| self.quota_roots(dir));
+        let new_roots = self.quota_roots(parent);
+        let left: Vec<Ino> = old_roots
+            .iter()
+            .filter(|dir| !new_roots.contains(dir))
+            .cloned()
+            .collect();
+        let entered: Vec<Ino> = new_roots
+            .iter()
+            .filter(|dir| !old_roots.contains(dir))
+            .cloned()
+            .collect();
+        if !left.is_empty() || !entered.is_empty() {
+            let usage = match self.inodes.get(&ino) {
+                Some(inode) => self.entry_usage(&inode.read().unwrap().contents),
+                None => return,
+            };
+            self.index.discharge(&left, usage);
+            self.index.charge(&entered, usage);
+        }
+    }
+
+    /// Record that the entry for `ino`, which has contents
+    /// `contents`, has been removed from its directory.
+    pub fn entry_removed(&mut self, ino: Ino, contents: &Contents) {
+        let parent = self.index.parents.remove(&ino);
+        if let Some(parent) = parent {
+            let roots = self.quota_roots(parent);
+            if !roots.is_empty() {
+                let usage = self.entry_usage(contents);
+                self.index.discharge(&roots, usage);
+            }
+        }
+        self.index.tree_hashes.lock().unwrap().remove(&ino);
+        self.index.invalidate(parent);
+        self.index.remove_contents(ino, contents);
+    }
+
+    /// Record that the contents of `ino` have changed from `old` to
+    /// `new`, e.g. because it was finalized.
+    pub fn contents_replaced(&mut self, ino: Ino, old: &Contents, new: &Contents) {
+        if let Some(parent) = self.index.parents.get(&ino).cloned() {
+            let roots = self.quota_roots(parent);
+            if !roots.is_empty() {
+                let (old_usage, new_usage) = (self.entry_usage(old), self.entry_usage(new));
+                self.index.discharge(&roots, old_usage);
+                self.index.charge(&roots, new_usage);
+            }
+            self.index.invalidate(Some(parent));
+            self.index.remove_contents(ino, old);
+            self.index.add_contents(ino, new);
+        }
+    }
+
+    /// Set or remove the quota of the directory `ino`.
+    pub fn set_quota(&mut self, ino: Ino, quota: Option<Quota>) {
+        match quota {
+            Some(quota) => {
+                if self.quotas.insert(ino, quota).is_none() {
+                    let usage = self.quota_dir_usage(ino);
+                    self.index.quota_usage.insert(ino, usage);
+                }
+            }
+            None => {
+                self.quotas.remove(&ino);
+                self.index.quota_usage.remove(&ino);
+            }
+        }
+    }
+
+    /// Add an entry `name` for `ino` to the directory `parent`.
+    pub fn link(&mut self, parent: Ino, name: &str, ino: Ino) -> Result<()> {
+        {
+            let parent = self.get_inode(parent)?;
+            let mut parent = parent.write().unwrap();
+            let dir = parent.get_directory_mut()?;
+            dir.check_no_entry(name)?;
+            dir.entries.insert(name.to_string(), ino);
+        }
+        let inode = self.get_inode(ino)?;
+        self.entry_added(parent, ino, &inode.read().unwrap().contents);
+        Ok(())
+    }
+
+    /// Remove the entry `name` from the directory `parent`,
+    /// returning its inode.
+    pub fn unlink(&mut self, parent: Ino, name: &str) -> Result<Ino> {
+        let ino = self
+            .get_inode(parent)?
+            .write()
+            .unwrap()
+            .get_directory_mut()?
+            .entries
+            .remove(name)
+            .ok_or(Error::NoSuchEntry)?;
+        let inode = self.get_inode(ino)?;
+        self.entry_removed(ino, &inode.read().unwrap().contents);
+        Ok(ino)
+    }
+
+    /// Return the total size and number of the inodes below `ino`
+    /// (or the size of `ino` if it's a file). This is cheap for
+    /// directories with a quota, whose usage is kept up to date.
+    pub fn usage(&self, ino: Ino) -> Usage {
+        if let Some(usage) = self.index.quota_usage.get(&ino) {
+            let mutable_bytes: u64 = self
+                .index
+                .mutable_files
+                .iter()
+                .filter(|(file_ino, _)| self.is_below(**file_ino, ino))
+                .map(|(_, file)| file.file.len())
+                .sum();
+            return Usage {
+                bytes: usage.bytes + mutable_bytes,
+                inodes: usage.inodes,
+            };
+        }
+
+        let mut usage = Usage::default();
+        let mut todo = vec![ino];
+        while let Some(ino) = todo.pop() {
+            let inode = match self.inodes.get(&ino) {
+                Some(inode) => inode.read().unwrap(),
+                None => continue,
+            };
+            match &inode.contents {
+                Contents::Directory(dir) => todo.extend(dir.entries.values()),
+                Contents::RegularFile(file) => usage.bytes += file.length,
+                Contents::MutableFile(file) => usage.bytes += file.file.len(),
+                Contents::Symlink(_) => {}
+            }
+            usage.inodes += 1;
+        }
+        // Don't count `ino` itself.
+        usage.inodes = usage.inodes.saturating_sub(1);
+        usage
+    }
+
+    /// Return the usage of an entry with contents `contents`,
+    /// including itself and everything below it but not the bytes
+    /// of mutable files.
+    fn entry_usage(&self, contents: &Contents) -> Usage {
+        let mut usage = Usage {
+            bytes: 0,
+            inodes: 1,
+        };
+        let mut todo = vec![];
+        match contents {
+            Contents::Directory(dir) => todo.extend(dir.entries.values()),
+            Contents::RegularFile(file) => usage.bytes = file.length,
+            _ => {}
+        }
+        while let Some(ino) = todo.pop() {
+            let inode = match self.inodes.get(&ino) {
+                Some(inode) => inode.read().unwrap(),
+                None => continue,
+            };
+            match &inode.contents {
+                Contents::Directory(dir) => todo.extend(dir.entries.values()),
+                Contents::RegularFile(file) => usage.bytes += file.length,
+                _ => {}
+            }
+            usage.inodes += 1;
+        }
+        usage
+    }
+
+    /// Compute the usage of the directory `ino` to be stored in
+    /// `Index::quota_usage`.
+    fn quota_dir_usage(&self, ino: Ino) -> Usage {
+        match self.inodes.get(&ino) {
+            Some(inode) => {
+                let mut usage = self.entry_usage(&inode.read().unwrap().contents);
+                // Don't count `ino` itself.
+                usage.inodes -= 1;
+                usage
+            }
+            None => Usage::default(),
+        }
+    }
+
+    /// Return the tree hash of `ino`, or `None` if it is or contains
+    /// a mutable file. The tree hash of a file is the hash of its
+    /// contents, and that of a directory is derived from the names,
+    /// types and tree hashes of its entries, so two trees with the
+    /// same tree hash have the same contents. The tree hashes of
+    /// directories are kept until something below them changes, so
+    /// only the changed parts of a tree are rehashed.
+    pub fn tree_hash(&self, ino: Ino) -> Result<Option<Hash>> {
+        if let Some(hash) = self.index.tree_hashes.lock().unwrap().get(&ino) {
+            return Ok(hash.clone());
+        }
+
+        let entries = match &self.get_inode(ino)?.read().unwrap().contents {
+            Contents::RegularFile(file) => Ok(Some(file.hash.clone())),
+            Contents::MutableFile(_) => Ok(None),
+            Contents::Symlink(link) => Ok(Some(Hash::hash(&link.target[..])?.1)),
+            Contents::Directory(dir) => Err(dir.entries.clone()),
+        };
+
+        let hash = match entries {
+            Ok(hash) => hash,
+            Err(entries) => {
+                let mut buf = vec![];
+                let mut complete = true;
+                for (name, child) in &entries {
+                    let kind = match &self.get_inode(*child)?.read().unwrap().contents {
+                        Contents::Directory(_) => b'd',
+                        Contents::Symlink(_) => b'l',
+                        _ => b'f',
+                    };
+                    match self.tree_hash(*child)? {
+                        Some(child_hash) => {
+                            buf.extend_from_slice(name.as_bytes());
+                            buf.push(0);
+                            buf.push(kind);
+                            buf.extend_from_slice(&child_hash.0);
+                        }
+                        None => complete = false,
+                    }
+                }
+                let hash = if complete {
+                    Some(Hash::hash(&buf[..])?.1)
+                } else {
+                    None
+                };
+                self.index
+                    .tree_hashes
+                    .lock()
+                    .unwrap()
+                    .insert(ino, hash.clone());
+                hash
+            }
+        };
+
+        Ok(hash)
+    }
+
+    /// Return whether `ino` is `dir` or below it.
+    pub fn is_below(&self, ino: Ino, dir: Ino) -> bool {
+        let mut cur = Some(ino);
+        while let Some(ino) = cur {
+            if ino == dir {
+                return true;
+            }
+            cur = self.index.parents.get(&ino).cloned();
+        }
+        false
+    }
+
+    /// Return the directories with a quota that contain `ino`,
+    /// including `ino` itself.
+    pub fn quota_roots(&self, ino: Ino) -> Vec<Ino> {
+        let mut roots = vec![];
+        if self.quotas.is_empty() {
+            return roots;
+        }
+        let mut cur = Some(ino);
+        while let Some(ino) = cur {
+            if self.quotas.contains_key(&ino) {
+                roots.push(ino);
+            }
+            cur = self.index.parents.get(&ino).cloned();
+        }
+        roots
+    }
+
+    /// Fail if adding `extra` below `ino` would exceed a quota.
+    pub fn check_quota(&self, ino: Ino, extra: Usage) -> Result<()> {
+        for dir in self.quota_roots(ino) {
+            self.check_quota_of(dir, extra)?;
+        }
+        Ok(())
+    }
+
+    /// Fail if moving `ino` from directory `from` to directory `to`
+    /// would exceed a quota.
+    pub fn check_quota_move(&self, ino: Ino, from: Ino, to: Ino) -> Result<()> {
+        let from_roots = self.quota_roots(from);
+        let to_roots: Vec<Ino> = self
+            .quota_roots(to)
+            .into_iter()
+            .filter(|dir| !from_roots.contains(dir))
+            .collect();
+        if to_roots.is_empty() {
+            return Ok(());
+        }
+        let mut extra = self.usage(ino);
+        extra.inodes += 1;
+        for dir in to_roots {
+            self.check_quota_of(dir, extra)?;
+        }
+        Ok(())
+    }
+
+    fn check_quota_of(&self, dir: Ino, extra: Usage) -> Result<()> {
+        let quota = &self.quotas[&dir];
+        let usage = self.usage(dir);
+        if quota
+            .max_bytes
+            .map_or(false, |max| usage.bytes + extra.bytes > max)
+            || quota
+                .max_inodes
+                .map_or(false, |max| usage.inodes + extra.inodes > max)
+        {
+            return Err(Error::QuotaExceeded(dir));
+        }
+        Ok(())
+    }
+
+    /// Return the immutable files to which a tiering policy applies,
+    /// together with the directory of the policy. Files are governed
+    /// by the policy of their nearest ancestor that has one.
+    pub fn tiered_files(&self) -> Vec<(Ino, Hash, u64)> {
+        let mut files = vec![];
+        for policy_dir in self.tier_policies.keys() {
+            let mut todo = vec![*policy_dir];
+            while let Some(ino) = todo.pop() {
+                let inode = match self.inodes.get(&ino) {
+                    Some(inode) => inode.read().unwrap(),
+                    None => continue,
+                };
+                match &inode.contents {
+                    Contents::Directory(dir) => todo.extend(
+                        dir.entries
+                            .values()
+                            .filter(|child| !self.tier_policies.contains_key(child)),
+                    ),
+                    Contents::RegularFile(file) => {
+                        files.push((*policy_dir, file.hash.clone(), file.length))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        files
+    }
+
     fn alloc_inode(&mut self) -> Ino {
         let ino = self.next_ino;
         self.next_ino += 1;
@@ -66,6 +761,8 @@ impl Superblock {
         assert_eq!(inode.ino, 0);
         let ino = self.alloc_inode();
         inode.ino = ino;
+        inode.generation = std::cmp::max(self.next_generation, Time::now().0 as u64);
+        self.next_generation = inode.generation + 1;
         match self.inodes.entry(ino) {
             Entry::Vacant(e) => e.insert(Arc::new(RwLock::new(inode))),
             _ => panic!("inode {} already exists", ino),
@@ -73,6 +770,152 @@ impl Superblock {
         ino
     }
 
+    pub fn get_snapshot_dir(&self) -> Option<Ino> {
+        self.snapshot_dir
+    }
+
+    /// Return the names and root inodes of the snapshots.
+    pub fn snapshots(&self) -> BTreeMap<String, Ino> {
+        self.snapshot_dir
+            .and_then(|ino| self.inodes.get(&ino))
+            .and_then(|inode| {
+                inode
+                    .read()
+                    .unwrap()
+                    .get_directory()
+                    .ok()
+                    .map(|dir| dir.entries.clone())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Record a read-only copy of the tree below the root as
+    /// `.snapshots/<name>`. The files share their contents with the
+    /// originals. Fails if the tree contains mutable files.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<Ino> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(Error::BadArguments(format!(
+                "invalid snapshot name '{}'",
+                name
+            )));
+        }
+
+        if self.snapshots().contains_key(name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+
+        let mut todo = vec![self.root_ino];
+        while let Some(ino) = todo.pop() {
+            match &self.get_inode(ino)?.read().unwrap().contents {
+                Contents::Directory(dir) => todo.extend(dir.entries.values()),
+                Contents::MutableFile(_) => return Err(Error::NotImmutableFile(ino)),
+                _ => {}
+            }
+        }
+
+        let snapshot_dir = match self.snapshot_dir {
+            Some(ino) => ino,
+            None => {
+                let ino = self.add_inode(Inode {
+                    perm: 0o555,
+                    snapshot: true,
+                    ..Inode::new(Contents::Directory(Directory::new()))
+                });
+                self.snapshot_dir = Some(ino);
+                ino
+            }
+        };
+
+        let root = self.copy_tree(self.root_ino)?;
+        let created = Time::now();
+        {
+            let root = self.get_inode(root)?;
+            let mut root = root.write().unwrap();
+            root.crtime = created;
+            root.mtime = created;
+        }
+
+        self.link(snapshot_dir, name, root)?;
+        self.get_inode(snapshot_dir)?.write().unwrap().mtime = created;
+
+        Ok(root)
+    }
+
+    /// Copy the tree below `ino` into new read-only inodes.
+    fn copy_tree(&mut self, ino: Ino) -> Result<Ino> {
+        let (copy, entries) = {
+            let inode = self.get_inode(ino)?;
+            let inode = inode.read().unwrap();
+            let mut entries = BTreeMap::new();
+            let contents = match &inode.contents {
+                Contents::Directory(dir) => {
+                    entries = dir.entries.clone();
+                    Contents::Directory(Directory::new())
+                }
+                Contents::RegularFile(file) => Contents::RegularFile(RegularFile {
+                    length: file.length,
+                    hash: file.hash.clone(),
+                }),
+                Contents::Symlink(link) => Contents::Symlink(Symlink {
+                    target: link.target.clone(),
+                }),
+                Contents::MutableFile(_) => return Err(Error::NotImmutableFile(ino)),
+            };
+            let copy = Inode {
+                perm: inode.perm,
+                uid: inode.uid,
+                gid: inode.gid,
+                crtime: inode.crtime,
+                mtime: inode.mtime,
+                excluded_stores: inode.excluded_stores.clone(),
+                pinned_stores: inode.pinned_stores.clone(),
+                snapshot: true,
+                ..Inode::new(contents)
+            };
+            (copy, entries)
+        };
+
+        let copy_ino = self.add_inode(copy);
+
+        for (name, child) in entries {
+            let child_copy = self.copy_tree(child)?;
+            self.link(copy_ino, &name, child_copy)?;
+        }
+
+        Ok(copy_ino)
+    }
+
+    /// Delete the snapshot `name` and its inodes. Returns the hashes
+    /// of the files in the snapshot.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<Vec<Hash>> {
+        let snapshot_dir = self
+            .snapshot_dir
+            .ok_or_else(|| Error::NoSuchSnapshot(name.to_string()))?;
+        let root = match self.unlink(snapshot_dir, name) {
+            Err(Error::NoSuchEntry) => return Err(Error::NoSuchSnapshot(name.to_string())),
+            res => res?,
+        };
+        self.get_inode(snapshot_dir)?.write().unwrap().mtime = Time::now();
+
+        let mut hashes = vec![];
+        let mut todo = vec![root];
+        while let Some(ino) = todo.pop() {
+            if let Some(inode) = self.inodes.remove(&ino) {
+                let inode = inode.read().unwrap();
+                if ino != root {
+                    self.entry_removed(ino, &inode.contents);
+                }
+                match &inode.contents {
+                    Contents::Directory(dir) => todo.extend(dir.entries.values()),
+                    Contents::RegularFile(file) => hashes.push(file.hash.clone()),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(hashes)
+    }
+
     pub fn nr_inodes(&self) -> u64 {
         self.inodes.len() as u64
     }
@@ -90,7 +933,16 @@ impl Superblock {
     }
 
     pub fn lookup_path(&self, path: &Path) -> crate::store::Result<Arc<RwLock<Inode>>> {
-        let mut cur_inode = self.inodes.get(&self.root_ino).unwrap();
+        self.lookup_path_from(self.root_ino, path)
+    }
+
+    /// Look up `path` relative to the directory `ino`.
+    pub fn lookup_path_from(
+        &self,
+        ino: Ino,
+        path: &Path,
+    ) -> crate::store::Result<Arc<RwLock<Inode>>> {
+        let mut cur_inode = self.inodes.get(&ino).ok_or(Error::NoSuchInode(ino))?;
 
         for component in path.components() {
             if let Component::Normal(c) = component {
@@ -118,9 +970,36 @@ pub struct Inode {
     pub crtime: Time,
     pub mtime: Time,
     pub contents: Contents,
+    /// URLs of stores that this file must never be stored on. New
+    /// files inherit this from their parent directory.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub excluded_stores: BTreeSet<String>,
+    /// URLs of stores that must always have a copy of this file. New
+    /// files inherit this from their parent directory.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub pinned_stores: BTreeSet<String>,
+    /// URL of the store on which new files in this directory are
+    /// created. New directories inherit this from their parent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staging_store: Option<String>,
+    /// Whether this inode is part of a snapshot, and therefore
+    /// read-only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub snapshot: bool,
+    /// Distinguishes this inode from earlier inodes with the same
+    /// number, which can be reused if the state file is restored
+    /// from a backup. It's at least the creation time in nanoseconds
+    /// for that reason. Inodes created before generations were
+    /// recorded have generation 0.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub generation: u64,
     //parents: Vec<Ino>,
 }
 
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
 impl Inode {
     pub fn new(contents: Contents) -> Inode {
         let now = Time::now();
@@ -132,6 +1011,11 @@ impl Inode {
             crtime: now,
             mtime: now,
             contents,
+            excluded_stores: BTreeSet::new(),
+            pinned_stores: BTreeSet::new(),
+            staging_store: None,
+            snapshot: false,
+            generation: 0,
         }
     }
 
@@ -167,6 +1051,8 @@ pub enum Contents {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Directory {
+    /// Directory entries, ordered byte-wise by name. readdir returns
+    /// entries in this order, independent of the locale.
     pub entries: BTreeMap<String, Ino>, // FIXME: include type?
 }
 
@@ -196,18 +1082,55 @@ pub struct RegularFile {
     pub hash: Hash,
 }
 
+/// Maximum length in bytes of a symlink target.
+pub const MAX_SYMLINK_TARGET: usize = libc::PATH_MAX as usize;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Symlink {
-    pub target: String,
+    /// The target, which need not be valid UTF-8.
+    #[serde(with = "symlink_target")]
+    pub target: Vec<u8>,
 }
 
 impl Symlink {
-    pub fn new(target: String) -> Self {
-        Self { target }
+    pub fn new(target: Vec<u8>) -> Result<Self> {
+        if target.len() > MAX_SYMLINK_TARGET {
+            return Err(Error::NameTooLong);
+        }
+        Ok(Self { target })
+    }
+}
+
+/// Serialize symlink targets as strings if they're valid UTF-8, and
+/// as byte arrays otherwise.
+mod symlink_target {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(target: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(target) {
+            Ok(s) => s.serialize(serializer),
+            Err(_) => target.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Target {
+            Text(String),
+            Bytes(Vec<u8>),
+        }
+
+        Ok(match Target::deserialize(deserializer)? {
+            Target::Text(s) => s.into_bytes(),
+            Target::Bytes(b) => b,
+        })
     }
 }
 
 pub struct MutableFile {
+    /// The URL of the store containing the file.
+    pub store: String,
     pub file: Box<dyn crate::store::MutableFile>,
 }
 
@@ -217,23 +1140,75 @@ impl std::fmt::Debug for MutableFile {
     }
 }
 
+/// The serialized form of a mutable file: enough to find its data
+/// again after a restart.
+#[derive(Serialize, Deserialize)]
+struct SavedMutableFile {
+    store: String,
+    path: Option<PathBuf>,
+    length: u64,
+}
+
 impl serde::Serialize for MutableFile {
-    fn serialize<S>(&self, _serializer: S) -> std::result::Result<S::Ok, S::Error>
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        Err(serde::ser::Error::custom("cannot serialize a mutable file"))
+        SavedMutableFile {
+            store: self.store.clone(),
+            path: self.file.path(),
+            length: self.file.len(),
+        }
+        .serialize(serializer)
     }
 }
 
 impl<'de> serde::Deserialize<'de> for MutableFile {
-    fn deserialize<D>(_deserializer: D) -> std::result::Result<Self, D::Error>
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        Err(serde::de::Error::custom(
-            "cannot deserialize a mutable file",
-        ))
+        let saved = SavedMutableFile::deserialize(deserializer)?;
+        Ok(Self {
+            store: saved.store,
+            file: Box::new(DetachedFile {
+                path: saved.path,
+                length: saved.length,
+            }),
+        })
+    }
+}
+
+/// A mutable file loaded from a state file. Its data can't be
+/// accessed until `fusefs::reattach_mutable_files()` has reopened it
+/// in its store.
+struct DetachedFile {
+    path: Option<PathBuf>,
+    length: u64,
+}
+
+impl crate::store::MutableFile for DetachedFile {
+    fn write<'a>(&'a self, _offset: u64, _data: &'a [u8]) -> crate::store::Future<'a, ()> {
+        Box::pin(async { Err(Error::FileHandleInvalidated) })
+    }
+
+    fn read<'a>(&'a self, _offset: u64, _size: u32) -> crate::store::Future<'a, Vec<u8>> {
+        Box::pin(async { Err(Error::FileHandleInvalidated) })
+    }
+
+    fn finish<'a>(
+        &'a self,
+        _progress: crate::store::ProgressFn<'a>,
+    ) -> crate::store::Future<'a, (u64, Hash)> {
+        Box::pin(async { Err(Error::FileHandleInvalidated) })
+    }
+
+    fn len(&self) -> u64 {
+        self.length
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        self.path.clone()
     }
 }
 
@@ -244,6 +1219,18 @@ impl Superblock {
             inodes: HashMap::new(),
             root_ino,
             next_ino: root_ino,
+            next_generation: 0,
+            id: random_id(),
+            version: SUPERBLOCK_VERSION,
+            frozen: false,
+            media: BTreeMap::new(),
+            quotas: BTreeMap::new(),
+            tier_policies: BTreeMap::new(),
+            snapshot_dir: None,
+            pending_erases: HashSet::new(),
+            jobs: JobQueue::default(),
+            read_only: false,
+            index: Index::default(),
         };
         res.add_inode(Inode {
             perm: 0o700,
@@ -254,10 +1241,54 @@ impl Superblock {
         res
     }
 
+    /// Create a superblock containing the files `files` (given as
+    /// hash and size) in a directory named `lost+found`, named by
+    /// their hash. This is the last resort for recovering a
+    /// filesystem whose state file has been lost.
+    pub fn from_files(files: &[(Hash, u64)]) -> Self {
+        let mut res = Self::new();
+        let mut dir = Directory::new();
+        for (hash, length) in files {
+            let ino = res.add_inode(Inode::new(Contents::RegularFile(RegularFile {
+                length: *length,
+                hash: hash.clone(),
+            })));
+            dir.entries.insert(hash.to_hex(), ino);
+        }
+        let ino = res.add_inode(Inode::new(Contents::Directory(dir)));
+        let root = Arc::clone(res.inodes.get(&res.root_ino).unwrap());
+        root.write()
+            .unwrap()
+            .get_directory_mut()
+            .unwrap()
+            .entries
+            .insert("lost+found".into(), ino);
+        res.rebuild_index();
+        res
+    }
+
+    /// Read the superblock from the state file `path`, returning
+    /// `Error::CorruptState` if it cannot be parsed.
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_from_json(&mut std::fs::File::open(path)?)
+            .map_err(|err| Error::CorruptState(path.into(), err.to_string()))
+    }
+
+    /// Return the path of the copy of the previous version of the
+    /// state file `path`.
+    pub fn backup_path(path: &Path) -> PathBuf {
+        let mut backup_path: PathBuf = path.into();
+        backup_path.set_extension("json.bak");
+        backup_path
+    }
+
     pub fn open_from_json<R: Read>(
         json_data: &mut R,
     ) -> std::result::Result<Self, serde_json::error::Error> {
-        serde_json::from_reader(json_data)
+        let mut superblock: Self = serde_json::from_reader(json_data)?;
+        superblock.rebuild_index();
+        superblock.jobs.requeue_running();
+        Ok(superblock)
     }
 
     pub fn write_json<W: Write>(
@@ -1,6 +1,12 @@
-use fuse::FileAttr;
+use crate::stats::Counters;
+use fuser::FileAttr;
 use libc::c_int;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tracing::warn;
+use tracing_futures::Instrument;
 
 pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
 
@@ -14,16 +20,84 @@ impl From<c_int> for FuseError {
     }
 }
 
+/// Runs FUSE requests on the executor. Requests are counted and
+/// timed per operation, and operations can be limited to a maximum
+/// number of concurrently executing requests and given a deadline
+/// after which they fail with EIO.
+pub struct Dispatcher {
+    executor: tokio::runtime::Handle,
+    counters: Arc<Counters>,
+    limits: HashMap<String, Arc<Semaphore>>,
+    timeouts: HashMap<String, Duration>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        executor: tokio::runtime::Handle,
+        counters: Arc<Counters>,
+        limits: &[(String, usize)],
+        timeouts: &[(String, Duration)],
+    ) -> Self {
+        Self {
+            executor,
+            counters,
+            limits: limits
+                .iter()
+                .map(|(op, n)| (op.clone(), Arc::new(Semaphore::new(*n))))
+                .collect(),
+            timeouts: timeouts.iter().cloned().collect(),
+        }
+    }
+
+    fn spawn<T>(
+        &self,
+        op: &'static str,
+        fut: impl std::future::Future<Output = Result<T>> + Send + 'static,
+        reply: impl FnOnce(Result<T>) + Send + 'static,
+    ) {
+        self.counters.op(op);
+        let counters = Arc::clone(&self.counters);
+        let limit = self.limits.get(op).cloned();
+        let timeout = self.timeouts.get(op).cloned();
+        // FIXME: the fuse binding doesn't pass FUSE_INTERRUPT on to
+        // the filesystem, so a deadline is the only way to abort a
+        // request blocked on a hung store.
+        self.executor.spawn(
+            async move {
+                let _permit = match &limit {
+                    Some(limit) => Some(limit.acquire().await),
+                    None => None,
+                };
+                counters.begin(op);
+                let start = Instant::now();
+                let res = match timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, fut).await {
+                        Ok(res) => res,
+                        Err(_) => {
+                            warn!("'{}' request timed out after {:?}.", op, timeout);
+                            counters.timed_out(op);
+                            Err(libc::EIO.into())
+                        }
+                    },
+                    None => fut.await,
+                };
+                counters.end(op, start.elapsed());
+                reply(res);
+            }
+            .in_current_span(),
+        );
+    }
+}
+
 pub fn wrap_attr(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyAttr,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyAttr,
     fut: impl std::future::Future<Output = Result<(Duration, FileAttr)>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok(attr) => reply.attr(&attr.0, &attr.1),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(attr) => reply.attr(&attr.0, &attr.1),
+        Err(err) => reply.error(err.0),
     });
 }
 
@@ -34,67 +108,62 @@ pub struct EntryOk {
 }
 
 pub fn wrap_entry(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyEntry,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyEntry,
     fut: impl std::future::Future<Output = Result<EntryOk>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(entry) => reply.entry(&entry.ttl, &entry.attr, entry.generation),
+        Err(err) => reply.error(err.0),
     });
 }
 
 pub fn wrap_open(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyOpen,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyOpen,
     fut: impl std::future::Future<Output = Result<(u64, u32)>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok((fh, flags)) => reply.opened(fh, flags),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok((fh, flags)) => reply.opened(fh, flags),
+        Err(err) => reply.error(err.0),
     });
 }
 
 pub fn wrap_read(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyData,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyData,
     fut: impl std::future::Future<Output = Result<Vec<u8>>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok(data) => reply.data(&data),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(data) => reply.data(&data),
+        Err(err) => reply.error(err.0),
     });
 }
 
 pub fn wrap_write(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyWrite,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyWrite,
     fut: impl std::future::Future<Output = Result<u32>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok(n) => reply.written(n),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(n) => reply.written(n),
+        Err(err) => reply.error(err.0),
     });
 }
 
 pub fn wrap_empty(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyEmpty,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyEmpty,
     fut: impl std::future::Future<Output = Result<()>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok(()) => reply.ok(),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(()) => reply.ok(),
+        Err(err) => reply.error(err.0),
     });
 }
 
@@ -107,14 +176,37 @@ pub struct CreateOk {
 }
 
 pub fn wrap_create(
-    executor: &tokio::runtime::Handle,
-    reply: fuse::ReplyCreate,
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyCreate,
     fut: impl std::future::Future<Output = Result<CreateOk>> + Send + 'static,
 ) {
-    executor.spawn(async {
-        match fut.await {
-            Ok(data) => reply.created(&data.ttl, &data.attr, data.generation, data.fh, data.flags),
-            Err(err) => reply.error(err.0),
-        }
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(data) => reply.created(&data.ttl, &data.attr, data.generation, data.fh, data.flags),
+        Err(err) => reply.error(err.0),
+    });
+}
+
+pub fn wrap_lseek(
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyLseek,
+    fut: impl std::future::Future<Output = Result<i64>> + Send + 'static,
+) {
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok(offset) => reply.offset(offset),
+        Err(err) => reply.error(err.0),
+    });
+}
+
+pub fn wrap_ioctl(
+    dispatcher: &Dispatcher,
+    op: &'static str,
+    reply: fuser::ReplyIoctl,
+    fut: impl std::future::Future<Output = Result<(i32, Vec<u8>)>> + Send + 'static,
+) {
+    dispatcher.spawn(op, fut, move |res| match res {
+        Ok((result, data)) => reply.ioctl(result, &data),
+        Err(err) => reply.error(err.0),
     });
 }
@@ -1,29 +1,92 @@
+use crate::audit::AuditLog;
+use crate::encrypted_store::Keys;
 use crate::error::{Error, Result};
-use crate::fs::{Contents, Inode, Superblock};
+use crate::fs::{Contents, Inode, Superblock, Time, Usage};
 use crate::fuse_util::*;
 use crate::hash::Hash;
-use crate::store::MutableFile;
-use fuse::{ReplyEmpty, Request};
+use crate::jobs::{JobKind, ERASE_PRIORITY};
+use crate::power::PowerMonitor;
+use crate::stats::Counters;
+use fuser::{KernelConfig, MountOption, ReplyEmpty, Request, TimeOrNow};
 use futures::future::FutureExt;
 use libc::c_int;
-use log::{debug, error};
-use std::collections::{btree_map::Entry, HashMap};
+use std::collections::{btree_map::Entry, BTreeMap, HashMap, HashSet};
 use std::convert::{TryFrom, TryInto};
 use std::ffi::OsStr;
 use std::ops::Bound::{Excluded, Unbounded};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc, RwLock,
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, RwLock,
 };
 use std::time::{Duration, SystemTime};
+use tracing::{debug, debug_span, error, info, warn};
+use tracing_futures::Instrument;
 
 type Store = Arc<dyn crate::store::Store>;
 
 pub struct FilesystemState {
     pub superblock: Superblock,
-    file_handles: FileHandles,
+    /// Open files. This has its own lock so that opening, reading and
+    /// writing files only needs a read lock on the state.
+    file_handles: RwLock<FileHandles>,
     pub stores: Vec<Store>,
+    pub keys: Keys,
+    suspect_copies: HashSet<(String, Hash)>,
+    /// The file to which `fsyncdir` writes the superblock.
+    pub state_file: Option<PathBuf>,
+    /// Whether background I/O should be avoided.
+    pub power: Arc<PowerMonitor>,
+    pub mirror_stats: MirrorStats,
+    /// Stores that failed their last health check, with the error.
+    unhealthy_stores: HashMap<String, String>,
+    /// Whether to erase files from all stores once they're no longer
+    /// referenced.
+    pub secure_erase: bool,
+    /// Whether opening an immutable file checks that a store has
+    /// its contents.
+    pub check_open: bool,
+    pub audit_log: Option<AuditLog>,
+    pub counters: Arc<Counters>,
+    /// Files that have been read from an archive store and copied (or
+    /// are being copied) back to the fast store of their tiering
+    /// policy.
+    pub recalled: HashSet<Hash>,
+    /// Stores from which files must be read, keyed by inode and
+    /// process ID (`None` for all processes). They apply to file
+    /// handles opened afterwards.
+    pub read_overrides: HashMap<(crate::fs::Ino, Option<u32>), String>,
+    /// Number of running control requests.
+    control_requests: Arc<AtomicUsize>,
+    /// Format version of the state file when it was loaded.
+    pub schema_version: u32,
+    /// Number of orphaned mutable files in each store, found when
+    /// the filesystem was mounted.
+    pub orphaned_files: BTreeMap<String, usize>,
+    /// Hash of the superblock as last written to the state file.
+    saved_hash: Mutex<Option<Hash>>,
+    /// Mutable files that are being finalized. They can't be written,
+    /// truncated or finalized again until that's done.
+    finalizing: Mutex<HashSet<crate::fs::Ino>>,
+}
+
+/// Results of reading back newly mirrored files.
+#[derive(Default)]
+pub struct MirrorStats {
+    /// Percentage of mirrored files to read back.
+    pub verify_percent: u8,
+    pub verified: u64,
+    pub failed: u64,
+}
+
+impl MirrorStats {
+    pub fn record(&mut self, failed: bool) {
+        self.verified += 1;
+        if failed {
+            self.failed += 1;
+        }
+    }
 }
 
 struct FileHandles {
@@ -35,27 +98,244 @@ enum OpenFile {
     Regular(OpenRegularFile),
     Directory(OpenDirectory),
     Control(OpenControlFile),
+    Info(OpenInfoFile),
 }
 
 impl FilesystemState {
-    pub fn new(superblock: Superblock, stores: Vec<Store>) -> Self {
+    pub fn new(superblock: Superblock, stores: Vec<Store>, keys: Keys) -> Self {
         FilesystemState {
+            schema_version: superblock.get_version(),
+            orphaned_files: BTreeMap::new(),
+            saved_hash: Mutex::new(None),
+            finalizing: Mutex::new(HashSet::new()),
             superblock,
-            file_handles: FileHandles {
+            file_handles: RwLock::new(FileHandles {
                 next_fh: 1,
                 handles: HashMap::new(),
-            },
+            }),
             stores,
+            keys,
+            suspect_copies: HashSet::new(),
+            state_file: None,
+            power: Arc::new(PowerMonitor::new(None)),
+            mirror_stats: MirrorStats::default(),
+            unhealthy_stores: HashMap::new(),
+            secure_erase: false,
+            check_open: false,
+            audit_log: None,
+            counters: Arc::new(Counters::new()),
+            recalled: HashSet::new(),
+            read_overrides: HashMap::new(),
+            control_requests: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn is_finalizing(&self, ino: crate::fs::Ino) -> bool {
+        self.finalizing.lock().unwrap().contains(&ino)
+    }
+
+    fn set_health(&mut self, store: &Store, res: std::result::Result<(), String>) {
+        let url = store.get_url();
+        match res {
+            Ok(()) => {
+                if self.unhealthy_stores.remove(&url).is_some() {
+                    info!("Store '{}' is available again.", url);
+                }
+            }
+            Err(err) => {
+                if !self.unhealthy_stores.contains_key(&url) {
+                    warn!("Store '{}' is unhealthy: {}", url, err);
+                }
+                self.unhealthy_stores.insert(url, err);
+            }
+        }
+    }
+
+    /// Return whether a file reachable from the root or a snapshot,
+    /// or a deleted file that is still open, has the hash `hash`.
+    pub fn is_in_use(&self, hash: &Hash) -> bool {
+        self.superblock.is_referenced(hash)
+            || self
+                .file_handles
+                .read()
+                .unwrap()
+                .handles
+                .values()
+                .any(|open_file| match open_file {
+                    OpenFile::Regular(open_file) => match &open_file.inode.read().unwrap().contents
+                    {
+                        Contents::RegularFile(file) => file.hash == *hash,
+                        _ => false,
+                    },
+                    _ => false,
+                })
+    }
+
+    /// Called when a file with hash `hash` has been deleted or
+    /// closed. If secure erase is enabled and no other file has the
+    /// same contents, queue erasing it from the stores.
+    pub fn release_hash(&mut self, hash: Hash) {
+        if self.secure_erase && !self.is_in_use(&hash) {
+            info!("Queueing secure erase of {}.", hash.to_hex());
+            self.superblock.pending_erases.insert(hash);
+            self.queue_pending_erases();
+        }
+    }
+
+    /// Queue an erase job for every pending erase that doesn't have
+    /// one yet, e.g. because it failed or the daemon was restarted.
+    pub fn queue_pending_erases(&mut self) {
+        for hash in &self.superblock.pending_erases {
+            if !self.superblock.jobs.is_erasing(hash) {
+                self.superblock
+                    .jobs
+                    .enqueue(
+                        JobKind::Erase { hash: hash.clone() },
+                        ERASE_PRIORITY,
+                        None,
+                        None,
+                    )
+                    .unwrap();
+            }
+        }
+    }
+
+    pub fn is_healthy(&self, store: &Store) -> bool {
+        !self.unhealthy_stores.contains_key(&store.get_url())
+    }
+
+    /// Return the URLs of the unhealthy stores and the errors they
+    /// returned.
+    pub fn unhealthy_stores(&self) -> &HashMap<String, String> {
+        &self.unhealthy_stores
+    }
+
+    /// Record that `store` has a truncated copy of the file with
+    /// hash `hash`.
+    fn mark_suspect(&mut self, store: &Store, hash: &Hash) {
+        warn!(
+            "Store '{}' has a truncated copy of {}.",
+            store.get_url(),
+            hash.to_hex()
+        );
+        self.suspect_copies.insert((store.get_url(), hash.clone()));
+        for open_file in self.file_handles.read().unwrap().handles.values() {
+            if let OpenFile::Regular(open_file) = open_file {
+                // Other files can keep reading from this store.
+                let has_hash = match &open_file.inode.read().unwrap().contents {
+                    Contents::RegularFile(file) => file.hash == *hash,
+                    _ => false,
+                };
+                if !has_hash {
+                    continue;
+                }
+                let mut cur = open_file.store.write().unwrap();
+                if cur.as_ref().map_or(false, |st| Arc::ptr_eq(st, store)) {
+                    *cur = None;
+                }
+            }
+        }
+    }
+
+    fn is_suspect(&self, store: &Store, hash: &Hash) -> bool {
+        self.suspect_copies
+            .contains(&(store.get_url(), hash.clone()))
+    }
+
+    pub fn add_store(&mut self, store: Store) -> Result<()> {
+        let url = store.get_url();
+        if self.stores.iter().any(|st| st.get_url() == url) {
+            return Err(Error::DuplicateStore(url));
+        }
+        self.stores.push(store);
+        Ok(())
+    }
+
+    pub fn remove_store(&mut self, url: &str) -> Result<Store> {
+        let idx = self
+            .stores
+            .iter()
+            .position(|st| st.get_url() == url)
+            .ok_or_else(|| Error::UnknownStore(url.into()))?;
+
+        // Mutable files can't be finalized without their store.
+        let staged = self
+            .superblock
+            .get_mutable_files()
+            .iter()
+            .filter(|file| file.store == url)
+            .count();
+        if staged > 0 {
+            return Err(Error::StoreHasMutableFiles(url.into(), staged));
+        }
+        let store = self.stores.remove(idx);
+
+        // Make open files look for another store on their next read.
+        for open_file in self.file_handles.read().unwrap().handles.values() {
+            if let OpenFile::Regular(open_file) = open_file {
+                let mut cur = open_file.store.write().unwrap();
+                if cur.as_ref().map_or(false, |st| Arc::ptr_eq(st, &store)) {
+                    *cur = None;
+                }
+            }
         }
+
+        Ok(store)
+    }
+
+    /// Return the store from which process `pid` must read `ino`, if
+    /// it's attached.
+    fn read_override(&self, ino: crate::fs::Ino, pid: u32) -> Option<Store> {
+        let url = self
+            .read_overrides
+            .get(&(ino, Some(pid)))
+            .or_else(|| self.read_overrides.get(&(ino, None)))?;
+        self.stores.iter().find(|st| st.get_url() == *url).cloned()
     }
 
     pub fn sync(&self, path: &Path) -> std::io::Result<()> {
+        self.save(path, true).map(|_| ())
+    }
+
+    /// Write the superblock to `path` if it has changed since it was
+    /// last written. Returns whether it was written.
+    pub fn sync_if_changed(&self, path: &Path) -> std::io::Result<bool> {
+        self.save(path, false)
+    }
+
+    fn save(&self, path: &Path, force: bool) -> std::io::Result<bool> {
+        let mut json = vec![];
+        self.superblock.write_json(&mut json)?;
+        let hash = Hash::hash(&json[..])?.1;
+
+        // This also prevents concurrent writes of the state file. It
+        // may be poisoned if a save panicked, which is no reason not
+        // to save again.
+        let mut saved_hash = self
+            .saved_hash
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if !force && saved_hash.as_ref() == Some(&hash) {
+            return Ok(false);
+        }
+
         let mut temp_path: PathBuf = path.into();
         temp_path.set_extension("json.tmp");
         let mut file = std::fs::File::create(&temp_path)?;
-        self.superblock.write_json(&mut file).unwrap();
+        std::io::Write::write_all(&mut file, &json)?;
+        file.sync_all()?;
+        // Keep the previous version so that a corrupted state file
+        // can be recovered.
+        if path.exists() {
+            let backup_path = crate::fs::Superblock::backup_path(path);
+            if backup_path.exists() {
+                std::fs::remove_file(&backup_path)?;
+            }
+            std::fs::hard_link(path, &backup_path)?;
+        }
         std::fs::rename(temp_path, path)?;
-        Ok(())
+        *saved_hash = Some(hash);
+        Ok(true)
     }
 }
 
@@ -71,17 +351,24 @@ impl FileHandles {
         self.handles.remove(&fh).ok_or(Error::BadFileHandle(fh))
     }
 
-    fn get<'a>(&'a mut self, fh: u64) -> Result<&'a mut OpenFile> {
-        self.handles.get_mut(&fh).ok_or(Error::BadFileHandle(fh))
+    fn get<'a>(&'a self, fh: u64) -> Result<&'a OpenFile> {
+        self.handles.get(&fh).ok_or(Error::BadFileHandle(fh))
     }
 
-    fn get_regular<'a>(&'a mut self, fh: u64) -> Result<&'a mut OpenRegularFile> {
-        match self.handles.get_mut(&fh) {
+    fn get_regular<'a>(&'a self, fh: u64) -> Result<&'a OpenRegularFile> {
+        match self.handles.get(&fh) {
             Some(OpenFile::Regular(x)) => Ok(x),
             _ => Err(Error::BadFileHandle(fh)),
         }
     }
 
+    fn is_open_for_writing(&self, inode: &Arc<RwLock<Inode>>) -> bool {
+        self.handles.values().any(|open_file| match open_file {
+            OpenFile::Regular(x) => x.for_writing && Arc::ptr_eq(&x.inode, inode),
+            _ => false,
+        })
+    }
+
     fn get_directory<'a>(&'a mut self, fh: u64) -> Result<&'a mut OpenDirectory> {
         match self.handles.get_mut(&fh) {
             Some(OpenFile::Directory(x)) => Ok(x),
@@ -94,41 +381,159 @@ struct OpenRegularFile {
     inode: Arc<RwLock<Inode>>,
     for_writing: bool,
     store: RwLock<Option<Store>>,
+    /// The only store to read from, bypassing read-ahead and the
+    /// kernel page cache. Used to check a particular copy.
+    forced_store: Option<Store>,
+    readahead: Arc<Mutex<ReadAhead>>,
 }
 
 impl OpenRegularFile {
     fn new(inode: Arc<RwLock<Inode>>) -> Self {
+        let readahead_size = match &inode.read().unwrap().contents {
+            Contents::RegularFile(file) if file.length >= LARGE_FILE_SIZE => LARGE_READAHEAD_SIZE,
+            _ => READAHEAD_SIZE,
+        };
         Self {
             inode,
             for_writing: false,
             store: RwLock::new(None),
+            forced_store: None,
+            readahead: Arc::new(Mutex::new(ReadAhead {
+                next_offset: 0,
+                window: None,
+                size: readahead_size,
+            })),
         }
     }
+
+    /// The flags to return when opening this file. The kernel may
+    /// keep the page cache of immutable files across opens, since
+    /// their contents can't change.
+    fn open_flags(&self) -> u32 {
+        match self.inode.read().unwrap().contents {
+            _ if self.forced_store.is_some() => fuser::consts::FOPEN_DIRECT_IO,
+            Contents::RegularFile(_) => FOPEN_KEEP_CACHE,
+            _ => 0,
+        }
+    }
+}
+
+/// Amount of data to prefetch when a file is read sequentially.
+const READAHEAD_SIZE: u64 = 8 << 20;
+
+/// Amount of data to prefetch for immutable files of at least
+/// `LARGE_FILE_SIZE` bytes.
+const LARGE_READAHEAD_SIZE: u64 = 64 << 20;
+const LARGE_FILE_SIZE: u64 = 1 << 30;
+
+type Prefetch = futures::future::Shared<
+    std::pin::Pin<Box<dyn futures::Future<Output = Option<Arc<Vec<u8>>>> + Send>>,
+>;
+
+/// Read-ahead state of an open immutable file.
+struct ReadAhead {
+    /// Offset following the previous read.
+    next_offset: u64,
+    /// Offset and contents of the prefetched region.
+    window: Option<(u64, Prefetch)>,
+    /// Size of the prefetched region.
+    size: u64,
+}
+
+impl ReadAhead {
+    fn lookup(&self, offset: u64) -> Option<(u64, Prefetch)> {
+        match &self.window {
+            Some((start, prefetch)) if offset >= *start && offset < start + self.size => {
+                Some((*start, prefetch.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Record a read of `len` bytes at `offset`. If the file is being
+    /// read sequentially and we're past the middle of the current
+    /// window, start prefetching the data following this read.
+    fn schedule(
+        readahead: &Mutex<ReadAhead>,
+        store: &Store,
+        hash: &Hash,
+        offset: u64,
+        len: u64,
+        file_length: u64,
+        counters: &Arc<Counters>,
+    ) {
+        let mut ra = readahead.lock().unwrap();
+
+        let sequential = offset == ra.next_offset;
+        let end = offset + len;
+        ra.next_offset = end;
+
+        if !sequential || end >= file_length {
+            return;
+        }
+
+        if let Some((start, _)) = &ra.window {
+            if end >= *start && end < start + ra.size / 2 {
+                return;
+            }
+        }
+
+        let store = Arc::clone(store);
+        let hash = hash.clone();
+        let counters = Arc::clone(counters);
+        let size = ra.size as usize;
+        let fut: std::pin::Pin<Box<dyn futures::Future<Output = _> + Send>> = Box::pin(
+            async move {
+                let data = store.get(&hash, end, size).await.ok()?;
+                counters.read(&store.get_url(), data.len() as u64);
+                Some(Arc::new(data))
+            }
+            .instrument(debug_span!("readahead", offset = end)),
+        );
+        let prefetch = fut.shared();
+        tokio::spawn(prefetch.clone());
+        ra.window = Some((end, prefetch));
+    }
 }
 
 struct OpenDirectory {
     inode: Arc<RwLock<Inode>>,
     prev_dir_entry: String,
+    next_offset: i64,
 }
 
-type ControlFuture = std::pin::Pin<Box<dyn futures::Future<Output = String> + Send>>;
+struct OpenInfoFile {
+    data: Vec<u8>,
+}
 
 struct OpenControlFile {
     tx: tokio::sync::mpsc::UnboundedSender<u8>,
-    fut: futures::future::Shared<ControlFuture>,
+    output: Arc<futures::lock::Mutex<crate::control::ControlOutput>>,
+    /// Cancels the request when the file is closed, since nobody
+    /// can read its response anymore.
+    abort: futures::future::AbortHandle,
 }
 
+impl Drop for OpenControlFile {
+    fn drop(&mut self) {
+        self.abort.abort();
+    }
+}
+
+/// Maximum number of control requests that can run at the same time.
+const MAX_CONTROL_REQUESTS: usize = 64;
+
 impl Inode {
-    fn file_type(&self) -> fuse::FileType {
+    fn file_type(&self) -> fuser::FileType {
         match self.contents {
-            Contents::Directory(_) => fuse::FileType::Directory,
-            Contents::RegularFile(_) | Contents::MutableFile(_) => fuse::FileType::RegularFile,
-            Contents::Symlink(_) => fuse::FileType::Symlink,
+            Contents::Directory(_) => fuser::FileType::Directory,
+            Contents::RegularFile(_) | Contents::MutableFile(_) => fuser::FileType::RegularFile,
+            Contents::Symlink(_) => fuser::FileType::Symlink,
         }
     }
 }
 
-impl From<&Inode> for fuse::FileAttr {
+impl From<&Inode> for fuser::FileAttr {
     fn from(inode: &Inode) -> Self {
         Self {
             ino: inode.ino,
@@ -157,23 +562,86 @@ impl From<&Inode> for fuse::FileAttr {
 
 pub struct Filesystem {
     state: Arc<RwLock<FilesystemState>>,
-    executor: tokio::runtime::Handle,
+    dispatcher: Dispatcher,
+    ttl: CacheTtls,
+    info_files: bool,
+    counters: Arc<Counters>,
 }
 
 impl Filesystem {
-    pub fn new(state: Arc<RwLock<FilesystemState>>, executor: tokio::runtime::Handle) -> Self {
-        Filesystem { state, executor }
+    pub fn new(
+        state: Arc<RwLock<FilesystemState>>,
+        executor: tokio::runtime::Handle,
+        ttl: CacheTtls,
+        info_files: bool,
+        op_limits: &[(String, usize)],
+        op_timeouts: &[(String, Duration)],
+    ) -> Self {
+        let counters = Arc::clone(&state.read().unwrap().counters);
+        Filesystem {
+            state,
+            dispatcher: Dispatcher::new(executor, Arc::clone(&counters), op_limits, op_timeouts),
+            ttl,
+            info_files,
+            counters,
+        }
     }
 }
 
-static GENERATION_COUNT: AtomicU64 = AtomicU64::new(0);
+/// The TTL used for inodes that have been modified within the last
+/// `ttl`. This ensures that changes made through one file handle
+/// become visible quickly through other paths, while still allowing
+/// the kernel to cache inodes that are not being modified.
+const RECENTLY_MODIFIED_TTL: Duration = Duration::from_millis(100);
+
+/// Writes to a file update its mtime at most once per this interval.
+const MTIME_GRANULARITY: Duration = Duration::from_secs(1);
+
+/// How long the kernel may cache inode attributes and directory
+/// entries.
+#[derive(Clone, Copy)]
+pub struct CacheTtls {
+    pub default: Duration,
+    /// The TTL of immutable files. Since their size and contents can
+    /// never change, this can be much longer than `default`.
+    pub immutable: Duration,
+}
+
+impl CacheTtls {
+    fn for_inode(&self, inode: &Inode) -> Duration {
+        match inode.contents {
+            // Mutable files can change size through other handles.
+            Contents::MutableFile(_) => RECENTLY_MODIFIED_TTL,
+            _ if inode.mtime.elapsed() < self.default => RECENTLY_MODIFIED_TTL,
+            Contents::RegularFile(_) => self.immutable,
+            _ => self.default,
+        }
+    }
+}
 
 static CONTROL_INO: crate::fs::Ino = 0xfffffff0;
 pub static CONTROL_NAME: &str = ".hugefsctl1";
 
-fn control_inode_attrs() -> fuse::FileAttr {
+/// Name of the directory in the root that contains the snapshots.
+pub static SNAPSHOTS_NAME: &str = ".snapshots";
+
+/// Inode numbers of `.hugefsinfo` files are the inode number of
+/// their directory with this bit set.
+const INFO_INO_FLAG: crate::fs::Ino = 1 << 62;
+static INFO_NAME: &str = ".hugefsinfo";
+
+fn info_inode_attrs(ino: crate::fs::Ino) -> fuser::FileAttr {
+    fuser::FileAttr {
+        ino,
+        size: 0,
+        perm: 0o444,
+        ..control_inode_attrs()
+    }
+}
+
+fn control_inode_attrs() -> fuser::FileAttr {
     let time = SystemTime::UNIX_EPOCH;
-    fuse::FileAttr {
+    fuser::FileAttr {
         ino: CONTROL_INO,
         size: 1 << 20, // FIXME
         blocks: 0,
@@ -181,7 +649,7 @@ fn control_inode_attrs() -> fuse::FileAttr {
         mtime: time,
         ctime: time,
         crtime: time,
-        kind: fuse::FileType::RegularFile,
+        kind: fuser::FileType::RegularFile,
         perm: 0o600,
         nlink: 1,
         uid: 0,
@@ -192,14 +660,221 @@ fn control_inode_attrs() -> fuse::FileAttr {
     }
 }
 
-impl fuse::Filesystem for Filesystem {
-    fn init(&mut self, _req: &Request) -> std::result::Result<(), c_int> {
+/// If enabled, check that a healthy store has the contents of `ino`
+/// if it's an immutable file, so that opening a file whose contents
+/// are missing fails rather than its reads.
+pub async fn check_available(state: &Arc<RwLock<FilesystemState>>, ino: u64) -> Result<()> {
+    let (hash, stores) = {
+        let state = state.read().unwrap();
+        if !state.check_open {
+            return Ok(());
+        }
+        let inode = state.superblock.get_inode(ino)?;
+        let inode = inode.read().unwrap();
+        match &inode.contents {
+            Contents::RegularFile(file) => (
+                file.hash.clone(),
+                state
+                    .stores
+                    .iter()
+                    .filter(|st| state.is_healthy(st))
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            ),
+            _ => return Ok(()),
+        }
+    };
+
+    for store in &stores {
+        if let Ok(true) = store.has(&hash).await {
+            return Ok(());
+        }
+    }
+
+    error!(
+        "Cannot open inode {}: contents {} are missing from stores {}.",
+        ino,
+        hash.to_hex(),
+        stores
+            .iter()
+            .map(|st| format!("'{}'", st.get_url()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Err(Error::NoSuchHash(hash))
+}
+
+/// `renameat2()` flag that makes renaming fail if the target exists.
+pub const RENAME_NOREPLACE: u32 = 1;
+
+/// `renameat2()` flag that atomically swaps the source and target.
+pub const RENAME_EXCHANGE: u32 = 2;
+
+/// Rename `name` in directory `parent_ino` to `new_name` in directory
+/// `new_parent_ino`, with the semantics of `renameat2()`.
+pub fn rename_entry(
+    state: &mut FilesystemState,
+    parent_ino: u64,
+    name: &str,
+    new_parent_ino: u64,
+    new_name: &str,
+    flags: u32,
+) -> std::result::Result<(), FuseError> {
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0
+        || flags == RENAME_NOREPLACE | RENAME_EXCHANGE
+    {
+        return Err(libc::EINVAL.into());
+    }
+
+    state.superblock.check_mutable(parent_ino)?;
+    state.superblock.check_mutable(new_parent_ino)?;
+    let parent = state.superblock.get_inode(parent_ino)?;
+    let new_parent = state.superblock.get_inode(new_parent_ino)?;
+
+    let ino = parent.read().unwrap().get_directory()?.get_entry(name)?;
+    let target = new_parent
+        .read()
+        .unwrap()
+        .get_directory()?
+        .entries
+        .get(new_name)
+        .cloned();
+
+    // Renaming a file onto itself does nothing.
+    if target == Some(ino) {
+        return Ok(());
+    }
+
+    let is_dir = |ino| -> Result<bool> {
+        Ok(state
+            .superblock
+            .get_inode(ino)?
+            .read()
+            .unwrap()
+            .get_directory()
+            .is_ok())
+    };
+
+    // A directory can't be moved into its own subtree.
+    if is_dir(ino)? && state.superblock.is_below(new_parent_ino, ino) {
+        return Err(libc::EINVAL.into());
+    }
+
+    if flags & RENAME_EXCHANGE != 0 {
+        let target = target.ok_or(libc::ENOENT)?;
+        if is_dir(target)? && state.superblock.is_below(parent_ino, target) {
+            return Err(libc::EINVAL.into());
+        }
+
+        state
+            .superblock
+            .check_quota_move(ino, parent_ino, new_parent_ino)?;
+        state
+            .superblock
+            .check_quota_move(target, new_parent_ino, parent_ino)?;
+
+        if parent_ino == new_parent_ino {
+            let mut parent = parent.write().unwrap();
+            let dir = parent.get_directory_mut()?;
+            dir.entries.insert(name.to_string(), target);
+            dir.entries.insert(new_name.to_string(), ino);
+            parent.mtime = Time::now();
+        } else {
+            let mut parent = parent.write().unwrap();
+            let mut new_parent = new_parent.write().unwrap();
+            parent
+                .get_directory_mut()?
+                .entries
+                .insert(name.to_string(), target);
+            new_parent
+                .get_directory_mut()?
+                .entries
+                .insert(new_name.to_string(), ino);
+            parent.mtime = Time::now();
+            new_parent.mtime = Time::now();
+        }
+
+        state.superblock.entry_moved(target, parent_ino);
+        state.superblock.entry_moved(ino, new_parent_ino);
+
+        return Ok(());
+    }
+
+    // An existing target is replaced if it's of the same kind, and
+    // is an empty directory if it's a directory.
+    let mut removed_hash = None;
+    if let Some(target) = target {
+        if flags & RENAME_NOREPLACE != 0 {
+            return Err(libc::EEXIST.into());
+        }
+        let source_is_dir = is_dir(ino)?;
+        let target = state.superblock.get_inode(target)?;
+        let target = target.read().unwrap();
+        match (&target.contents, source_is_dir) {
+            (Contents::Directory(dir), true) if !dir.entries.is_empty() => {
+                return Err(libc::ENOTEMPTY.into())
+            }
+            (Contents::Directory(_), true) => {}
+            (Contents::Directory(_), false) => return Err(libc::EISDIR.into()),
+            (_, true) => return Err(libc::ENOTDIR.into()),
+            (Contents::RegularFile(file), false) => removed_hash = Some(file.hash.clone()),
+            (_, false) => {}
+        }
+    }
+
+    state
+        .superblock
+        .check_quota_move(ino, parent_ino, new_parent_ino)?;
+
+    if parent_ino == new_parent_ino {
+        let mut parent = parent.write().unwrap();
+        let dir = parent.get_directory_mut()?;
+        dir.entries.remove(name);
+        dir.entries.insert(new_name.to_string(), ino);
+        parent.mtime = Time::now();
+    } else {
+        let mut parent = parent.write().unwrap();
+        let mut new_parent = new_parent.write().unwrap();
+        parent.get_directory_mut()?.entries.remove(name);
+        new_parent
+            .get_directory_mut()?
+            .entries
+            .insert(new_name.to_string(), ino);
+        parent.mtime = Time::now();
+        new_parent.mtime = Time::now();
+    }
+
+    if let Some(target) = target {
+        let target_inode = state.superblock.get_inode(target)?;
+        state
+            .superblock
+            .entry_removed(target, &target_inode.read().unwrap().contents);
+        state.superblock.set_quota(target, None);
+        state.superblock.tier_policies.remove(&target);
+    }
+    state.superblock.entry_moved(ino, new_parent_ino);
+
+    if let Some(hash) = removed_hash {
+        state.release_hash(hash);
+    }
+
+    Ok(())
+}
+
+impl fuser::Filesystem for Filesystem {
+    fn init(
+        &mut self,
+        _req: &Request,
+        _config: &mut KernelConfig,
+    ) -> std::result::Result<(), c_int> {
         Ok(())
     }
 
-    fn destroy(&mut self, _req: &Request) {}
+    fn destroy(&mut self) {}
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+        self.counters.op("lookup");
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuse::ReplyEntry) {
         let state = self.state.read().unwrap();
 
         if parent == state.superblock.get_root_ino() && name == CONTROL_NAME {
@@ -207,16 +882,33 @@ impl fuse::Filesystem for Filesystem {
             return;
         }
 
+        if parent == state.superblock.get_root_ino() && name == SNAPSHOTS_NAME {
+            match state.superblock.get_snapshot_dir() {
+                Some(ino) => {
+                    let inode = state.superblock.get_inode(ino).unwrap();
+                    let inode = inode.read().unwrap();
+                    reply.entry(&self.ttl.default, &(&*inode).into(), inode.generation);
+                }
+                None => reply.error(libc::ENOENT),
+            }
+            return;
+        }
+
         let inode = state.superblock.get_inode(parent).unwrap();
         let inode = inode.read().unwrap();
         if let Contents::Directory(dir) = &inode.contents {
-            if let Some(entry) = dir.entries.get(name.to_str().unwrap()) {
-                let child = state.superblock.get_inode(*entry).unwrap();
+            if self.info_files && name == INFO_NAME {
+                // The contents are generated on open, so don't cache.
                 reply.entry(
-                    &Duration::from_secs(60),
-                    &(&*child.read().unwrap()).into(),
+                    &Duration::from_secs(0),
+                    &info_inode_attrs(parent | INFO_INO_FLAG),
                     0,
                 );
+            } else if let Some(entry) = dir.entries.get(name.to_str().unwrap()) {
+                let child = state.superblock.get_inode(*entry).unwrap();
+                let child = child.read().unwrap();
+                let ttl = std::cmp::min(self.ttl.for_inode(&inode), self.ttl.for_inode(&child));
+                reply.entry(&ttl, &(&*child).into(), child.generation);
             } else {
                 reply.error(libc::ENOENT);
             }
@@ -227,14 +919,24 @@ impl fuse::Filesystem for Filesystem {
 
     fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: fuse::ReplyAttr) {
-        let state = self.state.read().unwrap();
-        if ino == CONTROL_INO {
-            reply.attr(&Duration::from_secs(60), &control_inode_attrs());
-        } else {
-            let inode = state.superblock.get_inode(ino).unwrap();
-            reply.attr(&Duration::from_secs(60), &(&*inode.read().unwrap()).into());
-        }
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+        let span = debug_span!("getattr", ino);
+        let _enter = span.enter();
+
+        let state = Arc::clone(&self.state);
+        let ttl = self.ttl;
+
+        wrap_attr(&self.dispatcher, "getattr", reply, async move {
+            if ino == CONTROL_INO {
+                Ok((ttl.default, control_inode_attrs()))
+            } else if ino & INFO_INO_FLAG != 0 {
+                Ok((Duration::from_secs(0), info_inode_attrs(ino)))
+            } else {
+                let inode = state.read().unwrap().superblock.get_inode(ino)?;
+                let inode = inode.read().unwrap();
+                Ok((ttl.for_inode(&inode), (&*inode).into()))
+            }
+        });
     }
 
     fn setattr(
@@ -245,25 +947,57 @@ impl fuse::Filesystem for Filesystem {
         uid: Option<u32>,
         gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<SystemTime>,
-        mtime: Option<SystemTime>,
+        _atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
         _fh: Option<u64>,
         crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
-        reply: fuse::ReplyAttr,
+        reply: fuser::ReplyAttr,
     ) {
+        let span = debug_span!("setattr", ino);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
+        let ttl = self.ttl;
+
+        wrap_attr(&self.dispatcher, "setattr", reply, async move {
+            if let Some(size) = size {
+                let file = {
+                    let state = &mut *state.write().unwrap();
+                    state.superblock.check_mutable(ino)?;
+                    let inode = state.superblock.get_inode(ino)?;
+                    if state.is_finalizing(ino) {
+                        return Err(libc::EBUSY.into());
+                    }
+                    let file = match &inode.read().unwrap().contents {
+                        Contents::MutableFile(file) => Arc::clone(file),
+                        Contents::RegularFile(_) => return Err(libc::EPERM.into()),
+                        _ => return Err(libc::EISDIR.into()),
+                    };
+                    if size > file.file.len() {
+                        state.superblock.check_quota(
+                            ino,
+                            Usage {
+                                bytes: size - file.file.len(),
+                                inodes: 0,
+                            },
+                        )?;
+                    }
+                    file
+                };
+                file.file.set_len(size).await?;
+            }
 
-        wrap_attr(&self.executor, reply, async move {
             let state = &mut *state.write().unwrap();
+            state.superblock.check_mutable(ino)?;
             let inode = state.superblock.get_inode(ino)?;
             let mut inode = inode.write().unwrap();
 
-            if let Some(_) = size {
-                // FIXME: support truncating mutable files.
-                return Err(libc::ENOTSUP.into());
+            if size.is_some() {
+                inode.mtime = Time::now();
             }
 
             if let Some(mode) = mode {
@@ -278,26 +1012,31 @@ impl fuse::Filesystem for Filesystem {
                 inode.gid = gid;
             }
 
-            if let Some(mtime) = mtime {
-                inode.mtime = mtime.into();
+            match mtime {
+                Some(TimeOrNow::SpecificTime(mtime)) => inode.mtime = mtime.into(),
+                Some(TimeOrNow::Now) => inode.mtime = Time::now(),
+                None => {}
             }
 
             if let Some(crtime) = crtime {
                 inode.crtime = crtime.into();
             }
 
-            Ok((Duration::from_secs(60), (&*inode).into()))
+            Ok((ttl.for_inode(&inode), (&*inode).into()))
         });
     }
 
-    fn readlink(&mut self, _req: &Request, ino: u64, reply: fuse::ReplyData) {
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyData) {
+        let span = debug_span!("readlink", ino);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
-        wrap_read(&self.executor, reply, async move {
+        wrap_read(&self.dispatcher, "readlink", reply, async move {
             let state = &mut *state.write().unwrap();
             let inode = state.superblock.get_inode(ino)?;
             let inode = inode.read().unwrap();
             match &inode.contents {
-                Contents::Symlink(link) => Ok(link.target.as_bytes().to_vec()),
+                Contents::Symlink(link) => Ok(link.target.clone()),
                 _ => Err(libc::EINVAL.into()),
             }
         });
@@ -309,8 +1048,9 @@ impl fuse::Filesystem for Filesystem {
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
+        _umask: u32,
         _rdev: u32,
-        reply: fuse::ReplyEntry,
+        reply: fuser::ReplyEntry,
     ) {
         reply.error(libc::ENOTSUP);
     }
@@ -321,75 +1061,122 @@ impl fuse::Filesystem for Filesystem {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        reply: fuse::ReplyEntry,
+        _umask: u32,
+        reply: fuser::ReplyEntry,
     ) {
+        let span = debug_span!("mkdir", parent, ?name);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
         let name: String = name.to_str().unwrap().to_string();
         let uid = req.uid();
         let gid = req.gid();
 
-        wrap_entry(&self.executor, reply, async move {
+        wrap_entry(&self.dispatcher, "mkdir", reply, async move {
             let state = &mut *state.write().unwrap();
+            state.superblock.check_mutable(parent)?;
+            state.superblock.check_quota(
+                parent,
+                Usage {
+                    bytes: 0,
+                    inodes: 1,
+                },
+            )?;
             let parent = state.superblock.get_inode(parent)?;
             let mut parent = parent.write().unwrap();
+            let excluded_stores = parent.excluded_stores.clone();
+            let pinned_stores = parent.pinned_stores.clone();
+            let staging_store = parent.staging_store.clone();
             let dir = parent.get_directory_mut()?;
 
             dir.check_no_entry(&name)?;
 
             let inode = Inode {
+                excluded_stores,
+                pinned_stores,
+                staging_store,
                 perm: mode & 0o7777,
                 uid,
                 gid,
                 ..Inode::new(Contents::Directory(crate::fs::Directory::new()))
             };
 
-            let mut attr: fuse::FileAttr = (&inode).into();
+            let mut attr: fuser::FileAttr = (&inode).into();
             let ino = state.superblock.add_inode(inode);
+            let generation = state.superblock.get_inode(ino)?.read().unwrap().generation;
             dir.entries.insert(name, ino);
             attr.ino = ino;
+            parent.mtime = Time::now();
+            let child = state.superblock.get_inode(ino)?;
+            state
+                .superblock
+                .entry_added(parent.ino, ino, &child.read().unwrap().contents);
 
             Ok(crate::fuse_util::EntryOk {
-                ttl: Duration::from_secs(60),
+                ttl: RECENTLY_MODIFIED_TTL,
                 attr,
-                generation: GENERATION_COUNT.fetch_add(1, Ordering::Relaxed),
+                generation,
             })
         });
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let span = debug_span!("unlink", parent, ?name);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
         let name: String = name.to_str().unwrap().to_string();
 
-        wrap_empty(&self.executor, reply, async move {
+        wrap_empty(&self.dispatcher, "unlink", reply, async move {
             let state = &mut *state.write().unwrap();
-            let parent = state.superblock.get_inode(parent)?;
-            let mut parent = parent.write().unwrap();
-            let dir = parent.get_directory_mut()?;
-
-            match dir.entries.entry(name) {
-                Entry::Vacant(_) => Err(libc::ENOENT.into()),
-                Entry::Occupied(e) => {
-                    let child_ino = *e.get();
-                    let child = state.superblock.get_inode(child_ino)?;
-                    let child = child.read().unwrap();
-
-                    if let Contents::Directory(_) = &child.contents {
-                        Err(libc::EISDIR.into())
-                    } else {
-                        e.remove_entry();
-                        Ok(())
+            state.superblock.check_mutable(parent)?;
+
+            let removed_hash = {
+                let parent = state.superblock.get_inode(parent)?;
+                let mut parent = parent.write().unwrap();
+                let dir = parent.get_directory_mut()?;
+
+                match dir.entries.entry(name) {
+                    Entry::Vacant(_) => return Err(libc::ENOENT.into()),
+                    Entry::Occupied(e) => {
+                        let child_ino = *e.get();
+                        let child = state.superblock.get_inode(child_ino)?;
+                        let child = child.read().unwrap();
+
+                        match &child.contents {
+                            Contents::Directory(_) => return Err(libc::EISDIR.into()),
+                            contents => {
+                                e.remove_entry();
+                                parent.mtime = Time::now();
+                                state.superblock.entry_removed(child_ino, contents);
+                                match contents {
+                                    Contents::RegularFile(file) => Some(file.hash.clone()),
+                                    _ => None,
+                                }
+                            }
+                        }
                     }
                 }
+            };
+
+            if let Some(hash) = removed_hash {
+                state.release_hash(hash);
             }
+
+            Ok(())
         });
     }
 
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let span = debug_span!("rmdir", parent, ?name);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
         let name: String = name.to_str().unwrap().to_string();
 
-        wrap_empty(&self.executor, reply, async move {
+        wrap_empty(&self.dispatcher, "rmdir", reply, async move {
             let state = &mut *state.write().unwrap();
+            state.superblock.check_mutable(parent)?;
             let parent = state.superblock.get_inode(parent)?;
             let mut parent = parent.write().unwrap();
             let dir = parent.get_directory_mut()?;
@@ -404,6 +1191,10 @@ impl fuse::Filesystem for Filesystem {
                     if let Contents::Directory(dir) = &child.contents {
                         if dir.entries.is_empty() {
                             e.remove_entry();
+                            parent.mtime = Time::now();
+                            state.superblock.entry_removed(child_ino, &child.contents);
+                            state.superblock.set_quota(child_ino, None);
+                            state.superblock.tier_policies.remove(&child_ino);
                             Ok(())
                         } else {
                             Err(libc::ENOTEMPTY.into())
@@ -422,38 +1213,57 @@ impl fuse::Filesystem for Filesystem {
         parent: u64,
         name: &OsStr,
         link: &Path,
-        reply: fuse::ReplyEntry,
+        reply: fuser::ReplyEntry,
     ) {
+        let span = debug_span!("symlink", parent, ?name);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
         let name: String = name.to_str().unwrap().to_string();
-        let target: String = link.to_str().unwrap().to_string();
+        let target = link.as_os_str().as_bytes().to_vec();
         let uid = req.uid();
         let gid = req.gid();
 
-        wrap_entry(&self.executor, reply, async move {
+        wrap_entry(&self.dispatcher, "symlink", reply, async move {
             let state = &mut *state.write().unwrap();
+            state.superblock.check_mutable(parent)?;
+            state.superblock.check_quota(
+                parent,
+                Usage {
+                    bytes: 0,
+                    inodes: 1,
+                },
+            )?;
             let parent = state.superblock.get_inode(parent)?;
             let mut parent = parent.write().unwrap();
+            let excluded_stores = parent.excluded_stores.clone();
             let dir = parent.get_directory_mut()?;
 
             dir.check_no_entry(&name)?;
 
             let inode = Inode {
+                excluded_stores,
                 perm: 0o777,
                 uid,
                 gid,
-                ..Inode::new(Contents::Symlink(crate::fs::Symlink::new(target)))
+                ..Inode::new(Contents::Symlink(crate::fs::Symlink::new(target)?))
             };
 
-            let mut attr: fuse::FileAttr = (&inode).into();
+            let mut attr: fuser::FileAttr = (&inode).into();
             let ino = state.superblock.add_inode(inode);
+            let generation = state.superblock.get_inode(ino)?.read().unwrap().generation;
             dir.entries.insert(name, ino);
             attr.ino = ino;
+            parent.mtime = Time::now();
+            let child = state.superblock.get_inode(ino)?;
+            state
+                .superblock
+                .entry_added(parent.ino, ino, &child.read().unwrap().contents);
 
             Ok(crate::fuse_util::EntryOk {
-                ttl: Duration::from_secs(60),
+                ttl: RECENTLY_MODIFIED_TTL,
                 attr,
-                generation: GENERATION_COUNT.fetch_add(1, Ordering::Relaxed),
+                generation,
             })
         });
     }
@@ -465,37 +1275,26 @@ impl fuse::Filesystem for Filesystem {
         name: &OsStr,
         new_parent_ino: u64,
         new_name: &OsStr,
+        flags: u32,
         reply: ReplyEmpty,
     ) {
+        let span = debug_span!(
+            "rename",
+            parent_ino,
+            ?name,
+            new_parent_ino,
+            ?new_name,
+            flags
+        );
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
         let name: String = name.to_str().unwrap().to_string();
         let new_name: String = new_name.to_str().unwrap().to_string();
 
-        wrap_empty(&self.executor, reply, async move {
+        wrap_empty(&self.dispatcher, "rename", reply, async move {
             let state = &mut *state.write().unwrap();
-            let parent = state.superblock.get_inode(parent_ino)?;
-            let mut parent = parent.write().unwrap();
-            let dir = parent.get_directory_mut()?;
-
-            let ino = dir.get_entry(&name)?;
-
-            // ugly
-            if parent_ino == new_parent_ino {
-                dir.check_no_entry(&new_name)?;
-                dir.entries.remove(&name);
-                dir.entries.insert(new_name, ino);
-            } else {
-                let new_parent = state.superblock.get_inode(new_parent_ino)?;
-                let mut new_parent = new_parent.write().unwrap();
-                let new_dir = new_parent.get_directory_mut()?;
-
-                new_dir.check_no_entry(&new_name)?;
-
-                dir.entries.remove(&name);
-                new_dir.entries.insert(new_name, ino);
-            }
-
-            Ok(())
+            rename_entry(state, parent_ino, &name, new_parent_ino, &new_name, flags)
         });
     }
 
@@ -505,28 +1304,63 @@ impl fuse::Filesystem for Filesystem {
         _ino: u64,
         _newparent: u64,
         _newname: &OsStr,
-        reply: fuse::ReplyEntry,
+        reply: fuser::ReplyEntry,
     ) {
         reply.error(libc::ENOTSUP);
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: fuse::ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        let span = debug_span!("open", ino);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
+        let pid = req.pid();
+
+        wrap_open(&self.dispatcher, "open", reply, async move {
+            if ino & INFO_INO_FLAG != 0 {
+                let data = crate::control::directory_info(ino & !INFO_INO_FLAG, &state).await?;
+                let fh = state
+                    .read()
+                    .unwrap()
+                    .file_handles
+                    .write()
+                    .unwrap()
+                    .create(OpenFile::Info(OpenInfoFile { data }));
+                return Ok((fh, fuser::consts::FOPEN_DIRECT_IO));
+            }
 
-        wrap_open(&self.executor, reply, async move {
-            let mut state_ = state.write().unwrap();
+            if ino != CONTROL_INO {
+                check_available(&state, ino).await?;
+            }
+
+            let state_ = state.read().unwrap();
 
             if ino == CONTROL_INO {
+                let running = Arc::clone(&state_.control_requests);
+                if running.fetch_add(1, Ordering::SeqCst) >= MAX_CONTROL_REQUESTS {
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    return Err(libc::EBUSY.into());
+                }
                 let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<u8>();
-                let fut: ControlFuture =
-                    crate::control::handle_message(rx, Arc::clone(&state)).boxed();
-                let fut = fut.shared();
-                tokio::task::spawn(fut.clone());
+                let (progress, output) = crate::control::output_channel();
+                let (request, abort) = futures::future::abortable(crate::control::handle_message(
+                    rx,
+                    progress,
+                    Arc::clone(&state),
+                ));
+                tokio::task::spawn(async move {
+                    let _ = request.await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+                let output = Arc::new(futures::lock::Mutex::new(output));
+                let fh = state_
+                    .file_handles
+                    .write()
+                    .unwrap()
+                    .create(OpenFile::Control(OpenControlFile { tx, output, abort }));
                 return Ok((
-                    state_
-                        .file_handles
-                        .create(OpenFile::Control(OpenControlFile { tx, fut })),
-                    fuse::consts::FOPEN_DIRECT_IO, /* | fuse::consts::FOPEN_NONSEEKABLE */
+                    fh,
+                    fuser::consts::FOPEN_DIRECT_IO, /* | fuser::consts::FOPEN_NONSEEKABLE */
                 ));
             }
 
@@ -534,13 +1368,21 @@ impl fuse::Filesystem for Filesystem {
             if !inode.read().unwrap().is_file() {
                 return Err(libc::EISDIR.into());
             }
+            let for_writing = flags & libc::O_ACCMODE != libc::O_RDONLY;
+            if for_writing {
+                state_.superblock.check_writable()?;
+            }
 
-            Ok((
-                state_
-                    .file_handles
-                    .create(OpenFile::Regular(OpenRegularFile::new(inode))),
-                FOPEN_KEEP_CACHE,
-            ))
+            let mut open_file = OpenRegularFile::new(inode);
+            open_file.for_writing = for_writing;
+            open_file.forced_store = state_.read_override(ino, pid);
+            let flags = open_file.open_flags();
+            let fh = state_
+                .file_handles
+                .write()
+                .unwrap()
+                .create(OpenFile::Regular(open_file));
+            Ok((fh, flags))
         });
     }
 
@@ -551,97 +1393,21 @@ impl fuse::Filesystem for Filesystem {
         fh: u64,
         offset: i64,
         size: u32,
-        reply: fuse::ReplyData,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
     ) {
-        let state = Arc::clone(&self.state);
-        wrap_read(&self.executor, reply, async move {
-            enum File {
-                Regular(Option<Store>, Hash),
-                Mutable(Arc<crate::fs::MutableFile>),
-                Control(futures::future::Shared<ControlFuture>),
-            };
-
-            let file = {
-                let state = &mut *state.write().unwrap();
-                match state.file_handles.get(fh)? {
-                    OpenFile::Regular(open_file) => {
-                        let inode = open_file.inode.read().unwrap();
-                        assert_eq!(ino, inode.ino);
-                        match &inode.contents {
-                            Contents::RegularFile(reg) => File::Regular(
-                                open_file.store.read().unwrap().clone(),
-                                reg.hash.clone(),
-                            ),
-                            Contents::MutableFile(file) => File::Mutable(Arc::clone(file)),
-                            _ => return Err(libc::EISDIR.into()),
-                        }
-                    }
-                    OpenFile::Directory(_) => {
-                        return Err(libc::EISDIR.into());
-                    }
-                    OpenFile::Control(control_file) => File::Control(control_file.fut.clone()),
-                }
-            };
-
-            match file {
-                File::Regular(store, hash) => {
-                    if let Some(store) = store {
-                        let data = store
-                            .get(&hash, offset as u64, usize::try_from(size).unwrap())
-                            .await?;
-                        return Ok(data);
-                    } else {
-                        // Find a store that has this file.
-                        let stores = state.read().unwrap().stores.clone();
-                        for store in stores {
-                            match store
-                                .get(&hash, offset as u64, usize::try_from(size).unwrap())
-                                .await
-                            {
-                                Ok(data) => {
-                                    *state
-                                        .write()
-                                        .unwrap()
-                                        .file_handles
-                                        .get_regular(fh)?
-                                        .store
-                                        .write()
-                                        .unwrap() = Some(store);
-                                    return Ok(data);
-                                }
-                                Err(Error::NoSuchHash(_)) => continue,
-                                Err(err) => {
-                                    error!("Error reading file {}: {}", ino, err);
-                                    return Err(libc::EIO.into());
-                                }
-                            }
-                        }
-                        error!("Cannot find file {} with hash {}", ino, hash.to_hex());
-                        return Err(libc::ENOMEDIUM.into());
-                    }
-                }
-
-                File::Mutable(file) => match file.file.read(offset as u64, size).await {
-                    Ok(data) => return Ok(data),
-                    Err(err) => {
-                        error!("Error reading file {}: {}", ino, err);
-                        return Err(libc::EIO.into());
-                    }
-                },
+        let span = debug_span!("read", ino, fh, offset, size);
+        let _enter = span.enter();
 
-                File::Control(fut) => {
-                    let res = fut.await;
-                    // FIXME: inefficient
-                    return Ok(res
-                        .as_bytes()
-                        .iter()
-                        .skip(offset as usize)
-                        .take(size as usize)
-                        .map(|s| *s)
-                        .collect());
-                }
-            }
-        });
+        let state = Arc::clone(&self.state);
+        let counters = Arc::clone(&self.counters);
+        wrap_read(
+            &self.dispatcher,
+            "read",
+            reply,
+            read_file(state, counters, ino, fh, offset, size),
+        );
     }
 
     fn write(
@@ -651,74 +1417,81 @@ impl fuse::Filesystem for Filesystem {
         fh: u64,
         offset: i64,
         data: &[u8],
-        _flags: u32,
-        reply: fuse::ReplyWrite,
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
     ) {
+        let span = debug_span!("write", ino, fh, offset, len = data.len());
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
+        let counters = Arc::clone(&self.counters);
         let data = data.to_vec();
 
-        wrap_write(&self.executor, reply, async move {
-            let file = {
-                let state = &mut *state.write().unwrap();
+        wrap_write(
+            &self.dispatcher,
+            "write",
+            reply,
+            write_file(state, counters, ino, fh, offset, data),
+        );
+    }
 
-                match state.file_handles.get(fh)? {
-                    OpenFile::Regular(open_file) => {
-                        let inode = open_file.inode.read().unwrap();
-                        assert_eq!(ino, inode.ino);
-                        match &inode.contents {
-                            Contents::MutableFile(file) => Arc::clone(file),
-                            Contents::RegularFile(_) => return Err(libc::EPERM.into()),
-                            _ => return Err(libc::EISDIR.into()),
-                        }
-                    }
+    fn flush(&mut self, _req: &Request, _ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let span = debug_span!("flush", fh);
+        let _enter = span.enter();
 
-                    OpenFile::Control(control_file) => {
-                        for d in &data {
-                            control_file.tx.send(*d).map_err(|_| libc::ENOTCONN)?;
-                        }
-                        return Ok(data.len() as u32);
-                    }
+        let state = Arc::clone(&self.state);
 
-                    OpenFile::Directory(_) => return Err(libc::EISDIR.into()),
-                }
-            };
-
-            file.file.write(offset as u64, &data).await.unwrap();
-
-            Ok(data.len().try_into().unwrap())
+        wrap_empty(&self.dispatcher, "flush", reply, async move {
+            let file = get_mutable_file(&state, fh)?;
+            match file {
+                Some(file) => Ok(file.file.flush().await?),
+                None => Ok(()),
+            }
         });
     }
 
-    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        reply.ok();
-    }
-
     fn release(
         &mut self,
         _req: &Request,
         _ino: u64,
         fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
+        let span = debug_span!("release", fh);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
 
-        wrap_empty(&self.executor, reply, async move {
-            let (inode, mutable_file) = {
-                let state = &mut *state.write().unwrap();
-                match state.file_handles.remove(fh)? {
+        wrap_empty(&self.dispatcher, "release", reply, async move {
+            let inode = {
+                let mut state = state.write().unwrap();
+                let open_file = state.file_handles.write().unwrap().remove(fh)?;
+                match open_file {
                     OpenFile::Regular(open_file) => {
+                        // The file may have been deleted while it was open.
+                        let hash = match &open_file.inode.read().unwrap().contents {
+                            Contents::RegularFile(file) => Some(file.hash.clone()),
+                            _ => None,
+                        };
+                        if let Some(hash) = hash {
+                            state.release_hash(hash);
+                        }
                         if !open_file.for_writing {
                             return Ok(());
                         }
-                        let mut inode = open_file.inode.write().unwrap();
-                        if let Contents::MutableFile(file) = &mut inode.contents {
-                            (Arc::clone(&open_file.inode), Arc::clone(file))
-                        } else {
-                            return Ok(());
+                        // Catch up on mtime updates skipped by write().
+                        {
+                            let mut inode = open_file.inode.write().unwrap();
+                            if let Contents::MutableFile(_) = inode.contents {
+                                inode.mtime = Time::now();
+                            }
                         }
+                        open_file.inode
                     }
                     _ => {
                         return Ok(());
@@ -726,30 +1499,42 @@ impl fuse::Filesystem for Filesystem {
                 }
             };
 
-            let (length, hash) = mutable_file.file.finish().await.unwrap();
+            match finalize_file(&inode, &state, &|_| {}).await {
+                Ok(_) | Err(Error::FileBusy(_)) => Ok(()),
+                Err(err) => Err(err.into()),
+            }
+        });
+    }
 
-            debug!("finalised file with hash {}, size {}", hash, length);
+    fn fsync(&mut self, _req: &Request, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        let span = debug_span!("fsync", fh, datasync);
+        let _enter = span.enter();
 
-            inode.write().unwrap().contents =
-                Contents::RegularFile(crate::fs::RegularFile { length, hash });
+        let state = Arc::clone(&self.state);
 
-            Ok(())
+        wrap_empty(&self.dispatcher, "fsync", reply, async move {
+            let file = get_mutable_file(&state, fh)?;
+            match file {
+                Some(file) => Ok(file.file.sync(datasync).await?),
+                None => Ok(()),
+            }
         });
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.ok();
-    }
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        self.counters.op("opendir");
 
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: fuse::ReplyOpen) {
-        let mut state = self.state.write().unwrap();
+        let state = self.state.read().unwrap();
         let inode = state.superblock.get_inode(ino).unwrap();
-        if inode.read().unwrap().file_type() == fuse::FileType::Directory {
+        if inode.read().unwrap().file_type() == fuser::FileType::Directory {
             let fh = state
                 .file_handles
+                .write()
+                .unwrap()
                 .create(OpenFile::Directory(OpenDirectory {
                     inode,
                     prev_dir_entry: String::new(),
+                    next_offset: 0,
                 }));
             reply.opened(fh, 0);
         } else {
@@ -762,22 +1547,44 @@ impl fuse::Filesystem for Filesystem {
         _req: &Request,
         ino: u64,
         fh: u64,
-        _offset: i64,
-        mut reply: fuse::ReplyDirectory,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
     ) {
-        let state = &mut *self.state.write().unwrap();
-        if let Ok(open_dir) = state.file_handles.get_directory(fh) {
+        self.counters.op("readdir");
+
+        let state = self.state.read().unwrap();
+        let mut file_handles = state.file_handles.write().unwrap();
+        if let Ok(open_dir) = file_handles.get_directory(fh) {
             let inode = open_dir.inode.read().unwrap();
             assert_eq!(ino, inode.ino);
             if let Contents::Directory(dir) = &inode.contents {
+                /* Entries are returned in byte-wise order of their
+                 * names. If the caller seeked somewhere other than
+                 * where the previous call left off, resume from the
+                 * corresponding position in that order. */
+                if offset != open_dir.next_offset {
+                    open_dir.prev_dir_entry = match usize::try_from(offset) {
+                        Ok(n) if n > 0 => match dir.entries.keys().nth(n - 1) {
+                            Some(name) => name.clone(),
+                            // Seeking past the end yields no entries.
+                            None => {
+                                reply.ok();
+                                return;
+                            }
+                        },
+                        _ => String::new(),
+                    };
+                    open_dir.next_offset = offset;
+                }
+
                 // FIXME: clone
                 for (k, v) in dir
                     .entries
                     .range::<String, _>((Excluded(open_dir.prev_dir_entry.clone()), Unbounded))
                 {
                     if reply.add(
-                        ino,
-                        0, /* FIXME */
+                        *v,
+                        open_dir.next_offset + 1,
                         state
                             .superblock
                             .get_inode(*v)
@@ -790,6 +1597,7 @@ impl fuse::Filesystem for Filesystem {
                         break;
                     } else {
                         open_dir.prev_dir_entry = k.clone();
+                        open_dir.next_offset += 1;
                     }
                 }
 
@@ -803,9 +1611,12 @@ impl fuse::Filesystem for Filesystem {
         }
     }
 
-    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
-        let mut state = self.state.write().unwrap();
-        if let Ok(_) = state.file_handles.remove(fh) {
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.counters.op("releasedir");
+
+        let state = self.state.read().unwrap();
+        let removed = state.file_handles.write().unwrap().remove(fh);
+        if let Ok(_) = removed {
             reply.ok();
         } else {
             reply.error(libc::EBADF);
@@ -820,10 +1631,33 @@ impl fuse::Filesystem for Filesystem {
         _datasync: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        let span = debug_span!("fsyncdir");
+        let _enter = span.enter();
+        self.counters.op("fsyncdir");
+
+        // Directory changes only live in the superblock, so write it
+        // out. Serializing and syncing a large superblock is slow, so
+        // don't block the session loop or the runtime's workers.
+        let state = Arc::clone(&self.state);
+        wrap_empty(&self.dispatcher, "fsyncdir", reply, async move {
+            let state_file = match &state.read().unwrap().state_file {
+                Some(state_file) => state_file.clone(),
+                None => return Ok(()),
+            };
+            let res = tokio::task::spawn_blocking(move || state.read().unwrap().sync(&state_file))
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|res| res.map_err(|err| err.to_string()));
+            res.map_err(|err| -> FuseError {
+                error!("Cannot save the superblock: {}", err);
+                libc::EIO.into()
+            })
+        });
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuse::ReplyStatfs) {
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
+        self.counters.op("statfs");
+
         let state = self.state.read().unwrap();
         let bsize = 1 << 15;
         let cur_bytes = state.superblock.total_file_size();
@@ -849,7 +1683,7 @@ impl fuse::Filesystem for Filesystem {
         _ino: u64,
         _name: &OsStr,
         _value: &[u8],
-        _flags: u32,
+        _flags: i32,
         _position: u32,
         reply: ReplyEmpty,
     ) {
@@ -862,12 +1696,12 @@ impl fuse::Filesystem for Filesystem {
         _ino: u64,
         _name: &OsStr,
         _size: u32,
-        reply: fuse::ReplyXattr,
+        reply: fuser::ReplyXattr,
     ) {
         reply.error(libc::ENOTSUP);
     }
 
-    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: fuse::ReplyXattr) {
+    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: fuser::ReplyXattr) {
         reply.error(libc::ENOTSUP);
     }
 
@@ -875,7 +1709,7 @@ impl fuse::Filesystem for Filesystem {
         reply.error(libc::ENOTSUP);
     }
 
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+    fn access(&mut self, _req: &Request, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         // FIXME: should not be called with default_permissions
         reply.ok();
     }
@@ -886,9 +1720,13 @@ impl fuse::Filesystem for Filesystem {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _flags: u32,
-        reply: fuse::ReplyCreate,
+        _umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
     ) {
+        let span = debug_span!("create", parent, ?name);
+        let _enter = span.enter();
+
         let state = Arc::clone(&self.state);
         let name: String = name.to_str().unwrap().to_string();
         let uid = req.uid();
@@ -896,13 +1734,42 @@ impl fuse::Filesystem for Filesystem {
 
         // FIXME: check flags
 
-        wrap_create(&self.executor, reply, async move {
-            // FIXME: this creates a file even if creation fails.
-            let mutable_file = {
-                let stores = state.read().unwrap().stores.clone();
-                create_file(stores).await?
+        wrap_create(&self.dispatcher, "create", reply, async move {
+            state.read().unwrap().superblock.check_mutable(parent)?;
+
+            let (stores, excluded_stores, pinned_stores) = {
+                let state = state.read().unwrap();
+                state.superblock.check_quota(
+                    parent,
+                    Usage {
+                        bytes: 0,
+                        inodes: 1,
+                    },
+                )?;
+                let parent = state.superblock.get_inode(parent)?;
+                let excluded_stores = parent.read().unwrap().excluded_stores.clone();
+                let pinned_stores = parent.read().unwrap().pinned_stores.clone();
+                let staging_store = parent.read().unwrap().staging_store.clone();
+                let mut stores: Vec<Store> = state
+                    .stores
+                    .iter()
+                    .filter(|st| !excluded_stores.contains(&st.get_url()))
+                    .cloned()
+                    .collect();
+                // Prefer the directory's staging store if it's available.
+                if let Some(pos) = stores
+                    .iter()
+                    .position(|st| Some(st.get_url()) == staging_store && state.is_healthy(st))
+                {
+                    let store = stores.remove(pos);
+                    stores.insert(0, store);
+                }
+                (stores, excluded_stores, pinned_stores)
             };
 
+            // FIXME: this creates a file even if creation fails.
+            let mutable_file = create_file(stores).await?;
+
             let state = &mut *state.write().unwrap();
             let parent = state.superblock.get_inode(parent)?;
             let mut parent = parent.write().unwrap();
@@ -911,27 +1778,37 @@ impl fuse::Filesystem for Filesystem {
             dir.check_no_entry(&name)?;
 
             let inode = Inode {
+                excluded_stores,
+                pinned_stores,
                 perm: mode & 0o7777,
                 uid,
                 gid,
-                ..Inode::new(Contents::MutableFile(Arc::new(crate::fs::MutableFile {
-                    file: mutable_file,
-                })))
+                ..Inode::new(Contents::MutableFile(Arc::new(mutable_file)))
             };
 
-            let mut attr: fuse::FileAttr = (&inode).into();
+            let mut attr: fuser::FileAttr = (&inode).into();
             let ino = state.superblock.add_inode(inode);
+            let generation = state.superblock.get_inode(ino)?.read().unwrap().generation;
             dir.entries.insert(name, ino);
             attr.ino = ino;
+            parent.mtime = Time::now();
+            let child = state.superblock.get_inode(ino)?;
+            state
+                .superblock
+                .entry_added(parent.ino, ino, &child.read().unwrap().contents);
 
             let mut open_file = OpenRegularFile::new(state.superblock.get_inode(ino)?);
             open_file.for_writing = true;
-            let fh = state.file_handles.create(OpenFile::Regular(open_file));
+            let fh = state
+                .file_handles
+                .write()
+                .unwrap()
+                .create(OpenFile::Regular(open_file));
 
             Ok(crate::fuse_util::CreateOk {
-                ttl: Duration::from_secs(60),
+                ttl: RECENTLY_MODIFIED_TTL,
                 attr,
-                generation: GENERATION_COUNT.fetch_add(1, Ordering::Relaxed),
+                generation,
                 fh,
                 flags: 0, // FIXME
             })
@@ -946,9 +1823,9 @@ impl fuse::Filesystem for Filesystem {
         _lock_owner: u64,
         _start: u64,
         _end: u64,
-        _typ: u32,
+        _typ: i32,
         _pid: u32,
-        reply: fuse::ReplyLock,
+        reply: fuser::ReplyLock,
     ) {
         reply.error(libc::ENOTSUP);
     }
@@ -961,7 +1838,7 @@ impl fuse::Filesystem for Filesystem {
         _lock_owner: u64,
         _start: u64,
         _end: u64,
-        _typ: u32,
+        _typ: i32,
         _pid: u32,
         _sleep: bool,
         reply: ReplyEmpty,
@@ -975,17 +1852,1075 @@ impl fuse::Filesystem for Filesystem {
         _ino: u64,
         _blocksize: u32,
         _idx: u64,
-        reply: fuse::ReplyBmap,
+        reply: fuser::ReplyBmap,
     ) {
         reply.error(libc::ENOTSUP);
     }
+
+    fn ioctl(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        let span = debug_span!("ioctl", ino, cmd);
+        let _enter = span.enter();
+
+        let state = Arc::clone(&self.state);
+
+        wrap_ioctl(&self.dispatcher, "ioctl", reply, async move {
+            if cmd != FS_IOC_GETFLAGS {
+                return Err(libc::ENOTTY.into());
+            }
+            let inode = state.read().unwrap().superblock.get_inode(ino)?;
+            let flags = match inode.read().unwrap().contents {
+                Contents::RegularFile(_) => FS_IMMUTABLE_FL,
+                _ => 0,
+            };
+            let mut data = flags.to_ne_bytes().to_vec();
+            data.truncate(out_size as usize);
+            Ok((0, data))
+        });
+    }
+
+    fn lseek(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        let span = debug_span!("lseek", ino, offset, whence);
+        let _enter = span.enter();
+
+        let state = Arc::clone(&self.state);
+
+        // The kernel handles SEEK_SET, SEEK_CUR and SEEK_END itself.
+        // Only mutable files can have holes; immutable files are a
+        // single extent.
+        wrap_lseek(&self.dispatcher, "lseek", reply, async move {
+            let data = match whence {
+                libc::SEEK_DATA => true,
+                libc::SEEK_HOLE => false,
+                _ => return Err(libc::EINVAL.into()),
+            };
+            if offset < 0 {
+                return Err(libc::ENXIO.into());
+            }
+            let inode = state.read().unwrap().superblock.get_inode(ino)?;
+            let mutable_file = match &inode.read().unwrap().contents {
+                Contents::MutableFile(file) => Some(Arc::clone(file)),
+                _ => None,
+            };
+            let res = match mutable_file {
+                Some(file) => file.file.seek_extent(offset as u64, data).await?,
+                None => {
+                    let size = fuser::FileAttr::from(&*inode.read().unwrap()).size;
+                    if offset as u64 >= size {
+                        None
+                    } else if data {
+                        Some(offset as u64)
+                    } else {
+                        Some(size)
+                    }
+                }
+            };
+            match res {
+                Some(pos) => Ok(pos as i64),
+                None => Err(libc::ENXIO.into()),
+            }
+        });
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: fuser::ReplyWrite,
+    ) {
+        let span = debug_span!(
+            "copy_file_range",
+            ino_in,
+            ino_out,
+            offset_in,
+            offset_out,
+            len
+        );
+        let _enter = span.enter();
+
+        let state = Arc::clone(&self.state);
+        let counters = Arc::clone(&self.counters);
+
+        // Copying within the daemon saves passing the data through
+        // the kernel twice.
+        wrap_write(&self.dispatcher, "copy_file_range", reply, async move {
+            if flags != 0 {
+                return Err(libc::EINVAL.into());
+            }
+            let len = std::cmp::min(len, u64::from(u32::MAX));
+            let mut copied: u64 = 0;
+            while copied < len {
+                let size = std::cmp::min(len - copied, u64::from(COPY_RANGE_CHUNK_SIZE)) as u32;
+                let data = read_file(
+                    Arc::clone(&state),
+                    Arc::clone(&counters),
+                    ino_in,
+                    fh_in,
+                    offset_in + copied as i64,
+                    size,
+                )
+                .await?;
+                if data.is_empty() {
+                    break;
+                }
+                copied += u64::from(
+                    write_file(
+                        Arc::clone(&state),
+                        Arc::clone(&counters),
+                        ino_out,
+                        fh_out,
+                        offset_out + copied as i64,
+                        data,
+                    )
+                    .await?,
+                );
+            }
+            Ok(copied as u32)
+        });
+    }
+}
+
+/// `ioctl()` to get the inode flags, as shown by `lsattr`.
+const FS_IOC_GETFLAGS: u32 = 0x80086601;
+
+/// Inode flag reported for immutable files.
+const FS_IMMUTABLE_FL: u32 = 0x00000010;
+
+/// Size of the reads done by `copy_file_range()`.
+const COPY_RANGE_CHUNK_SIZE: u32 = 1 << 20;
+
+/// Read from the file open as `fh`.
+async fn read_file(
+    state: Arc<RwLock<FilesystemState>>,
+    counters: Arc<Counters>,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    size: u32,
+) -> std::result::Result<Vec<u8>, FuseError> {
+    enum File {
+        Forced(Store, Hash, u64),
+        Regular(Option<Store>, Hash, u64, Arc<Mutex<ReadAhead>>),
+        Mutable(Arc<crate::fs::MutableFile>),
+        Control(Arc<futures::lock::Mutex<crate::control::ControlOutput>>),
+    };
+
+    let file = {
+        let state = state.read().unwrap();
+        let file_handles = state.file_handles.read().unwrap();
+        match file_handles.get(fh)? {
+            OpenFile::Regular(open_file) => {
+                let inode = open_file.inode.read().unwrap();
+                assert_eq!(ino, inode.ino);
+                match &inode.contents {
+                    Contents::RegularFile(reg) if open_file.forced_store.is_some() => File::Forced(
+                        Arc::clone(open_file.forced_store.as_ref().unwrap()),
+                        reg.hash.clone(),
+                        reg.length,
+                    ),
+                    Contents::RegularFile(reg) => File::Regular(
+                        open_file.store.read().unwrap().clone(),
+                        reg.hash.clone(),
+                        reg.length,
+                        Arc::clone(&open_file.readahead),
+                    ),
+                    Contents::MutableFile(file) => File::Mutable(Arc::clone(file)),
+                    _ => return Err(libc::EISDIR.into()),
+                }
+            }
+            OpenFile::Directory(_) => {
+                return Err(libc::EISDIR.into());
+            }
+            OpenFile::Control(control_file) => File::Control(Arc::clone(&control_file.output)),
+            OpenFile::Info(info_file) => {
+                let data = &info_file.data;
+                let start = std::cmp::min(offset as usize, data.len());
+                let end = std::cmp::min(start + size as usize, data.len());
+                return Ok(data[start..end].to_vec());
+            }
+        }
+    };
+
+    match file {
+        File::Forced(store, hash, length) => {
+            let expected =
+                std::cmp::min(length.saturating_sub(offset as u64), u64::from(size)) as usize;
+            return match store
+                .get(&hash, offset as u64, usize::try_from(size).unwrap())
+                .await
+            {
+                Ok(data) if data.len() >= expected => {
+                    counters.read(&store.get_url(), data.len() as u64);
+                    Ok(data)
+                }
+                Ok(_) => {
+                    error!(
+                        "Store '{}' has a truncated copy of {}.",
+                        store.get_url(),
+                        hash.to_hex()
+                    );
+                    Err(libc::EIO.into())
+                }
+                Err(Error::NoSuchHash(_)) => Err(libc::ENOMEDIUM.into()),
+                Err(err @ Error::RecallPending(_, _)) => {
+                    info!("{}", err);
+                    Err(err.into())
+                }
+                Err(err) => {
+                    error!("Error reading file {}: {}", ino, err);
+                    counters.error(&store.get_url());
+                    Err(libc::EIO.into())
+                }
+            };
+        }
+
+        File::Regular(store, hash, length, readahead) => {
+            // Prefetching is speculative, so don't do it when idle.
+            let prefetch = !state.read().unwrap().power.is_idle();
+
+            /* A store that returns less data than the file's
+             * length implies has a truncated copy, so we
+             * should try another store. */
+            let expected =
+                std::cmp::min(length.saturating_sub(offset as u64), u64::from(size)) as usize;
+
+            let window = readahead.lock().unwrap().lookup(offset as u64);
+            if let Some((window_offset, fut)) = window {
+                if let Some(data) = fut.await {
+                    let start = (offset as u64 - window_offset) as usize;
+                    if data.len() >= start + expected {
+                        let data = data[start..start + expected].to_vec();
+                        if let Some(store) = store.filter(|_| prefetch) {
+                            ReadAhead::schedule(
+                                &readahead,
+                                &store,
+                                &hash,
+                                offset as u64,
+                                expected as u64,
+                                length,
+                                &counters,
+                            );
+                        }
+                        counters.readahead(true);
+                        return Ok(data);
+                    }
+                }
+            }
+
+            counters.readahead(false);
+
+            let (store, data) = read_from_stores(
+                &state,
+                ino,
+                fh,
+                store,
+                StoreRead {
+                    hash: &hash,
+                    offset: offset as u64,
+                    size,
+                    expected,
+                },
+            )
+            .await?;
+
+            if prefetch {
+                ReadAhead::schedule(
+                    &readahead,
+                    &store,
+                    &hash,
+                    offset as u64,
+                    data.len() as u64,
+                    length,
+                    &counters,
+                );
+            }
+
+            return Ok(data);
+        }
+
+        File::Mutable(file) => match file.file.read(offset as u64, size).await {
+            Ok(data) => {
+                counters.read(&file.store, data.len() as u64);
+                return Ok(data);
+            }
+            Err(err) => {
+                error!("Error reading file {}: {}", ino, err);
+                counters.error(&file.store);
+                return Err(libc::EIO.into());
+            }
+        },
+
+        File::Control(output) => {
+            return Ok(output.lock().await.read(offset as u64, size).await);
+        }
+    }
+}
+
+/// Write to the file open as `fh`. Returns the number of bytes written.
+async fn write_file(
+    state: Arc<RwLock<FilesystemState>>,
+    counters: Arc<Counters>,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    data: Vec<u8>,
+) -> std::result::Result<u32, FuseError> {
+    let file = {
+        let state = state.read().unwrap();
+        let file_handles = state.file_handles.read().unwrap();
+
+        match file_handles.get(fh)? {
+            OpenFile::Regular(open_file) => {
+                state.superblock.check_writable()?;
+                let file = {
+                    let mut inode = open_file.inode.write().unwrap();
+                    assert_eq!(ino, inode.ino);
+                    if state.is_finalizing(ino) {
+                        return Err(libc::EBUSY.into());
+                    }
+                    if inode.mtime.elapsed() >= MTIME_GRANULARITY {
+                        inode.mtime = Time::now();
+                    }
+                    match &inode.contents {
+                        Contents::MutableFile(file) => Arc::clone(file),
+                        Contents::RegularFile(_) => return Err(libc::EPERM.into()),
+                        _ => return Err(libc::EISDIR.into()),
+                    }
+                };
+                let end = offset as u64 + data.len() as u64;
+                if end > file.file.len() {
+                    state.superblock.check_quota(
+                        ino,
+                        Usage {
+                            bytes: end - file.file.len(),
+                            inodes: 0,
+                        },
+                    )?;
+                }
+                file
+            }
+
+            OpenFile::Control(control_file) => {
+                for d in &data {
+                    control_file.tx.send(*d).map_err(|_| libc::ENOTCONN)?;
+                }
+                return Ok(data.len() as u32);
+            }
+
+            OpenFile::Directory(_) => return Err(libc::EISDIR.into()),
+
+            OpenFile::Info(_) => return Err(libc::EBADF.into()),
+        }
+    };
+
+    if let Err(err) = file.file.write(offset as u64, &data).await {
+        counters.error(&file.store);
+        return Err(err.into());
+    }
+    counters.written(&file.store, data.len() as u64);
+
+    Ok(data.len().try_into().unwrap())
 }
 
-async fn create_file(stores: Vec<Store>) -> std::result::Result<Box<dyn MutableFile>, FuseError> {
+async fn create_file(stores: Vec<Store>) -> std::result::Result<crate::fs::MutableFile, FuseError> {
     for store in stores {
         if let Some(fut) = store.create_file() {
-            return Ok(fut.await.unwrap());
+            return Ok(crate::fs::MutableFile {
+                store: store.get_url(),
+                file: fut.await.unwrap(),
+            });
         }
     }
     Err(libc::EROFS.into())
 }
+
+/// Parse comma-separated FUSE mount options, rejecting those that
+/// hugefs doesn't support.
+pub fn parse_mount_options(options: &[String]) -> Result<Vec<MountOption>> {
+    let mut res = vec![];
+    for opt in options.iter().flat_map(|s| s.split(',')) {
+        let valid_name = |s: &str| !s.is_empty() && !s.contains(char::is_whitespace);
+        let mut parts = opt.splitn(2, '=');
+        let option = match (parts.next().unwrap(), parts.next()) {
+            ("allow_other", None) => Some(MountOption::AllowOther),
+            ("allow_root", None) => Some(MountOption::AllowRoot),
+            ("default_permissions", None) => Some(MountOption::DefaultPermissions),
+            ("max_read", Some(n)) if n.parse::<u32>().map(|n| n > 0).unwrap_or(false) => {
+                Some(MountOption::CUSTOM(opt.into()))
+            }
+            ("fsname", Some(s)) if valid_name(s) => Some(MountOption::FSName(s.into())),
+            ("subtype", Some(s)) if valid_name(s) => Some(MountOption::Subtype(s.into())),
+            _ => None,
+        };
+        match option {
+            Some(option) => res.push(option),
+            None => {
+                return Err(Error::BadArguments(format!(
+                    "invalid mount option '{}'",
+                    opt
+                )))
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// How often stores are checked.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a health check may take before the store is considered
+/// unhealthy.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Check whether `store` is available and record the result.
+/// Returns the error if it's not.
+pub async fn probe_store(state: &RwLock<FilesystemState>, store: &Store) -> Option<String> {
+    let res = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, store.check_health()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("health check timed out".into()),
+    };
+    state.write().unwrap().set_health(store, res.clone());
+    res.err()
+}
+
+/// Periodically write the superblock to the state file if it has
+/// changed, so that a crash loses at most `interval` of metadata
+/// changes.
+pub async fn autosave(state: Arc<RwLock<FilesystemState>>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        let path = match &state.read().unwrap().state_file {
+            Some(path) => path.clone(),
+            None => continue,
+        };
+        let state_ = Arc::clone(&state);
+        match tokio::task::spawn_blocking(move || state_.read().unwrap().sync_if_changed(&path))
+            .await
+            .unwrap()
+        {
+            Ok(true) => {
+                debug!("Saved the superblock.");
+                // Keep the ledgers about as fresh as the state file,
+                // so that gc of shared stores can make progress.
+                if let Err(err) = write_ledgers(Arc::clone(&state)).await {
+                    error!("Cannot write reference ledgers: {}", err);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => error!("Cannot save the superblock: {}", err),
+        }
+    }
+}
+
+/// Interval between attempts to bring offline files online.
+const RECALL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Periodically process the pending recalls of stores with offline
+/// media.
+pub async fn run_recalls(state: Arc<RwLock<FilesystemState>>) {
+    loop {
+        let stores = state.read().unwrap().stores.clone();
+        for store in stores {
+            match store.process_recalls().await {
+                Ok(0) => {}
+                Ok(n) => info!("Recalled {} files from store '{}'.", n, store.get_url()),
+                Err(err) => error!(
+                    "Cannot recall files from store '{}': {}",
+                    store.get_url(),
+                    err
+                ),
+            }
+        }
+        tokio::time::delay_for(RECALL_INTERVAL).await;
+    }
+}
+
+/// Periodically check the health of all stores.
+pub async fn check_stores(state: Arc<RwLock<FilesystemState>>) {
+    loop {
+        let stores = state.read().unwrap().stores.clone();
+        for store in stores {
+            probe_store(&state, &store).await;
+        }
+        tokio::time::delay_for(HEALTH_CHECK_INTERVAL).await;
+    }
+}
+
+/// Interval between checks of whether stores exceed their maximum size.
+const CAPACITY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Return whether a healthy store other than `store` has an intact
+/// copy of the file with hash `hash`.
+pub async fn has_other_copy(
+    state: &RwLock<FilesystemState>,
+    store: &Store,
+    hash: &Hash,
+    size: u64,
+) -> bool {
+    let stores = state.read().unwrap().stores.clone();
+    for other in stores {
+        if other.get_url() == store.get_url()
+            || !state.read().unwrap().is_healthy(&other)
+            || state.read().unwrap().is_suspect(&other, hash)
+        {
+            continue;
+        }
+        // Read the last byte to verify that the copy isn't truncated.
+        let present = if size == 0 {
+            other.has(hash).await.unwrap_or(false)
+        } else {
+            match other.get(hash, size - 1, 1).await {
+                Ok(data) => data.len() == 1,
+                Err(_) => false,
+            }
+        };
+        if present {
+            return true;
+        }
+    }
+    false
+}
+
+/// Remove files from `store` until it's below its maximum size,
+/// least recently accessed first. Only files that exist in another
+/// store are removed. Returns the number of bytes freed.
+pub async fn evict_from_store(state: &Arc<RwLock<FilesystemState>>, store: &Store) -> Result<u64> {
+    let max_size = match store.max_size() {
+        Some(max_size) => max_size,
+        None => return Ok(0),
+    };
+    let mut used = store.used_bytes().await?;
+    if used <= max_size {
+        return Ok(0);
+    }
+
+    let (files, pinned) = {
+        let state = state.read().unwrap();
+        (
+            state.superblock.all_files(),
+            state.superblock.pinned_files(&store.get_url()),
+        )
+    };
+    let hashes: Vec<Hash> = files
+        .keys()
+        .filter(|hash| !pinned.contains_key(hash))
+        .cloned()
+        .collect();
+    let present = store.has_many(&hashes).await?;
+    let mut candidates = vec![];
+    for (hash, size) in files {
+        if present.contains(&hash) {
+            let last_access = store.last_access(&hash).await?;
+            candidates.push((last_access, hash, size));
+        }
+    }
+    candidates.sort_by_key(|(last_access, _, _)| *last_access);
+
+    let mut freed = 0;
+    for (_, hash, size) in candidates {
+        if used <= max_size {
+            break;
+        }
+        if !has_other_copy(state, store, &hash, size).await {
+            continue;
+        }
+        info!(
+            "Evicting {} ({} bytes) from store '{}'.",
+            hash.to_hex(),
+            size,
+            store.get_url()
+        );
+        store.remove(&hash).await?;
+        used = used.saturating_sub(size);
+        freed += size;
+    }
+
+    if used > max_size {
+        warn!(
+            "Store '{}' exceeds its maximum size of {} bytes, but has no more files that exist elsewhere.",
+            store.get_url(),
+            max_size
+        );
+    }
+
+    Ok(freed)
+}
+
+/// Copy pinned files to the stores they're pinned to, if those
+/// stores are attached and don't have them. Returns the number of
+/// files copied.
+pub async fn restore_pins(state: &Arc<RwLock<FilesystemState>>) -> Result<usize> {
+    let (stores, verify_percent) = {
+        let state = state.read().unwrap();
+        (state.stores.clone(), state.mirror_stats.verify_percent)
+    };
+
+    let mut copied = 0;
+
+    for store in &stores {
+        if !state.read().unwrap().is_healthy(store) {
+            continue;
+        }
+        let url = store.get_url();
+        let files = state.read().unwrap().superblock.pinned_files(&url);
+        let hashes: Vec<Hash> = files.keys().cloned().collect();
+        let present = store.has_many(&hashes).await?;
+        for (hash, size) in files {
+            if present.contains(&hash) {
+                continue;
+            }
+            let from = crate::control::copy_to_store(
+                &hash,
+                size,
+                &stores,
+                store,
+                verify_percent,
+                &|_| {},
+                state,
+            )
+            .await?;
+            info!(
+                "Restored pinned file {} to store '{}' from '{}'.",
+                hash.to_hex(),
+                url,
+                from
+            );
+            copied += 1;
+        }
+    }
+
+    Ok(copied)
+}
+
+/// Interval between checks of whether pinned files are present.
+const PIN_CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Periodically restore pinned files that are missing from their
+/// stores, unless the daemon should be idle.
+pub async fn check_pins(state: Arc<RwLock<FilesystemState>>) {
+    loop {
+        let idle = state.read().unwrap().power.is_idle();
+        if !idle {
+            if let Err(err) = restore_pins(&state).await {
+                error!("Cannot restore pinned files: {}", err);
+            }
+        }
+        tokio::time::delay_for(PIN_CHECK_INTERVAL).await;
+    }
+}
+
+/// Periodically evict files from stores that exceed their maximum
+/// size.
+pub async fn enforce_capacity(state: Arc<RwLock<FilesystemState>>) {
+    loop {
+        let stores = state.read().unwrap().stores.clone();
+        for store in stores {
+            if let Err(err) = evict_from_store(&state, &store).await {
+                error!(
+                    "Cannot evict files from store '{}': {}",
+                    store.get_url(),
+                    err
+                );
+            }
+        }
+        tokio::time::delay_for(CAPACITY_CHECK_INTERVAL).await;
+    }
+}
+
+/// Write the set of hashes referenced by this filesystem to the
+/// ledger in each store.
+pub async fn write_ledgers(state: Arc<RwLock<FilesystemState>>) -> Result<()> {
+    let (id, hashes, stores) = {
+        let state = state.read().unwrap();
+        let mut hashes = state.superblock.reachable_hashes();
+        // Unlinked files can still be read while they're open.
+        for open_file in state.file_handles.read().unwrap().handles.values() {
+            if let OpenFile::Regular(open_file) = open_file {
+                let inode = open_file.inode.read().unwrap();
+                if let Contents::RegularFile(file) = &inode.contents {
+                    hashes.insert(file.hash.clone());
+                }
+            }
+        }
+        let hashes: Vec<Hash> = hashes.into_iter().collect();
+        (
+            state.superblock.get_id().to_string(),
+            hashes,
+            state.stores.clone(),
+        )
+    };
+
+    for store in stores {
+        store.write_ledger(&id, hashes.clone()).await?;
+    }
+
+    Ok(())
+}
+
+/// Flush the contents of all mutable files to their stores. Returns
+/// the number of files that could not be flushed.
+pub async fn flush_mutable_files(state: &RwLock<FilesystemState>) -> usize {
+    let files = state.read().unwrap().superblock.get_mutable_files();
+    let mut failed = 0;
+    for file in files {
+        if let Err(err) = file.file.sync(false).await {
+            error!(
+                "Cannot flush mutable file in store '{}': {}",
+                file.store, err
+            );
+            failed += 1;
+        }
+    }
+    failed
+}
+
+/// Reopen the mutable files of a superblock read from a state file,
+/// i.e. files that were being written when the daemon last stopped,
+/// and finalize them. Files that can't be reopened stay unreadable.
+/// Returns the number of files recovered.
+pub async fn reattach_mutable_files(state: &Arc<RwLock<FilesystemState>>) -> usize {
+    let inodes = state.read().unwrap().superblock.get_mutable_inodes();
+    let mut recovered = 0;
+    for inode in inodes {
+        let (ino, url, path) = {
+            let inode = inode.read().unwrap();
+            match &inode.contents {
+                Contents::MutableFile(file) => (inode.ino, file.store.clone(), file.file.path()),
+                _ => continue,
+            }
+        };
+        let store = state
+            .read()
+            .unwrap()
+            .stores
+            .iter()
+            .find(|st| st.get_url() == url)
+            .cloned();
+        let fut = match (&store, &path) {
+            (Some(store), Some(path)) => store.reopen_file(path),
+            _ => None,
+        };
+        let res = match fut {
+            Some(fut) => fut.await,
+            None => Err(Error::StoreUnavailable(url.clone())),
+        };
+        match res {
+            Ok(file) => {
+                {
+                    let state = &mut *state.write().unwrap();
+                    let mut inode_ = inode.write().unwrap();
+                    let old = std::mem::replace(
+                        &mut inode_.contents,
+                        Contents::MutableFile(Arc::new(crate::fs::MutableFile {
+                            store: url,
+                            file,
+                        })),
+                    );
+                    state
+                        .superblock
+                        .contents_replaced(ino, &old, &inode_.contents);
+                }
+                match finalize_file(&inode, state, &|_| {}).await {
+                    Ok(_) => recovered += 1,
+                    Err(err) => error!("Cannot finalize recovered file {}: {}", ino, err),
+                }
+            }
+            Err(err) => error!(
+                "Cannot reopen mutable file {} in store '{}': {}",
+                ino, url, err
+            ),
+        }
+    }
+    recovered
+}
+
+/// Return the mutable file opened through file handle `fh`, if any.
+fn get_mutable_file(
+    state: &RwLock<FilesystemState>,
+    fh: u64,
+) -> Result<Option<Arc<crate::fs::MutableFile>>> {
+    let state = state.read().unwrap();
+    let file_handles = state.file_handles.read().unwrap();
+    match file_handles.get(fh)? {
+        OpenFile::Regular(open_file) => match &open_file.inode.read().unwrap().contents {
+            Contents::MutableFile(file) => Ok(Some(Arc::clone(file))),
+            _ => Ok(None),
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Convert a mutable file into an immutable, content-addressed
+/// file. Returns `None` if the inode is not a mutable file.
+/// `progress` is called with the number of bytes hashed so far.
+pub async fn finalize_file(
+    inode: &Arc<RwLock<Inode>>,
+    state: &Arc<RwLock<FilesystemState>>,
+    progress: crate::store::ProgressFn<'_>,
+) -> Result<Option<(u64, Hash)>> {
+    let (ino, mutable_file) = {
+        let state = state.read().unwrap();
+        let file_handles = state.file_handles.read().unwrap();
+        let inode_ = inode.read().unwrap();
+        if let Contents::MutableFile(file) = &inode_.contents {
+            /* Marking the file as finalizing makes write() and
+             * truncation fail with EBUSY until its contents have been
+             * swapped below, so nothing written after finish() can
+             * be lost. */
+            if file_handles.is_open_for_writing(inode)
+                || !state.finalizing.lock().unwrap().insert(inode_.ino)
+            {
+                return Err(Error::FileBusy(inode_.ino));
+            }
+            (inode_.ino, Arc::clone(file))
+        } else {
+            return Ok(None);
+        }
+    };
+
+    let (length, hash) = match mutable_file.file.finish(progress).await {
+        Ok(res) => res,
+        Err(err) => {
+            state
+                .read()
+                .unwrap()
+                .finalizing
+                .lock()
+                .unwrap()
+                .remove(&ino);
+            return Err(err);
+        }
+    };
+
+    debug!("finalised file with hash {}, size {}", hash, length);
+
+    {
+        let state = &mut *state.write().unwrap();
+        let mut inode_ = inode.write().unwrap();
+        state.finalizing.lock().unwrap().remove(&ino);
+        /* finish() has consumed the temporary file, so the contents
+         * must be committed even if the file has been opened for
+         * writing in the meantime: writes were refused while it was
+         * finalizing, so the hash covers everything written. */
+        let unchanged = match &inode_.contents {
+            Contents::MutableFile(file) => Arc::ptr_eq(file, &mutable_file),
+            _ => false,
+        };
+        assert!(unchanged, "inode {} changed while finalizing", ino);
+
+        /* If another file already has these contents (e.g. because
+         * it was copied with cp or rsync), the stores already have
+         * them, so the new file only costs metadata. */
+        if state.superblock.is_referenced(&hash) {
+            info!(
+                "File {} duplicates existing contents {}; saved {} bytes.",
+                ino,
+                hash.to_hex(),
+                length
+            );
+            state.counters.deduplicated(length);
+        }
+
+        let old = std::mem::replace(
+            &mut inode_.contents,
+            Contents::RegularFile(crate::fs::RegularFile {
+                length,
+                hash: hash.clone(),
+            }),
+        );
+        state
+            .superblock
+            .contents_replaced(ino, &old, &inode_.contents);
+    }
+
+    Ok(Some((length, hash)))
+}
+
+/// If `store` is the archive store of a tiering policy, copy the
+/// file with hash `hash` back to the policy's fast store.
+fn recall_if_archived(state: &Arc<RwLock<FilesystemState>>, store: &Store, hash: &Hash) {
+    let url = store.get_url();
+    let is_archive = state
+        .read()
+        .unwrap()
+        .superblock
+        .tier_policies
+        .values()
+        .any(|policy| policy.archive == url);
+    if is_archive && state.write().unwrap().recalled.insert(hash.clone()) {
+        tokio::spawn(crate::tiering::recall(Arc::clone(state), hash.clone()));
+    }
+}
+
+/// Read `size` bytes at `offset` of the immutable file with hash
+/// `hash` and length `length` from the first store that has an intact
+/// copy, preferring healthy stores. This is for frontends other than
+/// FUSE, which don't have file handles.
+pub async fn read_hash(
+    state: &Arc<RwLock<FilesystemState>>,
+    hash: &Hash,
+    length: u64,
+    offset: u64,
+    size: u64,
+) -> Result<Vec<u8>> {
+    let expected = std::cmp::min(length.saturating_sub(offset), size);
+    let mut stores = state.read().unwrap().stores.clone();
+    stores.sort_by_key(|st| !state.read().unwrap().is_healthy(st));
+    let mut res = Err(Error::NoSuchHash(hash.clone()));
+    for store in stores {
+        if state.read().unwrap().is_suspect(&store, hash) {
+            continue;
+        }
+        match store
+            .get(hash, offset, usize::try_from(size).unwrap())
+            .await
+        {
+            Ok(data) if (data.len() as u64) < expected => {
+                state.write().unwrap().mark_suspect(&store, hash);
+            }
+            Ok(data) => {
+                state
+                    .read()
+                    .unwrap()
+                    .counters
+                    .read(&store.get_url(), data.len() as u64);
+                recall_if_archived(state, &store, hash);
+                return Ok(data);
+            }
+            Err(Error::NoSuchHash(_)) => {}
+            Err(err) => res = Err(err),
+        }
+    }
+    res
+}
+
+/// Read from the store that this file handle has used before, or
+/// otherwise from the first store that has an intact copy of the file.
+/// The part of a file that a read needs from the stores. `expected`
+/// is the minimum number of bytes a store must return for its answer
+/// to be trusted.
+struct StoreRead<'a> {
+    hash: &'a Hash,
+    offset: u64,
+    size: u32,
+    expected: usize,
+}
+
+async fn read_from_stores(
+    state: &Arc<RwLock<FilesystemState>>,
+    ino: u64,
+    fh: u64,
+    store: Option<Store>,
+    read: StoreRead<'_>,
+) -> std::result::Result<(Store, Vec<u8>), FuseError> {
+    let StoreRead {
+        hash,
+        offset,
+        size,
+        expected,
+    } = read;
+    if let Some(store) = store.filter(|st| state.read().unwrap().is_healthy(st)) {
+        match store
+            .get(hash, offset, usize::try_from(size).unwrap())
+            .await
+        {
+            Ok(data) if data.len() >= expected => {
+                state
+                    .read()
+                    .unwrap()
+                    .counters
+                    .read(&store.get_url(), data.len() as u64);
+                recall_if_archived(state, &store, hash);
+                return Ok((store, data));
+            }
+            Ok(_) => state.write().unwrap().mark_suspect(&store, hash),
+            Err(Error::NoSuchHash(_)) | Err(Error::RecallPending(_, _)) => {}
+            Err(err) => {
+                error!("Error reading file {}: {}", ino, err);
+                let state = &mut *state.write().unwrap();
+                state.counters.error(&store.get_url());
+                state.set_health(&store, Err(err.to_string()));
+            }
+        }
+    }
+
+    /* Find a store that has this file. Unhealthy stores are only
+     * tried if no other store has it. */
+    let mut stores = state.read().unwrap().stores.clone();
+    stores.sort_by_key(|st| !state.read().unwrap().is_healthy(st));
+    let mut failed = false;
+    let mut recalling = false;
+    for store in stores {
+        if state.read().unwrap().is_suspect(&store, hash) {
+            continue;
+        }
+        match store
+            .get(hash, offset, usize::try_from(size).unwrap())
+            .await
+        {
+            Ok(ref data) if data.len() < expected => {
+                state.write().unwrap().mark_suspect(&store, hash);
+                continue;
+            }
+            Ok(data) => {
+                {
+                    let state = state.read().unwrap();
+                    state.counters.read(&store.get_url(), data.len() as u64);
+                    *state
+                        .file_handles
+                        .read()
+                        .unwrap()
+                        .get_regular(fh)?
+                        .store
+                        .write()
+                        .unwrap() = Some(Arc::clone(&store));
+                }
+                recall_if_archived(state, &store, hash);
+                return Ok((store, data));
+            }
+            Err(Error::NoSuchHash(_)) => continue,
+            Err(Error::RecallPending(_, _)) => recalling = true,
+            Err(err) => {
+                error!("Error reading file {}: {}", ino, err);
+                let state = &mut *state.write().unwrap();
+                state.counters.error(&store.get_url());
+                state.set_health(&store, Err(err.to_string()));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return Err(libc::EIO.into());
+    }
+    if recalling {
+        info!("File {} is offline and is being recalled.", ino);
+        return Err(libc::EAGAIN.into());
+    }
+    error!("Cannot find file {} with hash {}", ino, hash.to_hex());
+    Err(libc::ENOMEDIUM.into())
+}
@@ -1,5 +1,9 @@
-use aes_ctr::stream_cipher::generic_array::GenericArray;
+use aes_ctr::stream_cipher::generic_array::{typenum::Unsigned, GenericArray};
 use blake2::Digest;
+use tokio::io::AsyncReadExt;
+
+/// Size of the chunks in which `Hash::hash_async()` reads its input.
+const HASH_CHUNK_SIZE: usize = 1 << 20;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct Hash(pub GenericArray<u8, <blake2::Blake2b as Digest>::OutputSize>);
@@ -11,10 +15,34 @@ impl Hash {
         Ok((n, Self(hasher.result())))
     }
 
-    pub fn from_hex(s: &str) -> Self {
-        // FIXME: return Result
-        let bytes = hex::decode(&s).unwrap();
-        Self(*GenericArray::from_slice(&bytes))
+    /// Hash the contents of an async reader, reading it in
+    /// fixed-size chunks. `progress` is called with the number of
+    /// bytes hashed after each chunk.
+    pub async fn hash_async<R: tokio::io::AsyncRead + Unpin>(
+        r: &mut R,
+        progress: crate::store::ProgressFn<'_>,
+    ) -> std::io::Result<(u64, Self)> {
+        let mut hasher = blake2::Blake2b::new();
+        let mut buf = vec![0u8; HASH_CHUNK_SIZE];
+        let mut n = 0;
+        loop {
+            let n2 = r.read(&mut buf).await?;
+            if n2 == 0 {
+                break;
+            }
+            hasher.input(&buf[..n2]);
+            n += n2 as u64;
+            progress(n);
+        }
+        Ok((n, Self(hasher.result())))
+    }
+
+    pub fn from_hex(s: &str) -> Option<Self> {
+        let bytes = hex::decode(&s).ok()?;
+        if bytes.len() != <blake2::Blake2b as Digest>::OutputSize::to_usize() {
+            return None;
+        }
+        Some(Self(*GenericArray::from_slice(&bytes)))
     }
 
     pub fn to_string(&self) -> String {
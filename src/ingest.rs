@@ -0,0 +1,109 @@
+use crate::control::{handle_adopt, Response};
+use crate::fusefs::FilesystemState;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+/// How often the inbox is scanned for new files.
+const INBOX_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a file must be unmodified before it's ingested, to avoid
+/// adopting files that are still being written.
+const INBOX_SETTLE_TIME: Duration = Duration::from_secs(60);
+
+/// A directory outside the filesystem whose files are automatically
+/// adopted into `dest`.
+pub struct Inbox {
+    pub dir: PathBuf,
+    /// Directory in the filesystem that receives the files.
+    pub dest: PathBuf,
+    /// Whether to delete files from the inbox once they're adopted.
+    pub remove: bool,
+    pub settle: Duration,
+    /// The modification times of the files that have already been
+    /// processed, so that failures are only reported once.
+    seen: HashMap<PathBuf, SystemTime>,
+}
+
+impl Inbox {
+    pub fn new(dir: PathBuf, dest: PathBuf, remove: bool) -> Self {
+        Self {
+            dir,
+            dest,
+            remove,
+            settle: INBOX_SETTLE_TIME,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Adopt the regular files in the inbox that haven't been
+    /// modified recently. Files whose name starts with '.' are
+    /// ignored. Returns the number of files adopted.
+    pub async fn scan(&mut self, state: &Arc<RwLock<FilesystemState>>) -> std::io::Result<usize> {
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let st = entry.metadata()?;
+            let name = entry.file_name();
+            if !st.is_file() || name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let mtime = st.modified()?;
+            if crate::clock::now()
+                .duration_since(mtime)
+                .unwrap_or_default()
+                < self.settle
+                || self.seen.get(&entry.path()) == Some(&mtime)
+            {
+                continue;
+            }
+            entries.push((entry.path(), name, mtime));
+        }
+
+        let mut adopted = 0;
+
+        for (src, name, mtime) in entries {
+            let dest = self.dest.join(&name);
+            // Files that are removed afterwards can be hard-linked
+            // into the store.
+            match handle_adopt(&src, &dest, None, self.remove, Arc::clone(state)).await {
+                Ok(Response::Adopt { hash, .. }) => {
+                    info!(
+                        "Ingested '{}' as '{}' ({}).",
+                        src.display(),
+                        dest.display(),
+                        hash.to_hex()
+                    );
+                    adopted += 1;
+                    if self.remove {
+                        if let Err(err) = std::fs::remove_file(&src) {
+                            warn!("Cannot remove '{}': {}", src.display(), err);
+                        }
+                        continue;
+                    }
+                }
+                Ok(_) => unreachable!(),
+                Err(err) => warn!("Cannot ingest '{}': {}", src.display(), err),
+            }
+            self.seen.insert(src, mtime);
+        }
+
+        Ok(adopted)
+    }
+}
+
+/// Periodically ingest the files in `inbox`, unless the daemon
+/// should be idle.
+pub async fn run_inbox(state: Arc<RwLock<FilesystemState>>, mut inbox: Inbox) {
+    loop {
+        let idle = state.read().unwrap().power.is_idle();
+        if !idle {
+            if let Err(err) = inbox.scan(&state).await {
+                error!("Cannot scan inbox '{}': {}", inbox.dir.display(), err);
+            }
+        }
+        tokio::time::delay_for(INBOX_POLL_INTERVAL).await;
+    }
+}
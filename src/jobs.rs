@@ -0,0 +1,223 @@
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Number of finished jobs to remember, so that resubmitting a job
+/// with the same idempotency key doesn't run it again.
+const MAX_FINISHED_JOBS: usize = 1000;
+
+/// Priority of the erase jobs queued when files are deleted.
+pub const ERASE_PRIORITY: i32 = 0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JobKind {
+    /// Copy the file at `path` to `store`.
+    Mirror { path: PathBuf, store: String },
+    /// Securely delete the file with hash `hash` from all stores.
+    Erase { hash: Hash },
+    /// Remove the copy of the file at `path` from `store`.
+    Evict { path: PathBuf, store: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done { from: Option<String> },
+    Failed { msg: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub kind: JobKind,
+    /// Jobs with a higher priority run first.
+    pub priority: i32,
+    pub idempotency_key: Option<String>,
+    /// An 'http://' URL to which the job is POSTed when it finishes.
+    pub callback: Option<String>,
+    pub status: JobStatus,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        match self.status {
+            JobStatus::Done { .. } | JobStatus::Failed { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Jobs submitted by external orchestrators through the control
+/// API. They are executed one at a time by `control::run_jobs()`.
+/// The queue is stored in the superblock, so queued jobs and
+/// idempotency keys survive restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JobQueue {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+impl JobQueue {
+    /// Add a job to the queue. If a job with the same idempotency
+    /// key exists, return its ID instead. The second element of the
+    /// result is whether the job already existed.
+    pub fn enqueue(
+        &mut self,
+        kind: JobKind,
+        priority: i32,
+        idempotency_key: Option<String>,
+        callback: Option<String>,
+    ) -> Result<(u64, bool)> {
+        if let Some(key) = &idempotency_key {
+            if let Some(job) = self
+                .jobs
+                .iter()
+                .find(|job| job.idempotency_key.as_ref() == Some(key))
+            {
+                return Ok((job.id, true));
+            }
+        }
+
+        if let Some(url) = &callback {
+            parse_http_url(url)?;
+        }
+
+        self.next_id += 1;
+        self.jobs.push(Job {
+            id: self.next_id,
+            kind,
+            priority,
+            idempotency_key,
+            callback,
+            status: JobStatus::Queued,
+        });
+
+        Ok((self.next_id, false))
+    }
+
+    /// Mark the queued job with the highest priority as running and
+    /// return it. Jobs with equal priority run in submission order.
+    pub fn start_next(&mut self) -> Option<Job> {
+        let job = self
+            .jobs
+            .iter_mut()
+            .filter(|job| job.status == JobStatus::Queued)
+            .min_by_key(|job| (-(job.priority as i64), job.id))?;
+        job.status = JobStatus::Running;
+        Some(job.clone())
+    }
+
+    pub fn finish(&mut self, id: u64, status: JobStatus) {
+        if let Some(job) = self.jobs.iter_mut().find(|job| job.id == id) {
+            job.status = status;
+        }
+
+        let finished = self.jobs.iter().filter(|job| job.is_finished()).count();
+        if finished > MAX_FINISHED_JOBS {
+            let mut excess = finished - MAX_FINISHED_JOBS;
+            self.jobs.retain(|job| {
+                if excess > 0 && job.is_finished() {
+                    excess -= 1;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+
+    /// Return whether an erase of `hash` is queued or running.
+    pub fn is_erasing(&self, hash: &Hash) -> bool {
+        self.jobs.iter().any(|job| match &job.kind {
+            JobKind::Erase { hash: h } => h == hash && !job.is_finished(),
+            _ => false,
+        })
+    }
+
+    /// Queue the jobs that were running when the daemon stopped
+    /// again.
+    pub fn requeue_running(&mut self) {
+        for job in &mut self.jobs {
+            if job.status == JobStatus::Running {
+                job.status = JobStatus::Queued;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.jobs.clone()
+    }
+}
+
+/// Split an 'http://host[:port]/path' URL into a socket address and
+/// a path. HTTPS is not supported.
+fn parse_http_url(url: &str) -> Result<(String, String)> {
+    if !url.starts_with("http://") {
+        return Err(Error::BadArguments(format!(
+            "callback URL '{}' is not an http:// URL",
+            url
+        )));
+    }
+    let rest = &url[7..];
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(Error::BadArguments(format!(
+            "callback URL '{}' has no host",
+            url
+        )));
+    }
+    let addr = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((addr, path.to_string()))
+}
+
+/// How long to wait for a job's callback URL to respond.
+pub const CALLBACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// POST `job` as JSON to its callback URL.
+pub async fn notify(job: &Job) -> Result<()> {
+    let url = match &job.callback {
+        Some(url) => url,
+        None => return Ok(()),
+    };
+
+    let (addr, path) = parse_http_url(url)?;
+    let body = serde_json::to_vec(job).unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(addr.as_str()).await?;
+    let header = format!(
+        "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+        path,
+        addr,
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+    let status = String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("")
+        .to_string();
+    if !status.starts_with('2') {
+        return Err(Error::CallbackFailed(url.clone(), status));
+    }
+
+    Ok(())
+}
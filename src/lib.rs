@@ -0,0 +1,67 @@
+//! hugefs stores files in content-addressed backing stores and
+//! exposes them through FUSE. The items re-exported here form the
+//! stable API for programs that embed hugefs stores or talk to a
+//! mounted filesystem; the remaining public modules exist for the
+//! `hugefs` binary and may change at any time.
+
+#![feature(atomic_min_max)]
+
+#[cfg(feature = "admin")]
+#[doc(hidden)]
+pub mod admin;
+#[doc(hidden)]
+pub mod audit;
+#[doc(hidden)]
+pub mod bench;
+pub mod client;
+mod clock;
+pub mod control;
+pub mod encrypted_store;
+pub mod error;
+#[cfg(feature = "chaos")]
+mod flaky_store;
+#[doc(hidden)]
+pub mod fs;
+mod fuse_util;
+pub mod fusefs;
+pub mod hash;
+#[doc(hidden)]
+pub mod ingest;
+#[doc(hidden)]
+pub mod jobs;
+pub mod local_store;
+#[doc(hidden)]
+pub mod manifest;
+pub mod memory_store;
+#[doc(hidden)]
+pub mod ninep;
+#[doc(hidden)]
+pub mod power;
+mod presence_cache;
+mod reference_store;
+#[doc(hidden)]
+pub mod replication;
+#[cfg(feature = "s3-gateway")]
+#[doc(hidden)]
+pub mod s3_gateway;
+//mod s3_store;
+mod shamir;
+mod stats;
+pub mod store;
+#[doc(hidden)]
+pub mod store_view;
+pub mod tape_store;
+#[cfg(test)]
+mod tests;
+#[doc(hidden)]
+pub mod tiering;
+
+pub use crate::{
+    client::{execute_request, get_fs_root},
+    control::{Request, Response},
+    encrypted_store::{EncryptedStore, Key, Keys},
+    error::{Error, Result},
+    fusefs::{Filesystem, FilesystemState},
+    hash::Hash,
+    store::{open_store, register_scheme, MutableFile, Store, StoreConstructor},
+};
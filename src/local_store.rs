@@ -1,22 +1,73 @@
 use crate::error::Error;
 use crate::hash::Hash;
-use crate::store::{Config, Future, Result, Store};
-use log::debug;
+use crate::store::{Config, Future, ProgressFn, Result, Store};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::process;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::SystemTime;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, debug_span, info};
+use tracing_futures::Instrument;
+
+/// Size of the writes used to overwrite erased files.
+const ERASE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Number of files above which `has_many()` may read the store
+/// directory instead of checking each file.
+const HAS_MANY_LIST_THRESHOLD: usize = 64;
+
+/// `has_many()` only reads the store directory if it's asked about
+/// at least one in this many of the files in the store.
+const HAS_MANY_LIST_FRACTION: usize = 10;
+
+/// Size of the blocks that `write_sparse()` leaves as a hole if they
+/// are entirely zero.
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Directory that contains a subdirectory for each cluster (see
+/// `Store::cluster()`).
+const CLUSTERS_DIR: &str = "clusters";
+
+/// File in the ledger directory whose mtime is when the first ledger
+/// was written. Filesystems write their ledger when they mount the
+/// store, so a file added after that is in some ledger or newer
+/// than it.
+const LEDGERS_SINCE: &str = "since";
+
+/// Store format in which files are stored in 'ab/cd/<hash>' (the
+/// first four hex digits of the hash) rather than in the root of the
+/// store, so that directories don't get millions of entries.
+pub const SHARDED_FORMAT: u32 = 2;
+
+/// The newest store format, which `migrate()` upgrades to. Stores
+/// with a newer format are refused, since their files may be
+/// somewhere this version doesn't look; older formats can still be
+/// used.
+pub const CURRENT_FORMAT: u32 = SHARDED_FORMAT;
+
+type Clusters = Arc<RwLock<HashMap<Hash, String>>>;
 
 pub struct LocalStore {
     root: PathBuf,
     config: Config,
+    /// When files were last read through this store. Supplements the
+    /// atime, which is often not updated (e.g. 'noatime' mounts).
+    accessed: Mutex<HashMap<Hash, SystemTime>>,
+    /// The cluster of each file that is stored in 'clusters/<name>'
+    /// rather than in the root of the store.
+    clusters: Clusters,
+    /// The number of directory entries found the last time the
+    /// store was read, or 0 if it hasn't been read yet.
+    nr_entries: AtomicUsize,
 }
 
 impl LocalStore {
-    pub fn new(root: PathBuf) -> std::io::Result<Self> {
+    pub fn new(root: PathBuf) -> Result<Self> {
         let root = root.canonicalize()?;
 
         let mut config_file: PathBuf = root.clone();
@@ -25,31 +76,482 @@ impl LocalStore {
         let mut config_json = String::new();
         File::open(config_file)?.read_to_string(&mut config_json)?;
 
-        let config = serde_json::from_str(&config_json).unwrap(); // FIXME
+        let config: Config = serde_json::from_str(&config_json).unwrap(); // FIXME
+
+        if config.format_version > CURRENT_FORMAT {
+            return Err(Error::UnsupportedStoreFormat(
+                root.to_str().unwrap().into(),
+                config.format_version,
+            ));
+        }
+
+        let mut clusters = HashMap::new();
+        let clusters_dir = root.join(CLUSTERS_DIR);
+        if clusters_dir.exists() {
+            for cluster in std::fs::read_dir(clusters_dir)? {
+                let cluster = cluster?;
+                let name = match cluster.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                for entry in std::fs::read_dir(cluster.path())? {
+                    if let Some(hash) = entry?.file_name().to_str().and_then(Hash::from_hex) {
+                        clusters.insert(hash, name.clone());
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            root,
+            config,
+            accessed: Mutex::new(HashMap::new()),
+            clusters: Arc::new(RwLock::new(clusters)),
+            nr_entries: AtomicUsize::new(0),
+        })
+    }
 
-        Ok(Self { root, config })
+    fn is_sharded(&self) -> bool {
+        self.config.format_version >= SHARDED_FORMAT
     }
 
+    /// The path of the file with the given hash.
+    fn path_of(&self, file_hash: &Hash) -> PathBuf {
+        locate(&self.root, &self.clusters, file_hash, self.is_sharded())
+    }
+
+    /// Return the entries of the root of the store, of its shard
+    /// directories and of its cluster directories.
+    fn read_dirs(&self) -> std::io::Result<Vec<std::fs::DirEntry>> {
+        let entries = read_dirs(&self.root)?;
+        self.nr_entries.store(entries.len(), Ordering::Relaxed);
+        Ok(entries)
+    }
+
+    fn ledger_dir(&self) -> PathBuf {
+        self.root.join("ledgers")
+    }
+
+    /// Delete the files in this store that are not referenced by
+    /// any ledger. Files that were added after the oldest ledger are
+    /// kept, since they may have been added by a filesystem that
+    /// hasn't updated its ledger yet. So are files added before the
+    /// first ledger, since they may belong to a filesystem that has
+    /// never written one. Files are dated by their ctime, since
+    /// adopted files keep the mtime of the original. Returns the
+    /// deleted hashes.
+    pub fn gc(&self, dry_run: bool) -> Result<Vec<Hash>> {
+        let since = std::fs::metadata(self.ledger_dir().join(LEDGERS_SINCE))
+            .and_then(|st| st.modified())
+            .map_err(|_| Error::NoLedgers(self.get_url()))?;
+
+        let mut referenced = HashSet::new();
+        let mut oldest_ledger = None;
+
+        for entry in std::fs::read_dir(self.ledger_dir())? {
+            let entry = entry?;
+            if entry.path().extension() != Some(std::ffi::OsStr::new("json")) {
+                continue;
+            }
+            let mtime = entry.metadata()?.modified()?;
+            oldest_ledger = Some(oldest_ledger.map_or(mtime, |t: SystemTime| t.min(mtime)));
+            let hashes: Vec<String> = serde_json::from_reader(File::open(entry.path())?)
+                .map_err(|err| Error::StorageError(Box::new(err)))?;
+            for hash in hashes {
+                referenced.insert(hash);
+            }
+        }
+
+        let oldest_ledger = oldest_ledger.ok_or_else(|| Error::NoLedgers(self.get_url()))?;
+
+        let mut deleted = vec![];
+
+        for entry in self.read_dirs()? {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let hash = match Hash::from_hex(&name) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            let added = ctime(&entry.metadata()?);
+            if referenced.contains(&name) || added < since || added >= oldest_ledger {
+                continue;
+            }
+            debug!("Deleting unreferenced file {}.", entry.path().display());
+            if !dry_run {
+                std::fs::remove_file(entry.path())?;
+                self.clusters.write().unwrap().remove(&hash);
+            }
+            deleted.push(hash);
+        }
+
+        Ok(deleted)
+    }
+
+    /// Add the files in `dir` that are named by their content hash
+    /// (in hex) to this store without rehashing them. Files are hard
+    /// linked if `link` is set, and copied otherwise. Returns the
+    /// hashes of the newly added files.
+    pub fn import(&self, dir: &Path, link: bool) -> Result<Vec<Hash>> {
+        // The file names and contents would have to be encrypted.
+        if self.config.key_fingerprint.is_some() || self.config.require_encryption {
+            return Err(Error::BadArguments(format!(
+                "cannot import into encrypted store '{}'",
+                self.get_url()
+            )));
+        }
+
+        let mut imported = vec![];
+
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let hash = match entry.file_name().to_str().and_then(Hash::from_hex) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let path = self.path_of(&hash);
+            if path.exists() {
+                continue;
+            }
+            debug!("Importing {}.", entry.path().display());
+            create_parent(&path)?;
+            if link {
+                std::fs::hard_link(entry.path(), &path)?;
+            } else {
+                let temp_path = self.make_temp_path();
+                copy_sparse(&entry.path(), &temp_path)?;
+                std::fs::rename(&temp_path, &path)?;
+            }
+            imported.push(hash);
+        }
+
+        Ok(imported)
+    }
+
+    /// Upgrade the store to `CURRENT_FORMAT` by recording the new
+    /// version in 'store-config.json' and moving the files in its
+    /// root into shard directories. This is safe while the store is
+    /// in use, since files are found in either layout. Processes that
+    /// opened the store earlier keep adding files to the root, so
+    /// the migration should be repeated after they're restarted.
+    /// Returns the number of files moved.
+    pub fn migrate(&self) -> Result<usize> {
+        if self.config.format_version < CURRENT_FORMAT {
+            let config_file = self.root.join("store-config.json");
+            let mut config: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&config_file)?)
+                    .map_err(|err| Error::StorageError(Box::new(err)))?;
+            config["format_version"] = CURRENT_FORMAT.into();
+            let temp_path = self.make_temp_path();
+            std::fs::write(&temp_path, serde_json::to_vec_pretty(&config).unwrap())?;
+            std::fs::rename(&temp_path, &config_file)?;
+        }
+
+        let mut moved = 0;
+
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let hash = match entry.file_name().to_str().and_then(Hash::from_hex) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let dest = shard_path(&self.root, &hash);
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            if dest.exists() {
+                std::fs::remove_file(entry.path())?;
+            } else {
+                std::fs::rename(entry.path(), &dest)?;
+            }
+            moved += 1;
+        }
+
+        info!(
+            "Moved {} files of store '{}' to shard directories.",
+            moved,
+            self.get_url()
+        );
+
+        Ok(moved)
+    }
+
+    /// Return the temporary files in this store that belong to a
+    /// process on this host that no longer exists, deleting them if
+    /// `remove` is set. Files of other hosts are left alone, since we
+    /// can't tell whether their processes still exist.
+    pub fn orphans(&self, remove: bool) -> Result<Vec<PathBuf>> {
+        let mut orphans = vec![];
+        let host = host_id();
+
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            // Names written by older versions lack the host ID.
+            let pid = match name.split('.').collect::<Vec<_>>()[..] {
+                ["temp", h, pid, _] if h == host => pid,
+                ["temp", pid, _] => pid,
+                _ => continue,
+            };
+            let pid: u32 = match pid.parse() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            if pid == process::id() || Path::new("/proc").join(pid.to_string()).exists() {
+                continue;
+            }
+            if remove {
+                debug!("Deleting orphaned file {}.", entry.path().display());
+                std::fs::remove_file(entry.path())?;
+            }
+            orphans.push(entry.path());
+        }
+
+        Ok(orphans)
+    }
+
+    /// Return the files in this store that contain plaintext data,
+    /// i.e. mutable files and files whose name is the hash of their
+    /// contents. (Encrypted files are named by their encrypted
+    /// plaintext hash.) This reads every file in the store.
+    pub fn plaintext_files(&self) -> Result<Vec<PathBuf>> {
+        let mut found = vec![];
+
+        for entry in self.read_dirs()? {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name.starts_with("temp.") {
+                found.push(entry.path());
+            } else if let Some(hash) = Hash::from_hex(&name) {
+                if Hash::hash(File::open(entry.path())?)?.1 == hash {
+                    found.push(entry.path());
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Return a path for a new temporary file. The name contains
+    /// the host ID and pid (used by `orphans()`) and a UUID, since pids
+    /// and timestamps can collide between hosts sharing a store. Older
+    /// versions omitted the host ID and used a timestamp instead of
+    /// the UUID.
     fn make_temp_path(&self) -> PathBuf {
         let mut path = self.root.clone();
         path.push(format!(
-            "temp.{}.{}",
+            "temp.{}.{}.{}",
+            host_id(),
             process::id(),
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_nanos()
+            uuid_v7()
         ));
         path
     }
 }
 
+/// Return the hostname, with dots replaced since temporary file
+/// names are split on dots.
+pub(crate) fn host_id() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "unknown".into();
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).replace('.', "-")
+}
+
+/// Generate a version 7 (time-ordered) UUID.
+fn uuid_v7() -> String {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes[6..]))
+        .expect("cannot read /dev/urandom");
+
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    bytes[..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+    bytes[6] = 0x70 | (bytes[6] & 0x0f);
+    bytes[8] = 0x80 | (bytes[8] & 0x3f);
+
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
 fn path_for_hash(root: impl Into<PathBuf>, file_hash: &Hash) -> PathBuf {
     let mut path: PathBuf = root.into();
     path.push(file_hash.to_hex());
     path
 }
 
+/// The path of the file with the given hash in `SHARDED_FORMAT`.
+fn shard_path(root: &Path, file_hash: &Hash) -> PathBuf {
+    let hex = file_hash.to_hex();
+    root.join(&hex[0..2]).join(&hex[2..4]).join(hex)
+}
+
+/// The path of the file with the given hash in the root of a store,
+/// or in its shard directory if `sharded` is set. During a migration
+/// files may be in either place, so return the other one if only it
+/// exists.
+/// The path of the file with the given hash in the store at `root`.
+fn locate(root: &Path, clusters: &Clusters, file_hash: &Hash, sharded: bool) -> PathBuf {
+    match clusters.read().unwrap().get(file_hash) {
+        Some(cluster) => path_for_hash(root.join(CLUSTERS_DIR).join(cluster), file_hash),
+        None => find_path(root, file_hash, sharded),
+    }
+}
+
+/// Return the entries of the root of the store at `root`, of its
+/// shard directories and of its cluster directories.
+fn read_dirs(root: &Path) -> std::io::Result<Vec<std::fs::DirEntry>> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if is_shard_dir(&entry)? {
+            for subdir in std::fs::read_dir(entry.path())? {
+                let subdir = subdir?;
+                if is_shard_dir(&subdir)? {
+                    for entry in std::fs::read_dir(subdir.path())? {
+                        entries.push(entry?);
+                    }
+                }
+            }
+        }
+        entries.push(entry);
+    }
+    let clusters_dir = root.join(CLUSTERS_DIR);
+    if clusters_dir.exists() {
+        for cluster in std::fs::read_dir(clusters_dir)? {
+            for entry in std::fs::read_dir(cluster?.path())? {
+                entries.push(entry?);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn find_path(root: &Path, file_hash: &Hash, sharded: bool) -> PathBuf {
+    let (preferred, other) = if sharded {
+        (shard_path(root, file_hash), path_for_hash(root, file_hash))
+    } else {
+        (path_for_hash(root, file_hash), shard_path(root, file_hash))
+    };
+    if !preferred.exists() && other.exists() {
+        other
+    } else {
+        preferred
+    }
+}
+
+/// Whether `entry` is a shard directory, i.e. a directory named by
+/// two hex digits.
+fn is_shard_dir(entry: &std::fs::DirEntry) -> std::io::Result<bool> {
+    let name = entry.file_name();
+    let is_hex = name.to_str().map_or(false, |s| {
+        s.len() == 2 && s.bytes().all(|b| b.is_ascii_hexdigit())
+    });
+    Ok(is_hex && entry.file_type()?.is_dir())
+}
+
+/// Return the time of the last status change of a file, e.g. when it
+/// was created or linked into the store.
+fn ctime(metadata: &std::fs::Metadata) -> SystemTime {
+    use std::os::unix::fs::MetadataExt;
+    SystemTime::UNIX_EPOCH
+        + std::time::Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32)
+}
+
+/// Create the parent directory of `path` if it's a shard directory
+/// that doesn't exist yet.
+fn create_parent(path: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(path.parent().unwrap())
+}
+
+/// Return the offset of the first data byte (if `data` is set) or
+/// hole at or after `offset` in `fd`, or `None` if there is no data
+/// at or after `offset`. Filesystems that don't track holes report
+/// the whole file as data.
+fn seek_extent(fd: RawFd, offset: u64, data: bool) -> std::io::Result<Option<u64>> {
+    let whence = if data {
+        libc::SEEK_DATA
+    } else {
+        libc::SEEK_HOLE
+    };
+    let res = unsafe { libc::lseek(fd, offset as libc::off_t, whence) };
+    if res < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(res as u64))
+}
+
+/// Copy `from` to `to`, leaving holes in `to` where `from` has
+/// them rather than filling them with zeros.
+fn copy_sparse(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut src = File::open(from)?;
+    let len = src.metadata()?.len();
+    let mut dst = File::create(to)?;
+    let mut offset = 0;
+    while offset < len {
+        let start = match seek_extent(src.as_raw_fd(), offset, true)? {
+            Some(start) => start,
+            None => break,
+        };
+        let end = seek_extent(src.as_raw_fd(), start, false)?.unwrap_or(len);
+        src.seek(SeekFrom::Start(start))?;
+        dst.seek(SeekFrom::Start(start))?;
+        std::io::copy(&mut (&mut src).take(end - start), &mut dst)?;
+        offset = end;
+    }
+    dst.set_len(len)?;
+    Ok(())
+}
+
+async fn copy_sparse_async(from: &Path, to: &Path) -> std::io::Result<()> {
+    let (from, to) = (from.to_owned(), to.to_owned());
+    tokio::task::spawn_blocking(move || copy_sparse(&from, &to))
+        .await
+        .unwrap()
+}
+
+/// Write `data` to `file`, skipping blocks that are entirely zero
+/// so that they become holes.
+async fn write_sparse(file: &mut tokio::fs::File, data: &[u8]) -> std::io::Result<()> {
+    let mut offset = 0;
+    for block in data.chunks(SPARSE_BLOCK_SIZE) {
+        if block.iter().any(|b| *b != 0) {
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(block).await?;
+        }
+        offset += block.len() as u64;
+    }
+    file.set_len(offset).await
+}
+
 async fn read_n<R: tokio::io::AsyncReadExt + std::marker::Unpin>(
     from: &mut R,
     mut buf: &mut [u8],
@@ -75,28 +577,264 @@ impl Store for LocalStore {
         self.root.to_str().unwrap().into()
     }
 
-    fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()> {
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    fn write_ledger<'a>(&'a self, fs_id: &'a str, hashes: Vec<Hash>) -> Future<'a, ()> {
+        Box::pin(async move {
+            let ledger_dir = self.ledger_dir();
+            tokio::fs::create_dir_all(&ledger_dir).await?;
+            match tokio::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(ledger_dir.join(LEDGERS_SINCE))
+                .await
+            {
+                Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {
+                    return Err(err.into())
+                }
+                _ => {}
+            }
+            let hashes: Vec<String> = hashes.iter().map(|h| h.to_hex()).collect();
+            let temp_path = self.make_temp_path();
+            tokio::fs::write(&temp_path, serde_json::to_vec(&hashes).unwrap()).await?;
+            tokio::fs::rename(&temp_path, ledger_dir.join(format!("{}.json", fs_id))).await?;
+            Ok(())
+        })
+    }
+
+    fn in_other_ledgers<'a>(&'a self, fs_id: &'a str, file_hash: &Hash) -> Future<'a, bool> {
+        let hex = file_hash.to_hex();
+        Box::pin(async move {
+            let own_ledger = format!("{}.json", fs_id);
+            let mut entries = match tokio::fs::read_dir(self.ledger_dir()).await {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension() != Some(std::ffi::OsStr::new("json"))
+                    || entry.file_name() == own_ledger.as_str()
+                {
+                    continue;
+                }
+                let hashes: Vec<String> = serde_json::from_slice(&tokio::fs::read(&path).await?)
+                    .map_err(|err| Error::StorageError(Box::new(err)))?;
+                if hashes.contains(&hex) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    fn find_orphans<'a>(&'a self, remove: bool) -> Future<'a, Vec<String>> {
+        Box::pin(async move {
+            Ok(self
+                .orphans(remove)?
+                .into_iter()
+                .map(|p| p.to_str().unwrap().into())
+                .collect())
+        })
+    }
+
+    fn erase<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
         let file_hash = file_hash.clone();
-        let path = path_for_hash(&self.root, &file_hash);
+        let path = self.path_of(&file_hash);
         Box::pin(async move {
-            if !path.exists() {
-                // FIXME: make atomic
-                debug!("Writing {}.", path.display());
-                let mut file = tokio::fs::File::create(path).await?;
-                file.write_all(data).await?;
+            let mut file = match tokio::fs::OpenOptions::new().write(true).open(&path).await {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(err.into()),
+            };
+            // Other names of the file (e.g. the original of an adopted
+            // file) still need the contents, so only unlink it.
+            if std::os::unix::fs::MetadataExt::nlink(&file.metadata().await?) > 1 {
+                debug!(
+                    "Not overwriting {} since it has other links.",
+                    path.display()
+                );
+                tokio::fs::remove_file(&path).await?;
+                self.clusters.write().unwrap().remove(&file_hash);
+                return Ok(());
+            }
+            // Note: this doesn't help on copy-on-write filesystems or
+            // SSDs that remap blocks.
+            debug!("Overwriting {}.", path.display());
+            let zeroes = vec![0u8; ERASE_CHUNK_SIZE];
+            let mut left = file.metadata().await?.len();
+            while left > 0 {
+                let n = std::cmp::min(left, ERASE_CHUNK_SIZE as u64) as usize;
+                file.write_all(&zeroes[..n]).await?;
+                left -= n as u64;
+            }
+            file.sync_all().await?;
+            tokio::fs::remove_file(&path).await?;
+            self.clusters.write().unwrap().remove(&file_hash);
+            Ok(())
+        })
+    }
+
+    fn adopt<'a>(&'a self, file_hash: &Hash, path: &'a Path, link: bool) -> Future<'a, ()> {
+        let dest = self.path_of(file_hash);
+        Box::pin(async move {
+            self.config.check_plaintext_allowed(&self.get_url())?;
+            if dest.exists() {
+                return Ok(());
+            }
+            debug!("Adopting {}.", path.display());
+            create_parent(&dest)?;
+            if link {
+                match tokio::fs::hard_link(path, &dest).await {
+                    Ok(()) => return Ok(()),
+                    // Not on the same filesystem, so copy it instead.
+                    Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            let temp_path = self.make_temp_path();
+            copy_sparse_async(path, &temp_path).await?;
+            tokio::fs::rename(&temp_path, &dest).await?;
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        let path = self.path_of(&file_hash);
+        self.accessed.lock().unwrap().remove(&file_hash);
+        Box::pin(async move {
+            debug!("Removing {}.", path.display());
+            match tokio::fs::remove_file(&path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            self.clusters.write().unwrap().remove(&file_hash);
+            Ok(())
+        })
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        self.config.max_size
+    }
+
+    fn used_bytes<'a>(&'a self) -> Future<'a, u64> {
+        Box::pin(async move {
+            let mut total = 0;
+            for entry in self.read_dirs()? {
+                let name = entry.file_name();
+                let name = name.to_str().unwrap_or("");
+                if Hash::from_hex(name).is_some() || name.starts_with("temp.") {
+                    total += entry.metadata()?.len();
+                }
+            }
+            Ok(total)
+        })
+    }
+
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        Box::pin(async move {
+            let mut files = vec![];
+            for entry in self.read_dirs()? {
+                if let Some(hash) = entry.file_name().to_str().and_then(Hash::from_hex) {
+                    files.push((hash, entry.metadata()?.len()));
+                }
+            }
+            Ok(files)
+        })
+    }
+
+    fn last_access<'a>(&'a self, file_hash: &Hash) -> Future<'a, Option<SystemTime>> {
+        let path = self.path_of(file_hash);
+        let accessed = self.accessed.lock().unwrap().get(file_hash).cloned();
+        Box::pin(async move {
+            let atime = match tokio::fs::metadata(&path).await {
+                Ok(st) => st.accessed().ok(),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            Ok(atime.max(accessed))
+        })
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        Box::pin(async move {
+            let st = tokio::fs::metadata(&self.root).await?;
+            if !st.is_dir() {
+                return Err(Error::StoreUnavailable(self.get_url()));
             }
             Ok(())
         })
     }
 
+    fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        let path = self.path_of(&file_hash);
+        let span = debug_span!("store_add", hash = %file_hash.to_hex(), len = data.len());
+        Box::pin(
+            async move {
+                self.config.check_plaintext_allowed(&self.get_url())?;
+                if !path.exists() {
+                    // FIXME: make atomic
+                    debug!("Writing {}.", path.display());
+                    create_parent(&path)?;
+                    let mut file = tokio::fs::File::create(path).await?;
+                    write_sparse(&mut file, data).await?;
+                }
+                Ok(())
+            }
+            .instrument(span),
+        )
+    }
+
     fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool> {
         let file_hash = file_hash.clone();
         Box::pin(async move {
-            let path = path_for_hash(&self.root, &file_hash);
+            let path = self.path_of(&file_hash);
             Ok(path.exists())
         })
     }
 
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        Box::pin(async move {
+            let root = self.root.clone();
+
+            /* Reading the store directory costs about as much as
+             * checking each of its files, so it's only worth it if
+             * we're asked about a good part of them. */
+            let nr_entries = self.nr_entries.load(Ordering::Relaxed);
+            if file_hashes.len() >= HAS_MANY_LIST_THRESHOLD
+                && nr_entries > 0
+                && file_hashes.len() * HAS_MANY_LIST_FRACTION >= nr_entries
+            {
+                let entries = tokio::task::spawn_blocking(move || read_dirs(&root))
+                    .await
+                    .unwrap()?;
+                self.nr_entries.store(entries.len(), Ordering::Relaxed);
+                let wanted: HashSet<&Hash> = file_hashes.iter().collect();
+                return Ok(entries
+                    .iter()
+                    .filter_map(|entry| entry.file_name().to_str().and_then(Hash::from_hex))
+                    .filter(|file_hash| wanted.contains(file_hash))
+                    .collect());
+            }
+
+            let file_hashes = file_hashes.to_vec();
+            let clusters = self.clusters.clone();
+            let sharded = self.is_sharded();
+            Ok(tokio::task::spawn_blocking(move || {
+                file_hashes
+                    .into_iter()
+                    .filter(|file_hash| locate(&root, &clusters, file_hash, sharded).exists())
+                    .collect()
+            })
+            .await
+            .unwrap())
+        })
+    }
+
     fn get<'a>(
         &'a self,
         file_hash: &Hash,
@@ -104,25 +842,84 @@ impl Store for LocalStore {
         size: usize,
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<Vec<u8>>> + Send + 'a>> {
         let file_hash = file_hash.clone();
+        let span = debug_span!("store_get", hash = %file_hash.to_hex(), offset, size);
+        Box::pin(
+            async move {
+                let path = self.path_of(&file_hash);
+                let mut file = match tokio::fs::File::open(path).await {
+                    // The file may have been moved by a migration.
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        tokio::fs::File::open(self.path_of(&file_hash)).await
+                    }
+                    res => res,
+                }
+                .map_err(|err| {
+                    if err.kind() == std::io::ErrorKind::NotFound {
+                        Error::NoSuchHash(file_hash.clone())
+                    } else {
+                        Error::StorageError(Box::new(err))
+                    }
+                })?;
+                self.accessed
+                    .lock()
+                    .unwrap()
+                    .insert(file_hash.clone(), SystemTime::now());
+                file.seek(std::io::SeekFrom::Start(offset)).await?;
+                let mut buf = vec![0u8; size as usize];
+                let n = read_n(&mut file, &mut buf).await?;
+                assert!(n <= size as usize);
+                buf.resize(n, 0);
+                Ok(buf)
+            }
+            .instrument(span),
+        )
+    }
+
+    /// Copy the files into 'clusters/<name>', so that they're
+    /// written contiguously and end up in the same directory.
+    fn cluster<'a>(&'a self, name: &'a str, file_hashes: &'a [Hash]) -> Future<'a, usize> {
         Box::pin(async move {
-            let path = path_for_hash(&self.root, &file_hash);
-            let mut file = tokio::fs::File::open(path).await.map_err(|err| {
-                if err.kind() == std::io::ErrorKind::NotFound {
-                    Error::NoSuchHash(file_hash.clone())
-                } else {
-                    Error::StorageError(Box::new(err))
+            if !self.config.cluster_by_directory {
+                return Ok(0);
+            }
+            let cluster_dir = self.root.join(CLUSTERS_DIR).join(name);
+            let mut moved = 0;
+            for file_hash in file_hashes {
+                if self
+                    .clusters
+                    .read()
+                    .unwrap()
+                    .get(file_hash)
+                    .map(|s| s.as_str())
+                    == Some(name)
+                {
+                    continue;
+                }
+                let path = self.path_of(file_hash);
+                if !path.exists() {
+                    continue;
                 }
-            })?;
-            file.seek(std::io::SeekFrom::Start(offset)).await?;
-            let mut buf = vec![0u8; size as usize];
-            let n = read_n(&mut file, &mut buf).await?;
-            assert!(n <= size as usize);
-            buf.resize(n, 0);
-            Ok(buf)
+                tokio::fs::create_dir_all(&cluster_dir).await?;
+                debug!("Moving {} to cluster '{}'.", path.display(), name);
+                let temp_path = self.make_temp_path();
+                copy_sparse_async(&path, &temp_path).await?;
+                tokio::fs::rename(&temp_path, path_for_hash(&cluster_dir, file_hash)).await?;
+                self.clusters
+                    .write()
+                    .unwrap()
+                    .insert(file_hash.clone(), name.into());
+                tokio::fs::remove_file(&path).await?;
+                moved += 1;
+            }
+            Ok(moved)
         })
     }
 
     fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn crate::store::MutableFile>>> {
+        // Mutable files are never encrypted.
+        if self.config.require_encryption {
+            return None;
+        }
         Some(Box::pin(async move {
             let temp_path = self.make_temp_path();
             let file = tokio::fs::OpenOptions::new()
@@ -135,23 +932,58 @@ impl Store for LocalStore {
                 temp_path,
                 file: futures::lock::Mutex::new(Some(file)),
                 len: AtomicU64::new(0),
+                clusters: self.clusters.clone(),
+                sharded: self.is_sharded(),
+            });
+            Ok(handle)
+        }))
+    }
+
+    fn reopen_file<'a>(
+        &'a self,
+        path: &'a Path,
+    ) -> Option<Future<'a, Box<dyn crate::store::MutableFile>>> {
+        if self.config.require_encryption {
+            return None;
+        }
+        Some(Box::pin(async move {
+            // Only open our own temporary files.
+            let is_temp = path.parent() == Some(self.root.as_path())
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| name.starts_with("temp."));
+            if !is_temp {
+                return Err(Error::BadPath(path.into()));
+            }
+            let file = tokio::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .await?;
+            let len = file.metadata().await?.len();
+            let handle: Box<dyn crate::store::MutableFile> = Box::new(MutableFile {
+                temp_path: path.into(),
+                file: futures::lock::Mutex::new(Some(file)),
+                len: AtomicU64::new(len),
+                clusters: self.clusters.clone(),
+                sharded: self.is_sharded(),
             });
             Ok(handle)
         }))
     }
 }
 
+/// A file being written. Its temporary file is not deleted when it's
+/// dropped, since the state file may refer to it so that it can be
+/// reopened after a restart. `finish()` renames or removes it, and
+/// `orphans()` collects it if it's abandoned.
 struct MutableFile {
     temp_path: PathBuf,
     file: futures::lock::Mutex<Option<tokio::fs::File>>,
     len: AtomicU64,
-}
-
-impl Drop for MutableFile {
-    fn drop(&mut self) {
-        // FIXME: only do this when necessary
-        let _ = std::fs::remove_file(&self.temp_path);
-    }
+    clusters: Clusters,
+    sharded: bool,
 }
 
 impl crate::store::MutableFile for MutableFile {
@@ -166,7 +998,7 @@ impl crate::store::MutableFile for MutableFile {
                     .fetch_max(offset + data.len() as u64, Ordering::Relaxed);
                 Ok(())
             } else {
-                panic!("write handle invalidated by previous write error") // FIXME: return error
+                Err(Error::FileHandleInvalidated)
             }
         })
     }
@@ -183,29 +1015,27 @@ impl crate::store::MutableFile for MutableFile {
                 buf.resize(n, 0);
                 Ok(buf)
             } else {
-                panic!("write handle invalidated by previous write error") // FIXME: return error
+                Err(Error::FileHandleInvalidated)
             }
         })
     }
 
-    fn finish<'a>(&'a self) -> Future<'a, (u64, Hash)> {
+    fn finish<'a>(&'a self, progress: ProgressFn<'a>) -> Future<'a, (u64, Hash)> {
         Box::pin(async move {
             let mut file_lock = self.file.lock().await;
             if let Some(mut file) = file_lock.take() {
                 file.seek(std::io::SeekFrom::Start(0)).await?;
-                // FIXME: make this async and in bounded memory
-                let mut buf = vec![];
-                file.read_to_end(&mut buf).await?;
-                let (len, hash) = Hash::hash(&buf[..])?;
-                let final_path = path_for_hash(self.temp_path.clone().parent().unwrap(), &hash);
-                if final_path.exists() {
+                let (len, hash) = Hash::hash_async(&mut file, progress).await?;
+                let final_path = find_path(self.temp_path.parent().unwrap(), &hash, self.sharded);
+                if final_path.exists() || self.clusters.read().unwrap().contains_key(&hash) {
                     tokio::fs::remove_file(self.temp_path.clone()).await?;
                 } else {
+                    create_parent(&final_path)?;
                     tokio::fs::rename(self.temp_path.clone(), final_path).await?;
                 }
                 Ok((len, hash))
             } else {
-                panic!("write handle invalidated by previous write error") // FIXME: return error
+                Err(Error::FileHandleInvalidated)
             }
         })
     }
@@ -213,4 +1043,67 @@ impl crate::store::MutableFile for MutableFile {
     fn len(&self) -> u64 {
         self.len.load(Ordering::Relaxed)
     }
+
+    fn path(&self) -> Option<PathBuf> {
+        Some(self.temp_path.clone())
+    }
+
+    fn flush<'a>(&'a self) -> Future<'a, ()> {
+        Box::pin(async move {
+            let mut file_lock = self.file.lock().await;
+            if let Some(mut file) = file_lock.take() {
+                file.flush().await?;
+                *file_lock = Some(file);
+                Ok(())
+            } else {
+                Err(Error::FileHandleInvalidated)
+            }
+        })
+    }
+
+    fn sync<'a>(&'a self, datasync: bool) -> Future<'a, ()> {
+        Box::pin(async move {
+            let mut file_lock = self.file.lock().await;
+            if let Some(mut file) = file_lock.take() {
+                file.flush().await?;
+                if datasync {
+                    file.sync_data().await?;
+                } else {
+                    file.sync_all().await?;
+                }
+                *file_lock = Some(file);
+                Ok(())
+            } else {
+                Err(Error::FileHandleInvalidated)
+            }
+        })
+    }
+
+    fn set_len<'a>(&'a self, len: u64) -> Future<'a, ()> {
+        Box::pin(async move {
+            let mut file_lock = self.file.lock().await;
+            if let Some(mut file) = file_lock.take() {
+                file.set_len(len).await?;
+                *file_lock = Some(file);
+                self.len.store(len, Ordering::Relaxed);
+                Ok(())
+            } else {
+                Err(Error::FileHandleInvalidated)
+            }
+        })
+    }
+
+    fn seek_extent<'a>(&'a self, offset: u64, data: bool) -> Future<'a, Option<u64>> {
+        Box::pin(async move {
+            let mut file_lock = self.file.lock().await;
+            if let Some(mut file) = file_lock.take() {
+                file.flush().await?;
+                let res = seek_extent(file.as_raw_fd(), offset, data);
+                *file_lock = Some(file);
+                Ok(res?)
+            } else {
+                Err(Error::FileHandleInvalidated)
+            }
+        })
+    }
 }
@@ -1,30 +1,23 @@
-#![feature(atomic_min_max)]
-
-mod control;
-mod encrypted_store;
-mod error;
-mod fs;
-mod fuse_util;
-mod fusefs;
-mod hash;
-mod local_store;
-//mod s3_store;
-mod store;
-
-use crate::{
-    control::{FileType, Request, Response},
-    encrypted_store::{Key, KeyFingerprint},
+use fuser::MountOption;
+use hugefs::{
+    audit, bench,
+    control::{self, Change, FileType, FindFilter, Request, Response},
+    encrypted_store::{self, Key, KeyFingerprint, Keys},
     error::Error,
-    store::Store,
+    fs, fusefs,
+    hash::Hash,
+    ingest, jobs, local_store, manifest, ninep, power, replication,
+    store::{self, Store},
+    store_view, tiering,
 };
-use log::debug;
-use std::collections::HashMap;
-use std::ffi::OsString;
-use std::io::{BufReader, Seek, Write};
+use std::collections::BTreeMap;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::runtime::Runtime;
+use tracing::{debug, error, info, warn};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "hugefs", about = "Hugefs interface")]
@@ -39,14 +32,160 @@ enum CLI {
         mount_point: PathBuf,
 
         #[structopt(name = "store", short = "s", long = "store")]
-        /// Backing stores
+        /// Backing stores ('ref:<path>' attaches a store read-only)
         stores: Vec<String>,
 
         #[structopt(name = "key", short = "k", long = "key")]
         /// Key files
         key_files: Vec<PathBuf>,
+
+        #[structopt(long = "key-share")]
+        /// Key shares to be combined into a key
+        key_shares: Vec<PathBuf>,
+
+        #[structopt(long = "ttl", default_value = "60")]
+        /// Number of seconds the kernel may cache unmodified inodes
+        ttl: u64,
+
+        #[structopt(long = "immutable-ttl", default_value = "86400")]
+        /// Number of seconds the kernel may cache immutable files
+        immutable_ttl: u64,
+
+        #[structopt(long = "info-files")]
+        /// Show a .hugefsinfo file with replication status in every directory
+        info_files: bool,
+
+        #[structopt(long = "power-hook")]
+        /// Program that exits with a non-zero status if background I/O should pause
+        power_hook: Option<PathBuf>,
+
+        #[structopt(long = "verify-mirrors", default_value = "0")]
+        /// Percentage of mirrored files to read back from the destination store
+        verify_mirrors: u8,
+
+        #[structopt(long = "secure-erase")]
+        /// Overwrite and delete files from all stores once they're no longer referenced
+        secure_erase: bool,
+
+        #[structopt(long = "check-open")]
+        /// Fail opening files whose contents are missing from all stores
+        check_open: bool,
+
+        #[structopt(long = "audit-log")]
+        /// File to which secure erasures are recorded
+        audit_log: Option<PathBuf>,
+
+        #[structopt(long = "max-concurrent")]
+        /// Limit the number of concurrent FUSE requests of a type ('<op>=<n>', e.g. 'read=16')
+        max_concurrent: Vec<String>,
+
+        #[structopt(long = "op-timeout")]
+        /// Fail FUSE requests of a type with EIO after a number of seconds ('<op>=<secs>', e.g. 'read=30')
+        op_timeouts: Vec<String>,
+
+        #[structopt(long = "inbox")]
+        /// Directory whose files are automatically adopted into the filesystem
+        inbox: Option<PathBuf>,
+
+        #[structopt(long = "inbox-dest", default_value = "")]
+        /// Directory in the filesystem that receives the files from the inbox
+        inbox_dest: PathBuf,
+
+        #[structopt(long = "inbox-remove")]
+        /// Delete files from the inbox once they have been adopted
+        inbox_remove: bool,
+
+        #[structopt(long = "fallback-to-backup")]
+        /// Use the previous version of the state file if it's corrupt
+        fallback_to_backup: bool,
+
+        #[structopt(long = "replicate-to")]
+        /// Keep a copy of the state file at this path for a standby
+        replicate_to: Option<PathBuf>,
+
+        #[structopt(long = "standby")]
+        /// Mount read-only, reloading the state file whenever the primary replaces it
+        standby: bool,
+
+        #[structopt(long = "read-only")]
+        /// Reject all modifications and leave the state file and stores untouched
+        read_only: bool,
+
+        #[structopt(long = "autosave-interval", default_value = "30")]
+        /// Number of seconds between saves of the state file if it has changed (0 to disable)
+        autosave_interval: u64,
+
+        #[structopt(short = "o", long = "option")]
+        /// FUSE mount options (allow_other, allow_root, max_read=<n>, fsname=<name>, subtype=<name>)
+        options: Vec<String>,
+
+        #[structopt(long = "threads")]
+        /// Number of threads that process FUSE requests (defaults to the number of CPUs)
+        threads: Option<usize>,
+
+        #[structopt(long = "admin-listen")]
+        /// Address (e.g. 127.0.0.1:7070) on which to serve the HTTP admin API
+        admin_listen: Option<std::net::SocketAddr>,
+
+        #[structopt(long = "admin-token-file")]
+        /// File containing the bearer token that admin API requests must carry
+        admin_token_file: Option<PathBuf>,
+
+        #[structopt(long = "s3-listen")]
+        /// Address on which to serve the finalized files read-only over S3 (unauthenticated)
+        s3_listen: Option<std::net::SocketAddr>,
+
+        #[structopt(long = "s3-bucket", default_value = "hugefs")]
+        /// Name of the bucket served over S3
+        s3_bucket: String,
+
+        #[structopt(long = "9p-listen")]
+        /// Address on which to serve the filesystem read-only over 9P2000 (unauthenticated), for hosts without FUSE
+        ninep_listen: Option<std::net::SocketAddr>,
+
+        #[structopt(long = "upgrade-stores")]
+        /// Upgrade local stores with an older format (see 'hugefs migrate-store')
+        upgrade_stores: bool,
+    },
+
+    /// Mount a read-only view of the files in a store, named by their hash
+    #[structopt(name = "mount-store")]
+    MountStore {
+        /// Store URL
+        store: String,
+
+        /// Mount point
+        mount_point: PathBuf,
+    },
+
+    /// Show statistics of a mounted filesystem
+    #[structopt(name = "stats")]
+    Stats {
+        path: PathBuf,
+
+        #[structopt(long = "json")]
+        /// Print the statistics as JSON
+        json: bool,
     },
 
+    /// Show the result of the consistency check of a mounted filesystem
+    #[structopt(name = "health")]
+    Health {
+        path: PathBuf,
+
+        #[structopt(long = "json")]
+        /// Print the report as JSON
+        json: bool,
+    },
+
+    /// List the files that are waiting to be recalled from offline stores
+    #[structopt(name = "recalls")]
+    Recalls { path: PathBuf },
+
+    /// Store the files of each directory close together, in stores configured with 'cluster_by_directory'
+    #[structopt(name = "cluster")]
+    Cluster { path: PathBuf },
+
     /// Get the status of a file
     #[structopt(name = "status")]
     Status { path: PathBuf },
@@ -62,6 +201,549 @@ enum CLI {
     /// Copy a file to a backing store
     #[structopt(name = "mirror")]
     Mirror { path: PathBuf, store: String },
+
+    /// Add a file outside the filesystem without copying it through FUSE
+    #[structopt(name = "adopt")]
+    Adopt {
+        /// The file to add
+        src: PathBuf,
+
+        /// Path in the filesystem
+        dest: PathBuf,
+
+        #[structopt(short = "s", long = "store")]
+        /// The store to add the file to
+        store: Option<String>,
+
+        #[structopt(short = "l", long = "link")]
+        /// Hard link the file into the store instead of copying it
+        link: bool,
+
+        #[structopt(long = "move")]
+        /// Delete the source file afterwards
+        move_: bool,
+    },
+
+    /// Copy an immutable file without copying its contents
+    #[structopt(name = "clone")]
+    Clone { src: PathBuf, dst: PathBuf },
+
+    /// Show the differences between two directories or files, which may be in .snapshots
+    #[structopt(name = "diff")]
+    Diff { old: PathBuf, new: PathBuf },
+
+    /// Copy the files in a mounted filesystem or state file that are missing from another filesystem
+    #[structopt(name = "sync")]
+    Sync {
+        /// Directory in a mounted filesystem, or a state file
+        src: PathBuf,
+
+        /// Directory in a mounted filesystem
+        dst: PathBuf,
+    },
+
+    /// Measure the performance of a temporary filesystem with a standard workload
+    #[structopt(name = "bench")]
+    Bench {
+        #[structopt(name = "store", short = "s", long = "store")]
+        /// Backing stores (defaults to a temporary local store)
+        stores: Vec<String>,
+
+        #[structopt(long = "small-files", default_value = "1000")]
+        /// Number of small files to create
+        small_files: usize,
+
+        #[structopt(long = "small-file-size", default_value = "4096")]
+        /// Size of the small files in bytes
+        small_file_size: usize,
+
+        #[structopt(long = "large-file-size", default_value = "256")]
+        /// Size of the large file in MiB
+        large_file_size: u64,
+
+        #[structopt(long = "random-reads", default_value = "1000")]
+        /// Number of random reads from the large file
+        random_reads: usize,
+
+        #[structopt(long = "read-size", default_value = "65536")]
+        /// Size of the random reads in bytes
+        read_size: usize,
+
+        #[structopt(long = "stats", default_value = "10000")]
+        /// Number of stat calls on the small files
+        stats: usize,
+
+        #[structopt(long = "json")]
+        /// Print the report as JSON
+        json: bool,
+    },
+
+    /// Copy a directory in a mounted filesystem to an ordinary directory
+    #[structopt(name = "export")]
+    Export {
+        /// Directory in a mounted filesystem
+        src: PathBuf,
+
+        /// Destination directory
+        dest: PathBuf,
+
+        #[structopt(long = "write-checksum-cache")]
+        /// Record the hashes of the exported files in '.hugefs-hashes', so that the export can be checked with 'hugefs verify' and updated without rereading unchanged files
+        write_checksum_cache: bool,
+    },
+
+    /// Remove a file from a backing store if another store has it
+    #[structopt(name = "evict")]
+    Evict { path: PathBuf, store: String },
+
+    /// List the backing stores of a hugefs filesystem
+    #[structopt(name = "stores")]
+    Stores { path: PathBuf },
+
+    /// Convert mutable files into immutable files
+    #[structopt(name = "finalize")]
+    Finalize {
+        path: PathBuf,
+
+        #[structopt(short = "r", long = "recursive")]
+        /// Finalize all mutable files under a directory
+        recursive: bool,
+    },
+
+    /// Attach a backing store to a mounted filesystem
+    #[structopt(name = "add-store")]
+    AddStore {
+        path: PathBuf,
+
+        store: PathBuf,
+
+        #[structopt(name = "key", short = "k", long = "key")]
+        /// Key file
+        key_file: Option<PathBuf>,
+
+        #[structopt(long = "reference")]
+        /// Never write to or delete from this store
+        reference: bool,
+    },
+
+    /// Detach a backing store from a mounted filesystem
+    #[structopt(name = "remove-store")]
+    RemoveStore { path: PathBuf, store: String },
+
+    /// Show which key files can open which stores
+    #[structopt(name = "check-keys")]
+    CheckKeys {
+        #[structopt(name = "store", short = "s", long = "store")]
+        /// Backing stores
+        stores: Vec<String>,
+
+        #[structopt(name = "key", short = "k", long = "key")]
+        /// Key files
+        key_files: Vec<PathBuf>,
+    },
+
+    /// Manage key files
+    #[structopt(name = "key")]
+    Key(KeyCLI),
+
+    /// Prevent files from being stored on a store
+    #[structopt(name = "exclude")]
+    Exclude {
+        path: PathBuf,
+
+        store: String,
+
+        #[structopt(long = "remove")]
+        /// Allow the files to be stored on the store again
+        remove: bool,
+    },
+
+    /// Require files to always be present on a store
+    #[structopt(name = "pin")]
+    Pin {
+        path: PathBuf,
+
+        #[structopt(long = "store")]
+        store: String,
+
+        #[structopt(long = "remove")]
+        /// Allow the files to be evicted from the store again
+        remove: bool,
+    },
+
+    /// Set the store on which new files in a directory are created
+    #[structopt(name = "staging-store")]
+    StagingStore {
+        path: PathBuf,
+
+        /// The store, or none to use the first suitable store
+        store: Option<String>,
+    },
+
+    /// Manage stores on removable media
+    #[structopt(name = "media")]
+    Media(MediaCLI),
+
+    /// Submit and list background jobs
+    #[structopt(name = "jobs")]
+    Jobs(JobsCLI),
+
+    /// Limit the size of directory trees
+    #[structopt(name = "quota")]
+    Quota(QuotaCLI),
+
+    /// Force reads of a file to use a particular store
+    #[structopt(name = "read-from")]
+    ReadFrom {
+        path: PathBuf,
+
+        /// The store to read from (omit to read from any store again)
+        store: Option<String>,
+
+        #[structopt(long = "pid")]
+        /// Only apply to this process
+        pid: Option<u32>,
+    },
+
+    /// Move files that aren't read to an archive store
+    #[structopt(name = "tier")]
+    Tier(TierCLI),
+
+    /// Record read-only copies of the filesystem under .snapshots
+    #[structopt(name = "snapshot")]
+    Snapshot(SnapshotCLI),
+
+    /// Back up, restore and repair the filesystem metadata
+    #[structopt(name = "meta")]
+    Meta(MetaCLI),
+
+    /// Show the disk usage of a directory tree
+    #[structopt(name = "du")]
+    DiskUsage { path: PathBuf },
+
+    /// Delete files from a store that no filesystem references
+    #[structopt(name = "gc")]
+    GC {
+        store: PathBuf,
+
+        #[structopt(short = "n", long = "dry-run")]
+        /// Only show what would be deleted
+        dry_run: bool,
+    },
+
+    /// Add a directory of files named by their hash to a store
+    #[structopt(name = "import")]
+    Import {
+        store: PathBuf,
+
+        dir: PathBuf,
+
+        #[structopt(short = "l", long = "link")]
+        /// Hard link files instead of copying them
+        link: bool,
+    },
+
+    /// Move the files of a store into shard directories
+    #[structopt(name = "migrate-store")]
+    MigrateStore { store: PathBuf },
+
+    /// Find mutable files left behind by a crashed daemon
+    #[structopt(name = "fsck")]
+    Fsck {
+        #[structopt(name = "store", short = "s", long = "store")]
+        /// Backing stores
+        stores: Vec<PathBuf>,
+
+        #[structopt(long = "repair")]
+        /// Delete the orphaned files
+        repair: bool,
+    },
+
+    /// Check that stores requiring encryption contain no plaintext data
+    #[structopt(name = "doctor")]
+    Doctor {
+        #[structopt(name = "store", short = "s", long = "store")]
+        /// Backing stores
+        stores: Vec<PathBuf>,
+    },
+
+    /// Finalize all files and make the filesystem read-only
+    #[structopt(name = "freeze")]
+    Freeze {
+        path: PathBuf,
+
+        #[structopt(short = "m", long = "manifest")]
+        /// Write the manifest to this file instead of stdout
+        manifest: Option<PathBuf>,
+
+        #[structopt(long = "sign")]
+        /// Sign the manifest with this signing key
+        signing_key: Option<PathBuf>,
+    },
+
+    /// Check a directory tree against a manifest
+    #[structopt(name = "verify")]
+    Verify {
+        path: PathBuf,
+
+        #[structopt(short = "m", long = "manifest")]
+        /// Manifest produced by 'hugefs freeze'
+        manifest: PathBuf,
+
+        #[structopt(long = "public-key")]
+        /// Require the manifest to be signed by this (hex-encoded) key
+        public_key: Option<String>,
+
+        #[structopt(long = "unsigned")]
+        /// Accept manifests without a signature
+        unsigned: bool,
+    },
+
+    /// Make a frozen filesystem writable again
+    #[structopt(name = "thaw")]
+    Thaw { path: PathBuf },
+
+    /// List the files that have the given content hash
+    #[structopt(name = "which-path")]
+    WhichPath { path: PathBuf, hash: String },
+
+    /// List the files under a directory that match the given criteria
+    #[structopt(name = "find")]
+    Find {
+        path: PathBuf,
+
+        #[structopt(long = "min-size")]
+        /// Only files of at least this size (e.g. '1G')
+        min_size: Option<String>,
+
+        #[structopt(long = "max-size")]
+        /// Only files of at most this size
+        max_size: Option<String>,
+
+        #[structopt(long = "type")]
+        /// Only 'mutable' or 'immutable' files
+        file_type: Option<String>,
+
+        #[structopt(long = "newer")]
+        /// Only files modified less than this many days ago
+        newer_than_days: Option<u64>,
+
+        #[structopt(long = "older")]
+        /// Only files modified at least this many days ago
+        older_than_days: Option<u64>,
+
+        #[structopt(long = "present-in")]
+        /// Only files that this store has
+        present_in: Option<String>,
+
+        #[structopt(long = "missing-from")]
+        /// Only files that this store lacks
+        missing_from: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum MediaCLI {
+    /// Make a store known to the filesystem under a name
+    #[structopt(name = "register")]
+    Register {
+        path: PathBuf,
+        name: String,
+        store: PathBuf,
+    },
+
+    /// Attach a registered store and copy the files it's missing
+    #[structopt(name = "attach")]
+    Attach { path: PathBuf, name: String },
+
+    /// Record the contents of a registered store and detach it
+    #[structopt(name = "detach")]
+    Detach { path: PathBuf, name: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum JobsCLI {
+    /// Queue copying a file to a store
+    #[structopt(name = "mirror")]
+    Mirror {
+        path: PathBuf,
+
+        store: String,
+
+        #[structopt(short = "p", long = "priority", default_value = "0")]
+        /// Jobs with a higher priority run first
+        priority: i32,
+
+        #[structopt(long = "key")]
+        /// Don't queue the job if a job with this key exists
+        idempotency_key: Option<String>,
+
+        #[structopt(long = "callback")]
+        /// URL to POST the job to when it finishes
+        callback: Option<String>,
+    },
+
+    /// Queue removing the copy of a file from a store
+    #[structopt(name = "evict")]
+    Evict {
+        path: PathBuf,
+
+        store: String,
+
+        #[structopt(short = "p", long = "priority", default_value = "0")]
+        /// Jobs with a higher priority run first
+        priority: i32,
+
+        #[structopt(long = "key")]
+        /// Don't queue the job if a job with this key exists
+        idempotency_key: Option<String>,
+
+        #[structopt(long = "callback")]
+        /// URL to POST the job to when it finishes
+        callback: Option<String>,
+    },
+
+    /// Show queued, running and finished jobs
+    #[structopt(name = "list")]
+    List { path: PathBuf },
+}
+
+#[derive(Debug, StructOpt)]
+enum QuotaCLI {
+    /// Set or remove the quota of a directory
+    #[structopt(name = "set")]
+    Set {
+        path: PathBuf,
+
+        #[structopt(long = "bytes")]
+        /// Maximum total size of the files in the directory tree
+        max_bytes: Option<u64>,
+
+        #[structopt(long = "inodes")]
+        /// Maximum number of files and directories in the directory tree
+        max_inodes: Option<u64>,
+    },
+
+    /// Show the quotas and usage of all directories that have one
+    #[structopt(name = "list")]
+    List { path: PathBuf },
+}
+
+#[derive(Debug, StructOpt)]
+enum TierCLI {
+    /// Set the tiering policy of a directory
+    #[structopt(name = "set")]
+    Set {
+        path: PathBuf,
+
+        #[structopt(long = "fast")]
+        /// Store that holds files that are read regularly
+        fast: String,
+
+        #[structopt(long = "archive")]
+        /// Store to which files are moved when they become cold
+        archive: String,
+
+        #[structopt(long = "days", default_value = "30")]
+        /// Number of days after which unread files are cold
+        days: u64,
+    },
+
+    /// Remove the tiering policy of a directory
+    #[structopt(name = "clear")]
+    Clear { path: PathBuf },
+
+    /// Show the tiering policies of all directories that have one
+    #[structopt(name = "list")]
+    List { path: PathBuf },
+}
+
+#[derive(Debug, StructOpt)]
+enum SnapshotCLI {
+    /// Record the current contents of the filesystem
+    #[structopt(name = "create")]
+    Create { path: PathBuf, name: String },
+
+    /// Show the snapshots of the filesystem
+    #[structopt(name = "list")]
+    List { path: PathBuf },
+
+    /// Delete a snapshot
+    #[structopt(name = "delete")]
+    Delete { path: PathBuf, name: String },
+}
+
+#[derive(Debug, StructOpt)]
+enum MetaCLI {
+    /// Repair a corrupt state file from its backup or, failing that,
+    /// from the contents of the stores
+    #[structopt(name = "recover")]
+    Recover {
+        /// Filesystem state file
+        state_file: PathBuf,
+
+        #[structopt(name = "store", short = "s", long = "store")]
+        /// Stores whose files are recovered into 'lost+found'
+        stores: Vec<String>,
+
+        #[structopt(name = "key", short = "k", long = "key")]
+        /// Key files of encrypted stores
+        key_files: Vec<PathBuf>,
+
+        #[structopt(long = "key-share")]
+        /// Key shares to be combined into a key
+        key_shares: Vec<PathBuf>,
+    },
+
+    /// Write a copy of the metadata in a state file
+    #[structopt(name = "dump")]
+    Dump {
+        /// Filesystem state file
+        state_file: PathBuf,
+
+        #[structopt(short = "o", long = "out")]
+        /// Write the metadata to this file instead of stdout
+        out: Option<PathBuf>,
+
+        #[structopt(long = "sync")]
+        /// Make the daemon of the filesystem mounted here write its state file first
+        sync: Option<PathBuf>,
+    },
+
+    /// Replace a state file with a metadata dump (the filesystem must not be mounted)
+    #[structopt(name = "restore")]
+    Restore {
+        /// Metadata dump ('-' for stdin)
+        dump: PathBuf,
+
+        /// Filesystem state file
+        state_file: PathBuf,
+
+        #[structopt(long = "force")]
+        /// Overwrite an existing state file
+        force: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum KeyCLI {
+    /// Split a key into shares, a subset of which can reconstruct it
+    #[structopt(name = "split")]
+    Split {
+        key_file: PathBuf,
+
+        #[structopt(short = "n", default_value = "5")]
+        /// Number of shares
+        shares: u8,
+
+        #[structopt(short = "t", default_value = "3")]
+        /// Number of shares needed to reconstruct the key
+        threshold: u8,
+    },
+
+    /// Create a signing key for manifests
+    #[structopt(name = "new-signing-key")]
+    NewSigningKey { key_file: PathBuf },
 }
 
 fn read_key_file(key_file: &Path) -> Result<(KeyFingerprint, Key), std::io::Error> {
@@ -69,129 +751,1836 @@ fn read_key_file(key_file: &Path) -> Result<(KeyFingerprint, Key), std::io::Erro
     Ok((key.fingerprint(), key))
 }
 
-type Keys = HashMap<KeyFingerprint, Key>;
+/// Read the keys given by '--key' and the key given by '--key-share'.
+fn read_keys(key_files: &[PathBuf], key_shares: &[PathBuf]) -> Result<Keys, Error> {
+    let keys: Result<Keys, _> = key_files.iter().map(|k| read_key_file(k)).collect();
+    let mut keys = keys?;
+
+    if !key_shares.is_empty() {
+        let key = Key::from_share_files(key_shares)?;
+        keys.insert(key.fingerprint(), key);
+    }
+
+    Ok(keys)
+}
+
+/// Maximum number of times an interrupted FUSE session is restarted.
+const MAX_SESSION_RESTARTS: usize = 3;
+
+/// Parse a '<op>=<n>' concurrency limit.
+fn parse_op_limit(s: &str) -> Result<(String, usize), Error> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next().map(|n| n.parse::<usize>())) {
+        (Some(op), Some(Ok(n))) if !op.is_empty() && n > 0 => Ok((op.into(), n)),
+        _ => Err(Error::BadArguments(format!(
+            "invalid concurrency limit '{}'",
+            s
+        ))),
+    }
+}
 
-fn open_store(store_loc: &str, keys: &Keys) -> Result<Arc<dyn Store>, Error> {
-    let mut store: Arc<dyn Store> = Arc::new(local_store::LocalStore::new(store_loc.into())?);
+/// Parse a size in bytes with an optional 'K', 'M', 'G' or 'T'
+/// suffix (powers of 1024).
+fn parse_size(s: &str) -> Result<u64, Error> {
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| Error::BadArguments(format!("invalid size '{}'", s)))
+}
 
-    let config = store.get_config()?;
+/// Parse a '<op>=<secs>' request deadline.
+fn parse_op_timeout(s: &str) -> Result<(String, Duration), Error> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next().map(|n| n.parse::<u64>())) {
+        (Some(op), Some(Ok(n))) if !op.is_empty() && n > 0 => {
+            Ok((op.into(), Duration::from_secs(n)))
+        }
+        _ => Err(Error::BadArguments(format!("invalid timeout '{}'", s))),
+    }
+}
 
-    if let Some(key_fingerprint) = config.key_fingerprint {
-        debug!(
-            "Opening store '{}' using key with fingerprint {}.",
-            store_loc,
-            key_fingerprint.0.to_hex()
-        );
-        let key = keys
-            .get(&key_fingerprint)
-            .ok_or_else(|| Error::NoSuchKey(key_fingerprint))?;
-        store = Arc::new(encrypted_store::EncryptedStore::new(store, key.clone()));
+fn mount(
+    state_file: PathBuf,
+    mount_point: PathBuf,
+    stores: Vec<String>,
+    key_files: Vec<PathBuf>,
+    key_shares: Vec<PathBuf>,
+    ttl: fusefs::CacheTtls,
+    info_files: bool,
+    power_hook: Option<PathBuf>,
+    verify_mirrors: u8,
+    secure_erase: bool,
+    check_open: bool,
+    audit_log: Option<PathBuf>,
+    max_concurrent: Vec<String>,
+    op_timeouts: Vec<String>,
+    inbox: Option<ingest::Inbox>,
+    fallback_to_backup: bool,
+    replicate_to: Option<PathBuf>,
+    standby: bool,
+    read_only: bool,
+    mount_options: Vec<String>,
+    autosave_interval: Duration,
+    threads: Option<usize>,
+    admin_listen: Option<std::net::SocketAddr>,
+    admin_token_file: Option<PathBuf>,
+    s3_gateway: Option<(std::net::SocketAddr, String)>,
+    ninep_listen: Option<std::net::SocketAddr>,
+    upgrade_stores: bool,
+) -> Result<(), Error> {
+    if s3_gateway.is_some() && !cfg!(feature = "s3-gateway") {
+        return Err(Error::BadArguments(
+            "hugefs was built without the 's3-gateway' feature".into(),
+        ));
+    }
+    let mount_options = fusefs::parse_mount_options(&mount_options)?;
+    let admin_token = match (&admin_listen, admin_token_file) {
+        (None, _) => None,
+        (Some(_), None) => {
+            return Err(Error::BadArguments(
+                "--admin-listen requires --admin-token-file".into(),
+            ))
+        }
+        (Some(_), Some(path)) => {
+            if !cfg!(feature = "admin") {
+                return Err(Error::BadArguments(
+                    "hugefs was built without the 'admin' feature".into(),
+                ));
+            }
+            let token = std::fs::read_to_string(&path)?.trim().to_string();
+            if token.is_empty() {
+                return Err(Error::BadArguments(format!(
+                    "admin token file '{}' is empty",
+                    path.display()
+                )));
+            }
+            Some(token)
+        }
+    };
+    let read_only = read_only || standby;
+    if read_only && (replicate_to.is_some() || inbox.is_some()) {
+        return Err(Error::BadArguments(
+            "a read-only filesystem cannot replicate its state or have an inbox".into(),
+        ));
+    }
+
+    let op_limits = max_concurrent
+        .iter()
+        .map(|s| parse_op_limit(s))
+        .collect::<Result<Vec<_>, _>>()?;
+    let op_timeouts = op_timeouts
+        .iter()
+        .map(|s| parse_op_timeout(s))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut builder = tokio::runtime::Builder::new();
+    builder.threaded_scheduler().enable_all();
+    match threads {
+        Some(0) => {
+            return Err(Error::BadArguments(
+                "the number of threads must be positive".into(),
+            ))
+        }
+        Some(threads) => {
+            builder.core_threads(threads);
+        }
+        None => {}
+    }
+    let mut rt = builder.build().unwrap();
+
+    let keys = read_keys(&key_files, &key_shares)?;
+
+    if upgrade_stores {
+        for url in stores.iter().filter(|url| Path::new(url).is_dir()) {
+            local_store::LocalStore::new(url.into())?.migrate()?;
+        }
+    }
+
+    let stores: Result<Vec<_>, _> = stores.iter().map(|s| store::open_store(s, &keys)).collect();
+    let stores = stores?;
+
+    let mut superblock = if state_file.exists() {
+        match fs::Superblock::open(&state_file) {
+            Err(err @ Error::CorruptState(..)) if fallback_to_backup => {
+                error!("{}", err);
+                let backup_path = fs::Superblock::backup_path(&state_file);
+                warn!("Falling back to '{}'.", backup_path.display());
+                fs::Superblock::open(&backup_path)?
+            }
+            res => res?,
+        }
+    } else {
+        fs::Superblock::new()
+    };
+    superblock.read_only = read_only;
+
+    let fs_state = Arc::new(RwLock::new(fusefs::FilesystemState::new(
+        superblock, stores, keys,
+    )));
+
+    // A read-only mount must never write the state file, which may
+    // belong to another host.
+    if !read_only {
+        fs_state.write().unwrap().state_file = Some(state_file.clone());
+    }
+    fs_state.write().unwrap().mirror_stats.verify_percent = verify_mirrors;
+    fs_state.write().unwrap().secure_erase = secure_erase;
+    fs_state.write().unwrap().check_open = check_open;
+    fs_state.write().unwrap().audit_log = audit_log.map(audit::AuditLog::new);
+
+    let stores = fs_state.read().unwrap().stores.clone();
+    for store in &stores {
+        rt.block_on(fusefs::probe_store(&fs_state, store));
+    }
+
+    // Files that were being written when the daemon stopped are
+    // recovered before their temporary files could look orphaned.
+    if !read_only {
+        let recovered = rt.block_on(fusefs::reattach_mutable_files(&fs_state));
+        if recovered > 0 {
+            info!("Recovered {} files that were being written.", recovered);
+        }
+
+        // Register with the stores before adding anything to them,
+        // so that gc knows this filesystem uses them.
+        if let Err(err) = rt.block_on(fusefs::write_ledgers(Arc::clone(&fs_state))) {
+            error!("Cannot write reference ledgers: {}", err);
+        }
+    }
+
+    let mut orphaned_files = BTreeMap::new();
+    for store in &stores {
+        match rt.block_on(store.find_orphans(false)) {
+            Ok(orphans) if !orphans.is_empty() => {
+                orphaned_files.insert(store.get_url(), orphans.len());
+            }
+            Ok(_) => {}
+            Err(err) => error!("Cannot check store '{}': {}", store.get_url(), err),
+        }
+    }
+    fs_state.write().unwrap().orphaned_files = orphaned_files;
+    print_health(&control::HealthReport::new(&fs_state.read().unwrap()));
+    fs_state.write().unwrap().superblock.upgrade();
+
+    let power = Arc::new(power::PowerMonitor::new(power_hook));
+    fs_state.write().unwrap().power = Arc::clone(&power);
+    rt.spawn(power.run());
+
+    rt.spawn(fusefs::check_stores(Arc::clone(&fs_state)));
+    rt.spawn(fusefs::run_recalls(Arc::clone(&fs_state)));
+
+    if standby {
+        rt.spawn(replication::follow_state(
+            Arc::clone(&fs_state),
+            state_file.clone(),
+        ));
+    }
+
+    if !read_only {
+        rt.spawn(fusefs::enforce_capacity(Arc::clone(&fs_state)));
+
+        rt.spawn(tiering::run_tiering(Arc::clone(&fs_state)));
+
+        rt.spawn(fusefs::check_pins(Arc::clone(&fs_state)));
+
+        rt.spawn(control::run_jobs(Arc::clone(&fs_state)));
+
+        rt.spawn(control::retry_erases(Arc::clone(&fs_state)));
+    }
+
+    if let Some(inbox) = inbox {
+        rt.spawn(ingest::run_inbox(Arc::clone(&fs_state), inbox));
+    }
+
+    if !read_only && autosave_interval > Duration::from_secs(0) {
+        rt.spawn(fusefs::autosave(Arc::clone(&fs_state), autosave_interval));
+    }
+
+    if let Some(dest) = replicate_to {
+        rt.spawn(replication::replicate_state(Arc::clone(&fs_state), dest));
+    }
+
+    #[cfg(feature = "admin")]
+    {
+        if let (Some(addr), Some(token)) = (admin_listen, admin_token) {
+            rt.spawn(hugefs::admin::serve(addr, token, Arc::clone(&fs_state)));
+        }
+    }
+    #[cfg(not(feature = "admin"))]
+    let _ = admin_token;
+
+    #[cfg(feature = "s3-gateway")]
+    {
+        if let Some((addr, bucket)) = s3_gateway {
+            rt.spawn(hugefs::s3_gateway::serve(
+                addr,
+                bucket,
+                Arc::clone(&fs_state),
+            ));
+        }
+    }
+
+    if let Some(addr) = ninep_listen {
+        rt.spawn(ninep::serve(addr, Arc::clone(&fs_state)));
+    }
+
+    let mut options = vec![MountOption::DefaultPermissions];
+    if read_only {
+        options.push(MountOption::RO);
+    }
+    for opt in mount_options {
+        if !options.contains(&opt) {
+            options.push(opt);
+        }
+    }
+
+    // From here on, the state is written out however `mount` exits.
+    let guard = if read_only {
+        None
+    } else {
+        Some(StateGuard::new(Arc::clone(&fs_state), state_file.clone()))
+    };
+
+    rt.spawn(shutdown_on_signal(
+        Arc::clone(&fs_state),
+        mount_point.clone(),
+        if read_only {
+            None
+        } else {
+            Some(state_file.clone())
+        },
+    ));
+
+    // Run the FUSE session, restarting it if it was interrupted.
+    let mut restarts = 0;
+    let res = loop {
+        let fs = fusefs::Filesystem::new(
+            Arc::clone(&fs_state),
+            rt.handle().clone(),
+            ttl,
+            info_files,
+            &op_limits,
+            &op_timeouts,
+        );
+
+        match fuser::mount2(fs, &mount_point, &options) {
+            Err(err)
+                if err.kind() == std::io::ErrorKind::Interrupted
+                    && restarts < MAX_SESSION_RESTARTS =>
+            {
+                restarts += 1;
+                warn!("FUSE session was interrupted ({}); restarting it.", err);
+            }
+            Err(err) if err.raw_os_error() == Some(libc::ENODEV) => {
+                warn!("FUSE device went away; assuming the filesystem was unmounted.");
+                break Ok(());
+            }
+            Err(err) => {
+                error!("FUSE session failed: {}", err);
+                break Err(err);
+            }
+            Ok(()) => break Ok(()),
+        }
+    };
+
+    if let Some(guard) = guard {
+        if let Err(err) = rt.block_on(fusefs::write_ledgers(Arc::clone(&fs_state))) {
+            error!("Cannot write reference ledgers: {}", err);
+        }
+
+        drop(rt);
+
+        guard.sync()?;
+    }
+
+    res.map_err(Error::from)
+}
+
+/// Writes the superblock to the state file when dropped, so that
+/// metadata is saved even if `mount` exits through a panic or an
+/// error.
+struct StateGuard {
+    state: Option<Arc<RwLock<fusefs::FilesystemState>>>,
+    state_file: PathBuf,
+}
+
+impl StateGuard {
+    fn new(state: Arc<RwLock<fusefs::FilesystemState>>, state_file: PathBuf) -> Self {
+        Self {
+            state: Some(state),
+            state_file,
+        }
+    }
+
+    /// Write the state now, returning any error.
+    fn sync(mut self) -> std::io::Result<()> {
+        self.save()
+    }
+
+    /// Write the state if it hasn't been written yet, logging any
+    /// error. This must not panic, since it runs in `drop`.
+    fn save(&mut self) -> std::io::Result<()> {
+        let state = match self.state.take() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        // A panic may have poisoned the lock, but the superblock is
+        // still worth saving.
+        let res = state
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .sync(&self.state_file);
+        if let Err(err) = &res {
+            error!("Cannot write '{}': {}", self.state_file.display(), err);
+        }
+        res
+    }
+}
+
+impl Drop for StateGuard {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+/// Run `fusermount -u` with extra arguments `args`, returning whether
+/// it succeeded.
+async fn fusermount(mount_point: &Path, args: &[&str]) -> bool {
+    match tokio::process::Command::new("fusermount")
+        .arg("-u")
+        .args(args)
+        .arg(mount_point)
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            error!("fusermount failed with {}.", status);
+            false
+        }
+        Err(err) => {
+            error!("Cannot run fusermount: {}", err);
+            false
+        }
+    }
+}
+
+/// On SIGINT or SIGTERM, flush all mutable files and unmount
+/// `mount_point`, so that the FUSE session ends normally and the
+/// state is written out. If the filesystem is busy, it's unmounted
+/// lazily. A second signal writes the state to `state_file` and
+/// exits immediately.
+async fn shutdown_on_signal(
+    state: Arc<RwLock<fusefs::FilesystemState>>,
+    mount_point: PathBuf,
+    state_file: Option<PathBuf>,
+) {
+    use futures::future::FutureExt;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (mut int, mut term) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(int), Ok(term)) => (int, term),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("Cannot install signal handlers: {}", err);
+            return;
+        }
+    };
+
+    futures::future::select(int.recv().boxed(), term.recv().boxed()).await;
+
+    warn!("Received signal; unmounting '{}'.", mount_point.display());
+    let failed = fusefs::flush_mutable_files(&state).await;
+    if failed > 0 {
+        error!("{} mutable files could not be flushed.", failed);
+    }
+    if !fusermount(&mount_point, &[]).await {
+        warn!("Unmounting lazily since the filesystem is busy.");
+        fusermount(&mount_point, &["-z"]).await;
+    }
+
+    futures::future::select(int.recv().boxed(), term.recv().boxed()).await;
+
+    error!("Received second signal; exiting without unmounting.");
+    if let Some(state_file) = state_file {
+        if let Err(err) = state.read().unwrap().sync(&state_file) {
+            error!("Cannot write '{}': {}", state_file.display(), err);
+        }
+    }
+    std::process::exit(1);
+}
+
+fn meta_recover(state_file: &Path, stores: &[String], keys: &Keys) -> Result<(), Error> {
+    match fs::Superblock::open(state_file) {
+        Ok(_) => {
+            println!("State file '{}' is intact.", state_file.display());
+            return Ok(());
+        }
+        Err(Error::CorruptState(..)) => {}
+        Err(err) => return Err(err),
+    }
+
+    let backup_path = fs::Superblock::backup_path(state_file);
+    let superblock = match fs::Superblock::open(&backup_path) {
+        Ok(superblock) => {
+            println!("Restored '{}'.", backup_path.display());
+            superblock
+        }
+        Err(err) => {
+            warn!("Cannot use backup '{}': {}", backup_path.display(), err);
+            if stores.is_empty() {
+                return Err(Error::BadArguments(
+                    "no usable backup; specify the stores to recover files from".into(),
+                ));
+            }
+            let mut rt = Runtime::new().unwrap();
+            let mut files = vec![];
+            for url in stores {
+                let store = store::open_store(url, keys)?;
+                files.extend(rt.block_on(store.list())?);
+            }
+            files.sort_by(|a, b| a.0.to_hex().cmp(&b.0.to_hex()));
+            files.dedup_by(|a, b| a.0 == b.0);
+            println!("Recovered {} files into 'lost+found'.", files.len());
+            fs::Superblock::from_files(&files)
+        }
+    };
+
+    // Keep the corrupt file for inspection.
+    let mut corrupt_path = state_file.to_path_buf();
+    corrupt_path.set_extension("json.corrupt");
+    std::fs::rename(state_file, &corrupt_path)?;
+
+    superblock
+        .write_json(&mut std::fs::File::create(state_file)?)
+        .unwrap();
+
+    Ok(())
+}
+
+/// Mount a new filesystem on a temporary directory and run `workload`
+/// against it. Attribute caching is disabled so that every stat
+/// reaches the daemon.
+fn run_bench(stores: &[String], workload: &bench::Workload, json: bool) -> Result<(), Error> {
+    if workload.read_size == 0 {
+        return Err(Error::BadArguments("the read size must be positive".into()));
+    }
+
+    let tmp_dir = std::env::temp_dir().join(format!("hugefs-bench-{}", std::process::id()));
+    let mount_point = tmp_dir.join("mnt");
+    std::fs::create_dir_all(&mount_point)?;
+
+    let mut urls = stores.to_vec();
+    if urls.is_empty() {
+        let store_dir = tmp_dir.join("store");
+        std::fs::create_dir_all(&store_dir)?;
+        std::fs::write(
+            store_dir.join("store-config.json"),
+            format!(r#"{{"format_version":{}}}"#, local_store::CURRENT_FORMAT),
+        )?;
+        urls.push(store_dir.to_str().unwrap().into());
+    }
+
+    let mut rt = Runtime::new().unwrap();
+
+    let stores: Result<Vec<_>, _> = urls
+        .iter()
+        .map(|s| store::open_store(s, &Keys::new()))
+        .collect();
+    let fs_state = Arc::new(RwLock::new(fusefs::FilesystemState::new(
+        fs::Superblock::new(),
+        stores?,
+        Keys::new(),
+    )));
+
+    let fs = fusefs::Filesystem::new(
+        Arc::clone(&fs_state),
+        rt.handle().clone(),
+        fusefs::CacheTtls {
+            default: Duration::from_secs(0),
+            immutable: Duration::from_secs(0),
+        },
+        false,
+        &[],
+        &[],
+    );
+
+    let session = fuser::spawn_mount2(fs, &mount_point, &[MountOption::DefaultPermissions])?;
+    let res = bench::run(workload, &mount_point, &fs_state, &mut rt);
+    drop(session);
+    drop(rt);
+    let _ = std::fs::remove_dir_all(&tmp_dir);
+    let phases = res?;
+
+    if json {
+        let report = serde_json::json!({
+            "stores": urls,
+            "workload": workload,
+            "phases": phases,
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return Ok(());
+    }
+
+    println!("Stores: {}", urls.join(", "));
+    for phase in &phases {
+        println!(
+            "{:<24} {:>10} ops {:>10.3}s {:>12.1} ops/s {:>10.1} MiB/s",
+            phase.name,
+            phase.ops,
+            phase.seconds,
+            phase.ops_per_sec(),
+            phase.mib_per_sec()
+        );
+    }
+
+    Ok(())
+}
+
+fn mount_store(url: &str, mount_point: &Path) -> Result<(), Error> {
+    let mut rt = Runtime::new().unwrap();
+
+    let store = store::open_store(url, &Keys::new())?;
+    let handle = rt.handle().clone();
+    let view = rt.block_on(store_view::StoreView::new(store, handle))?;
+
+    let options = [MountOption::RO, MountOption::DefaultPermissions];
+
+    fuser::mount2(view, &mount_point, &options).unwrap();
+
+    Ok(())
+}
+
+fn get_fs_root(path: &Path) -> Result<(PathBuf, PathBuf), Error> {
+    hugefs::get_fs_root(path)
+}
+
+/// Execute a control request, showing a progress bar while it runs.
+fn execute_request(root: &Path, req: Request) -> Result<Response, Error> {
+    let mut showed_progress = false;
+    let res = hugefs::execute_request(root, req, |done, total| {
+        show_progress(done, total);
+        showed_progress = true;
+    });
+    if showed_progress {
+        eprintln!();
+    }
+    res
+}
+
+fn show_progress(done: u64, total: u64) {
+    if unsafe { libc::isatty(libc::STDERR_FILENO) } == 0 {
+        return;
+    }
+
+    const WIDTH: u64 = 40;
+    let filled = if total == 0 {
+        WIDTH
+    } else {
+        std::cmp::min(done, total) * WIDTH / total
+    };
+
+    eprint!(
+        "\r[{}{}] {}/{} bytes",
+        "=".repeat(filled as usize),
+        " ".repeat((WIDTH - filled) as usize),
+        done,
+        total
+    );
+}
+
+fn status(path: &Path) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    let req = Request::Status { path };
+
+    match execute_request(&root, req)? {
+        Response::Status(status) => {
+            println!(" Type: {}", status.info.get_type());
+            for store in &status.excluded_stores {
+                println!(" Excluded: {}", store);
+            }
+            for store in &status.pinned_stores {
+                println!(" Pinned: {}", store);
+            }
+            if let Some(store) = &status.staging_store {
+                println!(" Staging store: {}", store);
+            }
+            match status.info {
+                FileType::ImmutableFile {
+                    size,
+                    hash,
+                    stores,
+                    offline_media,
+                } => {
+                    println!(" Size: {}", size);
+                    println!(" Hash: {}", hash.to_hex());
+                    for store in stores {
+                        println!("Store: {}", store);
+                    }
+                    for media in offline_media {
+                        println!("Media: {} (offline)", media);
+                    }
+                }
+                FileType::Directory {
+                    tree_hash: Some(hash),
+                } => {
+                    println!(" Tree hash: {}", hash.to_hex());
+                }
+                _ => {}
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+enum Mode {
+    Unmirrored,
+    Mirrored,
+}
+
+fn find_files(path: &Path, mode: Mode) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    let req = Request::StatusTree { path };
+
+    match execute_request(&root, req)? {
+        Response::StatusTree(res) => {
+            for file in res.files {
+                let store_count = match file.info {
+                    FileType::ImmutableFile {
+                        stores,
+                        offline_media,
+                        ..
+                    } => stores.len() + offline_media.len(),
+                    FileType::MutableFile { .. } => 0,
+                    _ => continue,
+                };
+                if match &mode {
+                    Mode::Unmirrored => store_count < 2,
+                    Mode::Mirrored => store_count >= 2,
+                } {
+                    println!("{}", root.join(file.path).display());
+                }
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn mirror(path: &Path, store: &str) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    let req = Request::Mirror {
+        path: path.into(),
+        store: store.into(),
+    };
+
+    match execute_request(&root, req)? {
+        Response::Mirror(_) => {}
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn adopt(
+    src: &Path,
+    dest: &Path,
+    store: Option<String>,
+    link: bool,
+    move_: bool,
+) -> Result<(), Error> {
+    let src = src.canonicalize()?;
+    let (root, dest) = get_fs_root(dest)?;
+
+    let req = Request::Adopt {
+        src: src.clone(),
+        dest,
+        store,
+        link,
+    };
+
+    match execute_request(&root, req)? {
+        Response::Adopt { hash, size, store } => {
+            debug!("Added {} bytes to store '{}'.", size, store);
+            println!("{}", hash.to_hex());
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    if move_ {
+        std::fs::remove_file(&src)?;
+    }
+
+    Ok(())
+}
+
+fn clone(src: &Path, dst: &Path) -> Result<(), Error> {
+    let (root, src) = get_fs_root(src)?;
+    let (dst_root, dst) = get_fs_root(dst)?;
+    if root.join(fusefs::CONTROL_NAME).canonicalize()?
+        != dst_root.join(fusefs::CONTROL_NAME).canonicalize()?
+    {
+        return Err(Error::BadArguments(
+            "the source and destination must be in the same filesystem".into(),
+        ));
+    }
+
+    match execute_request(&root, Request::Clone { src, dst })? {
+        Response::Clone { .. } => Ok(()),
+        Response::Error { msg } => Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+}
+
+fn diff(old: &Path, new: &Path) -> Result<(), Error> {
+    let (root, old) = get_fs_root(old)?;
+    let (new_root, new) = get_fs_root(new)?;
+    if root.join(fusefs::CONTROL_NAME).canonicalize()?
+        != new_root.join(fusefs::CONTROL_NAME).canonicalize()?
+    {
+        return Err(Error::BadArguments(
+            "both paths must be in the same filesystem".into(),
+        ));
+    }
+
+    match execute_request(&root, Request::Diff { old, new })? {
+        Response::Diff { changes } => {
+            for entry in changes {
+                let c = match entry.change {
+                    Change::Added => '+',
+                    Change::Removed => '-',
+                    Change::Modified => 'M',
+                };
+                println!("{} {}", c, entry.path.display());
+            }
+            Ok(())
+        }
+        Response::Error { msg } => Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+}
+
+/// A file to be copied by `hugefs sync`.
+enum SyncEntry {
+    Directory,
+    ImmutableFile { hash: Hash, size: u64 },
+    MutableFile,
+    Symlink(PathBuf),
+}
+
+/// Return the files below `path` in a mounted filesystem.
+fn mount_entries(path: &Path) -> Result<Vec<(PathBuf, SyncEntry)>, Error> {
+    let (root, sub) = get_fs_root(path)?;
+
+    let files = match execute_request(&root, Request::StatusTree { path: sub.clone() })? {
+        Response::StatusTree(res) => res.files,
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    };
+
+    let mut entries = vec![];
+    for file in files {
+        let rel = file.path.strip_prefix(&sub).unwrap().to_path_buf();
+        let entry = match file.info {
+            FileType::Directory { .. } => SyncEntry::Directory,
+            FileType::ImmutableFile { hash, size, .. } => SyncEntry::ImmutableFile { hash, size },
+            FileType::MutableFile {} => SyncEntry::MutableFile,
+            FileType::Symlink {} => SyncEntry::Symlink(std::fs::read_link(path.join(&rel))?),
+        };
+        entries.push((rel, entry));
+    }
+
+    Ok(entries)
+}
+
+/// Return the files in the state file of an unmounted filesystem.
+fn state_entries(state_file: &Path) -> Result<Vec<(PathBuf, SyncEntry)>, Error> {
+    fn walk(
+        superblock: &fs::Superblock,
+        ino: fs::Ino,
+        path: PathBuf,
+        entries: &mut Vec<(PathBuf, SyncEntry)>,
+    ) -> Result<(), Error> {
+        let children = match &superblock.get_inode(ino)?.read().unwrap().contents {
+            fs::Contents::Directory(dir) => {
+                entries.push((path.clone(), SyncEntry::Directory));
+                dir.entries.clone()
+            }
+            fs::Contents::RegularFile(file) => {
+                entries.push((
+                    path,
+                    SyncEntry::ImmutableFile {
+                        hash: file.hash.clone(),
+                        size: file.length,
+                    },
+                ));
+                return Ok(());
+            }
+            fs::Contents::MutableFile(_) => {
+                entries.push((path, SyncEntry::MutableFile));
+                return Ok(());
+            }
+            fs::Contents::Symlink(link) => {
+                use std::os::unix::ffi::OsStrExt;
+                let target = std::ffi::OsStr::from_bytes(&link.target).into();
+                entries.push((path, SyncEntry::Symlink(target)));
+                return Ok(());
+            }
+        };
+        for (name, child) in children {
+            walk(superblock, child, path.join(name), entries)?;
+        }
+        Ok(())
+    }
+
+    let superblock = fs::Superblock::open(state_file)?;
+    let mut entries = vec![];
+    walk(
+        &superblock,
+        superblock.get_root_ino(),
+        PathBuf::new(),
+        &mut entries,
+    )?;
+    Ok(entries)
+}
+
+fn sync_fs(src: &Path, dst: &Path) -> Result<(), Error> {
+    let (dst_root, dst_sub) = get_fs_root(dst)?;
+
+    // Files can only be read from a mounted source.
+    let src_mount = !src.is_file();
+
+    let entries = if src_mount {
+        let (src_root, _) = get_fs_root(src)?;
+        if src_root.join(fusefs::CONTROL_NAME).canonicalize()?
+            == dst_root.join(fusefs::CONTROL_NAME).canonicalize()?
+        {
+            return Err(Error::BadArguments(
+                "the source and destination are in the same filesystem; use 'hugefs clone'".into(),
+            ));
+        }
+        mount_entries(src)?
+    } else {
+        state_entries(src)?
+    };
+
+    let (mut linked, mut copied, mut failed) = (0, 0, 0);
+
+    for (rel, entry) in entries {
+        let dst_file = dst.join(&rel);
+        let exists = dst_file.symlink_metadata().is_ok();
+
+        match entry {
+            SyncEntry::Directory => {
+                if !exists {
+                    std::fs::create_dir(&dst_file)?;
+                }
+            }
+            SyncEntry::Symlink(target) => {
+                if !exists {
+                    std::os::unix::fs::symlink(target, &dst_file)?;
+                }
+            }
+            SyncEntry::MutableFile if exists => {}
+            SyncEntry::MutableFile if src_mount => {
+                std::fs::copy(src.join(&rel), &dst_file)?;
+                copied += 1;
+            }
+            SyncEntry::MutableFile => {
+                warn!("Cannot copy mutable file '{}'.", rel.display());
+                failed += 1;
+            }
+            SyncEntry::ImmutableFile { hash, size } => {
+                if exists {
+                    match execute_request(
+                        &dst_root,
+                        Request::Status {
+                            path: dst_sub.join(&rel),
+                        },
+                    )? {
+                        Response::Status(status) => match status.info {
+                            FileType::ImmutableFile { hash: dst_hash, .. } if dst_hash == hash => {}
+                            _ => {
+                                warn!("'{}' differs in the destination.", dst_file.display());
+                                failed += 1;
+                            }
+                        },
+                        Response::Error { msg } => return Err(Error::ControlError(msg)),
+                        _ => panic!("Unexpected daemon response."),
+                    }
+                    continue;
+                }
+
+                // Avoid copying data that a destination store already has.
+                let req = Request::Link {
+                    path: dst_sub.join(&rel),
+                    hash: hash.clone(),
+                    size,
+                };
+                match execute_request(&dst_root, req)? {
+                    Response::Link { store } => {
+                        debug!("Linked '{}' from store '{}'.", rel.display(), store);
+                        linked += 1;
+                        continue;
+                    }
+                    Response::Error { msg } => debug!("Cannot link '{}': {}", rel.display(), msg),
+                    _ => panic!("Unexpected daemon response."),
+                }
+
+                if !src_mount {
+                    warn!(
+                        "No destination store has the contents of '{}'.",
+                        rel.display()
+                    );
+                    failed += 1;
+                    continue;
+                }
+
+                let req = Request::Adopt {
+                    src: src.canonicalize()?.join(&rel),
+                    dest: dst_sub.join(&rel),
+                    store: None,
+                    link: false,
+                };
+                match execute_request(&dst_root, req)? {
+                    Response::Adopt { .. } => copied += 1,
+                    Response::Error { msg } => {
+                        warn!("Cannot copy '{}': {}", rel.display(), msg);
+                        failed += 1;
+                    }
+                    _ => panic!("Unexpected daemon response."),
+                }
+            }
+        }
+    }
+
+    println!(
+        "Linked {} files, copied {} files, {} failed.",
+        linked, copied, failed
+    );
+
+    if failed > 0 {
+        return Err(Error::SyncFailed(failed));
+    }
+
+    Ok(())
+}
+
+fn export(src: &Path, dest: &Path, write_checksum_cache: bool) -> Result<(), Error> {
+    let entries = mount_entries(src)?;
+
+    // Hashes recorded by a previous export, which let us skip
+    // unchanged files.
+    let cache_file = dest.join(manifest::CHECKSUM_CACHE_NAME);
+    let old_hashes: BTreeMap<PathBuf, Hash> = std::fs::File::open(&cache_file)
+        .ok()
+        .and_then(|f| serde_json::from_reader::<_, manifest::Manifest>(f).ok())
+        .map(|m| m.files.into_iter().map(|f| (f.path, f.hash)).collect())
+        .unwrap_or_default();
+
+    std::fs::create_dir_all(dest)?;
+
+    let (mut copied, mut unchanged) = (0, 0);
+    let mut files = vec![];
+
+    for (rel, entry) in entries {
+        let src_file = src.join(&rel);
+        let dst_file = dest.join(&rel);
+
+        match entry {
+            SyncEntry::Directory => std::fs::create_dir_all(&dst_file)?,
+            SyncEntry::Symlink(target) => {
+                if dst_file.symlink_metadata().is_err() {
+                    std::os::unix::fs::symlink(target, &dst_file)?;
+                }
+            }
+            SyncEntry::MutableFile => {
+                warn!("'{}' is not finalized; it has no hash.", rel.display());
+                std::fs::copy(&src_file, &dst_file)?;
+                copied += 1;
+            }
+            SyncEntry::ImmutableFile { hash, size } => {
+                // Give the copy the original's mtime, so that rsync's
+                // quick check considers it unchanged.
+                let mtime = src_file.metadata()?.modified()?;
+                let up_to_date = old_hashes.get(&rel) == Some(&hash)
+                    && dst_file.metadata().ok().map_or(false, |st| {
+                        st.len() == size && st.modified().ok() == Some(mtime)
+                    });
+                if up_to_date {
+                    unchanged += 1;
+                } else {
+                    std::fs::copy(&src_file, &dst_file)?;
+                    std::fs::OpenOptions::new()
+                        .write(true)
+                        .open(&dst_file)?
+                        .set_modified(mtime)?;
+                    copied += 1;
+                }
+                files.push(manifest::ManifestFile {
+                    path: rel,
+                    size,
+                    hash,
+                });
+            }
+        }
+    }
+
+    if write_checksum_cache {
+        let tmp = cache_file.with_extension("tmp");
+        let mut out = std::fs::File::create(&tmp)?;
+        serde_json::to_writer_pretty(&mut out, &manifest::Manifest::new(files)).unwrap();
+        writeln!(out)?;
+        std::fs::rename(&tmp, &cache_file)?;
+    } else if cache_file.exists() {
+        // Don't leave behind hashes that may no longer be correct.
+        std::fs::remove_file(&cache_file)?;
+    }
+
+    println!("Copied {} files, {} unchanged.", copied, unchanged);
+
+    Ok(())
+}
+
+fn evict(path: &Path, store: &str) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    let req = Request::Evict {
+        path,
+        store: store.into(),
+    };
+
+    match execute_request(&root, req)? {
+        Response::Evict { removed: true } => {}
+        Response::Evict { removed: false } => {
+            warn!("Store '{}' does not have this file.", store);
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn finalize(path: &Path, recursive: bool) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    let req = Request::Finalize { path, recursive };
+
+    match execute_request(&root, req)? {
+        Response::Finalize(res) => {
+            for file in res.files {
+                println!("{} {}", file.hash.to_hex(), root.join(file.path).display());
+            }
+            for path in res.busy {
+                eprintln!(
+                    "Skipped '{}' since it's open for writing.",
+                    root.join(path).display()
+                );
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+/// Whether `url` starts with a URL scheme like 'tape:' rather than
+/// being a local path.
+fn has_scheme(url: &str) -> bool {
+    match url.find(':') {
+        Some(pos) => {
+            let scheme = &url[..pos];
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.')
+        }
+        None => false,
+    }
+}
+
+fn add_store(
+    path: &Path,
+    store: &Path,
+    key_file: Option<&Path>,
+    reference: bool,
+) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    // The daemon may have a different working directory. URLs with a
+    // scheme (e.g. 'tape:' or one registered by another crate) aren't
+    // local paths, so they're passed as is.
+    let url = match store.to_str() {
+        Some(url) if has_scheme(url) => url.to_string(),
+        _ => store.canonicalize()?.to_str().unwrap().to_string(),
+    };
+
+    let req = Request::AddStore {
+        url: if reference {
+            format!("ref:{}", url)
+        } else {
+            url
+        },
+        key_file: key_file.map(|k| k.canonicalize()).transpose()?,
+    };
+
+    match execute_request(&root, req)? {
+        Response::AddStore {} => {}
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn remove_store(path: &Path, store: &str) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    let req = Request::RemoveStore { url: store.into() };
+
+    match execute_request(&root, req)? {
+        Response::RemoveStore {} => {}
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn check_keys(stores: &[String], key_files: &[PathBuf]) -> Result<(), Error> {
+    let keys: Result<Vec<_>, _> = key_files.iter().map(|k| read_key_file(k)).collect();
+    let keys = keys?;
+
+    let mut rt = Runtime::new().unwrap();
+
+    let mut missing = None;
+
+    for store_loc in stores {
+        let store = local_store::LocalStore::new(store_loc.into())?;
+        match store.get_config()?.key_fingerprint {
+            None => println!("{}: not encrypted", store_loc),
+            Some(fp) => {
+                let store: Arc<dyn Store> = Arc::new(store);
+                let mut found = false;
+                for (key_file, (_, key)) in key_files.iter().zip(&keys) {
+                    let encrypted =
+                        encrypted_store::EncryptedStore::new(Arc::clone(&store), key.clone());
+                    if rt.block_on(encrypted.check_key())? {
+                        println!("{}: opened by {}", store_loc, key_file.display());
+                        found = true;
+                    }
+                }
+                // Stores that haven't been mounted read-write since
+                // key check objects were introduced can only be
+                // checked by fingerprint.
+                if !found {
+                    for (key_file, (key_fp, _)) in key_files.iter().zip(&keys) {
+                        if *key_fp == fp {
+                            println!(
+                                "{}: matches the fingerprint of {}, but has no key check object",
+                                store_loc,
+                                key_file.display()
+                            );
+                            found = true;
+                        }
+                    }
+                }
+                if !found {
+                    println!("{}: no key with fingerprint {}", store_loc, fp.0.to_hex());
+                    missing.get_or_insert(fp);
+                }
+            }
+        }
+    }
+
+    match missing {
+        Some(fp) => Err(Error::NoSuchKey(fp)),
+        None => Ok(()),
+    }
+}
+
+fn split_key(key_file: &Path, shares: u8, threshold: u8) -> Result<(), Error> {
+    if threshold == 0 || threshold > shares {
+        return Err(Error::BadArguments(
+            "the threshold must be between 1 and the number of shares".into(),
+        ));
+    }
+
+    let key = Key::from_file(key_file)?;
+
+    for share_file in key.split_to_files(key_file, shares, threshold)? {
+        println!("{}", share_file.display());
+    }
+
+    Ok(())
+}
+
+fn disk_usage(path: &Path) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::DiskUsage { path })? {
+        Response::DiskUsage(res) => {
+            println!("  Logical: {}", res.logical_size);
+            println!("   Unique: {}", res.unique_size);
+            println!("  Mutable: {}", res.mutable_size);
+            for store in res.stores {
+                println!("    Store: {} {}", store.size, store.url);
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn find(path: &Path, filter: FindFilter) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::Find { path, filter })? {
+        Response::Find { paths } => {
+            for path in paths {
+                println!("{}", root.join(path).display());
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn which_path(path: &Path, hash: &str) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    let hash = Hash::from_hex(hash).ok_or_else(|| Error::BadHash(hash.into()))?;
+
+    match execute_request(&root, Request::WhichPath { hash })? {
+        Response::WhichPath { paths } => {
+            for path in paths {
+                println!("{}", root.join(path).display());
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn gc(store: &Path, dry_run: bool) -> Result<(), Error> {
+    let store = local_store::LocalStore::new(store.into())?;
+
+    for hash in store.gc(dry_run)? {
+        println!("{}", hash.to_hex());
+    }
+
+    Ok(())
+}
+
+fn fsck(stores: &[PathBuf], repair: bool) -> Result<(), Error> {
+    for store in stores {
+        let store = local_store::LocalStore::new(store.into())?;
+        for path in store.orphans(repair)? {
+            println!("{}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn doctor(stores: &[PathBuf]) -> Result<(), Error> {
+    let mut violations = 0;
+
+    for store_loc in stores {
+        let store = local_store::LocalStore::new(store_loc.into())?;
+        let config = store.get_config()?;
+        if !config.require_encryption {
+            println!("{}: encryption not required", store_loc.display());
+            continue;
+        }
+        if config.key_fingerprint.is_none() {
+            println!(
+                "{}: encryption required but no key configured",
+                store_loc.display()
+            );
+            violations += 1;
+        }
+        for path in store.plaintext_files()? {
+            println!("{}: plaintext file {}", store_loc.display(), path.display());
+            violations += 1;
+        }
+    }
+
+    if violations > 0 {
+        return Err(Error::EncryptionViolations(violations));
+    }
+
+    Ok(())
+}
+
+fn import(store: &Path, dir: &Path, link: bool) -> Result<(), Error> {
+    let store = local_store::LocalStore::new(store.into())?;
+
+    for hash in store.import(dir, link)? {
+        println!("{}", hash.to_hex());
+    }
+
+    Ok(())
+}
+
+fn migrate_store(store: &Path) -> Result<(), Error> {
+    let store = local_store::LocalStore::new(store.into())?;
+    let moved = store.migrate()?;
+    eprintln!("Moved {} files.", moved);
+    Ok(())
+}
+
+fn freeze(
+    path: &Path,
+    manifest_file: Option<&Path>,
+    signing_key: Option<&Path>,
+) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    // Read the key first so we don't freeze the filesystem if it's bad.
+    let signing_key = signing_key.map(manifest::read_signing_key).transpose()?;
+
+    match execute_request(&root, Request::Freeze {})? {
+        Response::Freeze(res) => {
+            let mut files = vec![];
+            for file in res.manifest {
+                if file.stores.len() < 2 {
+                    eprintln!(
+                        "warning: '{}' is not mirrored",
+                        root.join(&file.path).display()
+                    );
+                }
+                files.push(manifest::ManifestFile {
+                    path: file.path,
+                    size: file.size,
+                    hash: file.hash,
+                });
+            }
+
+            let mut manifest = manifest::Manifest::new(files);
+            if let Some(key) = &signing_key {
+                manifest.sign(key);
+            }
+
+            let mut out: Box<dyn Write> = match manifest_file {
+                Some(p) => Box::new(std::fs::File::create(p)?),
+                None => Box::new(std::io::stdout()),
+            };
+            serde_json::to_writer_pretty(&mut out, &manifest).unwrap();
+            writeln!(out)?;
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn verify(
+    path: &Path,
+    manifest_file: &Path,
+    public_key: Option<&str>,
+    unsigned: bool,
+) -> Result<(), Error> {
+    let manifest: manifest::Manifest = serde_json::from_reader(std::fs::File::open(manifest_file)?)
+        .map_err(|err| Error::BadArguments(format!("invalid manifest: {}", err)))?;
+
+    let trusted = public_key.map(manifest::parse_public_key).transpose()?;
+
+    if !unsigned || trusted.is_some() || manifest.signature.is_some() {
+        let signer = manifest.verify_signature(trusted.as_ref())?;
+        eprintln!("Manifest signed by {}.", hex::encode(signer.as_bytes()));
+    }
+
+    let mismatches =
+        manifest.verify_tree(path, &[fusefs::CONTROL_NAME, manifest::CHECKSUM_CACHE_NAME])?;
+
+    for mismatch in &mismatches {
+        match mismatch {
+            manifest::Mismatch::Missing(p) => println!("missing: {}", p.display()),
+            manifest::Mismatch::Modified(p) => println!("modified: {}", p.display()),
+            manifest::Mismatch::Unexpected(p) => println!("unexpected: {}", p.display()),
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::VerificationFailed(mismatches.len()))
+    }
+}
+
+fn stats(path: &Path, json: bool) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::Stats {})? {
+        Response::Stats(res) if json => {
+            println!("{}", serde_json::to_string_pretty(&res).unwrap());
+        }
+        Response::Stats(res) => {
+            println!("Uptime: {}s", res.uptime);
+            println!("Power: {:?} ({})", res.power.mode, res.power.reason);
+            println!(
+                "Verified mirrors: {} ({} failed)",
+                res.mirrors_verified, res.mirror_verification_failures
+            );
+            println!(
+                "Read-ahead: {} hits, {} misses",
+                res.readahead_hits, res.readahead_misses
+            );
+            println!("Deduplicated: {} bytes", res.deduplicated_bytes);
+            for (op, n) in &res.ops {
+                match res.op_metrics.get(op) {
+                    Some(m) if m.completed > 0 => println!(
+                        "Op: {} {} in-flight {} (max {}) latency avg {}us max {}us timed out {}",
+                        op,
+                        n,
+                        m.in_flight,
+                        m.max_in_flight,
+                        m.total_micros / m.completed,
+                        m.max_micros,
+                        m.timed_out
+                    ),
+                    _ => println!("Op: {} {}", op, n),
+                }
+            }
+            for (url, store) in &res.stores {
+                println!(
+                    "Store: {} read {} written {} errors {}",
+                    url, store.bytes_read, store.bytes_written, store.errors
+                );
+            }
+            for (url, err) in &res.unhealthy_stores {
+                println!("Unhealthy: {} ({})", url, err);
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn print_health(report: &control::HealthReport) {
+    println!(
+        "State: {}",
+        if report.is_clean() {
+            "clean"
+        } else {
+            "needs attention"
+        }
+    );
+    if report.schema_version != fs::SUPERBLOCK_VERSION {
+        println!(
+            "Schema version: {} (current {})",
+            report.schema_version,
+            fs::SUPERBLOCK_VERSION
+        );
+    }
+    println!("Pending finalizations: {}", report.mutable_files);
+    for (url, n) in &report.orphaned_files {
+        println!(
+            "Orphans: {} has {} orphaned files; run 'hugefs fsck' to delete them",
+            url, n
+        );
+    }
+    for (url, err) in &report.unhealthy_stores {
+        println!("Unavailable: {} ({})", url, err);
+    }
+}
+
+fn health(path: &Path, json: bool) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::Health {})? {
+        Response::Health(report) if json => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+        Response::Health(report) => print_health(&report),
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn recalls(path: &Path) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::ListRecalls {})? {
+        Response::ListRecalls { recalls } => {
+            for recall in recalls {
+                let waiting = recall.requested.elapsed().unwrap_or_default();
+                println!(
+                    "{} {} {} bytes, waiting {}s",
+                    recall.store,
+                    recall.hash.to_hex(),
+                    recall.size,
+                    waiting.as_secs()
+                );
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+
+    Ok(())
+}
+
+fn cluster(path: &Path) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::Cluster { path })? {
+        Response::Cluster { clusters, moved } => {
+            println!("Moved {} files into {} clusters.", moved, clusters);
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
     }
 
-    Ok(store)
+    Ok(())
 }
 
-fn mount(
-    state_file: PathBuf,
-    mount_point: PathBuf,
-    stores: Vec<String>,
-    key_files: Vec<PathBuf>,
-) -> Result<(), Error> {
-    let rt = Runtime::new().unwrap();
+fn exclude(path: &Path, store: &str, remove: bool) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
 
-    let keys: Result<Keys, _> = key_files.iter().map(|k| read_key_file(k)).collect();
-    let keys = keys?;
+    let req = Request::Exclude {
+        path,
+        store: store.into(),
+        remove,
+    };
 
-    let stores: Result<Vec<_>, _> = stores.iter().map(|s| open_store(s, &keys)).collect();
-    let stores = stores?;
+    match execute_request(&root, req)? {
+        Response::Exclude { files } => {
+            println!("Updated {} files.", files);
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
 
-    let superblock = if state_file.exists() {
-        fs::Superblock::open_from_json(&mut std::fs::File::open(&state_file).unwrap()).unwrap()
-    } else {
-        fs::Superblock::new()
-    };
+    Ok(())
+}
 
-    let fs_state = Arc::new(RwLock::new(fusefs::FilesystemState::new(
-        superblock, stores,
-    )));
+fn pin(path: &Path, store: &str, remove: bool) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
 
-    let fs = fusefs::Filesystem::new(Arc::clone(&fs_state), rt.handle().clone());
+    let req = Request::Pin {
+        path,
+        store: store.into(),
+        remove,
+    };
 
-    let s: OsString = "default_permissions".into();
+    match execute_request(&root, req)? {
+        Response::Pin { files } => {
+            println!("Updated {} files.", files);
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
 
-    fuse::mount(fs, &mount_point, &vec![s.as_os_str()]).unwrap();
+    Ok(())
+}
 
-    drop(rt);
+fn staging_store(path: &Path, store: Option<String>) -> Result<(), Error> {
+    let (root, path) = get_fs_root(path)?;
 
-    fs_state.read().unwrap().sync(&state_file).unwrap();
+    match execute_request(&root, Request::SetStagingStore { path, store })? {
+        Response::SetStagingStore { dirs } => {
+            println!("Updated {} directories.", dirs);
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
 
     Ok(())
 }
 
-fn get_fs_root(path: &Path) -> Result<(PathBuf, PathBuf), Error> {
-    let mut path = PathBuf::from(path);
-    let mut sub: Vec<OsString> = vec![];
+fn jobs(cmd: JobsCLI) -> Result<(), Error> {
+    let (root, req) = match cmd {
+        JobsCLI::Mirror {
+            path,
+            store,
+            priority,
+            idempotency_key,
+            callback,
+        } => {
+            let (root, path) = get_fs_root(&path)?;
+            (
+                root,
+                Request::EnqueueJob {
+                    kind: jobs::JobKind::Mirror { path, store },
+                    priority,
+                    idempotency_key,
+                    callback,
+                },
+            )
+        }
+        JobsCLI::Evict {
+            path,
+            store,
+            priority,
+            idempotency_key,
+            callback,
+        } => {
+            let (root, path) = get_fs_root(&path)?;
+            (
+                root,
+                Request::EnqueueJob {
+                    kind: jobs::JobKind::Evict { path, store },
+                    priority,
+                    idempotency_key,
+                    callback,
+                },
+            )
+        }
+        JobsCLI::List { path } => (get_fs_root(&path)?.0, Request::ListJobs {}),
+    };
 
-    loop {
-        if path.clone().join(fusefs::CONTROL_NAME).exists() {
-            let mut sub2 = PathBuf::new();
-            for s in sub.iter().rev() {
-                sub2 = sub2.join(s);
+    match execute_request(&root, req)? {
+        Response::EnqueueJob { id, existing } => {
+            if existing {
+                println!("{} (already queued)", id);
+            } else {
+                println!("{}", id);
             }
-            debug!("Found root '{}', sub '{}'.", path.display(), sub2.display());
-            return Ok((path.into(), sub2));
         }
-        if let Some(file_name) = path.file_name() {
-            sub.push(file_name.into());
-        }
-        if !path.pop() {
-            return Err(Error::NotHugefs);
+        Response::ListJobs { jobs } => {
+            for job in jobs {
+                println!(
+                    "{} {} {:?} {:?}",
+                    job.id, job.priority, job.status, job.kind
+                );
+            }
         }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
     }
-}
-
-fn execute_request(root: &Path, req: Request) -> Result<Response, Error> {
-    let control_path = root.join(fusefs::CONTROL_NAME);
-
-    let mut control_file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(control_path)?;
 
-    let mut req_s = serde_json::to_string(&req).unwrap();
-    req_s.push('\n');
-
-    control_file.write_all(req_s.as_bytes())?;
+    Ok(())
+}
 
-    control_file.seek(std::io::SeekFrom::Start(0))?;
+fn quota(cmd: QuotaCLI) -> Result<(), Error> {
+    let (root, req) = match cmd {
+        QuotaCLI::Set {
+            path,
+            max_bytes,
+            max_inodes,
+        } => {
+            let (root, path) = get_fs_root(&path)?;
+            let quota = if max_bytes.is_none() && max_inodes.is_none() {
+                None
+            } else {
+                Some(fs::Quota {
+                    max_bytes,
+                    max_inodes,
+                })
+            };
+            (root, Request::SetQuota { path, quota })
+        }
+        QuotaCLI::List { path } => (get_fs_root(&path)?.0, Request::ListQuotas {}),
+    };
 
-    let res = serde_json::from_reader(BufReader::new(control_file))
-        .map_err(|_| Error::BadControlResponse)?;
+    let show = |max: Option<u64>| max.map_or("-".to_string(), |n| n.to_string());
 
-    debug!("Control response: {:?}", res);
+    match execute_request(&root, req)? {
+        Response::SetQuota {} => {}
+        Response::ListQuotas { quotas } => {
+            for q in quotas {
+                println!(
+                    "{}: {}/{} bytes, {}/{} inodes",
+                    root.join(&q.path).display(),
+                    q.usage.bytes,
+                    show(q.quota.max_bytes),
+                    q.usage.inodes,
+                    show(q.quota.max_inodes)
+                );
+            }
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
 
-    Ok(res)
+    Ok(())
 }
 
-fn status(path: &Path) -> Result<(), Error> {
+fn read_from(path: &Path, store: Option<String>, pid: Option<u32>) -> Result<(), Error> {
     let (root, path) = get_fs_root(path)?;
 
-    let req = Request::Status { path };
+    match execute_request(&root, Request::SetReadStore { path, store, pid })? {
+        Response::SetReadStore {} => Ok(()),
+        Response::Error { msg } => Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
+}
+
+fn tier(cmd: TierCLI) -> Result<(), Error> {
+    let (root, req) = match cmd {
+        TierCLI::Set {
+            path,
+            fast,
+            archive,
+            days,
+        } => {
+            let (root, path) = get_fs_root(&path)?;
+            let policy = fs::TierPolicy {
+                fast,
+                archive,
+                cold_after_days: days,
+            };
+            (
+                root,
+                Request::SetTierPolicy {
+                    path,
+                    policy: Some(policy),
+                },
+            )
+        }
+        TierCLI::Clear { path } => {
+            let (root, path) = get_fs_root(&path)?;
+            (root, Request::SetTierPolicy { path, policy: None })
+        }
+        TierCLI::List { path } => (get_fs_root(&path)?.0, Request::ListTierPolicies {}),
+    };
 
     match execute_request(&root, req)? {
-        Response::Status(status) => {
-            println!(" Type: {}", status.info.get_type());
-            match status.info {
-                FileType::ImmutableFile {
-                    size, hash, stores, ..
-                } => {
-                    println!(" Size: {}", size);
-                    println!(" Hash: {}", hash.to_hex());
-                    for store in stores {
-                        println!("Store: {}", store);
-                    }
-                }
-                _ => {}
+        Response::SetTierPolicy {} => {}
+        Response::ListTierPolicies { policies } => {
+            for p in policies {
+                println!(
+                    "{}: {} -> {} after {} days",
+                    root.join(&p.path).display(),
+                    p.policy.fast,
+                    p.policy.archive,
+                    p.policy.cold_after_days
+                );
             }
         }
         Response::Error { msg } => return Err(Error::ControlError(msg)),
@@ -201,73 +2590,177 @@ fn status(path: &Path) -> Result<(), Error> {
     Ok(())
 }
 
-fn traverse(
-    root: &Path,
-    path: &Path,
-    callback: &dyn Fn(&Path, usize) -> Result<(), Error>,
-) -> Result<(), Error> {
-    let req = Request::Status { path: path.into() };
+fn snapshot(cmd: SnapshotCLI) -> Result<(), Error> {
+    let (path, req) = match cmd {
+        SnapshotCLI::Create { path, name } => (path, Request::CreateSnapshot { name }),
+        SnapshotCLI::List { path } => (path, Request::ListSnapshots {}),
+        SnapshotCLI::Delete { path, name } => (path, Request::DeleteSnapshot { name }),
+    };
 
-    match execute_request(&root, req)? {
-        Response::Status(status) => match status.info {
-            FileType::Directory { .. } => {
-                for entry in std::fs::read_dir(root.join(path))? {
-                    let entry = entry.unwrap();
-                    let file_name = entry.file_name();
-                    if file_name == "." || file_name == ".." {
-                        continue;
-                    }
-                    traverse(root, &path.join(file_name), callback)?;
-                }
-            }
-            FileType::ImmutableFile { stores, .. } => {
-                callback(&root.join(path), stores.len())?;
-            }
-            FileType::MutableFile { .. } => {
-                callback(&root.join(path), 0)?;
+    match execute_request(&get_fs_root(&path)?.0, req)? {
+        Response::CreateSnapshot {} | Response::DeleteSnapshot {} => {}
+        Response::ListSnapshots { snapshots } => {
+            for s in snapshots {
+                println!(
+                    "{}: {} bytes, {} inodes, created {} hours ago",
+                    s.name,
+                    s.usage.bytes,
+                    s.usage.inodes,
+                    s.created.elapsed().as_secs() / 3600
+                );
             }
-            FileType::Symlink { .. } => {}
-        },
+        }
         Response::Error { msg } => return Err(Error::ControlError(msg)),
         _ => panic!("Unexpected daemon response."),
     }
 
     Ok(())
 }
+/// Recover, dump or restore the superblock. The daemon replaces the state file
+/// Dump or restore the superblock. The daemon replaces the state file
+/// atomically, so a dump taken while the filesystem is mounted is
+/// consistent, but it lacks changes made since the last autosave
+/// unless `--sync` is given.
+fn meta(cmd: MetaCLI) -> Result<(), Error> {
+    match cmd {
+        MetaCLI::Recover {
+            state_file,
+            stores,
+            key_files,
+            key_shares,
+        } => {
+            let keys = read_keys(&key_files, &key_shares)?;
+            meta_recover(&state_file, &stores, &keys)?;
+        }
 
-enum Mode {
-    Unmirrored,
-    Mirrored,
+        MetaCLI::Dump {
+            state_file,
+            out,
+            sync,
+        } => {
+            // An fsync of a directory makes the daemon save its state.
+            if let Some(mount_point) = sync {
+                std::fs::File::open(mount_point)?.sync_all()?;
+            }
+            let superblock = fs::Superblock::open(&state_file)?;
+            let mut json = vec![];
+            superblock.write_json(&mut json).unwrap();
+            match out {
+                Some(out) => {
+                    let temp_path = out.with_extension("tmp");
+                    std::fs::write(&temp_path, &json)?;
+                    std::fs::rename(&temp_path, &out)?;
+                }
+                None => std::io::stdout().write_all(&json)?,
+            }
+            debug!("Dumped {} inodes.", superblock.nr_inodes());
+        }
+
+        MetaCLI::Restore {
+            dump,
+            state_file,
+            force,
+        } => {
+            if state_file.exists() && !force {
+                return Err(Error::BadArguments(format!(
+                    "state file '{}' already exists; use --force to overwrite it",
+                    state_file.display()
+                )));
+            }
+            let superblock = if dump == Path::new("-") {
+                fs::Superblock::open_from_json(&mut std::io::stdin())
+                    .map_err(|err| Error::CorruptState(dump.clone(), err.to_string()))?
+            } else {
+                fs::Superblock::open(&dump)?
+            };
+            let mut json = vec![];
+            superblock.write_json(&mut json).unwrap();
+            let mut temp_path = state_file.clone();
+            temp_path.set_extension("json.tmp");
+            let mut file = std::fs::File::create(&temp_path)?;
+            file.write_all(&json)?;
+            file.sync_all()?;
+            if state_file.exists() {
+                let backup_path = fs::Superblock::backup_path(&state_file);
+                if backup_path.exists() {
+                    std::fs::remove_file(&backup_path)?;
+                }
+                std::fs::hard_link(&state_file, &backup_path)?;
+            }
+            std::fs::rename(&temp_path, &state_file)?;
+            eprintln!(
+                "Restored {} inodes to '{}'.",
+                superblock.nr_inodes(),
+                state_file.display()
+            );
+        }
+    }
+
+    Ok(())
 }
 
-fn find_files(path: &Path, mode: Mode) -> Result<(), Error> {
-    let (root, path) = get_fs_root(path)?;
+fn media(cmd: MediaCLI) -> Result<(), Error> {
+    let (path, req) = match cmd {
+        MediaCLI::Register { path, name, store } => (
+            path,
+            Request::RegisterMedia {
+                name,
+                url: store.canonicalize()?.to_str().unwrap().into(),
+            },
+        ),
+        MediaCLI::Attach { path, name } => (path, Request::AttachMedia { name }),
+        MediaCLI::Detach { path, name } => (path, Request::DetachMedia { name }),
+    };
+
+    let (root, _) = get_fs_root(&path)?;
 
-    traverse(&root, &path, &|path: &Path,
-                             store_count: usize|
-     -> Result<(), Error> {
-        if match &mode {
-            Mode::Unmirrored => store_count < 2,
-            Mode::Mirrored => store_count >= 2,
-        } {
-            println!("{}", root.join(path).display());
+    match execute_request(&root, req)? {
+        Response::RegisterMedia {} => {}
+        Response::AttachMedia { present, copied } => {
+            println!("{} files present, {} files copied", present, copied);
         }
-        Ok(())
-    })?;
+        Response::DetachMedia { present } => {
+            println!("{} files present", present);
+        }
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
 
     Ok(())
 }
 
-fn mirror(path: &Path, store: &str) -> Result<(), Error> {
-    let (root, path) = get_fs_root(path)?;
+fn thaw(path: &Path) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
 
-    let req = Request::Mirror {
-        path: path.into(),
-        store: store.into(),
-    };
+    match execute_request(&root, Request::Thaw {})? {
+        Response::Thaw {} => {}
+        Response::Error { msg } => return Err(Error::ControlError(msg)),
+        _ => panic!("Unexpected daemon response."),
+    }
 
-    match execute_request(&root, req)? {
-        Response::Mirror(_) => {}
+    Ok(())
+}
+
+fn stores(path: &Path) -> Result<(), Error> {
+    let (root, _) = get_fs_root(path)?;
+
+    match execute_request(&root, Request::ListStores {})? {
+        Response::ListStores(res) => {
+            for store in res.stores {
+                println!("Store: {}", store.url);
+                println!("  Writable: {}", if store.writable { "yes" } else { "no" });
+                if store.reference {
+                    println!("  Reference: yes");
+                }
+                if let Some(fp) = store.key_fingerprint {
+                    println!("  Key: {}", fp);
+                }
+                match store.error {
+                    None => println!("  Health: ok"),
+                    Some(err) => println!("  Health: {}", err),
+                }
+            }
+        }
         Response::Error { msg } => return Err(Error::ControlError(msg)),
         _ => panic!("Unexpected daemon response."),
     }
@@ -275,8 +2768,53 @@ fn mirror(path: &Path, store: &str) -> Result<(), Error> {
     Ok(())
 }
 
+/// Keeps the OpenTelemetry exporter alive until exit, so that
+/// pending spans are flushed.
+struct TracingGuard {
+    #[cfg(feature = "otlp")]
+    _otlp: Option<opentelemetry_otlp::Uninstall>,
+}
+
+/// Write log messages to stderr, filtered by $RUST_LOG. If built with
+/// the 'otlp' feature and $HUGEFS_OTLP_ENDPOINT is set, spans are also
+/// exported to that OpenTelemetry collector.
+fn init_tracing() -> TracingGuard {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+    let subscriber = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+
+    #[cfg(feature = "otlp")]
+    {
+        if let Ok(endpoint) = std::env::var("HUGEFS_OTLP_ENDPOINT") {
+            match opentelemetry_otlp::new_pipeline()
+                .with_endpoint(&endpoint)
+                .install()
+            {
+                Ok((tracer, uninstall)) => {
+                    let _ = subscriber
+                        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                        .try_init();
+                    return TracingGuard {
+                        _otlp: Some(uninstall),
+                    };
+                }
+                Err(err) => eprintln!("Cannot export traces to '{}': {}", endpoint, err),
+            }
+        }
+    }
+
+    let _ = subscriber.try_init();
+
+    TracingGuard {
+        #[cfg(feature = "otlp")]
+        _otlp: None,
+    }
+}
+
 fn main() -> Result<(), Error> {
-    let _ = env_logger::try_init();
+    let _tracing = init_tracing();
 
     match CLI::from_args() {
         CLI::Mount {
@@ -284,8 +2822,89 @@ fn main() -> Result<(), Error> {
             mount_point,
             stores,
             key_files,
+            key_shares,
+            ttl,
+            immutable_ttl,
+            info_files,
+            power_hook,
+            verify_mirrors,
+            secure_erase,
+            check_open,
+            audit_log,
+            max_concurrent,
+            op_timeouts,
+            inbox,
+            inbox_dest,
+            inbox_remove,
+            fallback_to_backup,
+            replicate_to,
+            standby,
+            read_only,
+            autosave_interval,
+            options,
+            threads,
+            admin_listen,
+            admin_token_file,
+            s3_listen,
+            s3_bucket,
+            ninep_listen,
+            upgrade_stores,
         } => {
-            mount(state_file, mount_point, stores, key_files)?;
+            mount(
+                state_file,
+                mount_point,
+                stores,
+                key_files,
+                key_shares,
+                fusefs::CacheTtls {
+                    default: Duration::from_secs(ttl),
+                    immutable: Duration::from_secs(immutable_ttl),
+                },
+                info_files,
+                power_hook,
+                verify_mirrors,
+                secure_erase,
+                check_open,
+                audit_log,
+                max_concurrent,
+                op_timeouts,
+                inbox
+                    .map(|dir| dir.canonicalize())
+                    .transpose()?
+                    .map(|dir| ingest::Inbox::new(dir, inbox_dest, inbox_remove)),
+                fallback_to_backup,
+                replicate_to,
+                standby,
+                read_only,
+                options,
+                Duration::from_secs(autosave_interval),
+                threads,
+                admin_listen,
+                admin_token_file,
+                s3_listen.map(|addr| (addr, s3_bucket)),
+                ninep_listen,
+                upgrade_stores,
+            )?;
+        }
+
+        CLI::MountStore { store, mount_point } => {
+            mount_store(&store, &mount_point)?;
+        }
+
+        CLI::Stats { path, json } => {
+            stats(&path, json)?;
+        }
+
+        CLI::Health { path, json } => {
+            health(&path, json)?;
+        }
+
+        CLI::Recalls { path } => {
+            recalls(&path)?;
+        }
+
+        CLI::Cluster { path } => {
+            cluster(&path)?;
         }
 
         CLI::Status { path } => {
@@ -303,6 +2922,229 @@ fn main() -> Result<(), Error> {
         CLI::Mirror { path, store } => {
             mirror(&path, &store)?;
         }
+
+        CLI::Adopt {
+            src,
+            dest,
+            store,
+            link,
+            move_,
+        } => {
+            adopt(&src, &dest, store, link, move_)?;
+        }
+
+        CLI::Clone { src, dst } => {
+            clone(&src, &dst)?;
+        }
+
+        CLI::Diff { old, new } => {
+            diff(&old, &new)?;
+        }
+
+        CLI::Sync { src, dst } => {
+            sync_fs(&src, &dst)?;
+        }
+
+        CLI::Bench {
+            stores,
+            small_files,
+            small_file_size,
+            large_file_size,
+            random_reads,
+            read_size,
+            stats,
+            json,
+        } => {
+            let workload = bench::Workload {
+                small_files,
+                small_file_size,
+                large_file_size: large_file_size * 1024 * 1024,
+                random_reads,
+                read_size,
+                stats,
+            };
+            run_bench(&stores, &workload, json)?;
+        }
+
+        CLI::Export {
+            src,
+            dest,
+            write_checksum_cache,
+        } => {
+            export(&src, &dest, write_checksum_cache)?;
+        }
+
+        CLI::Evict { path, store } => {
+            evict(&path, &store)?;
+        }
+
+        CLI::Stores { path } => {
+            stores(&path)?;
+        }
+
+        CLI::Finalize { path, recursive } => {
+            finalize(&path, recursive)?;
+        }
+
+        CLI::AddStore {
+            path,
+            store,
+            key_file,
+            reference,
+        } => {
+            add_store(
+                &path,
+                &store,
+                key_file.as_ref().map(|k| k.as_path()),
+                reference,
+            )?;
+        }
+
+        CLI::RemoveStore { path, store } => {
+            remove_store(&path, &store)?;
+        }
+
+        CLI::CheckKeys { stores, key_files } => {
+            check_keys(&stores, &key_files)?;
+        }
+
+        CLI::GC { store, dry_run } => {
+            gc(&store, dry_run)?;
+        }
+
+        CLI::Doctor { stores } => {
+            doctor(&stores)?;
+        }
+
+        CLI::Fsck { stores, repair } => {
+            fsck(&stores, repair)?;
+        }
+
+        CLI::Import { store, dir, link } => {
+            import(&store, &dir, link)?;
+        }
+
+        CLI::MigrateStore { store } => {
+            migrate_store(&store)?;
+        }
+
+        CLI::Freeze {
+            path,
+            manifest,
+            signing_key,
+        } => {
+            freeze(
+                &path,
+                manifest.as_ref().map(|p| p.as_path()),
+                signing_key.as_ref().map(|p| p.as_path()),
+            )?;
+        }
+
+        CLI::Verify {
+            path,
+            manifest,
+            public_key,
+            unsigned,
+        } => {
+            verify(
+                &path,
+                &manifest,
+                public_key.as_ref().map(|k| k.as_str()),
+                unsigned,
+            )?;
+        }
+
+        CLI::Thaw { path } => {
+            thaw(&path)?;
+        }
+
+        CLI::WhichPath { path, hash } => {
+            which_path(&path, &hash)?;
+        }
+
+        CLI::Find {
+            path,
+            min_size,
+            max_size,
+            file_type,
+            newer_than_days,
+            older_than_days,
+            present_in,
+            missing_from,
+        } => {
+            let filter = FindFilter {
+                min_size: min_size.as_deref().map(parse_size).transpose()?,
+                max_size: max_size.as_deref().map(parse_size).transpose()?,
+                file_type,
+                newer_than_days,
+                older_than_days,
+                present_in,
+                missing_from,
+            };
+            find(&path, filter)?;
+        }
+
+        CLI::DiskUsage { path } => {
+            disk_usage(&path)?;
+        }
+
+        CLI::Key(KeyCLI::Split {
+            key_file,
+            shares,
+            threshold,
+        }) => {
+            split_key(&key_file, shares, threshold)?;
+        }
+
+        CLI::Exclude {
+            path,
+            store,
+            remove,
+        } => {
+            exclude(&path, &store, remove)?;
+        }
+
+        CLI::Pin {
+            path,
+            store,
+            remove,
+        } => {
+            pin(&path, &store, remove)?;
+        }
+
+        CLI::StagingStore { path, store } => {
+            staging_store(&path, store)?;
+        }
+
+        CLI::Media(cmd) => {
+            media(cmd)?;
+        }
+
+        CLI::Meta(cmd) => {
+            meta(cmd)?;
+        }
+
+        CLI::Jobs(cmd) => {
+            jobs(cmd)?;
+        }
+
+        CLI::Quota(cmd) => {
+            quota(cmd)?;
+        }
+        CLI::ReadFrom { path, store, pid } => {
+            read_from(&path, store, pid)?;
+        }
+        CLI::Tier(cmd) => {
+            tier(cmd)?;
+        }
+        CLI::Snapshot(cmd) => {
+            snapshot(cmd)?;
+        }
+
+        CLI::Key(KeyCLI::NewSigningKey { key_file }) => {
+            let public_key = manifest::generate_signing_key(&key_file)?;
+            println!("{}", hex::encode(public_key.as_bytes()));
+        }
     }
 
     Ok(())
@@ -0,0 +1,200 @@
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest that `hugefs export --write-checksum-cache`
+/// writes to the root of the export.
+pub const CHECKSUM_CACHE_NAME: &str = ".hugefs-hashes";
+
+/// A list of the immutable files in a filesystem, optionally signed
+/// so that a tree can later be checked against it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<ManifestFile>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ManifestSignature>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub hash: Hash,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestSignature {
+    /// Hex-encoded ed25519 public key.
+    pub public_key: String,
+    /// Hex-encoded ed25519 signature of `Manifest::signed_data()`.
+    pub signature: String,
+}
+
+/// A difference between a manifest and a directory tree.
+#[derive(Debug)]
+pub enum Mismatch {
+    Missing(PathBuf),
+    Modified(PathBuf),
+    Unexpected(PathBuf),
+}
+
+impl Manifest {
+    pub fn new(mut files: Vec<ManifestFile>) -> Self {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Self {
+            files,
+            signature: None,
+        }
+    }
+
+    /// The data covered by the signature: one line per file
+    /// containing its hash, size and path.
+    /// The bytes covered by the signature. Paths are arbitrary byte
+    /// strings, so they're length-prefixed rather than delimited.
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = b"hugefs-manifest-1\n".to_vec();
+        for file in &self.files {
+            let path = file.path.as_os_str().as_bytes();
+            data.extend_from_slice(file.hash.to_hex().as_bytes());
+            data.extend_from_slice(&file.size.to_be_bytes());
+            data.extend_from_slice(&(path.len() as u64).to_be_bytes());
+            data.extend_from_slice(path);
+        }
+        data
+    }
+
+    pub fn sign(&mut self, key: &Keypair) {
+        let signature = key.sign(&self.signed_data());
+        self.signature = Some(ManifestSignature {
+            public_key: hex::encode(key.public.as_bytes()),
+            signature: hex::encode(&signature.to_bytes()[..]),
+        });
+    }
+
+    /// Check the signature of this manifest. If `trusted` is given,
+    /// the manifest must have been signed by that key. Returns the
+    /// public key that signed the manifest.
+    pub fn verify_signature(&self, trusted: Option<&PublicKey>) -> Result<PublicKey> {
+        let sig = self
+            .signature
+            .as_ref()
+            .ok_or_else(|| Error::BadSignature("manifest is not signed".into()))?;
+
+        let public_key = hex::decode(&sig.public_key)
+            .ok()
+            .and_then(|k| PublicKey::from_bytes(&k).ok())
+            .ok_or_else(|| Error::BadSignature("invalid public key".into()))?;
+
+        if let Some(trusted) = trusted {
+            if *trusted != public_key {
+                return Err(Error::BadSignature(format!(
+                    "manifest is signed by untrusted key {}",
+                    sig.public_key
+                )));
+            }
+        }
+
+        let signature = hex::decode(&sig.signature)
+            .ok()
+            .and_then(|s| Signature::from_bytes(&s).ok())
+            .ok_or_else(|| Error::BadSignature("invalid signature".into()))?;
+
+        public_key
+            .verify(&self.signed_data(), &signature)
+            .map_err(|_| Error::BadSignature("signature does not match".into()))?;
+
+        Ok(public_key)
+    }
+
+    /// Compare the regular files under `root` with this manifest.
+    /// Files whose name is in `ignore` are skipped.
+    pub fn verify_tree(&self, root: &Path, ignore: &[&str]) -> Result<Vec<Mismatch>> {
+        let mut found = BTreeMap::new();
+        scan_tree(root, Path::new(""), ignore, &mut found)?;
+
+        let mut mismatches = vec![];
+
+        for file in &self.files {
+            match found.remove(&file.path) {
+                None => mismatches.push(Mismatch::Missing(file.path.clone())),
+                Some(size) => {
+                    if size != file.size
+                        || Hash::hash(File::open(root.join(&file.path))?)?.1 != file.hash
+                    {
+                        mismatches.push(Mismatch::Modified(file.path.clone()));
+                    }
+                }
+            }
+        }
+
+        for path in found.into_iter().map(|(path, _)| path) {
+            mismatches.push(Mismatch::Unexpected(path));
+        }
+
+        Ok(mismatches)
+    }
+}
+
+/// Collect the paths and sizes of the regular files under `dir`.
+fn scan_tree(
+    root: &Path,
+    dir: &Path,
+    ignore: &[&str],
+    found: &mut BTreeMap<PathBuf, u64>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        if ignore.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let path = dir.join(entry.file_name());
+        let st = entry.metadata()?;
+        if st.is_dir() {
+            scan_tree(root, &path, ignore, found)?;
+        } else if st.is_file() {
+            found.insert(path, st.len());
+        }
+    }
+    Ok(())
+}
+
+/// Create a new ed25519 signing key in `path`, returning its public
+/// key.
+pub fn generate_signing_key(path: &Path) -> Result<PublicKey> {
+    let mut secret = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+    File::open("/dev/urandom")?.read_exact(&mut secret)?;
+    let secret = SecretKey::from_bytes(&secret).unwrap();
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(secret.as_bytes())?;
+    Ok(PublicKey::from(&secret))
+}
+
+pub fn read_signing_key(path: &Path) -> Result<Keypair> {
+    let mut secret = vec![];
+    File::open(path)?.read_to_end(&mut secret)?;
+    let secret = SecretKey::from_bytes(&secret)
+        .map_err(|_| Error::BadArguments(format!("'{}' is not a signing key", path.display())))?;
+    Ok(Keypair {
+        public: PublicKey::from(&secret),
+        secret,
+    })
+}
+
+pub fn parse_public_key(s: &str) -> Result<PublicKey> {
+    hex::decode(s)
+        .ok()
+        .and_then(|k| PublicKey::from_bytes(&k).ok())
+        .ok_or_else(|| Error::BadArguments(format!("invalid public key '{}'", s)))
+}
@@ -0,0 +1,201 @@
+use crate::error::Error;
+use crate::hash::Hash;
+use crate::store::{Future, MutableFile, ProgressFn, Store};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+type Objects = Arc<RwLock<HashMap<Hash, Arc<Vec<u8>>>>>;
+
+/// A store that keeps all files in memory. Mostly useful for testing.
+pub struct MemoryStore {
+    url: String,
+    objects: Objects,
+    max_size: Option<u64>,
+    accessed: Mutex<HashMap<Hash, SystemTime>>,
+}
+
+impl MemoryStore {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.into(),
+            objects: Arc::new(RwLock::new(HashMap::new())),
+            max_size: None,
+            accessed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+}
+
+impl Store for MemoryStore {
+    fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.objects
+                .write()
+                .unwrap()
+                .entry(file_hash)
+                .or_insert_with(|| Arc::new(data.to_vec()));
+            Ok(())
+        })
+    }
+
+    fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool> {
+        let res = self.objects.read().unwrap().contains_key(file_hash);
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            let data = self
+                .objects
+                .read()
+                .unwrap()
+                .get(&file_hash)
+                .cloned()
+                .ok_or_else(|| Error::NoSuchHash(file_hash.clone()))?;
+            self.accessed
+                .lock()
+                .unwrap()
+                .insert(file_hash, SystemTime::now());
+            Ok(data
+                .iter()
+                .skip(offset as usize)
+                .take(size)
+                .map(|b| *b)
+                .collect())
+        })
+    }
+
+    fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        Some(Box::pin(async move {
+            let file: Box<dyn MutableFile> = Box::new(MemoryMutableFile {
+                objects: Arc::clone(&self.objects),
+                data: Mutex::new(vec![]),
+            });
+            Ok(file)
+        }))
+    }
+
+    fn erase<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        self.remove(file_hash)
+    }
+
+    fn remove<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        self.objects.write().unwrap().remove(file_hash);
+        self.accessed.lock().unwrap().remove(file_hash);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        let objects = self.objects.read().unwrap();
+        let present = file_hashes
+            .iter()
+            .filter(|file_hash| objects.contains_key(file_hash))
+            .cloned()
+            .collect();
+        Box::pin(async move { Ok(present) })
+    }
+
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        let files = self
+            .objects
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(hash, data)| (hash.clone(), data.len() as u64))
+            .collect();
+        Box::pin(async move { Ok(files) })
+    }
+
+    fn used_bytes<'a>(&'a self) -> Future<'a, u64> {
+        let total = self
+            .objects
+            .read()
+            .unwrap()
+            .values()
+            .map(|data| data.len() as u64)
+            .sum();
+        Box::pin(async move { Ok(total) })
+    }
+
+    fn last_access<'a>(&'a self, file_hash: &Hash) -> Future<'a, Option<SystemTime>> {
+        let accessed = self.accessed.lock().unwrap().get(file_hash).cloned();
+        Box::pin(async move { Ok(accessed) })
+    }
+
+    fn get_url(&self) -> String {
+        self.url.clone()
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+}
+
+struct MemoryMutableFile {
+    objects: Objects,
+    data: Mutex<Vec<u8>>,
+}
+
+impl MutableFile for MemoryMutableFile {
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> Future<'a, ()> {
+        Box::pin(async move {
+            let mut buf = self.data.lock().unwrap();
+            let end = offset as usize + data.len();
+            if buf.len() < end {
+                buf.resize(end, 0);
+            }
+            buf[offset as usize..end].copy_from_slice(data);
+            Ok(())
+        })
+    }
+
+    fn read<'a>(&'a self, offset: u64, size: u32) -> Future<'a, Vec<u8>> {
+        Box::pin(async move {
+            Ok(self
+                .data
+                .lock()
+                .unwrap()
+                .iter()
+                .skip(offset as usize)
+                .take(size as usize)
+                .map(|b| *b)
+                .collect())
+        })
+    }
+
+    fn finish<'a>(&'a self, progress: ProgressFn<'a>) -> Future<'a, (u64, Hash)> {
+        Box::pin(async move {
+            let data = self.data.lock().unwrap().clone();
+            let (len, hash) = Hash::hash(&data[..])?;
+            progress(len);
+            self.objects
+                .write()
+                .unwrap()
+                .entry(hash.clone())
+                .or_insert_with(|| Arc::new(data));
+            Ok((len, hash))
+        })
+    }
+
+    fn len(&self) -> u64 {
+        self.data.lock().unwrap().len() as u64
+    }
+
+    fn set_len<'a>(&'a self, len: u64) -> Future<'a, ()> {
+        Box::pin(async move {
+            self.data.lock().unwrap().resize(len as usize, 0);
+            Ok(())
+        })
+    }
+}
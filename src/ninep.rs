@@ -0,0 +1,488 @@
+use crate::error::{Error, Result};
+use crate::fs::{Contents, Ino, Inode};
+use crate::fusefs::{read_hash, FilesystemState};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::{Arc, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+/// Largest message size we negotiate.
+const MAX_MSIZE: u32 = 1024 * 1024;
+
+/// Size of the header of Rread (size, type, tag and count).
+const READ_HEADER_SIZE: u32 = 11;
+
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const RERROR: u8 = 107;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TOPEN: u8 = 112;
+const TCREATE: u8 = 114;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+const TSTAT: u8 = 124;
+const TWSTAT: u8 = 126;
+
+const QTDIR: u8 = 0x80;
+const QTFILE: u8 = 0x00;
+const DMDIR: u32 = 0x8000_0000;
+
+const OWRITE: u8 = 0x01;
+const ORDWR: u8 = 0x02;
+const OTRUNC: u8 = 0x10;
+const ORCLOSE: u8 = 0x40;
+
+/// A file on the server that the client has a handle for.
+struct Fid {
+    /// The inodes from the root to this file, so that '..' can be
+    /// walked without parent pointers.
+    path: Vec<Ino>,
+    open: bool,
+    /// The byte offset of the next directory entry and the name of
+    /// the previous one.
+    dir_pos: (u64, String),
+}
+
+impl Fid {
+    fn ino(&self) -> Ino {
+        *self.path.last().unwrap()
+    }
+}
+
+/// Serve the filesystem read-only on `addr` using the 9P2000
+/// protocol, for hosts that can't use FUSE, e.g.
+/// 'mount -t 9p -o trans=tcp,port=<port>,version=9p2000 <host> <dir>'.
+/// Symlinks are not visible. Connections are not authenticated, so
+/// this should only listen on trusted networks.
+pub async fn serve(addr: SocketAddr, state: Arc<RwLock<FilesystemState>>) {
+    let mut listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Cannot listen on {}: {}", addr, err);
+            return;
+        }
+    };
+
+    info!("Serving 9P on {}.", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                debug!("Accepted 9P connection from {}.", peer);
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    if let Err(err) = Connection::new(state).run(stream).await {
+                        debug!("9P connection from {} ended: {}", peer, err);
+                    }
+                });
+            }
+            Err(err) => error!("Cannot accept 9P connection: {}", err),
+        }
+    }
+}
+
+/// A reader for the fields of a 9P message.
+struct Message<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(Error::BadArguments("truncated 9P message".into()));
+        }
+        let (res, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(res)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(
+            <[u8; 2]>::try_from(self.take(2)?).unwrap(),
+        ))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            <[u8; 4]>::try_from(self.take(4)?).unwrap(),
+        ))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(
+            <[u8; 8]>::try_from(self.take(8)?).unwrap(),
+        ))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| Error::BadArguments("9P string is not UTF-8".into()))
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn put_qid(buf: &mut Vec<u8>, inode: &Inode) {
+    let qtype = match inode.contents {
+        Contents::Directory(_) => QTDIR,
+        _ => QTFILE,
+    };
+    buf.push(qtype);
+    // The version changes whenever the file does.
+    buf.extend_from_slice(&((inode.mtime.0 / 1_000_000_000) as u32).to_le_bytes());
+    buf.extend_from_slice(&inode.ino.to_le_bytes());
+}
+
+fn file_length(inode: &Inode) -> u64 {
+    match &inode.contents {
+        Contents::RegularFile(file) => file.length,
+        Contents::MutableFile(file) => file.file.len(),
+        _ => 0,
+    }
+}
+
+fn encode_stat(inode: &Inode, name: &str) -> Vec<u8> {
+    let mut stat = vec![];
+    stat.extend_from_slice(&0u16.to_le_bytes()); // type
+    stat.extend_from_slice(&0u32.to_le_bytes()); // dev
+    put_qid(&mut stat, inode);
+    let mut mode = u32::from(inode.perm) & 0o777;
+    if let Contents::Directory(_) = inode.contents {
+        mode |= DMDIR;
+    }
+    stat.extend_from_slice(&mode.to_le_bytes());
+    let mtime = (inode.mtime.0 / 1_000_000_000) as u32;
+    stat.extend_from_slice(&mtime.to_le_bytes()); // atime
+    stat.extend_from_slice(&mtime.to_le_bytes());
+    stat.extend_from_slice(&file_length(inode).to_le_bytes());
+    put_string(&mut stat, name);
+    put_string(&mut stat, &inode.uid.to_string());
+    put_string(&mut stat, &inode.gid.to_string());
+    put_string(&mut stat, "");
+
+    let mut res = (stat.len() as u16).to_le_bytes().to_vec();
+    res.extend(stat);
+    res
+}
+
+fn is_visible(inode: &Inode) -> bool {
+    match inode.contents {
+        Contents::Symlink(_) => false,
+        _ => true,
+    }
+}
+
+struct Connection {
+    state: Arc<RwLock<FilesystemState>>,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Connection {
+    fn new(state: Arc<RwLock<FilesystemState>>) -> Self {
+        Self {
+            state,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        loop {
+            let mut size = [0u8; 4];
+            stream.read_exact(&mut size).await?;
+            let size = u32::from_le_bytes(size);
+            if size < 7 || size > self.msize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("bad 9P message size {}", size),
+                ));
+            }
+            let mut data = vec![0u8; size as usize - 4];
+            stream.read_exact(&mut data).await?;
+
+            let msg_type = data[0];
+            let tag = u16::from_le_bytes([data[1], data[2]]);
+            let mut msg = Message { data: &data[3..] };
+
+            let (res_type, body) = match self.handle(msg_type, &mut msg).await {
+                Ok(body) => (msg_type + 1, body),
+                Err(err) => {
+                    debug!("9P request {} failed: {}", msg_type, err);
+                    let mut body = vec![];
+                    put_string(&mut body, &err.to_string());
+                    (RERROR, body)
+                }
+            };
+
+            let mut reply = Vec::with_capacity(body.len() + 7);
+            reply.extend_from_slice(&(body.len() as u32 + 7).to_le_bytes());
+            reply.push(res_type);
+            reply.extend_from_slice(&tag.to_le_bytes());
+            reply.extend(body);
+            stream.write_all(&reply).await?;
+        }
+    }
+
+    fn get_fid(&self, fid: u32) -> Result<&Fid> {
+        self.fids
+            .get(&fid)
+            .ok_or_else(|| Error::BadArguments(format!("unknown fid {}", fid)))
+    }
+
+    fn get_inode(&self, ino: Ino) -> Result<Arc<RwLock<Inode>>> {
+        self.state.read().unwrap().superblock.get_inode(ino)
+    }
+
+    async fn handle(&mut self, msg_type: u8, msg: &mut Message<'_>) -> Result<Vec<u8>> {
+        let mut res = vec![];
+        match msg_type {
+            TVERSION => {
+                let msize = msg.u32()?;
+                let version = msg.string()?;
+                self.msize = std::cmp::min(msize, MAX_MSIZE);
+                self.fids.clear();
+                res.extend_from_slice(&self.msize.to_le_bytes());
+                if version.starts_with("9P2000") {
+                    put_string(&mut res, "9P2000");
+                } else {
+                    put_string(&mut res, "unknown");
+                }
+            }
+
+            TAUTH => {
+                return Err(Error::BadArguments("authentication not required".into()));
+            }
+
+            TATTACH => {
+                let fid = msg.u32()?;
+                let _afid = msg.u32()?;
+                let root = self.state.read().unwrap().superblock.get_root_ino();
+                put_qid(&mut res, &self.get_inode(root)?.read().unwrap());
+                self.fids.insert(
+                    fid,
+                    Fid {
+                        path: vec![root],
+                        open: false,
+                        dir_pos: (0, String::new()),
+                    },
+                );
+            }
+
+            TFLUSH => {
+                // Requests are handled in order, so there is nothing
+                // to cancel.
+                let _oldtag = msg.u16()?;
+            }
+
+            TWALK => {
+                let fid = msg.u32()?;
+                let newfid = msg.u32()?;
+                let nwname = msg.u16()?;
+                let mut path = self.get_fid(fid)?.path.clone();
+                let mut qids = vec![];
+                for i in 0..nwname {
+                    let name = msg.string()?;
+                    let next = if name == ".." {
+                        if path.len() > 1 {
+                            path.pop();
+                        }
+                        Ok(*path.last().unwrap())
+                    } else {
+                        let dir = self.get_inode(*path.last().unwrap())?;
+                        let dir = dir.read().unwrap();
+                        dir.get_directory().and_then(|dir| dir.get_entry(&name))
+                    };
+                    let inode = next.and_then(|ino| self.get_inode(ino));
+                    let inode = match inode {
+                        Ok(inode) if is_visible(&inode.read().unwrap()) => inode,
+                        Ok(_) | Err(_) if i == 0 => return Err(Error::NoSuchEntry),
+                        _ => break,
+                    };
+                    let inode = inode.read().unwrap();
+                    if name != ".." {
+                        path.push(inode.ino);
+                    }
+                    let mut qid = vec![];
+                    put_qid(&mut qid, &inode);
+                    qids.push(qid);
+                }
+                if qids.len() == nwname as usize {
+                    if newfid != fid && self.fids.contains_key(&newfid) {
+                        return Err(Error::BadArguments(format!("fid {} is in use", newfid)));
+                    }
+                    self.fids.insert(
+                        newfid,
+                        Fid {
+                            path,
+                            open: false,
+                            dir_pos: (0, String::new()),
+                        },
+                    );
+                }
+                res.extend_from_slice(&(qids.len() as u16).to_le_bytes());
+                for qid in qids {
+                    res.extend(qid);
+                }
+            }
+
+            TOPEN => {
+                let fid = msg.u32()?;
+                let mode = msg.u8()?;
+                // OEXEC (3) only reads.
+                if matches!(mode & 3, OWRITE | ORDWR) || mode & (OTRUNC | ORCLOSE) != 0 {
+                    return Err(Error::ReadOnly);
+                }
+                let ino = self.get_fid(fid)?.ino();
+                put_qid(&mut res, &self.get_inode(ino)?.read().unwrap());
+                res.extend_from_slice(&(self.msize - READ_HEADER_SIZE).to_le_bytes());
+                self.fids.get_mut(&fid).unwrap().open = true;
+            }
+
+            TCREATE | TWRITE | TWSTAT => return Err(Error::ReadOnly),
+
+            TREMOVE => {
+                // Tremove clunks the fid even if it fails.
+                let fid = msg.u32()?;
+                self.fids.remove(&fid);
+                return Err(Error::ReadOnly);
+            }
+
+            TCLUNK => {
+                let fid = msg.u32()?;
+                self.get_fid(fid)?;
+                self.fids.remove(&fid);
+            }
+
+            TSTAT => {
+                let fid = msg.u32()?;
+                let fid = self.get_fid(fid)?;
+                let name = if fid.path.len() > 1 {
+                    let parent = self.get_inode(fid.path[fid.path.len() - 2])?;
+                    let parent = parent.read().unwrap();
+                    parent
+                        .get_directory()?
+                        .entries
+                        .iter()
+                        .find(|(_, ino)| **ino == fid.ino())
+                        .map(|(name, _)| name.clone())
+                        .unwrap_or_default()
+                } else {
+                    "/".into()
+                };
+                let stat = encode_stat(&self.get_inode(fid.ino())?.read().unwrap(), &name);
+                res.extend_from_slice(&(stat.len() as u16).to_le_bytes());
+                res.extend(stat);
+            }
+
+            TREAD => {
+                let fid = msg.u32()?;
+                let offset = msg.u64()?;
+                let count = std::cmp::min(msg.u32()?, self.msize - READ_HEADER_SIZE);
+                let data = self.read(fid, offset, count).await?;
+                res.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                res.extend(data);
+            }
+
+            _ => {
+                return Err(Error::BadArguments(format!(
+                    "unsupported 9P message type {}",
+                    msg_type
+                )))
+            }
+        }
+        Ok(res)
+    }
+
+    async fn read(&mut self, fid: u32, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let (ino, open) = {
+            let fid = self.get_fid(fid)?;
+            (fid.ino(), fid.open)
+        };
+        if !open {
+            return Err(Error::BadArguments(format!("fid {} is not open", fid)));
+        }
+
+        enum File {
+            Directory,
+            Regular(crate::hash::Hash, u64),
+            Mutable(Arc<crate::fs::MutableFile>),
+        }
+
+        let file = match &self.get_inode(ino)?.read().unwrap().contents {
+            Contents::Directory(_) => File::Directory,
+            Contents::RegularFile(file) => File::Regular(file.hash.clone(), file.length),
+            Contents::MutableFile(file) => File::Mutable(Arc::clone(file)),
+            Contents::Symlink(_) => return Err(Error::NoSuchEntry),
+        };
+
+        match file {
+            File::Directory => self.read_dir(fid, ino, offset, count),
+            File::Regular(hash, length) => {
+                if offset >= length {
+                    return Ok(vec![]);
+                }
+                let size = std::cmp::min(u64::from(count), length - offset);
+                read_hash(&self.state, &hash, length, offset, size).await
+            }
+            File::Mutable(file) => file.file.read(offset, count).await,
+        }
+    }
+
+    /// Return as many complete stat entries of directory `ino` as fit
+    /// in `count` bytes, starting at `offset`, which must be 0 or the
+    /// end of the previous read. Like readdir in `fusefs`, this
+    /// resumes after the name of the previous entry, so large
+    /// directories aren't copied.
+    fn read_dir(&mut self, fid: u32, ino: Ino, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let (pos, mut prev) = match offset {
+            0 => (0, String::new()),
+            _ => self.fids[&fid].dir_pos.clone(),
+        };
+        if offset != pos {
+            return Err(Error::BadArguments("bad directory read offset".into()));
+        }
+
+        let mut res = vec![];
+        {
+            let state = self.state.read().unwrap();
+            let dir = state.superblock.get_inode(ino)?;
+            let dir = dir.read().unwrap();
+            for (name, child_ino) in dir
+                .get_directory()?
+                .entries
+                .range::<String, _>((Excluded(prev.clone()), Unbounded))
+            {
+                let child = state.superblock.get_inode(*child_ino)?;
+                let child = child.read().unwrap();
+                if is_visible(&child) {
+                    let entry = encode_stat(&child, name);
+                    if res.len() + entry.len() > count as usize {
+                        break;
+                    }
+                    res.extend_from_slice(&entry);
+                }
+                prev = name.clone();
+            }
+        }
+
+        self.fids.get_mut(&fid).unwrap().dir_pos = (pos + res.len() as u64, prev);
+        Ok(res)
+    }
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often the power state is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Background I/O is allowed.
+    Active,
+    /// Background I/O is paused, e.g. because we're on battery or
+    /// the disks are spun down.
+    Idle,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowerStatus {
+    pub mode: Mode,
+    pub reason: String,
+}
+
+/// Keeps track of whether the daemon should avoid background I/O.
+/// The mode is determined by a user-supplied hook if given, and by
+/// whether the machine is running on battery otherwise.
+pub struct PowerMonitor {
+    /// A program that exits with status 0 if the daemon may be
+    /// active and non-zero otherwise. Its first line of output is
+    /// used as the reason.
+    hook: Option<PathBuf>,
+    status: RwLock<PowerStatus>,
+}
+
+impl PowerMonitor {
+    pub fn new(hook: Option<PathBuf>) -> Self {
+        Self {
+            hook,
+            status: RwLock::new(PowerStatus {
+                mode: Mode::Active,
+                reason: "not checked yet".into(),
+            }),
+        }
+    }
+
+    pub fn status(&self) -> PowerStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.status.read().unwrap().mode == Mode::Idle
+    }
+
+    fn probe(&self) -> PowerStatus {
+        match &self.hook {
+            Some(hook) => match Command::new(hook).output() {
+                Ok(output) => PowerStatus {
+                    mode: if output.status.success() {
+                        Mode::Active
+                    } else {
+                        Mode::Idle
+                    },
+                    reason: String::from_utf8_lossy(&output.stdout)
+                        .lines()
+                        .next()
+                        .unwrap_or("power hook")
+                        .into(),
+                },
+                Err(err) => {
+                    warn!("Cannot run power hook '{}': {}", hook.display(), err);
+                    PowerStatus {
+                        mode: Mode::Active,
+                        reason: "power hook failed".into(),
+                    }
+                }
+            },
+            None => {
+                if on_battery() {
+                    PowerStatus {
+                        mode: Mode::Idle,
+                        reason: "on battery".into(),
+                    }
+                } else {
+                    PowerStatus {
+                        mode: Mode::Active,
+                        reason: "on mains power".into(),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Periodically update the power state.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            let monitor = Arc::clone(&self);
+            if let Ok(status) = tokio::task::spawn_blocking(move || monitor.probe()).await {
+                let mut cur = self.status.write().unwrap();
+                if status.mode != cur.mode {
+                    info!("Switching to {:?} mode ({}).", status.mode, status.reason);
+                }
+                *cur = status;
+            }
+            tokio::time::delay_for(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Return whether the machine has a mains power supply that is
+/// offline.
+fn on_battery() -> bool {
+    let read = |path: &Path| std::fs::read_to_string(path).unwrap_or_default();
+
+    std::fs::read_dir("/sys/class/power_supply")
+        .map(|entries| {
+            entries.filter_map(|e| e.ok()).any(|entry| {
+                read(&entry.path().join("type")).trim() == "Mains"
+                    && read(&entry.path().join("online")).trim() == "0"
+            })
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,286 @@
+use crate::error::Error;
+use crate::hash::Hash;
+use crate::store::{Config, Future, MutableFile, ProgressFn, RecallInfo, Result, Store};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long we remember that a store has a file.
+const POSITIVE_TTL: Duration = Duration::from_secs(3600);
+
+/// How long we remember that a store does not have a file.
+const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+
+/// Number of entries above which expired entries are purged.
+const MAX_ENTRIES: usize = 1 << 20;
+
+/// A cache of the results of `Store::has()`.
+pub struct PresenceCache {
+    entries: Mutex<HashMap<Hash, (bool, Instant)>>,
+}
+
+impl PresenceCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn lookup(&self, file_hash: &Hash) -> Option<bool> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(file_hash) {
+            Some((present, expiry)) if *expiry > Instant::now() => Some(*present),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, file_hash: &Hash, present: bool) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (_, expiry)| *expiry > now);
+        }
+        let ttl = if present { POSITIVE_TTL } else { NEGATIVE_TTL };
+        entries.insert(file_hash.clone(), (present, now + ttl));
+    }
+}
+
+/// A store wrapper that caches the results of `has()`, and updates
+/// the cache on `add()` and `get()`.
+pub struct CachedStore {
+    inner: Arc<dyn Store>,
+    cache: Arc<PresenceCache>,
+}
+
+impl CachedStore {
+    pub fn new(inner: Arc<dyn Store>) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(PresenceCache::new()),
+        }
+    }
+}
+
+impl Store for CachedStore {
+    fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inner.add(&file_hash, data).await?;
+            self.cache.insert(&file_hash, true);
+            Ok(())
+        })
+    }
+
+    fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            if let Some(present) = self.cache.lookup(&file_hash) {
+                return Ok(present);
+            }
+            let present = self.inner.has(&file_hash).await?;
+            self.cache.insert(&file_hash, present);
+            Ok(present)
+        })
+    }
+
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        Box::pin(async move {
+            let mut present = HashSet::new();
+            let mut unknown = vec![];
+            for file_hash in file_hashes {
+                match self.cache.lookup(file_hash) {
+                    Some(true) => {
+                        present.insert(file_hash.clone());
+                    }
+                    Some(false) => {}
+                    None => unknown.push(file_hash.clone()),
+                }
+            }
+            if !unknown.is_empty() {
+                let found = self.inner.has_many(&unknown).await?;
+                for file_hash in unknown {
+                    let is_present = found.contains(&file_hash);
+                    self.cache.insert(&file_hash, is_present);
+                    if is_present {
+                        present.insert(file_hash);
+                    }
+                }
+            }
+            Ok(present)
+        })
+    }
+
+    fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            match self.inner.get(&file_hash, offset, size).await {
+                Err(Error::NoSuchHash(h)) => {
+                    self.cache.insert(&file_hash, false);
+                    Err(Error::NoSuchHash(h))
+                }
+                res => res,
+            }
+        })
+    }
+
+    fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        let fut = self.inner.create_file()?;
+        Some(Box::pin(async move {
+            let file: Box<dyn MutableFile> = Box::new(CachedMutableFile {
+                inner: fut.await?,
+                cache: Arc::clone(&self.cache),
+            });
+            Ok(file)
+        }))
+    }
+
+    fn reopen_file<'a>(&'a self, path: &'a Path) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        let fut = self.inner.reopen_file(path)?;
+        Some(Box::pin(async move {
+            let file: Box<dyn MutableFile> = Box::new(CachedMutableFile {
+                inner: fut.await?,
+                cache: Arc::clone(&self.cache),
+            });
+            Ok(file)
+        }))
+    }
+
+    fn get_config(&self) -> Result<Config> {
+        self.inner.get_config()
+    }
+
+    fn get_url(&self) -> String {
+        self.inner.get_url()
+    }
+
+    fn is_reference(&self) -> bool {
+        self.inner.is_reference()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.inner.is_writable()
+    }
+
+    fn write_ledger<'a>(&'a self, fs_id: &'a str, hashes: Vec<Hash>) -> Future<'a, ()> {
+        self.inner.write_ledger(fs_id, hashes)
+    }
+
+    fn in_other_ledgers<'a>(&'a self, fs_id: &'a str, file_hash: &Hash) -> Future<'a, bool> {
+        self.inner.in_other_ledgers(fs_id, file_hash)
+    }
+
+    fn find_orphans<'a>(&'a self, remove: bool) -> Future<'a, Vec<String>> {
+        self.inner.find_orphans(remove)
+    }
+
+    fn erase<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inner.erase(&file_hash).await?;
+            self.cache.insert(&file_hash, false);
+            Ok(())
+        })
+    }
+
+    fn adopt<'a>(&'a self, file_hash: &Hash, path: &'a Path, link: bool) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inner.adopt(&file_hash, path, link).await?;
+            self.cache.insert(&file_hash, true);
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.inner.remove(&file_hash).await?;
+            self.cache.insert(&file_hash, false);
+            Ok(())
+        })
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        self.inner.max_size()
+    }
+
+    fn used_bytes<'a>(&'a self) -> Future<'a, u64> {
+        self.inner.used_bytes()
+    }
+
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        self.inner.list()
+    }
+
+    fn last_access<'a>(&'a self, file_hash: &Hash) -> Future<'a, Option<SystemTime>> {
+        self.inner.last_access(file_hash)
+    }
+
+    fn key_fingerprint(&self) -> Option<crate::encrypted_store::KeyFingerprint> {
+        self.inner.key_fingerprint()
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        self.inner.check_health()
+    }
+
+    fn recalls(&self) -> Vec<RecallInfo> {
+        self.inner.recalls()
+    }
+
+    fn process_recalls<'a>(&'a self) -> Future<'a, usize> {
+        self.inner.process_recalls()
+    }
+
+    fn cluster<'a>(&'a self, name: &'a str, file_hashes: &'a [Hash]) -> Future<'a, usize> {
+        self.inner.cluster(name, file_hashes)
+    }
+}
+
+struct CachedMutableFile {
+    inner: Box<dyn MutableFile>,
+    cache: Arc<PresenceCache>,
+}
+
+impl MutableFile for CachedMutableFile {
+    fn write<'a>(&'a self, offset: u64, data: &'a [u8]) -> Future<'a, ()> {
+        self.inner.write(offset, data)
+    }
+
+    fn read<'a>(&'a self, offset: u64, size: u32) -> Future<'a, Vec<u8>> {
+        self.inner.read(offset, size)
+    }
+
+    fn finish<'a>(&'a self, progress: ProgressFn<'a>) -> Future<'a, (u64, Hash)> {
+        Box::pin(async move {
+            let (len, hash) = self.inner.finish(progress).await?;
+            self.cache.insert(&hash, true);
+            Ok((len, hash))
+        })
+    }
+
+    fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    fn path(&self) -> Option<PathBuf> {
+        self.inner.path()
+    }
+
+    fn flush<'a>(&'a self) -> Future<'a, ()> {
+        self.inner.flush()
+    }
+
+    fn sync<'a>(&'a self, datasync: bool) -> Future<'a, ()> {
+        self.inner.sync(datasync)
+    }
+
+    fn set_len<'a>(&'a self, len: u64) -> Future<'a, ()> {
+        self.inner.set_len(len)
+    }
+
+    fn seek_extent<'a>(&'a self, offset: u64, data: bool) -> Future<'a, Option<u64>> {
+        self.inner.seek_extent(offset, data)
+    }
+}
@@ -0,0 +1,81 @@
+use crate::error::Error;
+use crate::hash::Hash;
+use crate::store::{Config, Future, MutableFile, RecallInfo, Result, Store};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// A store wrapper that makes a store read-only. Its files count as
+/// copies for status reporting, but nothing is ever written to or
+/// deleted from it. This is useful for stores on external drives
+/// that are only connected occasionally.
+pub struct ReferenceStore {
+    inner: Arc<dyn Store>,
+}
+
+impl ReferenceStore {
+    pub fn new(inner: Arc<dyn Store>) -> Self {
+        Self { inner }
+    }
+}
+
+impl Store for ReferenceStore {
+    fn add<'a>(&'a self, _file_hash: &Hash, _data: &'a [u8]) -> Future<'a, ()> {
+        Box::pin(async move { Err(Error::ReferenceStore(self.get_url())) })
+    }
+
+    fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool> {
+        self.inner.has(file_hash)
+    }
+
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        self.inner.has_many(file_hashes)
+    }
+
+    fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>> {
+        self.inner.get(file_hash, offset, size)
+    }
+
+    fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        None
+    }
+
+    fn erase<'a>(&'a self, _file_hash: &Hash) -> Future<'a, ()> {
+        Box::pin(async move { Err(Error::ReferenceStore(self.get_url())) })
+    }
+
+    fn remove<'a>(&'a self, _file_hash: &Hash) -> Future<'a, ()> {
+        Box::pin(async move { Err(Error::ReferenceStore(self.get_url())) })
+    }
+
+    fn get_config(&self) -> Result<Config> {
+        self.inner.get_config()
+    }
+
+    fn get_url(&self) -> String {
+        self.inner.get_url()
+    }
+
+    fn is_reference(&self) -> bool {
+        true
+    }
+
+    fn key_fingerprint(&self) -> Option<crate::encrypted_store::KeyFingerprint> {
+        self.inner.key_fingerprint()
+    }
+
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        self.inner.list()
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        self.inner.check_health()
+    }
+
+    fn recalls(&self) -> Vec<RecallInfo> {
+        self.inner.recalls()
+    }
+
+    fn process_recalls<'a>(&'a self) -> Future<'a, usize> {
+        self.inner.process_recalls()
+    }
+}
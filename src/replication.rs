@@ -0,0 +1,64 @@
+use crate::fs::Superblock;
+use crate::fusefs::FilesystemState;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+/// How often the superblock is shipped to, or reloaded by, a standby.
+const REPLICATION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Write the superblock to `dest` whenever it has changed, so that a
+/// standby can mount the same stores with near-live metadata. `dest`
+/// is replaced atomically, so it's always a complete state file.
+pub async fn replicate_state(state: Arc<RwLock<FilesystemState>>, dest: PathBuf) {
+    let mut last = vec![];
+    loop {
+        let mut json = vec![];
+        let res = state.read().unwrap().superblock.write_json(&mut json);
+        if let Err(err) = res {
+            error!("Cannot serialize state for '{}': {}", dest.display(), err);
+        } else if json != last {
+            let mut temp_path = dest.clone();
+            temp_path.set_extension("json.tmp");
+            match tokio::fs::write(&temp_path, &json)
+                .await
+                .and(tokio::fs::rename(&temp_path, &dest).await)
+            {
+                Ok(()) => last = json,
+                Err(err) => error!("Cannot replicate state to '{}': {}", dest.display(), err),
+            }
+        }
+        tokio::time::delay_for(REPLICATION_INTERVAL).await;
+    }
+}
+
+/// Reload the superblock from `state_file` whenever the primary has
+/// replaced it.
+pub async fn follow_state(state: Arc<RwLock<FilesystemState>>, state_file: PathBuf) {
+    let mut last_mtime: Option<SystemTime> = None;
+    loop {
+        let mtime = tokio::fs::metadata(&state_file)
+            .await
+            .and_then(|st| st.modified())
+            .ok();
+        if mtime.is_some() && mtime != last_mtime {
+            let path = state_file.clone();
+            match tokio::task::spawn_blocking(move || Superblock::open(&path))
+                .await
+                .unwrap()
+            {
+                Ok(mut superblock) => {
+                    if last_mtime.is_some() {
+                        info!("Reloaded state from '{}'.", state_file.display());
+                    }
+                    superblock.read_only = true;
+                    state.write().unwrap().superblock = superblock;
+                    last_mtime = mtime;
+                }
+                Err(err) => error!("Cannot reload '{}': {}", state_file.display(), err),
+            }
+        }
+        tokio::time::delay_for(REPLICATION_INTERVAL).await;
+    }
+}
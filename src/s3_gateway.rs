@@ -0,0 +1,585 @@
+use crate::error::{Error, Result};
+use crate::fs::{Contents, Ino, Superblock, Time};
+use crate::fusefs::{read_hash, FilesystemState};
+use crate::hash::Hash;
+use hyper::header::{
+    HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LAST_MODIFIED,
+    RANGE, RETRY_AFTER,
+};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Server, StatusCode};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tracing::{debug, error, info};
+
+/// Size of the reads from the stores while sending an object.
+const GET_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Default and maximum number of keys returned by ListObjectsV2.
+const MAX_KEYS: usize = 1000;
+
+type HttpRequest = hyper::Request<Body>;
+type HttpResponse = hyper::Response<Body>;
+
+/// An immutable file in the filesystem, as seen by S3 clients.
+struct Object {
+    hash: Hash,
+    length: u64,
+    mtime: Time,
+}
+
+/// Serve the filesystem read-only on `addr` through a minimal subset
+/// of the S3 API (ListObjectsV2, GetObject and HeadObject), using
+/// path-style URLs ('/<bucket>/<key>'). The keys are the paths of
+/// the finalized files in the filesystem; mutable files are not
+/// visible. Requests are not authenticated, so this should only
+/// listen on trusted networks.
+pub async fn serve(addr: SocketAddr, bucket: String, state: Arc<RwLock<FilesystemState>>) {
+    let bucket = Arc::new(bucket);
+
+    let make_service = make_service_fn(move |_| {
+        let bucket = Arc::clone(&bucket);
+        let state = Arc::clone(&state);
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                handle(req, Arc::clone(&bucket), Arc::clone(&state))
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(err) => {
+            error!("Cannot listen on {}: {}", addr, err);
+            return;
+        }
+    };
+
+    info!("Serving bucket '{}' over S3 on {}.", bucket, addr);
+
+    if let Err(err) = server.await {
+        error!("S3 gateway failed: {}", err);
+    }
+}
+
+async fn handle(
+    req: HttpRequest,
+    bucket: Arc<String>,
+    state: Arc<RwLock<FilesystemState>>,
+) -> std::result::Result<HttpResponse, hyper::Error> {
+    debug!("S3 request: {} {}", req.method(), req.uri());
+
+    let path = match percent_decode(req.uri().path()) {
+        Some(path) => path,
+        None => return Ok(s3_error(StatusCode::BAD_REQUEST, "InvalidURI", "")),
+    };
+    let path = path.trim_start_matches('/');
+    let (name, key) = match path.find('/') {
+        Some(n) => (&path[..n], &path[n + 1..]),
+        None => (path, ""),
+    };
+
+    if name != bucket.as_str() {
+        return Ok(s3_error(StatusCode::NOT_FOUND, "NoSuchBucket", name));
+    }
+
+    let head = match *req.method() {
+        Method::GET => false,
+        Method::HEAD => true,
+        _ => {
+            return Ok(s3_error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                key,
+            ))
+        }
+    };
+
+    if key.is_empty() {
+        let query = parse_query(req.uri().query().unwrap_or(""));
+        return Ok(list_objects(&bucket, &query, &state));
+    }
+
+    let range = req
+        .headers()
+        .get(RANGE)
+        .map(|value| value.to_str().unwrap_or("").to_string());
+
+    Ok(get_object(key, range.as_deref(), head, state).await)
+}
+
+/// Return the immutable file at `key`, if any.
+fn lookup_object(superblock: &Superblock, key: &str) -> Option<Object> {
+    let inode = superblock.lookup_path(Path::new(key)).ok()?;
+    let inode = inode.read().unwrap();
+    match &inode.contents {
+        Contents::RegularFile(file) => Some(Object {
+            hash: file.hash.clone(),
+            length: file.length,
+            mtime: inode.mtime,
+        }),
+        _ => None,
+    }
+}
+
+/// What a `ListWalk` should do after visiting a key.
+enum Visit {
+    Continue,
+    /// Skip the keys starting with this prefix.
+    SkipPrefix(String),
+    Stop,
+}
+
+/// A walk over the immutable files in the filesystem in key order,
+/// skipping subtrees that can't contain keys of interest.
+struct ListWalk<'a> {
+    superblock: &'a Superblock,
+    prefix: &'a str,
+    /// Only keys after this one are visited.
+    start_after: String,
+    /// Keys starting with this are not visited.
+    skip_prefix: Option<String>,
+}
+
+impl<'a> ListWalk<'a> {
+    fn wants_key(&self, key: &str) -> bool {
+        key.starts_with(self.prefix)
+            && key > self.start_after.as_str()
+            && self
+                .skip_prefix
+                .as_ref()
+                .map_or(true, |p| !key.starts_with(p.as_str()))
+    }
+
+    /// Whether any key starting with `dir_key` may be visited.
+    fn wants_dir(&self, dir_key: &str) -> bool {
+        (dir_key.starts_with(self.prefix) || self.prefix.starts_with(dir_key))
+            && (dir_key > self.start_after.as_str() || self.start_after.starts_with(dir_key))
+            && self
+                .skip_prefix
+                .as_ref()
+                .map_or(true, |p| !dir_key.starts_with(p.as_str()))
+    }
+
+    /// Call `f` on the immutable files below directory `ino`, whose
+    /// keys start with `dir_key`, in key order. Returns `false` if `f`
+    /// stopped the walk.
+    fn walk(
+        &mut self,
+        ino: Ino,
+        dir_key: &str,
+        f: &mut dyn FnMut(String, Object) -> Visit,
+    ) -> Result<bool> {
+        /* Sort the entries by key rather than by name, since the keys
+         * of a directory 'a' ('a/...') sort after a file 'a-b'. */
+        let mut children = vec![];
+        {
+            let dir = self.superblock.get_inode(ino)?;
+            let dir = dir.read().unwrap();
+            for (name, child_ino) in &dir.get_directory()?.entries {
+                let child = self.superblock.get_inode(*child_ino)?;
+                let child = child.read().unwrap();
+                match &child.contents {
+                    Contents::RegularFile(file) => children.push((
+                        format!("{}{}", dir_key, name),
+                        *child_ino,
+                        Some(Object {
+                            hash: file.hash.clone(),
+                            length: file.length,
+                            mtime: child.mtime,
+                        }),
+                    )),
+                    Contents::Directory(_) => {
+                        children.push((format!("{}{}/", dir_key, name), *child_ino, None))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (key, child_ino, object) in children {
+            match object {
+                Some(object) => {
+                    if self.wants_key(&key) {
+                        match f(key, object) {
+                            Visit::Continue => {}
+                            Visit::SkipPrefix(prefix) => self.skip_prefix = Some(prefix),
+                            Visit::Stop => return Ok(false),
+                        }
+                    }
+                }
+                None => {
+                    if self.wants_dir(&key) && !self.walk(child_ino, &key, f)? {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+pub(crate) fn list_objects(
+    bucket: &str,
+    query: &BTreeMap<String, String>,
+    state: &RwLock<FilesystemState>,
+) -> HttpResponse {
+    if query.get("list-type").map(|s| s.as_str()) != Some("2") {
+        return s3_error(
+            StatusCode::NOT_IMPLEMENTED,
+            "NotImplemented",
+            "Only ListObjectsV2 is supported.",
+        );
+    }
+
+    let prefix = query.get("prefix").cloned().unwrap_or_default();
+    let delimiter = query.get("delimiter").cloned().unwrap_or_default();
+    let max_keys = match query.get("max-keys").map(|s| s.parse::<usize>()) {
+        None => MAX_KEYS,
+        Some(Ok(n)) => std::cmp::min(n, MAX_KEYS),
+        Some(Err(_)) => return s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", "max-keys"),
+    };
+    // The continuation token is the last key or common prefix
+    // returned. Keys below a returned common prefix are skipped.
+    let token = query.get("continuation-token").cloned();
+    let start_after = token
+        .clone()
+        .or_else(|| query.get("start-after").cloned())
+        .unwrap_or_default();
+    let skip_prefix = token.filter(|token| !delimiter.is_empty() && token.ends_with(&delimiter));
+
+    let mut contents = vec![];
+    let mut common_prefixes: Vec<String> = vec![];
+    let mut last_key = None;
+    let mut truncated = false;
+    let mut visit = |key: String, object: Object| {
+        let rest = &key[prefix.len()..];
+        let common_prefix = match rest.find(delimiter.as_str()) {
+            Some(n) if !delimiter.is_empty() => {
+                Some(key[..prefix.len() + n + delimiter.len()].to_string())
+            }
+            _ => None,
+        };
+        if contents.len() + common_prefixes.len() >= max_keys {
+            truncated = true;
+            return Visit::Stop;
+        }
+        match common_prefix {
+            Some(common_prefix) => {
+                last_key = Some(common_prefix.clone());
+                common_prefixes.push(common_prefix.clone());
+                // The other keys with this prefix aren't listed.
+                Visit::SkipPrefix(common_prefix)
+            }
+            None => {
+                last_key = Some(key.clone());
+                contents.push((key, object));
+                Visit::Continue
+            }
+        }
+    };
+
+    {
+        let state = state.read().unwrap();
+        let superblock = &state.superblock;
+        // Start at the directory containing the prefix.
+        let dir_key = &prefix[..prefix.rfind('/').map_or(0, |n| n + 1)];
+        let dir = superblock.lookup_path(Path::new(dir_key)).ok();
+        let dir = dir.filter(|dir| dir.read().unwrap().get_directory().is_ok());
+        if let Some(dir) = dir {
+            let ino = dir.read().unwrap().ino;
+            let mut walk = ListWalk {
+                superblock,
+                prefix: &prefix,
+                start_after,
+                skip_prefix,
+            };
+            if let Err(err) = walk.walk(ino, dir_key, &mut visit) {
+                return s3_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "InternalError",
+                    &err.to_string(),
+                );
+            }
+        }
+    }
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">");
+    write!(xml, "<Name>{}</Name>", xml_escape(bucket)).unwrap();
+    write!(xml, "<Prefix>{}</Prefix>", xml_escape(&prefix)).unwrap();
+    if !delimiter.is_empty() {
+        write!(xml, "<Delimiter>{}</Delimiter>", xml_escape(&delimiter)).unwrap();
+    }
+    write!(xml, "<MaxKeys>{}</MaxKeys>", max_keys).unwrap();
+    write!(
+        xml,
+        "<KeyCount>{}</KeyCount>",
+        contents.len() + common_prefixes.len()
+    )
+    .unwrap();
+    write!(xml, "<IsTruncated>{}</IsTruncated>", truncated).unwrap();
+    if truncated {
+        if let Some(last_key) = last_key {
+            write!(
+                xml,
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                xml_escape(&last_key)
+            )
+            .unwrap();
+        }
+    }
+    for (key, object) in contents {
+        write!(
+            xml,
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>&quot;{}&quot;</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+            xml_escape(&key),
+            format_iso8601(&object.mtime),
+            etag(&object.hash),
+            object.length
+        )
+        .unwrap();
+    }
+    for common_prefix in common_prefixes {
+        write!(
+            xml,
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            xml_escape(&common_prefix)
+        )
+        .unwrap();
+    }
+    xml.push_str("</ListBucketResult>");
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/xml")
+        .body(Body::from(xml))
+        .unwrap()
+}
+
+async fn get_object(
+    key: &str,
+    range: Option<&str>,
+    head: bool,
+    state: Arc<RwLock<FilesystemState>>,
+) -> HttpResponse {
+    let object = match lookup_object(&state.read().unwrap().superblock, key) {
+        Some(object) => object,
+        None => return s3_error(StatusCode::NOT_FOUND, "NoSuchKey", key),
+    };
+
+    let (status, start, end) = match range {
+        None => (StatusCode::OK, 0, object.length),
+        Some(range) => match parse_range(range, object.length) {
+            Some((start, end)) => (StatusCode::PARTIAL_CONTENT, start, end),
+            None => return s3_error(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange", range),
+        },
+    };
+
+    let mut res = hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_LENGTH, end - start)
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, format!("\"{}\"", etag(&object.hash)))
+        .header(LAST_MODIFIED, format_http_date(&object.mtime));
+    if status == StatusCode::PARTIAL_CONTENT {
+        res = res.header(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, object.length),
+        );
+    }
+
+    if head || start == end {
+        return res.body(Body::empty()).unwrap();
+    }
+
+    /* Read the first chunk before answering, so that an unavailable
+     * file gets a proper error response. */
+    let size = std::cmp::min(end - start, GET_CHUNK_SIZE);
+    let first = match read_hash(&state, &object.hash, object.length, start, size).await {
+        Ok(data) => data,
+        Err(err @ Error::RecallPending(_, _)) => {
+            info!("{}", err);
+            return s3_error(StatusCode::SERVICE_UNAVAILABLE, "SlowDown", key);
+        }
+        Err(err) => {
+            error!("Cannot read '{}' for S3 client: {}", key, err);
+            return s3_error(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", key);
+        }
+    };
+
+    let (mut sender, body) = Body::channel();
+    let key = key.to_string();
+    tokio::spawn(async move {
+        let mut offset = start + first.len() as u64;
+        if sender.send_data(first.into()).await.is_err() {
+            return;
+        }
+        while offset < end {
+            let size = std::cmp::min(end - offset, GET_CHUNK_SIZE);
+            let data = match read_hash(&state, &object.hash, object.length, offset, size).await {
+                Ok(data) => data,
+                Err(err) => {
+                    error!("Cannot read '{}' for S3 client: {}", key, err);
+                    sender.abort();
+                    return;
+                }
+            };
+            offset += data.len() as u64;
+            if sender.send_data(data.into()).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    res.body(body).unwrap()
+}
+
+/// Parse an HTTP byte range ('bytes=<start>-<end>', 'bytes=<start>-'
+/// or 'bytes=-<suffix length>') of a file of `length` bytes into a
+/// half-open interval.
+pub(crate) fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    let (first, last) = spec.split_at(spec.find('-')?);
+    let last = &last[1..];
+    let (start, end) = if first.is_empty() {
+        let suffix: u64 = last.parse().ok()?;
+        (length.saturating_sub(suffix), length)
+    } else {
+        let start: u64 = first.parse().ok()?;
+        let end = if last.is_empty() {
+            length
+        } else {
+            std::cmp::min(last.parse::<u64>().ok()?.checked_add(1)?, length)
+        };
+        (start, end)
+    };
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+pub(crate) fn parse_query(query: &str) -> BTreeMap<String, String> {
+    query
+        .split('&')
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| {
+            let (name, value) = match s.find('=') {
+                Some(n) => (&s[..n], &s[n + 1..]),
+                None => (s, ""),
+            };
+            Some((percent_decode(name)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            res.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            res.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(res).ok()
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// The ETag of an object. S3 clients only compare it, so any stable
+/// value will do; we use a prefix of the content hash.
+fn etag(hash: &Hash) -> String {
+    hash.to_hex()[..32].to_string()
+}
+
+fn s3_error(status: StatusCode, code: &str, resource: &str) -> HttpResponse {
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<Error><Code>{}</Code><Resource>{}</Resource></Error>",
+        code,
+        xml_escape(resource)
+    );
+    let mut res = hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/xml");
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        res = res.header(RETRY_AFTER, HeaderValue::from_static("60"));
+    }
+    res.body(Body::from(xml)).unwrap()
+}
+
+/// Convert `time` to UTC (year, month, day, hour, minute, second,
+/// weekday with 0 = Sunday).
+fn to_utc(time: &Time) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let secs = time.0.div_euclid(1_000_000_000);
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+
+    // Howard Hinnant's civil_from_days.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let weekday = (days + 4).rem_euclid(7) as u32;
+
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+        weekday,
+    )
+}
+
+fn format_iso8601(time: &Time) -> String {
+    let (year, month, day, hour, min, sec, _) = to_utc(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year, month, day, hour, min, sec
+    )
+}
+
+pub(crate) fn format_http_date(time: &Time) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, min, sec, weekday) = to_utc(time);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[weekday as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        min,
+        sec
+    )
+}
@@ -1,7 +1,7 @@
 use crate::hash::Hash;
 use crate::store::Store;
 use futures::{compat::Future01CompatExt, future::FutureExt};
-use log::debug;
+use tracing::debug;
 use rusoto_core::Region;
 use rusoto_s3::{GetObjectRequest, S3Client, S3};
 
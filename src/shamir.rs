@@ -0,0 +1,110 @@
+/* Shamir secret sharing over GF(2^8), used to split a store key into
+ * shares such that any `threshold` of them suffice to reconstruct
+ * it. */
+
+use std::fs::File;
+use std::io::Read;
+
+/// Multiply in GF(2^8) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut res = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            res ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    res
+}
+
+fn inv(a: u8) -> u8 {
+    assert_ne!(a, 0);
+    // a^254 = a^-1 since the multiplicative group has order 255.
+    let mut res = 1;
+    for _ in 0..254 {
+        res = mul(res, a);
+    }
+    res
+}
+
+pub struct Share {
+    pub x: u8,
+    pub y: Vec<u8>,
+}
+
+/// Split `secret` into `n` shares, any `threshold` of which can
+/// reconstruct it.
+pub fn split(secret: &[u8], n: u8, threshold: u8) -> std::io::Result<Vec<Share>> {
+    assert!(threshold >= 1 && threshold <= n);
+
+    /* For every byte of the secret, generate a random polynomial of
+     * degree threshold - 1 whose constant term is that byte. */
+    let mut coeffs = vec![0u8; secret.len() * (threshold as usize - 1)];
+    File::open("/dev/urandom")?.read_exact(&mut coeffs)?;
+
+    Ok((1..=n)
+        .map(|x| Share {
+            x,
+            y: secret
+                .iter()
+                .enumerate()
+                .map(|(i, b)| {
+                    let degree = threshold as usize - 1;
+                    let poly = &coeffs[i * degree..(i + 1) * degree];
+                    // Horner's method, with the constant term added last.
+                    poly.iter().rev().fold(0, |acc, c| mul(acc ^ c, x)) ^ b
+                })
+                .collect(),
+        })
+        .collect())
+}
+
+/// Reconstruct a secret from at least `threshold` shares using
+/// Lagrange interpolation at x = 0. Fewer or invalid shares would
+/// silently yield a wrong secret, so they're rejected.
+pub fn combine(shares: &[Share], threshold: u8) -> std::io::Result<Vec<u8>> {
+    let invalid = |msg: String| Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+
+    if shares.is_empty() || shares.len() < usize::from(threshold) {
+        return invalid(format!(
+            "{} key shares are needed, but {} were given",
+            std::cmp::max(threshold, 1),
+            shares.len()
+        ));
+    }
+
+    let len = shares[0].y.len();
+
+    for (i, share) in shares.iter().enumerate() {
+        if share.x == 0 {
+            return invalid("key share has x-coordinate 0".into());
+        }
+        if shares[..i].iter().any(|s| s.x == share.x) {
+            return invalid(format!("key share {} was given more than once", share.x));
+        }
+        if share.y.len() != len {
+            return invalid("key shares have different lengths".into());
+        }
+    }
+
+    let mut secret = vec![0u8; len];
+
+    for (i, share_i) in shares.iter().enumerate() {
+        let mut basis = 1;
+        for (j, share_j) in shares.iter().enumerate() {
+            if i != j {
+                basis = mul(basis, mul(share_j.x, inv(share_i.x ^ share_j.x)));
+            }
+        }
+        for (s, y) in secret.iter_mut().zip(&share_i.y) {
+            *s ^= mul(basis, *y);
+        }
+    }
+
+    Ok(secret)
+}
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreCounters {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+}
+
+/// Latencies and concurrency of one type of FUSE request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OpMetrics {
+    /// Number of requests currently being processed.
+    pub in_flight: u64,
+    /// Highest number of requests processed at the same time.
+    pub max_in_flight: u64,
+    pub completed: u64,
+    pub total_micros: u64,
+    pub max_micros: u64,
+    /// Number of requests that failed because they exceeded their
+    /// deadline.
+    #[serde(default)]
+    pub timed_out: u64,
+}
+
+/// Counters of the activity since the filesystem was mounted,
+/// reported by 'hugefs stats'.
+pub struct Counters {
+    started: Instant,
+    ops: Mutex<BTreeMap<&'static str, u64>>,
+    op_metrics: Mutex<BTreeMap<&'static str, OpMetrics>>,
+    stores: Mutex<BTreeMap<String, StoreCounters>>,
+    readahead_hits: AtomicU64,
+    readahead_misses: AtomicU64,
+    deduplicated_bytes: AtomicU64,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            ops: Mutex::new(BTreeMap::new()),
+            op_metrics: Mutex::new(BTreeMap::new()),
+            stores: Mutex::new(BTreeMap::new()),
+            readahead_hits: AtomicU64::new(0),
+            readahead_misses: AtomicU64::new(0),
+            deduplicated_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a FUSE operation.
+    pub fn op(&self, name: &'static str) {
+        *self.ops.lock().unwrap().entry(name).or_insert(0) += 1;
+    }
+
+    /// Record that a request of type `op` has started executing.
+    pub fn begin(&self, op: &'static str) {
+        let mut metrics = self.op_metrics.lock().unwrap();
+        let m = metrics.entry(op).or_default();
+        m.in_flight += 1;
+        m.max_in_flight = m.max_in_flight.max(m.in_flight);
+    }
+
+    /// Record that a request of type `op` has finished after `elapsed`.
+    pub fn end(&self, op: &'static str, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let mut metrics = self.op_metrics.lock().unwrap();
+        let m = metrics.entry(op).or_default();
+        m.in_flight -= 1;
+        m.completed += 1;
+        m.total_micros += micros;
+        m.max_micros = m.max_micros.max(micros);
+    }
+
+    /// Record that a request of type `op` exceeded its deadline.
+    pub fn timed_out(&self, op: &'static str) {
+        self.op_metrics
+            .lock()
+            .unwrap()
+            .entry(op)
+            .or_default()
+            .timed_out += 1;
+    }
+
+    fn store(&self, url: &str, f: impl FnOnce(&mut StoreCounters)) {
+        let mut stores = self.stores.lock().unwrap();
+        match stores.get_mut(url) {
+            Some(counters) => f(counters),
+            None => f(stores.entry(url.into()).or_default()),
+        }
+    }
+
+    pub fn read(&self, url: &str, bytes: u64) {
+        self.store(url, |c| c.bytes_read += bytes);
+    }
+
+    pub fn written(&self, url: &str, bytes: u64) {
+        self.store(url, |c| c.bytes_written += bytes);
+    }
+
+    pub fn error(&self, url: &str) {
+        self.store(url, |c| c.errors += 1);
+    }
+
+    /// Count a read that was (or wasn't) served from the read-ahead
+    /// buffer.
+    pub fn readahead(&self, hit: bool) {
+        if hit {
+            &self.readahead_hits
+        } else {
+            &self.readahead_misses
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the bytes of a finalized file whose contents already
+    /// existed.
+    pub fn deduplicated(&self, bytes: u64) {
+        self.deduplicated_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uptime(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    pub fn ops(&self) -> BTreeMap<String, u64> {
+        self.ops
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, n)| (op.to_string(), *n))
+            .collect()
+    }
+
+    pub fn op_metrics(&self) -> BTreeMap<String, OpMetrics> {
+        self.op_metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(op, m)| (op.to_string(), m.clone()))
+            .collect()
+    }
+
+    pub fn stores(&self) -> BTreeMap<String, StoreCounters> {
+        self.stores.lock().unwrap().clone()
+    }
+
+    pub fn readahead_hits(&self) -> u64 {
+        self.readahead_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn readahead_misses(&self) -> u64 {
+        self.readahead_misses.load(Ordering::Relaxed)
+    }
+
+    pub fn deduplicated_bytes(&self) -> u64 {
+        self.deduplicated_bytes.load(Ordering::Relaxed)
+    }
+}
@@ -1,32 +1,211 @@
+use crate::encrypted_store::{EncryptedStore, Keys};
 use crate::error::Error;
 use crate::hash::Hash;
-use serde::Deserialize;
+use crate::local_store::LocalStore;
+use crate::memory_store::MemoryStore;
+use crate::presence_cache::CachedStore;
+use crate::reference_store::ReferenceStore;
+use crate::tape_store::TapeStore;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tracing::debug;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub type Future<'a, Res> =
     std::pin::Pin<Box<dyn std::future::Future<Output = Result<Res>> + Send + 'a>>;
 
+/// A function called with the number of bytes processed so far.
+pub type ProgressFn<'a> = &'a (dyn Fn(u64) + Send + Sync);
+
 pub trait Store: Send + Sync {
     fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()>;
 
     fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool>;
 
+    /// Return which of the given files this store has. Stores can
+    /// override this to check many files at once.
+    fn has_many<'a>(&'a self, file_hashes: &'a [Hash]) -> Future<'a, HashSet<Hash>> {
+        Box::pin(async move {
+            let mut present = HashSet::new();
+            for file_hash in file_hashes {
+                if self.has(file_hash).await? {
+                    present.insert(file_hash.clone());
+                }
+            }
+            Ok(present)
+        })
+    }
+
     fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>>;
 
     fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn MutableFile>>>;
 
+    /// Reopen a file created by `create_file()` in an earlier run,
+    /// given its `MutableFile::path()`. Returns `None` if this store
+    /// can't do that.
+    fn reopen_file<'a>(&'a self, _path: &'a Path) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        None
+    }
+
     fn get_config(&self) -> Result<Config> {
         Ok(Config::default())
     }
 
     fn get_url(&self) -> String;
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+
+    /// Whether this store is attached in reference mode, meaning
+    /// that files must never be added to or removed from it.
+    fn is_reference(&self) -> bool {
+        false
+    }
+
+    fn key_fingerprint(&self) -> Option<crate::encrypted_store::KeyFingerprint> {
+        None
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Record that the filesystem `fs_id` references the files with
+    /// the given hashes, so that garbage collection of this store
+    /// doesn't need access to the filesystem's metadata.
+    fn write_ledger<'a>(&'a self, _fs_id: &'a str, _hashes: Vec<Hash>) -> Future<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Whether the ledger of a filesystem other than `fs_id` lists
+    /// the file with the given hash.
+    fn in_other_ledgers<'a>(&'a self, _fs_id: &'a str, _file_hash: &Hash) -> Future<'a, bool> {
+        Box::pin(async { Ok(false) })
+    }
+
+    /// Return the mutable files in this store that were left behind
+    /// by a daemon that exited without finalizing them. Files that
+    /// are still listed in the daemon's state file are recovered by
+    /// its next mount, so only remove them (by setting `remove`)
+    /// once that has happened.
+    fn find_orphans<'a>(&'a self, _remove: bool) -> Future<'a, Vec<String>> {
+        Box::pin(async { Ok(vec![]) })
+    }
+
+    /// Delete the file with the given hash, overwriting its contents
+    /// first if the store supports it. Erasing a file that the store
+    /// doesn't have is not an error.
+    fn erase<'a>(&'a self, _file_hash: &Hash) -> Future<'a, ()> {
+        Box::pin(async move { Err(Error::EraseUnsupported(self.get_url())) })
+    }
+
+    /// Add the file at `path`, which has the given hash. Stores that
+    /// keep files on the local filesystem may hard link it if `link`
+    /// is set.
+    fn adopt<'a>(&'a self, file_hash: &Hash, path: &'a Path, _link: bool) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            let data = tokio::fs::read(path).await?;
+            self.add(&file_hash, &data).await
+        })
+    }
+
+    /// Delete the file with the given hash. Deleting a file that the
+    /// store doesn't have is not an error.
+    fn remove<'a>(&'a self, _file_hash: &Hash) -> Future<'a, ()> {
+        Box::pin(async move { Err(Error::RemoveUnsupported(self.get_url())) })
+    }
+
+    /// The number of bytes this store may use, beyond which files
+    /// that exist in other stores are evicted.
+    fn max_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// The number of bytes used by the files in this store.
+    fn used_bytes<'a>(&'a self) -> Future<'a, u64> {
+        Box::pin(async { Ok(0) })
+    }
+
+    /// When the file with the given hash was last read, if known.
+    fn last_access<'a>(&'a self, _file_hash: &Hash) -> Future<'a, Option<SystemTime>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Return the hashes and sizes of the files in this store.
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        Box::pin(async move { Err(Error::ListUnsupported(self.get_url())) })
+    }
+
+    /// Return the files that reads are waiting on to be brought
+    /// online, for stores with offline media such as tape.
+    fn recalls(&self) -> Vec<RecallInfo> {
+        vec![]
+    }
+
+    /// Bring the files returned by `recalls()` online. Returns the
+    /// number of recalled files.
+    fn process_recalls<'a>(&'a self) -> Future<'a, usize> {
+        Box::pin(async { Ok(0) })
+    }
+
+    /// Store the given files, which belong to the same directory,
+    /// close to each other so that restoring the directory reads
+    /// them sequentially. Returns the number of files that were
+    /// moved.
+    fn cluster<'a>(&'a self, _name: &'a str, _file_hashes: &'a [Hash]) -> Future<'a, usize> {
+        Box::pin(async { Ok(0) })
+    }
+}
+
+/// A file that was read from a store while it was offline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecallInfo {
+    pub store: String,
+    pub hash: Hash,
+    pub size: u64,
+    pub requested: SystemTime,
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
     pub key_fingerprint: Option<crate::encrypted_store::KeyFingerprint>,
+
+    /// If set, only encrypted data may be written to this store,
+    /// e.g. because it's synced to cloud storage.
+    #[serde(default)]
+    pub require_encryption: bool,
+
+    /// Maximum number of bytes to use (see `Store::max_size()`).
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// Whether `Store::cluster()` should group the files of a
+    /// directory together.
+    #[serde(default)]
+    pub cluster_by_directory: bool,
+
+    /// The version of the layout of the store. Stores without a
+    /// version have version 1 (see `local_store::CURRENT_FORMAT`).
+    #[serde(default)]
+    pub format_version: u32,
+}
+
+impl Config {
+    /// Fail if plaintext data would be written to a store with this
+    /// configuration.
+    pub fn check_plaintext_allowed(&self, url: &str) -> Result<()> {
+        if self.require_encryption && self.key_fingerprint.is_none() {
+            return Err(Error::EncryptionRequired(url.into()));
+        }
+        Ok(())
+    }
 }
 
 pub trait MutableFile: Send + Sync {
@@ -34,9 +213,138 @@ pub trait MutableFile: Send + Sync {
 
     fn read<'a>(&'a self, offset: u64, size: u32) -> Future<'a, Vec<u8>>;
 
-    fn finish<'a>(&'a self) -> Future<'a, (u64, Hash)>;
+    /// Hash the file and move it into the store. `progress` is
+    /// called with the number of bytes hashed so far.
+    fn finish<'a>(&'a self, progress: ProgressFn<'a>) -> Future<'a, (u64, Hash)>;
 
     fn len(&self) -> u64;
+
+    /// The file holding the data, if it survives a restart and can
+    /// be passed to `Store::reopen_file()`.
+    fn path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Wait until previous writes have been passed to the
+    /// underlying storage.
+    fn flush<'a>(&'a self) -> Future<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Flush and make previous writes durable. If `datasync` is set,
+    /// metadata doesn't need to be synced.
+    fn sync<'a>(&'a self, _datasync: bool) -> Future<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Truncate or extend the file to `len` bytes. Extending the
+    /// file should not allocate storage for the new range.
+    fn set_len<'a>(&'a self, _len: u64) -> Future<'a, ()> {
+        Box::pin(async { Err(Error::TruncateUnsupported) })
+    }
+
+    /// Return the offset of the first data byte (if `data` is set)
+    /// or hole at or after `offset`, like `SEEK_DATA` and
+    /// `SEEK_HOLE`. There is an implicit hole at the end of the
+    /// file. Returns `None` if `offset` is not before the end of the
+    /// file, or there is no data after it.
+    fn seek_extent<'a>(&'a self, offset: u64, data: bool) -> Future<'a, Option<u64>> {
+        let len = self.len();
+        Box::pin(async move {
+            Ok(if offset >= len {
+                None
+            } else if data {
+                Some(offset)
+            } else {
+                Some(len)
+            })
+        })
+    }
+}
+
+/// Size of the chunks in which `copy_file` reads from the source store.
+const COPY_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A function that opens a store given its URL.
+pub type StoreConstructor = fn(url: &str, keys: &Keys) -> Result<Arc<dyn Store>>;
+
+/// URL schemes handled by `open_store()` itself.
+const BUILTIN_SCHEMES: &[&str] = &["memory", "ref", "chaos", "tape"];
+
+/// Constructors of stores provided outside of hugefs, keyed by URL
+/// scheme.
+static SCHEMES: RwLock<BTreeMap<String, StoreConstructor>> = RwLock::new(BTreeMap::new());
+
+/// Make `open_store()` call `constructor` to open URLs of the form
+/// '<scheme>:...'. `constructor` receives the entire URL.
+pub fn register_scheme(scheme: &str, constructor: StoreConstructor) -> Result<()> {
+    let mut schemes = SCHEMES.write().unwrap();
+    if BUILTIN_SCHEMES.contains(&scheme) || schemes.contains_key(scheme) {
+        return Err(Error::SchemeExists(scheme.into()));
+    }
+    schemes.insert(scheme.into(), constructor);
+    Ok(())
+}
+
+/// Open the store at `url`, wrapping it in an `EncryptedStore` if
+/// its configuration says it's encrypted, and in a `CachedStore` to
+/// avoid repeated `has()` calls.
+pub fn open_store(url: &str, keys: &Keys) -> Result<Arc<dyn Store>> {
+    if url.starts_with("memory:") {
+        return Ok(Arc::new(MemoryStore::new(url)));
+    }
+
+    if let Some(pos) = url.find(':') {
+        let constructor = SCHEMES.read().unwrap().get(&url[..pos]).cloned();
+        if let Some(constructor) = constructor {
+            return constructor(url, keys);
+        }
+    }
+
+    /* 'ref:<url>' opens <url> in reference mode. */
+    if url.starts_with("ref:") {
+        let inner = open_store(&url[4..], keys)?;
+        return Ok(Arc::new(ReferenceStore::new(inner)));
+    }
+
+    /* 'tape:<path>' opens a tape store rooted at <path>. */
+    if url.starts_with("tape:") {
+        return Ok(Arc::new(TapeStore::new(url[5..].into())?));
+    }
+
+    /* 'chaos:<seed>:<url>' opens <url> with injected faults. */
+    #[cfg(feature = "chaos")]
+    {
+        if url.starts_with("chaos:") {
+            let mut parts = url.splitn(3, ':').skip(1);
+            let seed = parts
+                .next()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| Error::UnknownStore(url.into()))?;
+            let inner = open_store(parts.next().unwrap_or(""), keys)?;
+            return Ok(Arc::new(crate::flaky_store::FlakyStore::new(inner, seed)));
+        }
+    }
+
+    let mut store: Arc<dyn Store> = Arc::new(LocalStore::new(url.into())?);
+
+    let config = store.get_config()?;
+
+    if let Some(key_fingerprint) = &config.key_fingerprint {
+        debug!(
+            "Opening store '{}' using key with fingerprint {}.",
+            url,
+            key_fingerprint.0.to_hex()
+        );
+        let key = keys
+            .get(key_fingerprint)
+            .ok_or_else(|| Error::NoSuchKey(key_fingerprint.clone()))?;
+        store = Arc::new(EncryptedStore::new(store, key.clone()));
+    } else {
+        config.check_plaintext_allowed(url)?;
+    }
+
+    Ok(Arc::new(CachedStore::new(store)))
 }
 
 pub async fn copy_file(
@@ -44,14 +352,56 @@ pub async fn copy_file(
     size: u64,
     src_store: &dyn Store,
     dst_store: &dyn Store,
+    progress: &(dyn Fn(u64) + Send + Sync),
+    verify: bool,
 ) -> Result<()> {
-    // FIXME: copy in smaller chunks, or stream directly from src_store to dst_store.
+    // FIXME: stream directly from src_store to dst_store.
+
+    let mut data = Vec::with_capacity(usize::try_from(size).unwrap());
 
-    let data = src_store
-        .get(file_hash, 0, usize::try_from(size).unwrap())
-        .await?;
+    loop {
+        progress(data.len() as u64);
+        let chunk_size = std::cmp::min(COPY_CHUNK_SIZE, size - data.len() as u64);
+        let chunk = src_store
+            .get(
+                file_hash,
+                data.len() as u64,
+                usize::try_from(chunk_size).unwrap(),
+            )
+            .await?;
+        data.extend_from_slice(&chunk);
+        if chunk.is_empty() || data.len() as u64 >= size {
+            break;
+        }
+    }
+
+    progress(data.len() as u64);
+
+    // Don't store a truncated copy under the full file's hash.
+    if data.len() as u64 != size {
+        return Err(Error::MirrorCorrupt(src_store.get_url(), file_hash.clone()));
+    }
 
     dst_store.add(file_hash, &data).await?;
 
+    if verify {
+        let mut offset = 0;
+        while offset < data.len() {
+            let chunk_size = std::cmp::min(COPY_CHUNK_SIZE as usize, data.len() - offset);
+            let chunk = dst_store.get(file_hash, offset as u64, chunk_size).await?;
+            if chunk.is_empty() || chunk[..] != data[offset..offset + chunk.len()] {
+                return Err(Error::MirrorCorrupt(dst_store.get_url(), file_hash.clone()));
+            }
+            offset += chunk.len();
+        }
+    }
+
     Ok(())
 }
+
+/// Return whether a newly mirrored file should be read back to check
+/// it, given the percentage of files to check. The decision is based
+/// on the hash, which is uniformly distributed.
+pub fn should_verify(file_hash: &Hash, percent: u8) -> bool {
+    u32::from(file_hash.0[0]) * 100 < u32::from(percent) * 256
+}
@@ -0,0 +1,170 @@
+use crate::fuse_util::*;
+use crate::hash::Hash;
+use crate::stats::Counters;
+use crate::store::Store;
+use fuser::{FileAttr, FileType, Request};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::debug_span;
+
+const ROOT_INO: u64 = 1;
+
+/// Since the view never changes, the kernel may cache everything.
+const TTL: Duration = Duration::from_secs(86400);
+
+/// A read-only filesystem containing the files of a store, named by
+/// their hash. It doesn't need a superblock, so it can be used to
+/// recover files or to inspect a mirror. The listing is taken when
+/// the view is created.
+pub struct StoreView {
+    store: Arc<dyn Store>,
+    dispatcher: Dispatcher,
+    /// The files in the store, ordered by name. The inode number of
+    /// a file is its index plus 2.
+    files: Vec<(String, Hash, u64)>,
+    by_name: HashMap<String, u64>,
+    created: SystemTime,
+}
+
+impl StoreView {
+    pub async fn new(
+        store: Arc<dyn Store>,
+        executor: tokio::runtime::Handle,
+    ) -> crate::error::Result<Self> {
+        let mut files: Vec<_> = store
+            .list()
+            .await?
+            .into_iter()
+            .map(|(hash, size)| (hash.to_hex(), hash, size))
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        let by_name = files
+            .iter()
+            .enumerate()
+            .map(|(n, (name, _, _))| (name.clone(), n as u64 + 2))
+            .collect();
+        Ok(Self {
+            store,
+            dispatcher: Dispatcher::new(executor, Arc::new(Counters::new()), &[], &[]),
+            files,
+            by_name,
+            created: crate::clock::now(),
+        })
+    }
+
+    fn get_file(&self, ino: u64) -> Option<&(String, Hash, u64)> {
+        ino.checked_sub(2)
+            .and_then(|n| self.files.get(usize::try_from(n).ok()?))
+    }
+
+    fn attrs(&self, ino: u64) -> Option<FileAttr> {
+        let (kind, perm, size) = if ino == ROOT_INO {
+            (FileType::Directory, 0o555, self.files.len() as u64)
+        } else {
+            (FileType::RegularFile, 0o444, self.get_file(ino)?.2)
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: 0,
+            atime: self.created,
+            mtime: self.created,
+            ctime: self.created,
+            crtime: self.created,
+            kind,
+            perm,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+            blksize: 1024,
+        })
+    }
+}
+
+impl fuser::Filesystem for StoreView {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+        if parent != ROOT_INO {
+            return reply.error(libc::ENOTDIR);
+        }
+        match name
+            .to_str()
+            .and_then(|name| self.by_name.get(name))
+            .and_then(|ino| self.attrs(*ino))
+        {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+        match self.attrs(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        if self.get_file(ino).is_none() {
+            reply.error(libc::ENOENT);
+        } else if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+        } else {
+            reply.opened(0, FOPEN_KEEP_CACHE);
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyData,
+    ) {
+        let span = debug_span!("read", ino, offset, size);
+        let _enter = span.enter();
+
+        let store = Arc::clone(&self.store);
+        let file = self
+            .get_file(ino)
+            .map(|(_, hash, size)| (hash.clone(), *size));
+
+        wrap_read(&self.dispatcher, "read", reply, async move {
+            let (hash, file_size) = file.ok_or(libc::ENOENT)?;
+            let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+            if offset >= file_size {
+                return Ok(vec![]);
+            }
+            let size = std::cmp::min(size as u64, file_size - offset) as usize;
+            Ok(store.get(&hash, offset, size).await?)
+        });
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectory,
+    ) {
+        if ino != ROOT_INO {
+            return reply.error(libc::ENOTDIR);
+        }
+        let start = usize::try_from(offset).unwrap_or(0);
+        for (n, (name, _, _)) in self.files.iter().enumerate().skip(start) {
+            if reply.add(n as u64 + 2, n as i64 + 1, FileType::RegularFile, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
@@ -0,0 +1,344 @@
+use crate::error::Error;
+use crate::hash::Hash;
+use crate::store::{Future, MutableFile, RecallInfo, Store};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+/// Number of staged bytes above which the staged files are written
+/// to tape as a container.
+const DEFAULT_BATCH_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Where a file is stored on tape.
+#[derive(Clone, Serialize, Deserialize)]
+struct Location {
+    container: u64,
+    offset: u64,
+    length: u64,
+}
+
+/// A store for tape libraries, e.g. an LTFS mount. Since writing
+/// small files to tape is slow, added files are first written to a
+/// staging directory, and then appended to tape in batches as a
+/// single container ('tape/<n>.dat') with an index ('tape/<n>.idx').
+/// Reading a file that is only on tape doesn't block; instead a
+/// recall is queued and the read fails with `Error::RecallPending`
+/// until `process_recalls()` has copied the file to the 'online'
+/// directory.
+pub struct TapeStore {
+    root: PathBuf,
+    batch_size: u64,
+    index: RwLock<HashMap<Hash, Location>>,
+    /// Lengths of the files in the staging directory.
+    staged: Mutex<HashMap<Hash, u64>>,
+    recalls: Mutex<HashMap<Hash, SystemTime>>,
+}
+
+impl TapeStore {
+    pub fn new(root: PathBuf) -> std::io::Result<Self> {
+        let root = root.canonicalize()?;
+
+        for dir in &["staging", "tape", "online"] {
+            std::fs::create_dir_all(root.join(dir))?;
+        }
+
+        let mut index = HashMap::new();
+        for entry in std::fs::read_dir(root.join("tape"))? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "idx") {
+                continue;
+            }
+            let container_index: HashMap<Hash, Location> =
+                serde_json::from_slice(&std::fs::read(&path)?)?;
+            index.extend(container_index);
+        }
+
+        let mut staged = HashMap::new();
+        for entry in std::fs::read_dir(root.join("staging"))? {
+            let entry = entry?;
+            if let Some(hash) = entry.file_name().to_str().and_then(Hash::from_hex) {
+                staged.insert(hash, entry.metadata()?.len());
+            }
+        }
+
+        Ok(Self {
+            root,
+            batch_size: DEFAULT_BATCH_SIZE,
+            index: RwLock::new(index),
+            staged: Mutex::new(staged),
+            recalls: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_batch_size(mut self, batch_size: u64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    fn staging_path(&self, file_hash: &Hash) -> PathBuf {
+        self.root.join("staging").join(file_hash.to_hex())
+    }
+
+    fn online_path(&self, file_hash: &Hash) -> PathBuf {
+        self.root.join("online").join(file_hash.to_hex())
+    }
+
+    fn container_path(&self, container: u64, ext: &str) -> PathBuf {
+        self.root
+            .join("tape")
+            .join(format!("{}.{}", container, ext))
+    }
+
+    fn write_container_index(&self, container: u64) -> std::io::Result<()> {
+        let index = self.index.read().unwrap();
+        let container_index: HashMap<&Hash, &Location> = index
+            .iter()
+            .filter(|(_, loc)| loc.container == container)
+            .collect();
+        let tmp = self.container_path(container, "idx.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&container_index).unwrap())?;
+        std::fs::rename(&tmp, self.container_path(container, "idx"))
+    }
+
+    /// Append all staged files to tape as a new container.
+    pub fn flush(&self) -> std::io::Result<()> {
+        let mut staged = self.staged.lock().unwrap();
+        if staged.is_empty() {
+            return Ok(());
+        }
+
+        let container = {
+            let index = self.index.read().unwrap();
+            index
+                .values()
+                .map(|loc| loc.container + 1)
+                .max()
+                .unwrap_or(0)
+        };
+
+        let mut locations = vec![];
+        let mut out = std::fs::File::create(self.container_path(container, "dat"))?;
+        let mut offset = 0;
+        for file_hash in staged.keys() {
+            let data = std::fs::read(self.staging_path(file_hash))?;
+            out.write_all(&data)?;
+            locations.push((
+                file_hash.clone(),
+                Location {
+                    container,
+                    offset,
+                    length: data.len() as u64,
+                },
+            ));
+            offset += data.len() as u64;
+        }
+        out.sync_all()?;
+
+        self.index.write().unwrap().extend(locations);
+        self.write_container_index(container)?;
+
+        for file_hash in staged.keys() {
+            std::fs::remove_file(self.staging_path(file_hash))?;
+        }
+        info!(
+            "Wrote {} files ({} bytes) to tape container {} of store '{}'.",
+            staged.len(),
+            offset,
+            container,
+            self.get_url()
+        );
+        staged.clear();
+
+        Ok(())
+    }
+
+    /// Copy a file from tape to the online directory.
+    fn recall(&self, file_hash: &Hash) -> std::io::Result<()> {
+        let loc = match self.index.read().unwrap().get(file_hash) {
+            Some(loc) => loc.clone(),
+            None => return Ok(()),
+        };
+        let mut file = std::fs::File::open(self.container_path(loc.container, "dat"))?;
+        file.seek(SeekFrom::Start(loc.offset))?;
+        let mut data = vec![];
+        file.take(loc.length).read_to_end(&mut data)?;
+        let path = self.online_path(file_hash);
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, &data)?;
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+fn read_range(path: &Path, offset: u64, size: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = vec![];
+    file.take(size as u64).read_to_end(&mut data)?;
+    Ok(Some(data))
+}
+
+impl Store for TapeStore {
+    fn add<'a>(&'a self, file_hash: &Hash, data: &'a [u8]) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            if self.index.read().unwrap().contains_key(&file_hash)
+                || self.staged.lock().unwrap().contains_key(&file_hash)
+            {
+                return Ok(());
+            }
+
+            let path = self.staging_path(&file_hash);
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+
+            let total: u64 = {
+                let mut staged = self.staged.lock().unwrap();
+                staged.insert(file_hash, data.len() as u64);
+                staged.values().sum()
+            };
+            if total >= self.batch_size {
+                self.flush()?;
+            }
+            Ok(())
+        })
+    }
+
+    fn has<'a>(&'a self, file_hash: &Hash) -> Future<'a, bool> {
+        let res = self.index.read().unwrap().contains_key(file_hash)
+            || self.staged.lock().unwrap().contains_key(file_hash);
+        Box::pin(async move { Ok(res) })
+    }
+
+    fn get<'a>(&'a self, file_hash: &Hash, offset: u64, size: usize) -> Future<'a, Vec<u8>> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            for path in &[self.staging_path(&file_hash), self.online_path(&file_hash)] {
+                if let Some(data) = read_range(path, offset, size)? {
+                    return Ok(data);
+                }
+            }
+
+            if !self.index.read().unwrap().contains_key(&file_hash) {
+                return Err(Error::NoSuchHash(file_hash));
+            }
+
+            let mut recalls = self.recalls.lock().unwrap();
+            if !recalls.contains_key(&file_hash) {
+                debug!(
+                    "Queueing recall of {} from store '{}'.",
+                    file_hash.to_hex(),
+                    self.get_url()
+                );
+                recalls.insert(file_hash.clone(), SystemTime::now());
+            }
+            Err(Error::RecallPending(self.get_url(), file_hash))
+        })
+    }
+
+    fn create_file<'a>(&'a self) -> Option<Future<'a, Box<dyn MutableFile>>> {
+        None
+    }
+
+    fn get_url(&self) -> String {
+        format!("tape:{}", self.root.display())
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    fn check_health<'a>(&'a self) -> Future<'a, ()> {
+        Box::pin(async move {
+            let st = tokio::fs::metadata(self.root.join("tape")).await?;
+            if !st.is_dir() {
+                return Err(Error::StoreUnavailable(self.get_url()));
+            }
+            Ok(())
+        })
+    }
+
+    /// Forget the file and delete its staged or online copy. Its
+    /// data remains on tape until the tape is reclaimed.
+    fn remove<'a>(&'a self, file_hash: &Hash) -> Future<'a, ()> {
+        let file_hash = file_hash.clone();
+        Box::pin(async move {
+            self.staged.lock().unwrap().remove(&file_hash);
+            self.recalls.lock().unwrap().remove(&file_hash);
+            for path in &[self.staging_path(&file_hash), self.online_path(&file_hash)] {
+                match tokio::fs::remove_file(path).await {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(err.into())
+                    }
+                    _ => {}
+                }
+            }
+            let loc = self.index.write().unwrap().remove(&file_hash);
+            if let Some(loc) = loc {
+                self.write_container_index(loc.container)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn list<'a>(&'a self) -> Future<'a, Vec<(Hash, u64)>> {
+        Box::pin(async move {
+            let mut files: Vec<(Hash, u64)> = self
+                .index
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(hash, loc)| (hash.clone(), loc.length))
+                .collect();
+            let seen: HashSet<Hash> = files.iter().map(|(hash, _)| hash.clone()).collect();
+            files.extend(
+                self.staged
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(hash, _)| !seen.contains(hash))
+                    .map(|(hash, len)| (hash.clone(), *len)),
+            );
+            Ok(files)
+        })
+    }
+
+    fn recalls(&self) -> Vec<RecallInfo> {
+        let index = self.index.read().unwrap();
+        self.recalls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(hash, requested)| RecallInfo {
+                store: self.get_url(),
+                hash: hash.clone(),
+                size: index.get(hash).map_or(0, |loc| loc.length),
+                requested: *requested,
+            })
+            .collect()
+    }
+
+    fn process_recalls<'a>(&'a self) -> Future<'a, usize> {
+        Box::pin(async move {
+            let pending: Vec<Hash> = self.recalls.lock().unwrap().keys().cloned().collect();
+            for file_hash in &pending {
+                self.recall(file_hash)?;
+                self.recalls.lock().unwrap().remove(file_hash);
+                info!(
+                    "Recalled {} from store '{}'.",
+                    file_hash.to_hex(),
+                    self.get_url()
+                );
+            }
+            Ok(pending.len())
+        })
+    }
+}
@@ -0,0 +1,2599 @@
+use crate::{
+    control::{self, FileType, Request, Response},
+    fs::{Contents, Inode, Superblock},
+    fusefs::FilesystemState,
+    memory_store::MemoryStore,
+    store::Store,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+fn new_state(nr_stores: usize) -> Arc<RwLock<FilesystemState>> {
+    let stores: Vec<Arc<dyn Store>> = (0..nr_stores)
+        .map(|n| -> Arc<dyn Store> { Arc::new(MemoryStore::new(&format!("memory:{}", n))) })
+        .collect();
+    Arc::new(RwLock::new(FilesystemState::new(
+        Superblock::new(),
+        stores,
+        HashMap::new(),
+    )))
+}
+
+/// Create a mutable file in the root directory of `state`.
+async fn create_file(state: &Arc<RwLock<FilesystemState>>, name: &str, data: &[u8]) {
+    let store = Arc::clone(&state.read().unwrap().stores[0]);
+    let file = store.create_file().unwrap().await.unwrap();
+    file.write(0, data).await.unwrap();
+
+    let state = &mut *state.write().unwrap();
+    let ino = state
+        .superblock
+        .add_inode(Inode::new(Contents::MutableFile(Arc::new(
+            crate::fs::MutableFile {
+                store: store.get_url(),
+                file,
+            },
+        ))));
+    let root = state.superblock.get_root_ino();
+    state.superblock.link(root, name, ino).unwrap();
+}
+
+/// Send a request over a control channel, returning the final response.
+async fn request(state: &Arc<RwLock<FilesystemState>>, req: Request) -> Response {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    for b in serde_json::to_string(&req).unwrap().bytes() {
+        tx.send(b).unwrap();
+    }
+    tx.send(b'\n').unwrap();
+
+    let (progress, mut output) = control::output_channel();
+    control::handle_message(rx, progress, Arc::clone(state)).await;
+
+    let mut buf = vec![];
+    loop {
+        let data = output.read(buf.len() as u64, 4096).await;
+        if data.is_empty() {
+            break;
+        }
+        buf.extend(data);
+    }
+
+    let buf = String::from_utf8(buf).unwrap();
+    serde_json::from_str(buf.lines().last().unwrap()).unwrap()
+}
+
+fn stores_of(res: Response) -> Vec<String> {
+    match res {
+        Response::Status(status) => match status.info {
+            FileType::ImmutableFile { stores, .. } => stores,
+            info => panic!("unexpected file type {:?}", info),
+        },
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn finalize_and_mirror() {
+    let state = new_state(2);
+
+    create_file(&state, "foo", b"Hello World").await;
+
+    match request(
+        &state,
+        Request::Finalize {
+            path: "foo".into(),
+            recursive: false,
+        },
+    )
+    .await
+    {
+        Response::Finalize(res) => {
+            assert_eq!(res.files.len(), 1);
+            assert_eq!(res.files[0].size, 11);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let status = request(&state, Request::Status { path: "foo".into() }).await;
+    assert_eq!(stores_of(status), vec!["memory:0".to_string()]);
+
+    state.write().unwrap().mirror_stats.verify_percent = 100;
+
+    match request(
+        &state,
+        Request::Mirror {
+            path: "foo".into(),
+            store: "memory:1".into(),
+        },
+    )
+    .await
+    {
+        Response::Mirror(res) => assert_eq!(res.from, Some("memory:0".into())),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let status = request(&state, Request::Status { path: "foo".into() }).await;
+    assert_eq!(stores_of(status).len(), 2);
+    assert_eq!(state.read().unwrap().mirror_stats.verified, 1);
+    assert_eq!(state.read().unwrap().mirror_stats.failed, 0);
+
+    match request(&state, Request::Stats {}).await {
+        Response::Stats(res) => {
+            assert_eq!(res.stores["memory:0"].bytes_read, 11);
+            assert_eq!(res.stores["memory:1"].bytes_written, 11);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn finalize_recursive() {
+    let state = new_state(1);
+
+    create_file(&state, "a", b"foo").await;
+    create_file(&state, "b", b"foo").await;
+    create_file(&state, "c", b"barbaz").await;
+
+    match request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await
+    {
+        Response::Finalize(res) => assert_eq!(res.files.len(), 3),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::DiskUsage { path: "".into() }).await {
+        Response::DiskUsage(res) => {
+            assert_eq!(res.logical_size, 12);
+            assert_eq!(res.unique_size, 9);
+            assert_eq!(res.stores[0].size, 9);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+/// Mount a filesystem through the kernel and exercise it using
+/// ordinary file operations.
+#[cfg(feature = "fuse-tests")]
+#[test]
+fn kernel_mount() {
+    use std::io::Write;
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mount_point = std::env::temp_dir().join(format!("hugefs-test-{}", std::process::id()));
+    std::fs::create_dir_all(&mount_point).unwrap();
+
+    let state = new_state(2);
+    let fs = crate::fusefs::Filesystem::new(
+        Arc::clone(&state),
+        rt.handle().clone(),
+        crate::fusefs::CacheTtls {
+            default: std::time::Duration::from_secs(60),
+            immutable: std::time::Duration::from_secs(3600),
+        },
+        true,
+        &[("read".into(), 1)],
+        &[],
+    );
+
+    let session = fuser::spawn_mount2(fs, &mount_point, &[]).unwrap();
+
+    let path = mount_point.join("foo");
+    std::fs::File::create(&path)
+        .unwrap()
+        .write_all(b"Hello World")
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"Hello World");
+
+    let metrics = state.read().unwrap().counters.op_metrics();
+    assert!(metrics["read"].completed > 0);
+    assert_eq!(metrics["read"].in_flight, 0);
+    assert_eq!(metrics["read"].max_in_flight, 1);
+
+    let info: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(mount_point.join(".hugefsinfo")).unwrap()).unwrap();
+    assert!(info.get("foo").is_some());
+
+    std::fs::create_dir(mount_point.join("dir")).unwrap();
+    std::fs::rename(&path, mount_point.join("dir/bar")).unwrap();
+    assert_eq!(
+        std::fs::read(mount_point.join("dir/bar")).unwrap(),
+        b"Hello World"
+    );
+    assert!(std::fs::remove_dir(mount_point.join("dir")).is_err());
+
+    // POSIX rename semantics.
+    std::fs::create_dir(mount_point.join("dir/sub")).unwrap();
+    std::fs::create_dir(mount_point.join("empty")).unwrap();
+    let err = |res: std::io::Result<()>| res.unwrap_err().raw_os_error().unwrap();
+    assert_eq!(
+        err(std::fs::rename(
+            mount_point.join("dir"),
+            mount_point.join("dir/sub/dir")
+        )),
+        libc::EINVAL
+    );
+    assert_eq!(
+        err(std::fs::rename(
+            mount_point.join("empty"),
+            mount_point.join("dir")
+        )),
+        libc::ENOTEMPTY
+    );
+    assert_eq!(
+        err(std::fs::rename(
+            mount_point.join("dir/bar"),
+            mount_point.join("empty")
+        )),
+        libc::EISDIR
+    );
+    assert_eq!(
+        err(std::fs::rename(
+            mount_point.join("empty"),
+            mount_point.join("dir/bar")
+        )),
+        libc::ENOTDIR
+    );
+    std::fs::rename(mount_point.join("empty"), mount_point.join("dir/sub")).unwrap();
+    std::fs::write(mount_point.join("baz"), b"Bye").unwrap();
+    std::fs::rename(mount_point.join("baz"), mount_point.join("dir/bar")).unwrap();
+    assert_eq!(std::fs::read(mount_point.join("dir/bar")).unwrap(), b"Bye");
+
+    std::fs::remove_dir(mount_point.join("dir/sub")).unwrap();
+    std::fs::remove_file(mount_point.join("dir/bar")).unwrap();
+    std::fs::remove_dir(mount_point.join("dir")).unwrap();
+
+    drop(session);
+    let _ = std::fs::remove_dir(&mount_point);
+}
+
+#[cfg(feature = "chaos")]
+#[tokio::test]
+async fn flaky_reads() {
+    let store: Arc<dyn Store> = Arc::new(MemoryStore::new("memory:0"));
+    let data: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+    let (_, hash) = crate::hash::Hash::hash(&data[..]).unwrap();
+    store.add(&hash, &data).await.unwrap();
+
+    let flaky = crate::flaky_store::FlakyStore::new(store, 42);
+
+    for offset in 0..20 {
+        // Reads may fail or be short, but must never return wrong data.
+        if let Ok(res) = flaky.get(&hash, offset, 100).await {
+            assert!(res.len() <= 100);
+            assert_eq!(
+                &res[..],
+                &data[offset as usize..offset as usize + res.len()]
+            );
+        }
+    }
+}
+
+#[test]
+fn mock_time() {
+    use crate::fs::Time;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    crate::clock::set_mock_time(UNIX_EPOCH + Duration::from_secs(1_000_000_000));
+    let t = Time::now();
+    assert_eq!(t.0, 1_000_000_000 * 1_000_000_000);
+    assert_eq!(t.elapsed(), Duration::from_secs(0));
+    crate::clock::advance(Duration::from_secs(30 * 86400));
+    assert_eq!(t.elapsed(), Duration::from_secs(30 * 86400));
+    crate::clock::reset();
+}
+
+#[test]
+fn key_shares() {
+    use crate::encrypted_store::Key;
+
+    let dir = std::env::temp_dir().join(format!("hugefs-shares-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("key"), &[42u8; 32][..]).unwrap();
+    let key = Key::from_file(&dir.join("key")).unwrap();
+    let shares = key.split_to_files(&dir.join("key"), 5, 3).unwrap();
+
+    let combined = Key::from_share_files(&shares[1..4]).unwrap();
+    assert_eq!(combined.fingerprint(), key.fingerprint());
+
+    /* Too few, duplicate or corrupt shares are rejected rather
+     * than yielding a wrong key. */
+    assert!(Key::from_share_files(&shares[..2]).is_err());
+    assert!(
+        Key::from_share_files(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_err()
+    );
+    let mut data = std::fs::read(&shares[2]).unwrap();
+    data[5] ^= 1;
+    std::fs::write(&shares[2], data).unwrap();
+    assert!(Key::from_share_files(&shares[..3]).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn key_check() {
+    use crate::encrypted_store::{EncryptedStore, Key};
+    use aes_ctr::stream_cipher::generic_array::GenericArray;
+
+    let inner: Arc<dyn Store> = Arc::new(MemoryStore::new("memory:0"));
+    let store = EncryptedStore::new(
+        Arc::clone(&inner),
+        Key(GenericArray::clone_from_slice(&[1u8; 32])),
+    );
+    let other = EncryptedStore::new(
+        Arc::clone(&inner),
+        Key(GenericArray::clone_from_slice(&[2u8; 32])),
+    );
+
+    assert!(!store.check_key().await.unwrap());
+    store.write_ledger("fs", vec![]).await.unwrap();
+    assert!(store.check_key().await.unwrap());
+    assert!(!other.check_key().await.unwrap());
+}
+
+#[test]
+fn signed_manifest() {
+    use crate::manifest::{self, Manifest, ManifestFile, Mismatch};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-manifest-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("tree")).unwrap();
+    std::fs::write(dir.join("tree/foo"), b"Hello World").unwrap();
+
+    let public_key = manifest::generate_signing_key(&dir.join("key")).unwrap();
+    let key = manifest::read_signing_key(&dir.join("key")).unwrap();
+
+    let mut m = Manifest::new(vec![ManifestFile {
+        path: "foo".into(),
+        size: 11,
+        hash: crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1,
+    }]);
+    m.sign(&key);
+
+    assert!(m.verify_signature(Some(&public_key)).is_ok());
+    assert!(m.verify_tree(&dir.join("tree"), &[]).unwrap().is_empty());
+
+    std::fs::write(dir.join("tree/foo"), b"Hello Wordl").unwrap();
+    std::fs::write(dir.join("tree/bar"), b"").unwrap();
+    let mismatches = m.verify_tree(&dir.join("tree"), &[]).unwrap();
+    assert!(matches!(mismatches[0], Mismatch::Modified(_)));
+    assert!(matches!(mismatches[1], Mismatch::Unexpected(_)));
+
+    m.files[0].size = 12;
+    assert!(m.verify_signature(None).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn offline_media() {
+    let state = new_state(1);
+
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "foo".into(),
+            recursive: false,
+        },
+    )
+    .await;
+
+    let name = "usb".to_string();
+
+    request(
+        &state,
+        Request::RegisterMedia {
+            name: name.clone(),
+            url: "memory:usb".into(),
+        },
+    )
+    .await;
+
+    match request(&state, Request::AttachMedia { name: name.clone() }).await {
+        Response::AttachMedia { present, copied } => {
+            assert_eq!(present, 1);
+            assert_eq!(copied, 1);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let status = request(&state, Request::Status { path: "foo".into() }).await;
+    assert_eq!(stores_of(status).len(), 2);
+
+    match request(&state, Request::DetachMedia { name: name.clone() }).await {
+        Response::DetachMedia { present } => assert_eq!(present, 1),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::Status { path: "foo".into() }).await {
+        Response::Status(status) => match status.info {
+            FileType::ImmutableFile {
+                stores,
+                offline_media,
+                ..
+            } => {
+                assert_eq!(stores.len(), 1);
+                assert_eq!(offline_media, vec![name]);
+            }
+            info => panic!("unexpected file type {:?}", info),
+        },
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn require_encryption() {
+    use crate::{error::Error, hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-encryption-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("store-config.json"),
+        br#"{"require_encryption":true}"#,
+    )
+    .unwrap();
+
+    let url = dir.to_str().unwrap();
+    assert!(matches!(
+        crate::store::open_store(url, &HashMap::new()),
+        Err(Error::EncryptionRequired(_))
+    ));
+
+    let store = LocalStore::new(dir.clone()).unwrap();
+    let (_, hash) = Hash::hash(&b"secret"[..]).unwrap();
+    assert!(matches!(
+        store.add(&hash, b"secret").await,
+        Err(Error::EncryptionRequired(_))
+    ));
+    assert!(store.create_file().is_none());
+
+    // Simulate a plaintext file written by a misconfigured daemon.
+    std::fs::write(dir.join(hash.to_hex()), b"secret").unwrap();
+    std::fs::write(
+        dir.join(Hash::hash(&b"other"[..]).unwrap().1.to_hex()),
+        b"ciphertext",
+    )
+    .unwrap();
+    assert_eq!(
+        store.plaintext_files().unwrap(),
+        vec![dir.join(hash.to_hex())]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn mirror_jobs() {
+    use crate::jobs::{JobKind, JobStatus};
+
+    let state = new_state(2);
+
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "foo".into(),
+            recursive: false,
+        },
+    )
+    .await;
+
+    let enqueue = |priority, key: &str, store: &str| Request::EnqueueJob {
+        kind: JobKind::Mirror {
+            path: "foo".into(),
+            store: store.into(),
+        },
+        priority,
+        idempotency_key: Some(key.into()),
+        callback: None,
+    };
+
+    match request(&state, enqueue(0, "a", "memory:1")).await {
+        Response::EnqueueJob { id, existing } => assert_eq!((id, existing), (1, false)),
+        res => panic!("unexpected response {:?}", res),
+    }
+    match request(&state, enqueue(0, "a", "memory:1")).await {
+        Response::EnqueueJob { id, existing } => assert_eq!((id, existing), (1, true)),
+        res => panic!("unexpected response {:?}", res),
+    }
+    request(&state, enqueue(1, "b", "memory:2")).await;
+
+    // The higher-priority job runs first.
+    assert!(control::run_next_job(&state).await);
+    assert_eq!(
+        state.read().unwrap().superblock.jobs.list()[0].status,
+        JobStatus::Queued
+    );
+    assert!(control::run_next_job(&state).await);
+    assert!(!control::run_next_job(&state).await);
+
+    match request(&state, Request::ListJobs {}).await {
+        Response::ListJobs { jobs } => {
+            assert_eq!(
+                jobs[0].status,
+                JobStatus::Done {
+                    from: Some("memory:0".into())
+                }
+            );
+            assert!(matches!(jobs[1].status, JobStatus::Failed { .. }));
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let status = request(&state, Request::Status { path: "foo".into() }).await;
+    assert_eq!(stores_of(status).len(), 2);
+
+    // The queue, including idempotency keys, survives a restart.
+    let mut json = vec![];
+    state
+        .read()
+        .unwrap()
+        .superblock
+        .write_json(&mut json)
+        .unwrap();
+    let mut superblock = Superblock::open_from_json(&mut &json[..]).unwrap();
+    assert_eq!(superblock.jobs.list().len(), 2);
+    let kind = JobKind::Mirror {
+        path: "foo".into(),
+        store: "memory:1".into(),
+    };
+    assert_eq!(
+        superblock
+            .jobs
+            .enqueue(kind, 0, Some("a".into()), None)
+            .unwrap(),
+        (1, true)
+    );
+}
+
+#[tokio::test]
+async fn secure_erase() {
+    let state = new_state(2);
+    let log_file = std::env::temp_dir().join(format!("hugefs-audit-{}", std::process::id()));
+    state.write().unwrap().secure_erase = true;
+    state.write().unwrap().audit_log = Some(crate::audit::AuditLog::new(log_file.clone()));
+
+    for name in &["foo", "bar"] {
+        create_file(&state, name, b"Hello World").await;
+    }
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+    request(
+        &state,
+        Request::Mirror {
+            path: "foo".into(),
+            store: "memory:1".into(),
+        },
+    )
+    .await;
+
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+
+    let unlink = |name: &str| {
+        let mut state = state.write().unwrap();
+        let root = state.superblock.get_root_ino();
+        state.superblock.unlink(root, name).unwrap();
+        state.release_hash(hash.clone());
+    };
+
+    // 'bar' still references the contents.
+    unlink("foo");
+    assert!(!control::run_next_job(&state).await);
+
+    unlink("bar");
+    assert!(state
+        .read()
+        .unwrap()
+        .superblock
+        .pending_erases
+        .contains(&hash));
+    assert!(control::run_next_job(&state).await);
+    assert!(state.read().unwrap().superblock.pending_erases.is_empty());
+
+    let stores = state.read().unwrap().stores.clone();
+    for store in stores {
+        assert!(!store.has(&hash).await.unwrap());
+    }
+
+    let log = std::fs::read_to_string(&log_file).unwrap();
+    let record: serde_json::Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+    assert_eq!(record["event"], "Erased");
+    assert_eq!(record["stores"].as_array().unwrap().len(), 2);
+
+    std::fs::remove_file(&log_file).unwrap();
+}
+
+#[tokio::test]
+async fn quotas() {
+    use crate::fs::{Quota, Usage};
+
+    let state = new_state(1);
+
+    create_file(&state, "foo", b"Hello World").await;
+
+    request(
+        &state,
+        Request::SetQuota {
+            path: "".into(),
+            quota: Some(Quota {
+                max_bytes: Some(16),
+                max_inodes: Some(2),
+            }),
+        },
+    )
+    .await;
+
+    match request(&state, Request::ListQuotas {}).await {
+        Response::ListQuotas { quotas } => {
+            assert_eq!(quotas.len(), 1);
+            assert_eq!(quotas[0].usage.bytes, 11);
+            assert_eq!(quotas[0].usage.inodes, 1);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // The usage is kept up to date as files are added, finalized
+    // and removed.
+    create_file(&state, "bar", b"Hi").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "bar".into(),
+            recursive: false,
+        },
+    )
+    .await;
+    {
+        let state = state.read().unwrap();
+        let usage = state.superblock.usage(state.superblock.get_root_ino());
+        assert_eq!((usage.bytes, usage.inodes), (13, 2));
+    }
+    {
+        let state = &mut *state.write().unwrap();
+        let root = state.superblock.get_root_ino();
+        state.superblock.unlink(root, "bar").unwrap();
+        let usage = state.superblock.usage(root);
+        assert_eq!((usage.bytes, usage.inodes), (11, 1));
+    }
+
+    let state = state.read().unwrap();
+    let root = state.superblock.get_root_ino();
+    let check = |bytes, inodes| state.superblock.check_quota(root, Usage { bytes, inodes });
+    assert!(check(5, 1).is_ok());
+    assert!(check(6, 0).is_err());
+    assert!(check(0, 2).is_err());
+}
+
+#[tokio::test]
+async fn evict_mirrored_files() {
+    let stores: Vec<Arc<dyn Store>> = vec![
+        Arc::new(MemoryStore::new("memory:0").with_max_size(12)),
+        Arc::new(MemoryStore::new("memory:1")),
+    ];
+    let state = Arc::new(RwLock::new(FilesystemState::new(
+        Superblock::new(),
+        stores.clone(),
+        HashMap::new(),
+    )));
+
+    for name in &["a", "b", "c"] {
+        create_file(&state, name, name.repeat(6).as_bytes()).await;
+    }
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+    for name in &["a", "b"] {
+        request(
+            &state,
+            Request::Mirror {
+                path: name.into(),
+                store: "memory:1".into(),
+            },
+        )
+        .await;
+    }
+
+    // Access 'c' first, so it's the least recently used file. It
+    // must be kept since it doesn't exist anywhere else.
+    let hash = |name: &str| {
+        crate::hash::Hash::hash(name.repeat(6).as_bytes())
+            .unwrap()
+            .1
+    };
+    for name in &["c", "a", "b"] {
+        stores[0].get(&hash(name), 0, 6).await.unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let freed = crate::fusefs::evict_from_store(&state, &stores[0])
+        .await
+        .unwrap();
+    assert_eq!(freed, 6);
+    assert!(!stores[0].has(&hash("a")).await.unwrap());
+    assert!(stores[0].has(&hash("b")).await.unwrap());
+    assert!(stores[0].has(&hash("c")).await.unwrap());
+    assert!(stores[1].has(&hash("a")).await.unwrap());
+    assert_eq!(stores[0].used_bytes().await.unwrap(), 12);
+}
+
+#[tokio::test]
+async fn tiering() {
+    let state = new_state(2);
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+    request(
+        &state,
+        Request::SetTierPolicy {
+            path: "".into(),
+            policy: Some(crate::fs::TierPolicy {
+                fast: "memory:0".into(),
+                archive: "memory:1".into(),
+                cold_after_days: 0,
+            }),
+        },
+    )
+    .await;
+
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+    let stores = state.read().unwrap().stores.clone();
+
+    assert_eq!(crate::tiering::demote_cold_files(&state).await.unwrap(), 1);
+    assert!(!stores[0].has(&hash).await.unwrap());
+    assert!(stores[1].has(&hash).await.unwrap());
+
+    crate::tiering::recall(Arc::clone(&state), hash.clone()).await;
+    assert!(stores[0].has(&hash).await.unwrap());
+
+    match request(&state, Request::ListTierPolicies {}).await {
+        Response::ListTierPolicies { policies } => assert_eq!(policies.len(), 1),
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn read_store_override() {
+    let state = new_state(2);
+    create_file(&state, "foo", b"Hello World").await;
+
+    match request(
+        &state,
+        Request::SetReadStore {
+            path: "foo".into(),
+            store: Some("memory:2".into()),
+            pid: None,
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    request(
+        &state,
+        Request::SetReadStore {
+            path: "foo".into(),
+            store: Some("memory:1".into()),
+            pid: Some(1234),
+        },
+    )
+    .await;
+    assert_eq!(state.read().unwrap().read_overrides.len(), 1);
+
+    request(
+        &state,
+        Request::SetReadStore {
+            path: "foo".into(),
+            store: None,
+            pid: Some(1234),
+        },
+    )
+    .await;
+    assert!(state.read().unwrap().read_overrides.is_empty());
+}
+
+#[tokio::test]
+async fn evict() {
+    let state = new_state(2);
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    let evict = |store: &str| {
+        request(
+            &state,
+            Request::Evict {
+                path: "foo".into(),
+                store: store.into(),
+            },
+        )
+    };
+
+    // The only copy can't be evicted.
+    match evict("memory:0").await {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    request(
+        &state,
+        Request::Mirror {
+            path: "foo".into(),
+            store: "memory:1".into(),
+        },
+    )
+    .await;
+
+    match evict("memory:0").await {
+        Response::Evict { removed: true } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+    match evict("memory:1").await {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+    let stores = state.read().unwrap().stores.clone();
+    assert!(!stores[0].has(&hash).await.unwrap());
+    assert!(stores[1].has(&hash).await.unwrap());
+}
+
+#[tokio::test]
+async fn pinning() {
+    let state = new_state(2);
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    match request(
+        &state,
+        Request::Pin {
+            path: "foo".into(),
+            store: "memory:1".into(),
+            remove: false,
+        },
+    )
+    .await
+    {
+        Response::Pin { files: 1 } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // The missing copy is restored.
+    assert_eq!(crate::fusefs::restore_pins(&state).await.unwrap(), 1);
+    assert_eq!(crate::fusefs::restore_pins(&state).await.unwrap(), 0);
+
+    match request(
+        &state,
+        Request::Evict {
+            path: "foo".into(),
+            store: "memory:1".into(),
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+    let stores = state.read().unwrap().stores.clone();
+    assert!(stores[1].has(&hash).await.unwrap());
+}
+
+#[tokio::test]
+async fn control_output_is_discarded() {
+    let (progress, mut output) = control::output_channel();
+    progress.report(1, 2);
+    drop(progress);
+
+    let head = output.read(0, 5).await;
+    assert_eq!(head.len(), 5);
+    let tail = output.read(5, 4096).await;
+    assert!(tail.ends_with(b"\n"));
+
+    // Output before the last read offset is gone.
+    assert!(output.read(0, 4096).await.is_empty());
+    assert!(output.read((5 + tail.len()) as u64, 4096).await.is_empty());
+}
+
+#[tokio::test]
+async fn adopt() {
+    let state = new_state(2);
+    let src = std::env::temp_dir().join(format!("hugefs-adopt-{}", std::process::id()));
+    std::fs::write(&src, b"Hello World").unwrap();
+
+    let res = request(
+        &state,
+        Request::Adopt {
+            src: src.clone(),
+            dest: "foo".into(),
+            store: Some("memory:1".into()),
+            link: false,
+        },
+    )
+    .await;
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+    match res {
+        Response::Adopt { hash: h, size, .. } => {
+            assert_eq!(h, hash);
+            assert_eq!(size, 11);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    assert_eq!(
+        stores_of(request(&state, Request::Status { path: "foo".into() }).await),
+        vec!["memory:1".to_string()]
+    );
+
+    // Adopting onto an existing name fails.
+    match request(
+        &state,
+        Request::Adopt {
+            src: src.clone(),
+            dest: "foo".into(),
+            store: None,
+            link: false,
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    std::fs::remove_file(&src).unwrap();
+}
+
+#[tokio::test]
+async fn snapshots() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+
+    // Mutable files can't be snapshotted.
+    match request(&state, Request::CreateSnapshot { name: "s1".into() }).await {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    match request(&state, Request::CreateSnapshot { name: "s1".into() }).await {
+        Response::CreateSnapshot {} => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::CreateSnapshot { name: "s1".into() }).await {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::ListSnapshots {}).await {
+        Response::ListSnapshots { snapshots } => {
+            assert_eq!(snapshots.len(), 1);
+            assert_eq!(snapshots[0].name, "s1");
+            assert_eq!(snapshots[0].usage.bytes, 11);
+            assert_eq!(snapshots[0].usage.inodes, 1);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+    {
+        let state = state.read().unwrap();
+        let root = state.superblock.snapshots()["s1"];
+        assert!(state.superblock.check_mutable(root).is_err());
+        assert!(state
+            .superblock
+            .check_mutable(state.superblock.get_root_ino())
+            .is_ok());
+    }
+
+    // The snapshot keeps the file referenced after it's deleted.
+    let root_ino = state.read().unwrap().superblock.get_root_ino();
+    state
+        .write()
+        .unwrap()
+        .superblock
+        .unlink(root_ino, "foo")
+        .unwrap();
+    assert!(state.read().unwrap().superblock.is_referenced(&hash));
+
+    match request(&state, Request::DeleteSnapshot { name: "s1".into() }).await {
+        Response::DeleteSnapshot {} => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+    assert!(!state.read().unwrap().superblock.is_referenced(&hash));
+
+    match request(&state, Request::ListSnapshots {}).await {
+        Response::ListSnapshots { snapshots } => assert!(snapshots.is_empty()),
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn ingest_inbox() {
+    let state = new_state(1);
+    let dir = std::env::temp_dir().join(format!("hugefs-inbox-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("foo"), b"Hello World").unwrap();
+    std::fs::write(dir.join(".partial"), b"Hello").unwrap();
+
+    let mut inbox = crate::ingest::Inbox::new(dir.clone(), "".into(), true);
+    inbox.settle = std::time::Duration::from_secs(0);
+
+    assert_eq!(inbox.scan(&state).await.unwrap(), 1);
+    assert!(!dir.join("foo").exists());
+    assert!(dir.join(".partial").exists());
+    assert_eq!(
+        stores_of(request(&state, Request::Status { path: "foo".into() }).await),
+        vec!["memory:0".to_string()]
+    );
+
+    // A file that conflicts with an existing one stays in the inbox.
+    std::fs::write(dir.join("foo"), b"Bye").unwrap();
+    assert_eq!(inbox.scan(&state).await.unwrap(), 0);
+    assert!(dir.join("foo").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn clone() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+
+    // Mutable files can't be cloned.
+    match request(
+        &state,
+        Request::Clone {
+            src: "foo".into(),
+            dst: "bar".into(),
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    let ino = match request(
+        &state,
+        Request::Clone {
+            src: "foo".into(),
+            dst: "bar".into(),
+        },
+    )
+    .await
+    {
+        Response::Clone { ino } => ino,
+        res => panic!("unexpected response {:?}", res),
+    };
+
+    let state = state.read().unwrap();
+    let orig = state.superblock.lookup_path("foo".as_ref()).unwrap();
+    let copy = state.superblock.get_inode(ino).unwrap();
+    match (
+        &orig.read().unwrap().contents,
+        &copy.read().unwrap().contents,
+    ) {
+        (Contents::RegularFile(a), Contents::RegularFile(b)) => {
+            assert_eq!(a.hash, b.hash);
+            assert_eq!(a.length, b.length);
+        }
+        _ => panic!("expected immutable files"),
+    }
+    assert_eq!(
+        state
+            .superblock
+            .usage(state.superblock.get_root_ino())
+            .inodes,
+        2
+    );
+}
+
+#[tokio::test]
+async fn store_view() {
+    let store = Arc::new(MemoryStore::new("memory:0"));
+    let (_, hash) = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap();
+    store.add(&hash, b"Hello World").await.unwrap();
+
+    assert_eq!(store.list().await.unwrap(), vec![(hash, 11)]);
+
+    assert!(
+        crate::store_view::StoreView::new(store, tokio::runtime::Handle::current())
+            .await
+            .is_ok()
+    );
+}
+
+#[tokio::test]
+async fn check_open() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+    state.write().unwrap().check_open = true;
+
+    let ino = state
+        .read()
+        .unwrap()
+        .superblock
+        .lookup_path("foo".as_ref())
+        .unwrap()
+        .read()
+        .unwrap()
+        .ino;
+    assert!(crate::fusefs::check_available(&state, ino).await.is_ok());
+
+    let hash = crate::hash::Hash::hash(&b"Hello World"[..]).unwrap().1;
+    let store = Arc::clone(&state.read().unwrap().stores[0]);
+    store.remove(&hash).await.unwrap();
+
+    match crate::fusefs::check_available(&state, ino).await {
+        Err(crate::error::Error::NoSuchHash(h)) => assert_eq!(h, hash),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn has_many() {
+    let inner = Arc::new(MemoryStore::new("memory:0"));
+    let store = crate::presence_cache::CachedStore::new(inner.clone());
+    let (_, foo) = crate::hash::Hash::hash(&b"foo"[..]).unwrap();
+    let (_, bar) = crate::hash::Hash::hash(&b"bar"[..]).unwrap();
+    store.add(&foo, b"foo").await.unwrap();
+
+    let present = store.has_many(&[foo.clone(), bar.clone()]).await.unwrap();
+    assert!(present.contains(&foo));
+    assert!(!present.contains(&bar));
+
+    // Results are cached.
+    inner.add(&bar, b"bar").await.unwrap();
+    let present = store.has_many(&[foo.clone(), bar.clone()]).await.unwrap();
+    assert_eq!(present.len(), 1);
+}
+
+#[tokio::test]
+async fn remove_store_with_mutable_files() {
+    let state = new_state(2);
+    create_file(&state, "foo", b"Hello").await;
+    let remove = || Request::RemoveStore {
+        url: "memory:0".into(),
+    };
+
+    match request(&state, remove()).await {
+        Response::Error { msg } => assert!(msg.contains("being written")),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    request(
+        &state,
+        Request::Finalize {
+            path: "foo".into(),
+            recursive: false,
+        },
+    )
+    .await;
+    match request(&state, remove()).await {
+        Response::RemoveStore {} => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn status_tree_batches() {
+    let state = new_state(1);
+    for n in 0..1500 {
+        create_file(&state, &format!("{:04}", n), b"x").await;
+    }
+    let req = || Request::StatusTree { path: "".into() };
+
+    // Over a control file, the final response only has the last batch.
+    match request(&state, req()).await {
+        Response::StatusTree(res) => assert_eq!(res.files.len(), 501),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match control::execute(req(), Arc::clone(&state)).await {
+        Response::StatusTree(res) => {
+            assert_eq!(res.files.len(), 1501);
+            assert_eq!(res.files[1].path, std::path::Path::new("0000"));
+            assert_eq!(res.files[1500].path, std::path::Path::new("1499"));
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn local_has_many() {
+    use crate::hash::Hash;
+    use crate::local_store::LocalStore;
+
+    let dir = std::env::temp_dir().join(format!("hugefs-has-many-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("store-config.json"), b"{}").unwrap();
+    let store = LocalStore::new(dir.clone()).unwrap();
+
+    let hashes: Vec<Hash> = (0..200)
+        .map(|n| Hash::hash(n.to_string().as_bytes()).unwrap().1)
+        .collect();
+    for (n, hash) in hashes.iter().enumerate().filter(|(n, _)| n % 2 == 0) {
+        store.add(hash, n.to_string().as_bytes()).await.unwrap();
+    }
+
+    // Both checking each file and reading the store (which is only
+    // done once its size is known) find the same files.
+    let present = store.has_many(&hashes).await.unwrap();
+    assert_eq!(present.len(), 100);
+    store.list().await.unwrap();
+    assert_eq!(store.has_many(&hashes).await.unwrap(), present);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn rename_flags() {
+    use crate::fusefs::{rename_entry, RENAME_EXCHANGE, RENAME_NOREPLACE};
+
+    let state = new_state(1);
+    create_file(&state, "foo", b"foo").await;
+    create_file(&state, "bar", b"bar").await;
+
+    let state = &mut *state.write().unwrap();
+    let root = state.superblock.get_root_ino();
+    let entry = |state: &FilesystemState, name: &str| {
+        state
+            .superblock
+            .get_inode(root)
+            .unwrap()
+            .read()
+            .unwrap()
+            .get_directory()
+            .unwrap()
+            .get_entry(name)
+            .unwrap()
+    };
+    let (foo, bar) = (entry(state, "foo"), entry(state, "bar"));
+
+    assert!(rename_entry(state, root, "foo", root, "bar", RENAME_NOREPLACE).is_err());
+    assert!(rename_entry(
+        state,
+        root,
+        "foo",
+        root,
+        "bar",
+        RENAME_NOREPLACE | RENAME_EXCHANGE
+    )
+    .is_err());
+    assert!(rename_entry(state, root, "foo", root, "baz", RENAME_EXCHANGE).is_err());
+
+    assert!(rename_entry(state, root, "foo", root, "bar", RENAME_EXCHANGE).is_ok());
+    assert_eq!(entry(state, "foo"), bar);
+    assert_eq!(entry(state, "bar"), foo);
+
+    assert!(rename_entry(state, root, "foo", root, "baz", RENAME_NOREPLACE).is_ok());
+    assert_eq!(entry(state, "baz"), bar);
+}
+
+#[tokio::test]
+async fn deduplicate_on_finalize() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "foo".into(),
+            recursive: false,
+        },
+    )
+    .await;
+    assert_eq!(state.read().unwrap().counters.deduplicated_bytes(), 0);
+
+    create_file(&state, "bar", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "bar".into(),
+            recursive: false,
+        },
+    )
+    .await;
+    assert_eq!(state.read().unwrap().counters.deduplicated_bytes(), 11);
+}
+
+#[tokio::test]
+async fn staging_store() {
+    let state = new_state(2);
+
+    match request(
+        &state,
+        Request::SetStagingStore {
+            path: "".into(),
+            store: Some("memory:2".into()),
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(
+        &state,
+        Request::SetStagingStore {
+            path: "".into(),
+            store: Some("memory:1".into()),
+        },
+    )
+    .await
+    {
+        Response::SetStagingStore { dirs: 1 } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::Status { path: "".into() }).await {
+        Response::Status(status) => {
+            assert_eq!(status.staging_store, Some("memory:1".to_string()))
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn diff() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello").await;
+    create_file(&state, "bar", b"World").await;
+
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    for dir in &["old", "new", "copy"] {
+        let state = &mut *state.write().unwrap();
+        let ino = state
+            .superblock
+            .add_inode(Inode::new(Contents::Directory(crate::fs::Directory::new())));
+        let root = state.superblock.get_root_ino();
+        state.superblock.link(root, dir, ino).unwrap();
+    }
+
+    for (src, dst) in &[
+        ("foo", "old/foo"),
+        ("bar", "old/bar"),
+        ("bar", "old/gone"),
+        ("foo", "new/foo"),
+        ("foo", "new/bar"),
+        ("bar", "new/baz"),
+        ("foo", "copy/foo"),
+        ("bar", "copy/bar"),
+        ("bar", "copy/gone"),
+    ] {
+        match request(
+            &state,
+            Request::Clone {
+                src: (*src).into(),
+                dst: (*dst).into(),
+            },
+        )
+        .await
+        {
+            Response::Clone { .. } => {}
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+
+    let tree_hash = |path: &str| {
+        let state = state.read().unwrap();
+        let ino = state
+            .superblock
+            .lookup_path(std::path::Path::new(path))
+            .unwrap()
+            .read()
+            .unwrap()
+            .ino;
+        state.superblock.tree_hash(ino).unwrap()
+    };
+
+    assert!(tree_hash("old").is_some());
+    assert_eq!(tree_hash("old"), tree_hash("copy"));
+    assert_ne!(tree_hash("old"), tree_hash("new"));
+
+    match request(&state, Request::Status { path: "old".into() }).await {
+        Response::Status(status) => match status.info {
+            FileType::Directory { tree_hash: hash } => assert_eq!(hash, tree_hash("old")),
+            info => panic!("unexpected file type {:?}", info),
+        },
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(
+        &state,
+        Request::Diff {
+            old: "old".into(),
+            new: "copy".into(),
+        },
+    )
+    .await
+    {
+        Response::Diff { changes } => assert!(changes.is_empty()),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(
+        &state,
+        Request::Diff {
+            old: "old".into(),
+            new: "new".into(),
+        },
+    )
+    .await
+    {
+        Response::Diff { changes } => {
+            let changes: Vec<_> = changes
+                .iter()
+                .map(|entry| (entry.path.to_str().unwrap(), format!("{:?}", entry.change)))
+                .collect();
+            assert_eq!(
+                changes,
+                vec![
+                    ("bar", "Modified".to_string()),
+                    ("gone", "Removed".to_string()),
+                    ("baz", "Added".to_string()),
+                ]
+            );
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // Changing a tree invalidates the stored tree hashes above it.
+    {
+        let state = &mut *state.write().unwrap();
+        let copy = state
+            .superblock
+            .lookup_path(std::path::Path::new("copy"))
+            .unwrap()
+            .read()
+            .unwrap()
+            .ino;
+        state.superblock.unlink(copy, "gone").unwrap();
+    }
+    assert_ne!(tree_hash("old"), tree_hash("copy"));
+    assert!(tree_hash("").is_some());
+}
+
+#[tokio::test]
+async fn stats_store_health() {
+    use crate::local_store::LocalStore;
+
+    let state = new_state(1);
+
+    let dir = std::env::temp_dir().join(format!("hugefs-health-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("store-config.json"), b"{}").unwrap();
+    let store: Arc<dyn Store> = Arc::new(LocalStore::new(dir.clone()).unwrap());
+    state.write().unwrap().stores.push(Arc::clone(&store));
+
+    assert!(crate::fusefs::probe_store(&state, &store).await.is_none());
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(crate::fusefs::probe_store(&state, &store).await.is_some());
+
+    match request(&state, Request::Stats {}).await {
+        Response::Stats(res) => {
+            let unhealthy: Vec<_> = res.unhealthy_stores.keys().cloned().collect();
+            assert_eq!(unhealthy, vec![store.get_url()]);
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn corrupt_state() {
+    use crate::{error::Error, hash::Hash};
+
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+
+    let dir = std::env::temp_dir().join(format!("hugefs-state-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let state_file = dir.join("state.json");
+
+    state.read().unwrap().sync(&state_file).unwrap();
+    state.read().unwrap().sync(&state_file).unwrap();
+    let backup_path = Superblock::backup_path(&state_file);
+    assert!(backup_path.exists());
+
+    std::fs::write(&state_file, b"{\"inodes\":").unwrap();
+    assert!(matches!(
+        Superblock::open(&state_file),
+        Err(Error::CorruptState(..))
+    ));
+
+    let superblock = Superblock::open(&backup_path).unwrap();
+    assert!(superblock.lookup_path(std::path::Path::new("foo")).is_ok());
+
+    let (size, hash) = Hash::hash(&b"Hello World"[..]).unwrap();
+    let superblock = Superblock::from_files(&[(hash.clone(), size)]);
+    let path = std::path::Path::new("lost+found").join(hash.to_hex());
+    match &superblock
+        .lookup_path(&path)
+        .unwrap()
+        .read()
+        .unwrap()
+        .contents
+    {
+        Contents::RegularFile(file) => assert_eq!(file.length, size),
+        _ => panic!("expected an immutable file"),
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn diff_snapshots() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello").await;
+    create_file(&state, "bar", b"World").await;
+
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    match request(&state, Request::CreateSnapshot { name: "s1".into() }).await {
+        Response::CreateSnapshot {} => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    create_file(&state, "baz", b"!").await;
+
+    match request(
+        &state,
+        Request::Diff {
+            old: ".snapshots/s1".into(),
+            new: "".into(),
+        },
+    )
+    .await
+    {
+        Response::Diff { changes } => {
+            assert_eq!(changes.len(), 1);
+            assert_eq!(changes[0].path.to_str(), Some("baz"));
+            assert!(matches!(changes[0].change, control::Change::Added));
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(
+        &state,
+        Request::Status {
+            path: ".snapshots/s1/foo".into(),
+        },
+    )
+    .await
+    {
+        Response::Status(status) => assert_eq!(status.info.get_type(), "immutable"),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(
+        &state,
+        Request::Diff {
+            old: ".snapshots/s2".into(),
+            new: "".into(),
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn link() {
+    use crate::hash::Hash;
+
+    let state = new_state(1);
+    let store = Arc::clone(&state.read().unwrap().stores[0]);
+    let (size, hash) = Hash::hash(&b"Hello World"[..]).unwrap();
+
+    // The contents must already be in a store.
+    match request(
+        &state,
+        Request::Link {
+            path: "foo".into(),
+            hash: hash.clone(),
+            size,
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    store.add(&hash, b"Hello World").await.unwrap();
+
+    match request(
+        &state,
+        Request::Link {
+            path: "foo".into(),
+            hash: hash.clone(),
+            size,
+        },
+    )
+    .await
+    {
+        Response::Link { store } => assert_eq!(store, "memory:0"),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::Status { path: "foo".into() }).await {
+        Response::Status(status) => match status.info {
+            FileType::ImmutableFile {
+                size: file_size,
+                hash: file_hash,
+                ..
+            } => {
+                assert_eq!(file_size, size);
+                assert_eq!(file_hash, hash);
+            }
+            info => panic!("unexpected file type {:?}", info),
+        },
+        res => panic!("unexpected response {:?}", res),
+    }
+}
+
+#[tokio::test]
+async fn health_report() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+
+    match request(&state, Request::Health {}).await {
+        Response::Health(report) => {
+            assert_eq!(report.schema_version, crate::fs::SUPERBLOCK_VERSION);
+            assert_eq!(report.mutable_files, 1);
+            assert!(report.is_clean());
+        }
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    state
+        .write()
+        .unwrap()
+        .orphaned_files
+        .insert("memory:0".into(), 2);
+
+    match request(&state, Request::Health {}).await {
+        Response::Health(report) => assert!(!report.is_clean()),
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // State files predating versioning have version 0.
+    let mut json = vec![];
+    Superblock::new().write_json(&mut json).unwrap();
+    let mut value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    value.as_object_mut().unwrap().remove("version");
+    let json = serde_json::to_vec(&value).unwrap();
+    let superblock = Superblock::open_from_json(&mut &json[..]).unwrap();
+    assert_eq!(superblock.get_version(), 0);
+}
+
+#[tokio::test]
+async fn replication() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "".into(),
+            recursive: true,
+        },
+    )
+    .await;
+
+    let dir = std::env::temp_dir().join(format!("hugefs-replica-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let replica = dir.join("state.json");
+
+    tokio::spawn(crate::replication::replicate_state(
+        Arc::clone(&state),
+        replica.clone(),
+    ));
+
+    for _ in 0..100 {
+        if replica.exists() {
+            break;
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+    }
+
+    let standby = new_state(1);
+    tokio::spawn(crate::replication::follow_state(
+        Arc::clone(&standby),
+        replica.clone(),
+    ));
+
+    for _ in 0..100 {
+        if standby
+            .read()
+            .unwrap()
+            .superblock
+            .lookup_path(std::path::Path::new("foo"))
+            .is_ok()
+        {
+            break;
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+    }
+
+    let standby = standby.read().unwrap();
+    assert!(standby
+        .superblock
+        .lookup_path(std::path::Path::new("foo"))
+        .is_ok());
+    assert!(standby.superblock.check_writable().is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn read_only() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+    state.write().unwrap().superblock.read_only = true;
+
+    match request(
+        &state,
+        Request::Finalize {
+            path: "foo".into(),
+            recursive: false,
+        },
+    )
+    .await
+    {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    match request(&state, Request::CreateSnapshot { name: "s1".into() }).await {
+        Response::Error { .. } => {}
+        res => panic!("unexpected response {:?}", res),
+    }
+
+    // The flag isn't persisted.
+    let mut json = vec![];
+    state
+        .read()
+        .unwrap()
+        .superblock
+        .write_json(&mut json)
+        .unwrap();
+    assert!(
+        !Superblock::open_from_json(&mut &json[..])
+            .unwrap()
+            .read_only
+    );
+}
+
+#[test]
+fn mount_options() {
+    let parse = |opts: &[&str]| {
+        crate::fusefs::parse_mount_options(&opts.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    };
+    assert_eq!(
+        parse(&["allow_other,fsname=archive", "max_read=131072"]).unwrap(),
+        vec![
+            fuser::MountOption::AllowOther,
+            fuser::MountOption::FSName("archive".into()),
+            fuser::MountOption::CUSTOM("max_read=131072".into()),
+        ]
+    );
+    assert!(parse(&["max_read=0"]).is_err());
+    assert!(parse(&["fsname="]).is_err());
+    assert!(parse(&["allow_other=1"]).is_err());
+    assert!(parse(&["suid"]).is_err());
+}
+
+#[test]
+fn store_schemes() {
+    use crate::{encrypted_store::Keys, error::Error, store};
+
+    fn open_vault(url: &str, _keys: &Keys) -> store::Result<Arc<dyn Store>> {
+        Ok(Arc::new(MemoryStore::new(url)))
+    }
+
+    store::register_scheme("vault", open_vault).unwrap();
+    assert!(matches!(
+        store::register_scheme("vault", open_vault),
+        Err(Error::SchemeExists(_))
+    ));
+    assert!(matches!(
+        store::register_scheme("memory", open_vault),
+        Err(Error::SchemeExists(_))
+    ));
+
+    let store = store::open_store("vault:lib0", &Keys::new()).unwrap();
+    assert_eq!(store.get_url(), "vault:lib0");
+}
+
+#[tokio::test]
+async fn autosave() {
+    let state = new_state(1);
+
+    let dir = std::env::temp_dir().join(format!("hugefs-autosave-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let state_file = dir.join("state.json");
+
+    assert!(state.read().unwrap().sync_if_changed(&state_file).unwrap());
+    assert!(!state.read().unwrap().sync_if_changed(&state_file).unwrap());
+
+    create_file(&state, "foo", b"Hello World").await;
+    assert!(state.read().unwrap().sync_if_changed(&state_file).unwrap());
+
+    let superblock = Superblock::open(&state_file).unwrap();
+    assert!(superblock.lookup_path(std::path::Path::new("foo")).is_ok());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn local_store_orphans() {
+    use crate::local_store::{host_id, LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-orphans-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // The store canonicalizes its root.
+    let dir = dir.canonicalize().unwrap();
+    std::fs::write(dir.join("store-config.json"), b"{}").unwrap();
+    let store = LocalStore::new(dir.clone()).unwrap();
+
+    // No process has this pid, since it's above the kernel's limit.
+    let pid = 999_999_999;
+    let ours = format!("temp.{}.{}.x", host_id(), pid);
+    let legacy = format!("temp.{}.x", pid);
+    let other_host = format!("temp.some-other-host.{}.x", pid);
+    for name in &[&ours, &legacy, &other_host] {
+        std::fs::write(dir.join(name), b"").unwrap();
+    }
+
+    let mut orphans = store.orphans(true).unwrap();
+    orphans.sort();
+    let mut expected = vec![dir.join(&ours), dir.join(&legacy)];
+    expected.sort();
+    assert_eq!(orphans, expected);
+    assert!(dir.join(&other_host).exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn reattach_mutable_files() {
+    use crate::{hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-reattach-{}", std::process::id()));
+    let store_dir = dir.join("store");
+    std::fs::create_dir_all(&store_dir).unwrap();
+    std::fs::write(store_dir.join("store-config.json"), b"{}").unwrap();
+    let state_file = dir.join("state.json");
+
+    let store: Arc<dyn Store> = Arc::new(LocalStore::new(store_dir.clone()).unwrap());
+    let state = Arc::new(RwLock::new(FilesystemState::new(
+        Superblock::new(),
+        vec![Arc::clone(&store)],
+        HashMap::new(),
+    )));
+    create_file(&state, "foo", b"Hello World").await;
+    assert_eq!(crate::fusefs::flush_mutable_files(&state).await, 0);
+    state.read().unwrap().sync(&state_file).unwrap();
+    drop(state);
+
+    /* After a restart, the file is reopened and finalized. */
+    let restarted = Arc::new(RwLock::new(FilesystemState::new(
+        Superblock::open(&state_file).unwrap(),
+        vec![Arc::clone(&store)],
+        HashMap::new(),
+    )));
+    assert_eq!(restarted.read().unwrap().superblock.mutable_files(), 1);
+    assert_eq!(crate::fusefs::reattach_mutable_files(&restarted).await, 1);
+    let (size, hash) = Hash::hash(&b"Hello World"[..]).unwrap();
+    match &restarted
+        .read()
+        .unwrap()
+        .superblock
+        .lookup_path(std::path::Path::new("foo"))
+        .unwrap()
+        .read()
+        .unwrap()
+        .contents
+    {
+        Contents::RegularFile(file) => assert_eq!((file.length, &file.hash), (size, &hash)),
+        _ => panic!("expected an immutable file"),
+    }
+    assert_eq!(store.get(&hash, 0, 5).await.unwrap(), b"Hello");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn tape_store() {
+    use crate::{error::Error, hash::Hash, tape_store::TapeStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-tape-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let (_, hash1) = Hash::hash(&b"Hello"[..]).unwrap();
+    let (_, hash2) = Hash::hash(&b"World"[..]).unwrap();
+
+    {
+        let store = TapeStore::new(dir.clone()).unwrap().with_batch_size(10);
+        store.add(&hash1, b"Hello").await.unwrap();
+        assert_eq!(store.get(&hash1, 1, 3).await.unwrap(), b"ell");
+        store.add(&hash2, b"World").await.unwrap();
+        assert!(dir.join("tape/0.dat").exists());
+        assert!(store.has(&hash1).await.unwrap());
+    }
+
+    /* Files that are only on tape must be recalled first. */
+    let store = TapeStore::new(dir.clone()).unwrap();
+    assert_eq!(store.list().await.unwrap().len(), 2);
+    assert!(store.recalls().is_empty());
+    assert!(matches!(
+        store.get(&hash2, 0, 5).await,
+        Err(Error::RecallPending(_, _))
+    ));
+    let recalls = store.recalls();
+    assert_eq!(recalls.len(), 1);
+    assert_eq!(recalls[0].hash, hash2);
+    assert_eq!(recalls[0].size, 5);
+
+    assert_eq!(store.process_recalls().await.unwrap(), 1);
+    assert!(store.recalls().is_empty());
+    assert_eq!(store.get(&hash2, 0, 5).await.unwrap(), b"World");
+
+    store.remove(&hash2).await.unwrap();
+    assert!(!store.has(&hash2).await.unwrap());
+    assert!(TapeStore::new(dir.clone())
+        .unwrap()
+        .has(&hash1)
+        .await
+        .unwrap());
+    assert!(!TapeStore::new(dir.clone())
+        .unwrap()
+        .has(&hash2)
+        .await
+        .unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn local_store_clusters() {
+    use crate::{hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-cluster-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("store-config.json"),
+        r#"{"cluster_by_directory":true}"#,
+    )
+    .unwrap();
+
+    let (_, hash1) = Hash::hash(&b"Hello"[..]).unwrap();
+    let (_, hash2) = Hash::hash(&b"World"[..]).unwrap();
+    let hashes = vec![hash1.clone(), hash2.clone()];
+
+    {
+        let store = LocalStore::new(dir.clone()).unwrap();
+        store.add(&hash1, b"Hello").await.unwrap();
+        store.add(&hash2, b"World").await.unwrap();
+        assert_eq!(store.cluster("a", &hashes).await.unwrap(), 2);
+        assert_eq!(store.cluster("a", &hashes).await.unwrap(), 0);
+        assert_eq!(store.get(&hash1, 1, 3).await.unwrap(), b"ell");
+        assert_eq!(store.list().await.unwrap().len(), 2);
+        assert!(dir.join("clusters/a").join(hash2.to_hex()).exists());
+        assert!(!dir.join(hash2.to_hex()).exists());
+    }
+
+    let store = LocalStore::new(dir.clone()).unwrap();
+    assert!(store.has(&hash1).await.unwrap());
+    store.remove(&hash2).await.unwrap();
+    assert!(!store.has(&hash2).await.unwrap());
+    assert_eq!(store.list().await.unwrap().len(), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn local_store_gc() {
+    use crate::{error::Error, hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-gc-{}", std::process::id()));
+    let store_dir = dir.join("store");
+    std::fs::create_dir_all(&store_dir).unwrap();
+    std::fs::write(store_dir.join("store-config.json"), b"{}").unwrap();
+    let store = LocalStore::new(store_dir).unwrap();
+    let tick = || std::thread::sleep(std::time::Duration::from_millis(20));
+
+    let (_, legacy) = Hash::hash(&b"legacy"[..]).unwrap();
+    store.add(&legacy, b"legacy").await.unwrap();
+    assert!(matches!(store.gc(true), Err(Error::NoLedgers(_))));
+    let original = dir.join("original");
+    std::fs::write(&original, b"adopted").unwrap();
+    tick();
+
+    store.write_ledger("fs1", vec![]).await.unwrap();
+    tick();
+    let (_, garbage) = Hash::hash(&b"garbage"[..]).unwrap();
+    store.add(&garbage, b"garbage").await.unwrap();
+    let (_, live) = Hash::hash(&b"live"[..]).unwrap();
+    store.add(&live, b"live").await.unwrap();
+    tick();
+    store.write_ledger("fs1", vec![live.clone()]).await.unwrap();
+
+    /* The adopted file has an old mtime, but was added after the
+     * ledger. */
+    tick();
+    let (_, adopted) = Hash::hash(&b"adopted"[..]).unwrap();
+    store.adopt(&adopted, &original, true).await.unwrap();
+
+    assert_eq!(store.gc(false).unwrap(), vec![garbage.clone()]);
+    assert!(!store.has(&garbage).await.unwrap());
+    for hash in &[legacy, live, adopted] {
+        assert!(store.has(hash).await.unwrap());
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn local_store_erase_shared() {
+    use crate::{hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-erase-{}", std::process::id()));
+    let store_dir = dir.join("store");
+    std::fs::create_dir_all(&store_dir).unwrap();
+    std::fs::write(store_dir.join("store-config.json"), b"{}").unwrap();
+    let store = LocalStore::new(store_dir).unwrap();
+
+    let (_, hash) = Hash::hash(&b"Hello"[..]).unwrap();
+    store.add(&hash, b"Hello").await.unwrap();
+    store.write_ledger("fs1", vec![hash.clone()]).await.unwrap();
+    assert!(store.in_other_ledgers("fs2", &hash).await.unwrap());
+    assert!(!store.in_other_ledgers("fs1", &hash).await.unwrap());
+
+    /* Erasing an adopted file leaves the original intact. */
+    let original = dir.join("original");
+    std::fs::write(&original, b"World").unwrap();
+    let (_, hash2) = Hash::hash(&b"World"[..]).unwrap();
+    store.adopt(&hash2, &original, true).await.unwrap();
+    store.erase(&hash2).await.unwrap();
+    assert!(!store.has(&hash2).await.unwrap());
+    assert_eq!(std::fs::read(&original).unwrap(), b"World");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn local_store_sparse_files() {
+    use crate::{hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-sparse-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("store-config.json"), b"{}").unwrap();
+    let store = LocalStore::new(dir.clone()).unwrap();
+
+    const OFFSET: u64 = 1024 * 1024;
+    let file = store.create_file().unwrap().await.unwrap();
+    file.write(OFFSET, b"Hello").await.unwrap();
+    assert_eq!(file.len(), OFFSET + 5);
+    assert_eq!(file.read(OFFSET - 2, 4).await.unwrap(), b"\0\0He");
+
+    /* Filesystems without hole support report everything as data. */
+    let data = file.seek_extent(0, true).await.unwrap().unwrap();
+    assert!(data <= OFFSET);
+    assert_eq!(
+        file.seek_extent(data, false).await.unwrap(),
+        Some(OFFSET + 5)
+    );
+    assert_eq!(file.seek_extent(OFFSET + 5, true).await.unwrap(), None);
+
+    file.set_len(OFFSET + 2).await.unwrap();
+    assert_eq!(file.len(), OFFSET + 2);
+    file.set_len(2 * OFFSET).await.unwrap();
+    assert_eq!(file.read(OFFSET, 4).await.unwrap(), b"He\0\0");
+
+    let mut contents = vec![0u8; 2 * OFFSET as usize];
+    contents[OFFSET as usize..OFFSET as usize + 2].copy_from_slice(b"He");
+    let (len, hash) = file.finish(&|_| {}).await.unwrap();
+    assert_eq!((len, hash.clone()), Hash::hash(&contents[..]).unwrap());
+    assert_eq!(store.get(&hash, OFFSET - 1, 3).await.unwrap(), b"\0He");
+
+    /* Files added in one piece are written sparsely too. */
+    let (_, hash2) = Hash::hash(&contents[1..]).unwrap();
+    store.add(&hash2, &contents[1..]).await.unwrap();
+    assert_eq!(store.get(&hash2, OFFSET - 2, 4).await.unwrap(), b"\0He\0");
+    assert_eq!(
+        std::fs::metadata(dir.join(hash2.to_hex())).unwrap().len(),
+        2 * OFFSET - 1
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn local_store_migration() {
+    use crate::{error::Error, hash::Hash, local_store::LocalStore};
+
+    let dir = std::env::temp_dir().join(format!("hugefs-migrate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("store-config.json"), r#"{"max_size":1000}"#).unwrap();
+
+    let (_, hash1) = Hash::hash(&b"Hello"[..]).unwrap();
+    let (_, hash2) = Hash::hash(&b"World"[..]).unwrap();
+    let sharded = |hash: &Hash| {
+        let hex = hash.to_hex();
+        dir.join(&hex[0..2]).join(&hex[2..4]).join(hex)
+    };
+
+    let old = LocalStore::new(dir.clone()).unwrap();
+    old.add(&hash1, b"Hello").await.unwrap();
+    assert!(dir.join(hash1.to_hex()).exists());
+
+    assert_eq!(LocalStore::new(dir.clone()).unwrap().migrate().unwrap(), 1);
+    assert!(sharded(&hash1).exists());
+    assert!(!dir.join(hash1.to_hex()).exists());
+
+    /* A store opened before the migration still finds the file. */
+    assert_eq!(old.get(&hash1, 1, 3).await.unwrap(), b"ell");
+
+    let store = LocalStore::new(dir.clone()).unwrap();
+    assert_eq!(store.get_config().unwrap().max_size, Some(1000));
+    store.add(&hash2, b"World").await.unwrap();
+    assert!(sharded(&hash2).exists());
+    assert!(store.has(&hash1).await.unwrap());
+    assert_eq!(store.list().await.unwrap().len(), 2);
+    assert_eq!(store.migrate().unwrap(), 0);
+    assert_eq!(store.get_config().unwrap().format_version, 2);
+
+    let file = store.create_file().unwrap().await.unwrap();
+    file.write(0, b"Hello!").await.unwrap();
+    let (_, hash3) = file.finish(&|_| {}).await.unwrap();
+    assert!(sharded(&hash3).exists());
+
+    store.remove(&hash1).await.unwrap();
+    assert!(!store.has(&hash1).await.unwrap());
+
+    /* Stores written by a newer version are refused. */
+    std::fs::write(dir.join("store-config.json"), r#"{"format_version":1000}"#).unwrap();
+    assert!(matches!(
+        LocalStore::new(dir.clone()),
+        Err(Error::UnsupportedStoreFormat(_, 1000))
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn find_files() {
+    use crate::control::FindFilter;
+
+    let state = new_state(2);
+    create_file(&state, "a", b"Hello").await;
+    create_file(&state, "b", &[0u8; 2000]).await;
+    request(
+        &state,
+        Request::Finalize {
+            path: "a".into(),
+            recursive: false,
+        },
+    )
+    .await;
+
+    let find = |filter: FindFilter| {
+        let state = Arc::clone(&state);
+        async move {
+            match request(
+                &state,
+                Request::Find {
+                    path: "".into(),
+                    filter,
+                },
+            )
+            .await
+            {
+                Response::Find { paths } => Ok(paths
+                    .iter()
+                    .map(|p| p.to_str().unwrap().to_string())
+                    .collect::<Vec<_>>()),
+                Response::Error { msg } => Err(msg),
+                res => panic!("unexpected response {:?}", res),
+            }
+        }
+    };
+
+    assert_eq!(find(FindFilter::default()).await.unwrap(), vec!["a", "b"]);
+    let filter = FindFilter {
+        min_size: Some(1000),
+        ..Default::default()
+    };
+    assert_eq!(find(filter).await.unwrap(), vec!["b"]);
+    let filter = FindFilter {
+        file_type: Some("immutable".into()),
+        ..Default::default()
+    };
+    assert_eq!(find(filter).await.unwrap(), vec!["a"]);
+    let filter = FindFilter {
+        older_than_days: Some(1),
+        ..Default::default()
+    };
+    assert!(find(filter).await.unwrap().is_empty());
+    let filter = FindFilter {
+        present_in: Some("memory:0".into()),
+        missing_from: Some("memory:1".into()),
+        ..Default::default()
+    };
+    assert_eq!(find(filter).await.unwrap(), vec!["a", "b"]);
+    let filter = FindFilter {
+        missing_from: Some("memory:0".into()),
+        ..Default::default()
+    };
+    assert!(find(filter).await.unwrap().is_empty());
+
+    let filter = FindFilter {
+        file_type: Some("symlink".into()),
+        ..Default::default()
+    };
+    assert!(find(filter).await.is_err());
+    let filter = FindFilter {
+        present_in: Some("memory:2".into()),
+        ..Default::default()
+    };
+    assert!(find(filter).await.is_err());
+}
+
+#[tokio::test]
+async fn execute_without_control_file() {
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello").await;
+
+    assert!(matches!(
+        control::execute(Request::Stats {}, Arc::clone(&state)).await,
+        Response::Stats(_)
+    ));
+
+    /* gc only works on attached local stores. */
+    for store in &["memory:0", "/nonexistent"] {
+        let req = Request::Gc {
+            store: store.to_string(),
+            dry_run: true,
+        };
+        assert!(matches!(
+            control::execute(req, Arc::clone(&state)).await,
+            Response::Error { .. }
+        ));
+    }
+}
+
+#[cfg(feature = "fuse-tests")]
+#[test]
+fn bench_workload() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mount_point =
+        std::env::temp_dir().join(format!("hugefs-bench-test-{}", std::process::id()));
+    std::fs::create_dir_all(&mount_point).unwrap();
+
+    let state = new_state(1);
+    let fs = crate::fusefs::Filesystem::new(
+        Arc::clone(&state),
+        rt.handle().clone(),
+        crate::fusefs::CacheTtls {
+            default: std::time::Duration::from_secs(0),
+            immutable: std::time::Duration::from_secs(0),
+        },
+        false,
+        &[],
+        &[],
+    );
+    let session = fuser::spawn_mount2(fs, &mount_point, &[]).unwrap();
+
+    let workload = crate::bench::Workload {
+        small_files: 10,
+        small_file_size: 100,
+        large_file_size: 3 * 1024 * 1024 + 1,
+        random_reads: 20,
+        read_size: 4096,
+        stats: 50,
+    };
+    let phases = crate::bench::run(&workload, &mount_point, &state, &mut rt).unwrap();
+    drop(session);
+
+    assert_eq!(phases.len(), 4);
+    assert_eq!(phases[0].ops, 10);
+    assert_eq!(phases[1].bytes, 3 * 1024 * 1024 + 1);
+    assert_eq!(phases[2].ops, 20);
+    assert_eq!(phases[3].ops, 50);
+
+    std::fs::remove_dir_all(&mount_point).unwrap();
+}
+
+#[cfg(feature = "s3-gateway")]
+#[test]
+fn s3_ranges() {
+    use crate::s3_gateway::{format_http_date, parse_range};
+
+    assert_eq!(parse_range("bytes=0-9", 100), Some((0, 10)));
+    assert_eq!(parse_range("bytes=90-", 100), Some((90, 100)));
+    assert_eq!(parse_range("bytes=90-200", 100), Some((90, 100)));
+    assert_eq!(parse_range("bytes=-10", 100), Some((90, 100)));
+    assert_eq!(parse_range("bytes=100-", 100), None);
+    assert_eq!(parse_range("bytes=5-4", 100), None);
+    assert_eq!(parse_range("items=0-9", 100), None);
+
+    assert_eq!(
+        format_http_date(&crate::fs::Time::from_nanos(784111777, 0)),
+        "Sun, 06 Nov 1994 08:49:37 GMT"
+    );
+}
+
+#[cfg(feature = "s3-gateway")]
+#[tokio::test]
+async fn s3_list_objects() {
+    let state = new_state(1);
+
+    {
+        let superblock = &mut state.write().unwrap().superblock;
+        let mut add = |dir: &str, name: &str, contents: Contents| {
+            let ino = superblock.add_inode(Inode::new(contents));
+            let parent = superblock.lookup_path(std::path::Path::new(dir)).unwrap();
+            let parent = parent.read().unwrap().ino;
+            superblock.link(parent, name, ino).unwrap();
+        };
+        let file = || {
+            Contents::RegularFile(crate::fs::RegularFile {
+                length: 3,
+                hash: crate::hash::Hash::hash(&b"foo"[..]).unwrap().1,
+            })
+        };
+        let dir = || Contents::Directory(crate::fs::Directory::new());
+        add("", "a", dir());
+        add("", "a-b", file());
+        add("", "empty", dir());
+        add("", "z", file());
+        add("a", "c", dir());
+        add("a", "x", file());
+        add("a/c", "y", file());
+    }
+
+    let list = |query: &str| {
+        let query = crate::s3_gateway::parse_query(query);
+        let res = crate::s3_gateway::list_objects("bucket", &query, &state);
+        async move {
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let tags = |tag: &str| -> Vec<String> {
+                body.split(&format!("<{}>", tag))
+                    .skip(1)
+                    .map(|s| s[..s.find('<').unwrap()].to_string())
+                    .collect()
+            };
+            (
+                tags("Key"),
+                tags("Prefix").into_iter().skip(1).collect::<Vec<_>>(),
+                tags("NextContinuationToken").pop(),
+            )
+        }
+    };
+
+    let (keys, prefixes, _) = list("list-type=2").await;
+    assert_eq!(keys, vec!["a-b", "a/c/y", "a/x", "z"]);
+    assert!(prefixes.is_empty());
+
+    let (keys, _, _) = list("list-type=2&prefix=a/c").await;
+    assert_eq!(keys, vec!["a/c/y"]);
+
+    let (keys, prefixes, _) = list("list-type=2&delimiter=/").await;
+    assert_eq!(keys, vec!["a-b", "z"]);
+    assert_eq!(prefixes, vec!["a/"]);
+
+    let (keys, prefixes, token) = list("list-type=2&delimiter=/&max-keys=2").await;
+    assert_eq!(keys, vec!["a-b"]);
+    assert_eq!(prefixes, vec!["a/"]);
+    assert_eq!(token.as_deref(), Some("a/"));
+
+    let (keys, prefixes, token) =
+        list("list-type=2&delimiter=/&max-keys=2&continuation-token=a/").await;
+    assert_eq!(keys, vec!["z"]);
+    assert!(prefixes.is_empty());
+    assert_eq!(token, None);
+}
+
+#[tokio::test]
+async fn ninep_read() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let state = new_state(1);
+    create_file(&state, "foo", b"Hello World").await;
+    create_file(&state, "bar", b"").await;
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    tokio::spawn(crate::ninep::serve(addr, Arc::clone(&state)));
+
+    let mut stream = loop {
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::delay_for(std::time::Duration::from_millis(10)).await,
+        }
+    };
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut res = (s.len() as u16).to_le_bytes().to_vec();
+        res.extend_from_slice(s.as_bytes());
+        res
+    }
+
+    async fn call(stream: &mut tokio::net::TcpStream, msg_type: u8, body: &[u8]) -> (u8, Vec<u8>) {
+        let mut msg = (body.len() as u32 + 7).to_le_bytes().to_vec();
+        msg.push(msg_type);
+        msg.extend_from_slice(&1u16.to_le_bytes());
+        msg.extend_from_slice(body);
+        stream.write_all(&msg).await.unwrap();
+        let mut size = [0u8; 4];
+        stream.read_exact(&mut size).await.unwrap();
+        let mut res = vec![0u8; u32::from_le_bytes(size) as usize - 4];
+        stream.read_exact(&mut res).await.unwrap();
+        (res[0], res[3..].to_vec())
+    }
+
+    let mut body = 8192u32.to_le_bytes().to_vec();
+    body.extend(string("9P2000.L"));
+    let (res_type, res) = call(&mut stream, 100, &body).await;
+    assert_eq!(res_type, 101);
+    assert_eq!(&res[4..], &string("9P2000")[..]);
+
+    let mut body = 0u32.to_le_bytes().to_vec();
+    body.extend_from_slice(&(!0u32).to_le_bytes());
+    body.extend(string("user"));
+    body.extend(string(""));
+    assert_eq!(call(&mut stream, 104, &body).await.0, 105);
+
+    let mut body = 0u32.to_le_bytes().to_vec();
+    body.extend_from_slice(&1u32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend(string("foo"));
+    let (res_type, res) = call(&mut stream, 110, &body).await;
+    assert_eq!(res_type, 111);
+    assert_eq!(u16::from_le_bytes([res[0], res[1]]), 1);
+
+    let mut body = 1u32.to_le_bytes().to_vec();
+    body.push(1); // OWRITE
+    assert_eq!(call(&mut stream, 112, &body).await.0, 107);
+    body[4] = 0; // OREAD
+    assert_eq!(call(&mut stream, 112, &body).await.0, 113);
+
+    let mut body = 1u32.to_le_bytes().to_vec();
+    body.extend_from_slice(&6u64.to_le_bytes());
+    body.extend_from_slice(&100u32.to_le_bytes());
+    let (res_type, res) = call(&mut stream, 116, &body).await;
+    assert_eq!(res_type, 117);
+    assert_eq!(&res[4..], b"World");
+
+    /* Directory reads resume after the last entry returned. */
+    let mut body = 0u32.to_le_bytes().to_vec();
+    body.push(0);
+    assert_eq!(call(&mut stream, 112, &body).await.0, 113);
+    let read_dir = |offset: u64, count: u32| {
+        let mut body = 0u32.to_le_bytes().to_vec();
+        body.extend_from_slice(&offset.to_le_bytes());
+        body.extend_from_slice(&count.to_le_bytes());
+        body
+    };
+    let (_, all) = call(&mut stream, 116, &read_dir(0, 8192)).await;
+    let first_len = u16::from_le_bytes([all[4], all[5]]) as usize + 2;
+    assert!(all.len() - 4 > first_len);
+    let (_, first) = call(&mut stream, 116, &read_dir(0, first_len as u32 + 1)).await;
+    assert_eq!(&first[4..], &all[4..4 + first_len]);
+    let (_, rest) = call(&mut stream, 116, &read_dir(first_len as u64, 8192)).await;
+    assert_eq!(&rest[4..], &all[4 + first_len..]);
+    let (_, end) = call(&mut stream, 116, &read_dir(all.len() as u64 - 4, 8192)).await;
+    assert_eq!(end.len(), 4);
+}
+
+#[test]
+fn inode_generations() {
+    let mut superblock = Superblock::new();
+    let ino1 = superblock.add_inode(Inode::new(Contents::Directory(crate::fs::Directory::new())));
+    let ino2 = superblock.add_inode(Inode::new(Contents::Directory(crate::fs::Directory::new())));
+    let generation = |superblock: &Superblock, ino| {
+        superblock
+            .get_inode(ino)
+            .unwrap()
+            .read()
+            .unwrap()
+            .generation
+    };
+    let (gen1, gen2) = (generation(&superblock, ino1), generation(&superblock, ino2));
+    assert!(gen1 > 0);
+    assert!(gen2 > gen1);
+
+    let mut json = vec![];
+    superblock.write_json(&mut json).unwrap();
+    let superblock = Superblock::open_from_json(&mut &json[..]).unwrap();
+    assert_eq!(generation(&superblock, ino1), gen1);
+    assert_eq!(generation(&superblock, ino2), gen2);
+}
@@ -0,0 +1,176 @@
+use crate::control::copy_to_store;
+use crate::error::Result;
+use crate::fs::TierPolicy;
+use crate::fusefs::FilesystemState;
+use crate::hash::Hash;
+use crate::store::Store;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+/// Interval between scans for cold files.
+const TIERING_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+fn find_store(stores: &[Arc<dyn Store>], url: &str) -> Option<Arc<dyn Store>> {
+    stores.iter().find(|st| st.get_url() == url).cloned()
+}
+
+fn is_cold(policy: &TierPolicy, last_access: Option<SystemTime>) -> bool {
+    let cold_after = Duration::from_secs(policy.cold_after_days * SECONDS_PER_DAY);
+    last_access.map_or(true, |t| {
+        crate::clock::now()
+            .duration_since(t)
+            .unwrap_or(Duration::from_secs(0))
+            >= cold_after
+    })
+}
+
+/// Move the files that haven't been read recently from the fast
+/// store of their tiering policy to the archive store. Returns the
+/// number of files moved.
+pub async fn demote_cold_files(state: &Arc<RwLock<FilesystemState>>) -> Result<usize> {
+    let (files, stores, verify_percent) = {
+        let state = state.read().unwrap();
+        let mut pinned = HashMap::new();
+        let mut excluded = HashMap::new();
+        let mut files = vec![];
+        for (dir, hash, size) in state.superblock.tiered_files() {
+            let policy = &state.superblock.tier_policies[&dir];
+            // Files pinned to the fast store stay there, and files
+            // excluded from the archive never go there.
+            if !pinned
+                .entry(policy.fast.clone())
+                .or_insert_with(|| state.superblock.pinned_files(&policy.fast))
+                .contains_key(&hash)
+                && !excluded
+                    .entry(policy.archive.clone())
+                    .or_insert_with(|| state.superblock.excluded_hashes(&policy.archive))
+                    .contains(&hash)
+            {
+                files.push((policy.clone(), hash, size));
+            }
+        }
+        (
+            files,
+            state.stores.clone(),
+            state.mirror_stats.verify_percent,
+        )
+    };
+
+    let mut moved = 0;
+
+    for (policy, hash, size) in files {
+        let (fast, archive) = match (
+            find_store(&stores, &policy.fast),
+            find_store(&stores, &policy.archive),
+        ) {
+            (Some(fast), Some(archive)) => (fast, archive),
+            _ => continue,
+        };
+
+        if !fast.has(&hash).await? || !is_cold(&policy, fast.last_access(&hash).await?) {
+            continue;
+        }
+
+        if !archive.has(&hash).await? {
+            copy_to_store(
+                &hash,
+                size,
+                &[Arc::clone(&fast)],
+                &archive,
+                verify_percent,
+                &|_| {},
+                state,
+            )
+            .await?;
+        }
+
+        // Never drop the last intact copy, e.g. if the archive store
+        // turned out to be unhealthy.
+        if !crate::fusefs::has_other_copy(state, &fast, &hash, size).await {
+            error!(
+                "Not removing cold file {} from store '{}' since it has no other copy.",
+                hash.to_hex(),
+                policy.fast
+            );
+            continue;
+        }
+
+        info!(
+            "Moved cold file {} from store '{}' to '{}'.",
+            hash.to_hex(),
+            policy.fast,
+            policy.archive
+        );
+        fast.remove(&hash).await?;
+        state.write().unwrap().recalled.remove(&hash);
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+/// Copy a file that was read from an archive store back to the fast
+/// stores of the tiering policies that apply to it.
+pub async fn recall(state: Arc<RwLock<FilesystemState>>, hash: Hash) {
+    let (targets, size, stores) = {
+        let state = state.read().unwrap();
+        let mut targets = vec![];
+        let mut size = 0;
+        for (dir, file_hash, file_size) in state.superblock.tiered_files() {
+            if file_hash == hash {
+                let fast = &state.superblock.tier_policies[&dir].fast;
+                if !targets.contains(fast) {
+                    targets.push(fast.clone());
+                }
+                size = file_size;
+            }
+        }
+        (targets, size, state.stores.clone())
+    };
+
+    for url in targets {
+        let fast = match find_store(&stores, &url) {
+            Some(fast) => fast,
+            None => continue,
+        };
+        match fast.has(&hash).await {
+            Ok(false) => {}
+            _ => continue,
+        }
+        match copy_to_store(&hash, size, &stores, &fast, 0, &|_| {}, &state).await {
+            Ok(from) => info!(
+                "Recalled file {} from store '{}' to '{}'.",
+                hash.to_hex(),
+                from,
+                url
+            ),
+            Err(err) => {
+                error!(
+                    "Cannot recall file {} to store '{}': {}",
+                    hash.to_hex(),
+                    url,
+                    err
+                );
+                state.write().unwrap().recalled.remove(&hash);
+            }
+        }
+    }
+}
+
+/// Periodically move cold files to archive stores, unless the
+/// daemon should be idle.
+pub async fn run_tiering(state: Arc<RwLock<FilesystemState>>) {
+    loop {
+        let idle = state.read().unwrap().power.is_idle();
+        if !idle {
+            if let Err(err) = demote_cold_files(&state).await {
+                error!("Cannot move cold files: {}", err);
+            }
+        }
+        tokio::time::delay_for(TIERING_INTERVAL).await;
+    }
+}